@@ -12,6 +12,7 @@ use syn::{DeriveInput, Meta, MetaList};
 pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
     let mut tokio_argument = None;
     let mut smol_argument = None;
+    let mut async_std_argument = None;
 
     let args = parse_macro_input!(meta with Punctuated::<Meta, syn::Token![,]>::parse_terminated);
     for arg in args {
@@ -19,6 +20,8 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
             tokio_argument = Some(arg);
         } else if arg.path().is_ident("smol") {
             smol_argument = Some(arg);
+        } else if arg.path().is_ident("async_std") {
+            async_std_argument = Some(arg);
         } else {
             return quote_spanned! { arg.span() => compile_error!("Unknown attribute argument"); }
                 .into();
@@ -27,11 +30,27 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
 
     let mut input = parse_macro_input!(input as syn::ItemFn);
 
+    let is_async =
+        tokio_argument.is_some() || smol_argument.is_some() || async_std_argument.is_some();
+
     let return_type = match input.sig.output {
         syn::ReturnType::Default => quote! { () },
         syn::ReturnType::Type(_, ref ty) => quote! { #ty },
     };
 
+    // Streaming functions send the items they produce one by one, so the output channel carries
+    // items rather than the return value
+    let stream_item = match input.sig.output {
+        syn::ReturnType::Default => None,
+        syn::ReturnType::Type(_, ref ty) => {
+            streamed_item(ty, if is_async { "Stream" } else { "Iterator" })
+        }
+    };
+    let output_type = match stream_item {
+        Some(ref item) => quote! { #item },
+        None => return_type.clone(),
+    };
+
     let generic_params = &input.sig.generics;
     let generics = {
         let params: Vec<_> = input
@@ -47,6 +66,12 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
             .collect();
         quote! { <#(#params,)*> }
     };
+    let channel_generic_params = {
+        let mut generics = input.sig.generics.clone();
+        generics.params.push(syn::parse_quote! { CrossmistParentToChild: ::crossmist::Object + 'static });
+        generics.params.push(syn::parse_quote! { CrossmistChildToParent: ::crossmist::Object + 'static });
+        generics
+    };
     let generic_phantom: Vec<_> = input
         .sig
         .generics
@@ -144,15 +169,55 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
     };
 
     let return_type_wrapped;
-    let pin;
-    if tokio_argument.is_some() || smol_argument.is_some() {
-        return_type_wrapped = quote! { ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #return_type>>> };
-        pin = quote! { ::std::boxed::Box::pin };
-    } else {
-        return_type_wrapped = return_type.clone();
-        pin = quote! {};
+    let invoke;
+    let invocation = quote! { #type_ident::invoke(#(#args_from_tuple,)*) };
+    match (&stream_item, is_async) {
+        (None, false) => {
+            return_type_wrapped = return_type.clone();
+            invoke = invocation;
+        }
+        (None, true) => {
+            return_type_wrapped = quote! { ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #return_type>>> };
+            invoke = quote! { ::std::boxed::Box::pin(#invocation) };
+        }
+        (Some(item), false) => {
+            return_type_wrapped = quote! { ::std::boxed::Box<dyn ::std::iter::Iterator<Item = #item>> };
+            invoke = quote! { ::std::boxed::Box::new(#invocation) };
+        }
+        (Some(item), true) => {
+            let stream = quote! { ::std::pin::Pin<::std::boxed::Box<dyn ::crossmist::imp::futures_core::Stream<Item = #item>>> };
+            return_type_wrapped = quote! { ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = #stream>>> };
+            invoke = quote! {{
+                let future = #invocation;
+                ::std::boxed::Box::pin(async move { ::std::boxed::Box::pin(future.await) as #stream })
+            }};
+        }
     }
 
+    // The body of the child. A streaming function sends the items from inside the body, as an
+    // asynchronous one has to be polled by the runtime
+    let call_entry = quote! { entry.func.deserialize().expect("Failed to deserialize entry").call_object_box(()) };
+    let (body_args, body_return_type, body_result) = match stream_item {
+        Some(ref item) => (
+            quote! { entry: #entry_ident #generics, output_tx_handle: ::crossmist::handles::RawHandle },
+            quote! { () },
+            if is_async {
+                quote! { ::crossmist::imp::send_stream::<#item>(output_tx_handle, #call_entry.await).await }
+            } else {
+                quote! { ::crossmist::imp::send_items::<#item>(output_tx_handle, #call_entry) }
+            },
+        ),
+        None => (
+            quote! { entry: #entry_ident #generics },
+            return_type.clone(),
+            if is_async {
+                quote! { #call_entry.await }
+            } else {
+                call_entry
+            },
+        ),
+    };
+
     let body;
     if let Some(arg) = tokio_argument {
         let async_attribute = match arg {
@@ -164,8 +229,8 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
         };
         body = quote! {
             #async_attribute
-            async fn body #generic_params (entry: #entry_ident #generics) -> #return_type {
-                entry.func.deserialize().expect("Failed to deserialize entry").call_object_box(()).await
+            async fn body #generic_params (#body_args) -> #body_return_type {
+                #body_result
             }
         };
     } else if let Some(arg) = smol_argument {
@@ -176,47 +241,168 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
             }
         }
         body = quote! {
-            fn body #generic_params (entry: #entry_ident #generics) -> #return_type {
-                ::crossmist::imp::async_io::block_on(entry.func.deserialize().expect("Failed to deserialize entry").call_object_box(()))
+            fn body #generic_params (#body_args) -> #body_return_type {
+                ::crossmist::imp::async_io::block_on(async move { #body_result })
+            }
+        };
+    } else if let Some(arg) = async_std_argument {
+        match arg {
+            Meta::Path(_) => {}
+            _ => {
+                return quote_spanned! { arg.span() => compile_error!("Invalid syntax for 'async_std' argument"); }.into();
+            }
+        }
+        body = quote! {
+            fn body #generic_params (#body_args) -> #body_return_type {
+                ::crossmist::imp::async_std::task::block_on(async move { #body_result })
             }
         };
     } else {
         body = quote! {
-            fn body #generic_params (entry: #entry_ident #generics) -> #return_type {
-                entry.func.deserialize().expect("Failed to deserialize entry").call_object_box(())
+            fn body #generic_params (#body_args) -> #body_return_type {
+                #body_result
             }
         };
     }
 
+    let run_body = if stream_item.is_some() {
+        quote! {
+            body(self, args.0);
+        }
+    } else {
+        quote! {
+            let return_value = body(self);
+            // Avoid explicitly sending a () result
+            if ::crossmist::imp::if_void::<#return_type>().is_none() {
+                // If this function is async, there shouldn't be any task running at this
+                // moment, so it is fine (and more efficient) to use a sync sender
+                ::crossmist::imp::send_output::<#return_type>(args.0, return_value);
+            }
+        }
+    };
+
     let impl_code = if has_references {
         quote! {}
+    } else if let Some(ref item) = stream_item {
+        quote! {
+            pub fn spawn #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::ChildStream<#item>> {
+                self.spawn_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*)
+            }
+            pub fn spawn_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::ChildStream<#item>> {
+                use ::crossmist::BindValue;
+                unsafe { ::crossmist::blocking::spawn_stream(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options) }
+            }
+
+            ::crossmist::if_tokio! {
+                pub async fn spawn_tokio #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::tokio::ChildStream<#item>> {
+                    self.spawn_tokio_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*).await
+                }
+                pub async fn spawn_tokio_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::tokio::ChildStream<#item>> {
+                    use ::crossmist::BindValue;
+                    unsafe { ::crossmist::tokio::spawn_stream(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options).await }
+                }
+            }
+
+            ::crossmist::if_smol! {
+                pub async fn spawn_smol #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::smol::ChildStream<#item>> {
+                    self.spawn_smol_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*).await
+                }
+                pub async fn spawn_smol_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::smol::ChildStream<#item>> {
+                    use ::crossmist::BindValue;
+                    unsafe { ::crossmist::smol::spawn_stream(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options).await }
+                }
+            }
+
+            ::crossmist::if_async_std! {
+                pub async fn spawn_async_std #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::async_std::ChildStream<#item>> {
+                    self.spawn_async_std_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*).await
+                }
+                pub async fn spawn_async_std_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::async_std::ChildStream<#item>> {
+                    use ::crossmist::BindValue;
+                    unsafe { ::crossmist::async_std::spawn_stream(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options).await }
+                }
+            }
+        }
     } else {
         quote! {
             pub fn spawn #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::Child<#return_type>> {
+                self.spawn_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*)
+            }
+            pub fn spawn_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::Child<#return_type>> {
                 use ::crossmist::BindValue;
-                unsafe { ::crossmist::blocking::spawn(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound))))) }
+                unsafe { ::crossmist::blocking::spawn(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options) }
             }
             pub fn run #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<#return_type> {
-                self.spawn(#(#arg_names,)*)?.join()
+                Ok(self.spawn(#(#arg_names,)*)?.join()?)
+            }
+            pub fn spawn_deferred_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::PendingChild<#return_type>> {
+                use ::crossmist::BindValue;
+                unsafe { ::crossmist::blocking::spawn_deferred(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options) }
+            }
+            pub fn spawn_with_channel #channel_generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<(::crossmist::Child<#return_type>, ::crossmist::Duplex<CrossmistParentToChild, CrossmistChildToParent>)> {
+                self.spawn_with_channel_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*)
+            }
+            pub fn spawn_with_channel_with #channel_generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<(::crossmist::Child<#return_type>, ::crossmist::Duplex<CrossmistParentToChild, CrossmistChildToParent>)> {
+                use ::crossmist::BindValue;
+                unsafe { ::crossmist::control::spawn_with_channel(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options) }
+            }
+            pub fn spawn_with_kill_handle #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<(::crossmist::Child<#return_type>, ::crossmist::KillHandle)> {
+                self.spawn_with_kill_handle_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*)
+            }
+            pub fn spawn_with_kill_handle_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<(::crossmist::Child<#return_type>, ::crossmist::KillHandle)> {
+                let child = self.spawn_with(options, #(#arg_names,)*)?;
+                let kill_handle = child.get_kill_handle();
+                Ok((child, kill_handle))
             }
 
             ::crossmist::if_tokio! {
                 pub async fn spawn_tokio #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::tokio::Child<#return_type>> {
+                    self.spawn_tokio_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*).await
+                }
+                pub async fn spawn_tokio_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::tokio::Child<#return_type>> {
                     use ::crossmist::BindValue;
-                    unsafe { ::crossmist::tokio::spawn(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound))))).await }
+                    unsafe { ::crossmist::tokio::spawn(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options).await }
                 }
                 pub async fn run_tokio #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<#return_type> {
-                    self.spawn_tokio(#(#arg_names,)*).await?.join().await
+                    Ok(self.spawn_tokio(#(#arg_names,)*).await?.join().await?)
+                }
+                pub fn spawn_tokio_deferred_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::tokio::PendingChild<#return_type>> {
+                    use ::crossmist::BindValue;
+                    unsafe { ::crossmist::tokio::spawn_deferred(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options) }
                 }
             }
 
             ::crossmist::if_smol! {
                 pub async fn spawn_smol #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::smol::Child<#return_type>> {
+                    self.spawn_smol_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*).await
+                }
+                pub async fn spawn_smol_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::smol::Child<#return_type>> {
                     use ::crossmist::BindValue;
-                    unsafe { ::crossmist::smol::spawn(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound))))).await }
+                    unsafe { ::crossmist::smol::spawn(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options).await }
                 }
                 pub async fn run_smol #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<#return_type> {
-                    self.spawn_smol(#(#arg_names,)*).await?.join().await
+                    Ok(self.spawn_smol(#(#arg_names,)*).await?.join().await?)
+                }
+                pub fn spawn_smol_deferred_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::smol::PendingChild<#return_type>> {
+                    use ::crossmist::BindValue;
+                    unsafe { ::crossmist::smol::spawn_deferred(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options) }
+                }
+            }
+
+            ::crossmist::if_async_std! {
+                pub async fn spawn_async_std #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<::crossmist::async_std::Child<#return_type>> {
+                    self.spawn_async_std_with(&::crossmist::SpawnOptions::new(), #(#arg_names,)*).await
+                }
+                pub async fn spawn_async_std_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::async_std::Child<#return_type>> {
+                    use ::crossmist::BindValue;
+                    unsafe { ::crossmist::async_std::spawn(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options).await }
+                }
+                pub async fn run_async_std #generic_params(&self, #(#fn_args,)*) -> ::std::io::Result<#return_type> {
+                    Ok(self.spawn_async_std(#(#arg_names,)*).await?.join().await?)
+                }
+                pub fn spawn_async_std_deferred_with #generic_params(&self, options: &::crossmist::SpawnOptions, #(#fn_args,)*) -> ::std::io::Result<::crossmist::async_std::PendingChild<#return_type>> {
+                    use ::crossmist::BindValue;
+                    unsafe { ::crossmist::async_std::spawn_deferred(::std::boxed::Box::new(::crossmist::CallWrapper(#entry_ident:: #generics ::new(::std::boxed::Box::new(#bound)))), options) }
                 }
             }
         }
@@ -242,20 +428,9 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
             type Output = i32;
             #[allow(unreachable_code, clippy::diverging_sub_expression)] // If func returns !
             fn call_object_once(self, args: (::crossmist::handles::RawHandle,)) -> Self::Output {
+                ::crossmist::imp::report_panics::<#output_type>(args.0);
                 #body
-                let return_value = body(self);
-                // Avoid explicitly sending a () result
-                if ::crossmist::imp::if_void::<#return_type>().is_none() {
-                    use ::crossmist::handles::FromRawHandle;
-                    // If this function is async, there shouldn't be any task running at this
-                    // moment, so it is fine (and more efficient) to use a sync sender
-                    let output_tx_handle = args.0;
-                    let mut output_tx = unsafe {
-                        ::crossmist::Sender::<#return_type>::from_raw_handle(output_tx_handle)
-                    };
-                    output_tx.send(&return_value)
-                        .expect("Failed to send subprocess output");
-                }
+                #run_body
                 0
             }
         }
@@ -263,17 +438,17 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
         impl #generic_params ::crossmist::InternalFnOnce<(#(#fn_types,)*)> for #type_ident {
             type Output = #return_type_wrapped;
             fn call_object_once(self, args: (#(#fn_types,)*)) -> Self::Output {
-                #pin(#type_ident::invoke(#(#args_from_tuple,)*))
+                #invoke
             }
         }
         impl #generic_params ::crossmist::InternalFnMut<(#(#fn_types,)*)> for #type_ident {
             fn call_object_mut(&mut self, args: (#(#fn_types,)*)) -> Self::Output {
-                #pin(#type_ident::invoke(#(#args_from_tuple,)*))
+                #invoke
             }
         }
         impl #generic_params ::crossmist::InternalFn<(#(#fn_types,)*)> for #type_ident {
             fn call_object(&self, args: (#(#fn_types,)*)) -> Self::Output {
-                #pin(#type_ident::invoke(#(#args_from_tuple,)*))
+                #invoke
             }
         }
 
@@ -295,6 +470,31 @@ pub fn func(meta: TokenStream, input: TokenStream) -> TokenStream {
     TokenStream::from(expanded)
 }
 
+// Extract `T` from `impl Iterator<Item = T>` or `impl Stream<Item = T>`
+fn streamed_item(ty: &syn::Type, trait_name: &str) -> Option<syn::Type> {
+    let syn::Type::ImplTrait(ref impl_trait) = *ty else {
+        return None;
+    };
+    impl_trait.bounds.iter().find_map(|bound| {
+        let syn::TypeParamBound::Trait(ref bound) = *bound else {
+            return None;
+        };
+        let segment = bound.path.segments.last()?;
+        if segment.ident != trait_name {
+            return None;
+        }
+        let syn::PathArguments::AngleBracketed(ref args) = segment.arguments else {
+            return None;
+        };
+        args.args.iter().find_map(|arg| match *arg {
+            syn::GenericArgument::Binding(ref binding) if binding.ident == "Item" => {
+                Some(binding.ty.clone())
+            }
+            _ => None,
+        })
+    })
+}
+
 #[proc_macro_attribute]
 pub fn main(_meta: TokenStream, input: TokenStream) -> TokenStream {
     let mut input = parse_macro_input!(input as syn::ItemFn);
@@ -336,12 +536,50 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
     let generic_params = &input.generics.params;
     let generics_impl = quote! { <#generic_params> };
 
-    let generics_where = input.generics.where_clause;
+    let type_params: Vec<_> = input
+        .generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
 
-    let expanded = match input.data {
-        syn::Data::Struct(struct_) => {
-            let field_types: Vec<_> = struct_.fields.iter().map(|field| &field.ty).collect();
+    let field_types: Vec<_> = match input.data {
+        syn::Data::Struct(ref struct_) => struct_.fields.iter().map(|field| &field.ty).collect(),
+        syn::Data::Enum(ref enum_) => enum_
+            .variants
+            .iter()
+            .flat_map(|variant| variant.fields.iter().map(|field| &field.ty))
+            .collect(),
+        syn::Data::Union(_) => unimplemented!(),
+    };
 
+    // Require each field that depends on generic parameters to be serializable, so that the
+    // parameters themselves don't have to be bounded. Fields that mention the type itself are
+    // skipped, as bounding them would make trait resolution of recursive types cycle; they are
+    // serializable whenever the other fields are.
+    let field_bounds: Vec<_> = field_types
+        .iter()
+        .filter(|ty| {
+            let tokens = ty.to_token_stream();
+            mentions(&tokens, |ident| type_params.contains(ident))
+                && !mentions(&tokens, |token| token == ident)
+        })
+        .map(|ty| quote! { #ty: ::crossmist::Object })
+        .collect();
+
+    let generics_where = {
+        let predicates: Vec<_> = match input.generics.where_clause {
+            Some(ref w) => w.predicates.iter().map(|p| p.to_token_stream()).collect(),
+            None => Vec::new(),
+        };
+        quote! {
+            where
+                #(#predicates,)*
+                #(#field_bounds,)*
+        }
+    };
+
+    let expanded = match input.data {
+        syn::Data::Struct(ref struct_) => {
             let serialize_fields = match struct_.fields {
                 syn::Fields::Named(ref fields) => fields
                     .named
@@ -367,6 +605,27 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                 syn::Fields::Unit => Vec::new(),
             };
 
+            let size_hint_fields: Vec<_> = match struct_.fields {
+                syn::Fields::Named(ref fields) => fields
+                    .named
+                    .iter()
+                    .map(|field| {
+                        let ident = &field.ident;
+                        quote! { ::crossmist::Object::serialized_size_hint(&self.#ident) }
+                    })
+                    .collect(),
+                syn::Fields::Unnamed(ref fields) => fields
+                    .unnamed
+                    .iter()
+                    .enumerate()
+                    .map(|(i, _)| {
+                        let i = syn::Index::from(i);
+                        quote! { ::crossmist::Object::serialized_size_hint(&self.#i) }
+                    })
+                    .collect(),
+                syn::Fields::Unit => Vec::new(),
+            };
+
             let deserialize_fields = match struct_.fields {
                 syn::Fields::Named(ref fields) => {
                     let deserialize_fields = fields.named.iter().map(|field| {
@@ -390,13 +649,33 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                 }
             };
 
-            let generics_where_pod: Vec<_> = match generics_where {
-                Some(ref w) => w.predicates.iter().collect(),
-                None => Vec::new(),
+            let deserialize_into_fields: Vec<_> = match struct_.fields {
+                syn::Fields::Named(ref fields) => fields
+                    .named
+                    .iter()
+                    .map(|field| {
+                        let ident = &field.ident;
+                        quote! {
+                            unsafe { d.deserialize_into(&mut self.#ident) }?;
+                        }
+                    })
+                    .collect(),
+                syn::Fields::Unnamed(ref fields) => fields
+                    .unnamed
+                    .iter()
+                    .enumerate()
+                    .map(|(i, _)| {
+                        let i = syn::Index::from(i);
+                        quote! {
+                            unsafe { d.deserialize_into(&mut self.#i) }?;
+                        }
+                    })
+                    .collect(),
+                syn::Fields::Unit => Vec::new(),
             };
+
             let generics_where_pod = quote! {
-                where
-                    #(#generics_where_pod,)*
+                #generics_where
                     #(for<'serde> ::crossmist::imp::Identity<'serde, #field_types>: ::crossmist::imp::PlainOldData,)*
             };
 
@@ -405,22 +684,37 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                     fn serialize_self_non_trivial<'serde>(&'serde self, s: &mut ::crossmist::Serializer<'serde>) {
                         #(#serialize_fields)*
                     }
+                    fn serialized_size_hint(&self) -> ::std::option::Option<usize> {
+                        ::crossmist::imp::sum_size_hints(&[#(#size_hint_fields,)*])
+                    }
                     unsafe fn deserialize_self_non_trivial(d: &mut ::crossmist::Deserializer) -> ::std::io::Result<Self> {
                         #deserialize_fields
                     }
+                    unsafe fn deserialize_into_non_trivial(&mut self, d: &mut ::crossmist::Deserializer) -> ::std::io::Result<()> {
+                        #(#deserialize_into_fields)*
+                        Ok(())
+                    }
                 }
                 unsafe impl #generics_impl ::crossmist::imp::PlainOldData for #ident #generics #generics_where_pod {}
             }
         }
-        syn::Data::Enum(enum_) => {
-            let field_types: Vec<_> = enum_
-                .variants
-                .iter()
-                .flat_map(|variant| variant.fields.iter().map(|field| &field.ty))
-                .collect();
+        syn::Data::Enum(ref enum_) => {
+            // The variant index is stored in the smallest integer type that fits it, and is omitted
+            // if there is only one variant
+            let tag_type = match enum_.variants.len() {
+                0..=1 => None,
+                2..=0x100 => Some(quote! { u8 }),
+                0x101..=0x10000 => Some(quote! { u16 }),
+                _ => Some(quote! { u32 }),
+            };
+            let serialize_tag = |i: usize| match tag_type {
+                Some(ref tag_type) => quote! { s.serialize_temporary(#i as #tag_type); },
+                None => quote! {},
+            };
 
             let serialize_variants = enum_.variants.iter().enumerate().map(|(i, variant)| {
                 let ident = &variant.ident;
+                let serialize_tag = serialize_tag(i);
                 match &variant.fields {
                     syn::Fields::Named(fields) => {
                         let (refs, sers): (Vec<_>, Vec<_>) = fields
@@ -433,7 +727,7 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                             .unzip();
                         quote! {
                             Self::#ident{ #(#refs,)* } => {
-                                s.serialize(&(#i as usize));
+                                #serialize_tag
                                 #(#sers)*
                             }
                         }
@@ -447,7 +741,7 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                             .unzip();
                         quote! {
                             Self::#ident(#(#refs,)*) => {
-                                s.serialize(&(#i as usize));
+                                #serialize_tag
                                 #(#sers)*
                             }
                         }
@@ -455,13 +749,62 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                     syn::Fields::Unit => {
                         quote! {
                             Self::#ident => {
-                                s.serialize(&(#i as usize));
+                                #serialize_tag
                             }
                         }
                     }
                 }
             });
 
+            let tag_size = match tag_type {
+                Some(ref tag_type) => quote! { ::std::mem::size_of::<#tag_type>() },
+                None => quote! { 0 },
+            };
+            let size_hint_variants = enum_.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                match &variant.fields {
+                    syn::Fields::Named(fields) => {
+                        let (refs, hints): (Vec<_>, Vec<_>) = fields
+                            .named
+                            .iter()
+                            .map(|field| {
+                                let ident = &field.ident;
+                                (
+                                    quote! { ref #ident },
+                                    quote! { ::crossmist::Object::serialized_size_hint(#ident) },
+                                )
+                            })
+                            .unzip();
+                        quote! {
+                            Self::#ident{ #(#refs,)* } => ::crossmist::imp::sum_size_hints(
+                                &[::std::option::Option::Some(#tag_size), #(#hints,)*]
+                            )
+                        }
+                    }
+                    syn::Fields::Unnamed(fields) => {
+                        let (refs, hints): (Vec<_>, Vec<_>) = (0..fields.unnamed.len())
+                            .map(|i| {
+                                let ident = format_ident!("a{}", i);
+                                (
+                                    quote! { ref #ident },
+                                    quote! { ::crossmist::Object::serialized_size_hint(#ident) },
+                                )
+                            })
+                            .unzip();
+                        quote! {
+                            Self::#ident(#(#refs,)*) => ::crossmist::imp::sum_size_hints(
+                                &[::std::option::Option::Some(#tag_size), #(#hints,)*]
+                            )
+                        }
+                    }
+                    syn::Fields::Unit => {
+                        quote! {
+                            Self::#ident => ::std::option::Option::Some(#tag_size)
+                        }
+                    }
+                }
+            });
+
             let deserialize_variants = enum_.variants.iter().enumerate().map(|(i, variant)| {
                 let ident = &variant.ident;
 
@@ -489,16 +832,16 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                 }
             });
 
-            let generics_where_pod: Vec<_> = match generics_where {
-                Some(ref w) => w.predicates.iter().collect(),
-                None => Vec::new(),
-            };
             let generics_where_pod = quote! {
-                where
-                    #(#generics_where_pod,)*
+                #generics_where
                     #(for<'serde> ::crossmist::imp::Identity<'serde, #field_types>: ::crossmist::imp::PlainOldData,)*
             };
 
+            let deserialize_tag = match tag_type {
+                Some(ref tag_type) => quote! { d.deserialize::<#tag_type>()? as usize },
+                None => quote! { 0usize },
+            };
+
             quote! {
                 unsafe impl #generics_impl ::crossmist::NonTrivialObject for #ident #generics #generics_where {
                     fn serialize_self_non_trivial<'serde>(&'serde self, s: &mut ::crossmist::Serializer<'serde>) {
@@ -506,8 +849,13 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
                             #(#serialize_variants,)*
                         }
                     }
+                    fn serialized_size_hint(&self) -> ::std::option::Option<usize> {
+                        match self {
+                            #(#size_hint_variants,)*
+                        }
+                    }
                     unsafe fn deserialize_self_non_trivial(d: &mut ::crossmist::Deserializer) -> ::std::io::Result<Self> {
-                        match d.deserialize::<usize>()? {
+                        match #deserialize_tag {
                             #(#deserialize_variants,)*
                             _ => panic!("Unexpected enum variant"),
                         }
@@ -521,3 +869,21 @@ pub fn derive_object(input: TokenStream) -> TokenStream {
 
     TokenStream::from(expanded)
 }
+
+// Check if a token stream contains an identifier matching the predicate, looking into groups.
+fn mentions(
+    tokens: &proc_macro2::TokenStream,
+    mut predicate: impl FnMut(&proc_macro2::Ident) -> bool,
+) -> bool {
+    fn walk(
+        tokens: &proc_macro2::TokenStream,
+        predicate: &mut dyn FnMut(&proc_macro2::Ident) -> bool,
+    ) -> bool {
+        tokens.clone().into_iter().any(|token| match token {
+            proc_macro2::TokenTree::Ident(ref ident) => predicate(ident),
+            proc_macro2::TokenTree::Group(ref group) => walk(&group.stream(), predicate),
+            _ => false,
+        })
+    }
+    walk(tokens, &mut predicate)
+}
@@ -1,8 +1,8 @@
 //! Generic asynchronous implementation.
 //!
 //! This module contains generic definitions for functions using arbitrary asynchronous runtimes.
-//! The [`crossmist::tokio`] and [`crossmist::smol`] modules provides type and functions definitions
-//! for their respective runtimes. You should probably use those.
+//! The [`crossmist::tokio`], [`crossmist::smol`], and `crossmist::async_std` modules provide type and
+//! function definitions for their respective runtimes. You should probably use those.
 //!
 //!
 //! ## Channels
@@ -40,37 +40,154 @@
 //!
 //! let child = my_process.spawn_tokio().await?;
 //! ```
+//!
+//!
+//! ## Custom runtimes
+//!
+//! Channels are generic over the [`AsyncStream`] trait, which adapts the underlying OS stream to a
+//! runtime. To use a runtime crossmist has no module for, implement [`AsyncStream`] for a wrapper
+//! around the runtime's I/O object and use the generic types and functions of this module, e.g.
+//! [`channel`]`::<MyStream, T>()` or `Receiver::<MyStream, T>::try_from(sync_receiver)`. On
+//! Unix-like systems, the stream is a `SOCK_SEQPACKET` socket, so the wrapper has to be based on
+//! readiness notifications rather than on `AsyncRead`/`AsyncWrite`:
+//!
+//! ```ignore
+//! struct MyStream(my_runtime::Async<UnixStream>);
+//!
+//! unsafe impl AsyncStream for MyStream {
+//!     fn try_new(stream: SyncStream) -> Result<Self> {
+//!         stream.set_nonblocking(true)?;
+//!         Ok(Self(my_runtime::Async::new(stream)?))
+//!     }
+//!     fn as_handle(&self) -> BorrowedHandle<'_> {
+//!         self.0.get_ref().as_fd()
+//!     }
+//!     fn as_raw_handle(&self) -> RawHandle {
+//!         self.0.get_ref().as_raw_fd()
+//!     }
+//!     const IS_BLOCKING: bool = false;
+//!     async fn blocking_write<T>(&self, mut f: impl FnMut() -> Result<T> + Send) -> Result<T> {
+//!         self.0.write_with(|_| f()).await
+//!     }
+//!     fn poll_blocking_write<T>(
+//!         &self,
+//!         cx: &mut Context<'_>,
+//!         mut f: impl FnMut() -> Result<T>,
+//!     ) -> Poll<Result<T>> {
+//!         loop {
+//!             match f() {
+//!                 Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+//!                 result => return Poll::Ready(result),
+//!             }
+//!             ready!(self.0.poll_writable(cx))?;
+//!         }
+//!     }
+//!     async fn blocking_read<T>(&self, mut f: impl FnMut() -> Result<T> + Send) -> Result<T> {
+//!         self.0.read_with(|_| f()).await
+//!     }
+//!     fn poll_blocking_read<T>(
+//!         &self,
+//!         cx: &mut Context<'_>,
+//!         mut f: impl FnMut() -> Result<T>,
+//!     ) -> Poll<Result<T>> {
+//!         // Same as above, with poll_readable
+//!     }
+//!     // Optional, the default implementation waits on a separate thread
+//!     async fn wait_readable(&self, timeout: Duration) -> Result<bool> {
+//!         my_runtime::timeout(timeout, self.0.readable()).await.unwrap_or(Ok(false))
+//!     }
+//! }
+//! ```
+//!
+//! The stream must also implement [`Object`], so that channels can be passed to other processes.
+//! Serialize the handle with [`Serializer::serialize_handle`] and rebuild the stream from an
+//! [`OwnedHandle`] with [`AsyncStream::try_new`]. Children can be started with the synchronous API,
+//! converting the channels passed to them afterwards.
+//!
+//! If the channels do not have to pass handles, the `byte_stream` module, enabled by the
+//! `futures-io` feature, provides channels over any stream implementing the `futures-io` traits
+//! without implementing [`AsyncStream`].
 
 #[cfg(unix)]
-use crate::internals::{socketpair, SingleObjectReceiver, SingleObjectSender};
+use crate::internals::{
+    set_buffer_size, socketpair, ReceiveState, SendQueue, SingleObjectReceiver, SingleObjectSender,
+};
+use crate::serde::retain_buffer;
 use crate::{
-    handles::{AsRawHandle, BorrowedHandle, FromRawHandle, IntoRawHandle, RawHandle},
-    imp, subprocess, FnOnceObject, Object, Serializer,
+    handles::{AsHandle, BorrowedHandle, FromRawHandle, IntoRawHandle, OwnedHandle, RawHandle},
+    imp::{self, Output},
+    subprocess, ChannelOptions, Deserializer, FnOnceObject, NonTrivialObject, Object, Serializer,
+    SpawnOptions,
 };
 use std::fmt;
-use std::future::Future;
-use std::io::{Error, ErrorKind, Result};
+use std::future::{poll_fn, Future};
+use std::io::{Error, ErrorKind, PipeReader, Result};
 use std::marker::PhantomData;
-use std::sync::{Arc, Mutex};
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 #[cfg(windows)]
 use {
     crate::{
+        handles::AsRawHandle,
         imp::implements,
-        internals::{deserialize_with_handles, serialize_with_handles},
+        internals::{
+            create_sealed_buffer, deserialize_mapped_with_handles,
+            deserialize_mapped_with_handles_into, deserialize_with_handles,
+            deserialize_with_handles_into, pipe_bytes_available, serialize_with_handles,
+        },
+        options::Framing,
         pod::PlainOldData,
+        shm::ReadOnlyMapping,
+    },
+    std::{borrow::Cow, mem::MaybeUninit, os::windows::io},
+    windows::Win32::{
+        Foundation,
+        System::{Pipes, Threading, WindowsProgramming},
     },
-    std::{mem::MaybeUninit, os::windows::io},
-    windows::Win32::System::{Pipes, Threading, WindowsProgramming},
 };
 
+/// The synchronous stream a channel is built on.
+///
+/// This is a `SOCK_SEQPACKET` Unix socket on Unix-like systems and an anonymous pipe on Windows.
 #[cfg(unix)]
-pub(crate) type SyncStream = std::os::unix::net::UnixStream;
+pub type SyncStream = std::os::unix::net::UnixStream;
+/// The synchronous stream a channel is built on.
+///
+/// This is a `SOCK_SEQPACKET` Unix socket on Unix-like systems and an anonymous pipe on Windows.
 #[cfg(windows)]
-pub(crate) type SyncStream = std::fs::File;
+pub type SyncStream = std::fs::File;
 
 /// Runtime-dependent stream implementation.
+///
+/// This trait can be implemented outside of crossmist to support other runtimes, see
+/// [Custom runtimes](self#custom-runtimes). The required items differ between platforms, as
+/// channels are built on sockets on Unix-like systems and on pipes on Windows:
+///
+/// - On Unix-like systems, `IS_BLOCKING` and the readiness-based `blocking_*` and
+///   `poll_blocking_*` methods.
+/// - On Windows, `read` and `write`.
+///
+/// A portable implementation thus needs a `#[cfg]` for each platform. If handles do not have to
+/// be passed, the channels of the `byte_stream` module, which work with any `futures-io` stream,
+/// may be simpler to use.
+///
+/// # Safety
+///
+/// The implementation must perform I/O on exactly the stream it was created from and honor the
+/// blocking semantics documented on each method, as the framing code relies on both.
 pub unsafe trait AsyncStream: Object + Sized {
     /// Create the stream from a sync stream.
+    ///
+    /// On Unix-like systems, crossmist also uses this method to wait for other kinds of file
+    /// descriptors, such as a pidfd or a pipe, wrapped into a [`SyncStream`]: it creates a stream
+    /// and calls `blocking_read` with a closure that does not use the socket API. The
+    /// implementation must thus accept any file descriptor the runtime can poll for readability,
+    /// and must not assume that it is a socket beyond making it non-blocking.
     fn try_new(stream: SyncStream) -> Result<Self>;
 
     /// Borrow a handle to the underlying stream.
@@ -79,7 +196,10 @@ pub unsafe trait AsyncStream: Object + Sized {
     /// Get a raw handle to the underlying stream.
     fn as_raw_handle(&self) -> RawHandle;
 
-    /// Whether socket operations should be blocking.
+    /// Whether operations on the stream block the thread they are performed on.
+    ///
+    /// If set, waits that would otherwise be delegated to a helper thread, such as waiting for a
+    /// child to exit, are performed synchronously instead.
     #[cfg(unix)]
     const IS_BLOCKING: bool;
 
@@ -92,6 +212,18 @@ pub unsafe trait AsyncStream: Object + Sized {
         &self,
         f: impl FnMut() -> Result<T> + Send,
     ) -> impl Future<Output = Result<T>> + Send;
+    /// Poll a write.
+    ///
+    /// Calls `f`. If it returns `Err(WouldBlock)`, arranges for the task of `cx` to be woken once
+    /// the stream becomes writable and returns [`Poll::Pending`]. When the function returns
+    /// anything other than `Err(WouldBlock)`, returns [`Poll::Ready`]. `f` may be called several
+    /// times during a single poll.
+    #[cfg(unix)]
+    fn poll_blocking_write<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>>;
     /// Perform a write.
     #[cfg(windows)]
     fn write(&mut self, buf: &[u8]) -> impl Future<Output = Result<()>> + Send;
@@ -105,26 +237,107 @@ pub unsafe trait AsyncStream: Object + Sized {
         &self,
         f: impl FnMut() -> Result<T> + Send,
     ) -> impl Future<Output = Result<T>> + Send;
+    /// Poll a read.
+    ///
+    /// Calls `f`. If it returns `Err(WouldBlock)`, arranges for the task of `cx` to be woken once
+    /// the stream becomes readable and returns [`Poll::Pending`]. When the function returns
+    /// anything other than `Err(WouldBlock)`, returns [`Poll::Ready`]. `f` may be called several
+    /// times during a single poll.
+    #[cfg(unix)]
+    fn poll_blocking_read<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>>;
     /// Perform a read.
     #[cfg(windows)]
     fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<()>> + Send;
+
+    /// Wait until the stream becomes readable, i.e. until a read would not block.
+    ///
+    /// Returns `Ok(false)` if `timeout` elapses before that. A `timeout` too long to be represented
+    /// as a deadline, e.g. [`Duration::MAX`], never elapses.
+    ///
+    /// The default implementation waits on a new thread, which outlives the future until the
+    /// stream becomes readable or `timeout` elapses. Runtimes that can wait for readiness should
+    /// override it. On Windows, anonymous pipes cannot be waited for, so both the default
+    /// implementation and the implementations provided by crossmist check the pipe every
+    /// millisecond.
+    fn wait_readable(&self, timeout: Duration) -> impl Future<Output = Result<bool>> + Send {
+        wait_readable_on_thread(self.as_handle(), timeout)
+    }
+}
+
+#[derive(Default)]
+struct ThreadWait {
+    result: Option<Result<bool>>,
+    waker: Option<Waker>,
+}
+
+fn wait_readable_on_thread(
+    handle: BorrowedHandle<'_>,
+    timeout: Duration,
+) -> impl Future<Output = Result<bool>> + Send + 'static {
+    // Duplicated so that the thread does not depend on the stream staying alive
+    let handle = handle.try_clone_to_owned();
+    async move {
+        let mut handle = Some(handle?);
+        let state = Arc::new(Mutex::new(ThreadWait::default()));
+        poll_fn(|cx| {
+            let mut wait = state.lock().expect("Wait mutex is poisoned");
+            if let Some(result) = wait.result.take() {
+                return Poll::Ready(result);
+            }
+            wait.waker = Some(cx.waker().clone());
+            if let Some(handle) = handle.take() {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    let result = crate::internals::poll_readable(handle.as_handle(), timeout);
+                    let mut wait = state.lock().expect("Wait mutex is poisoned");
+                    wait.result = Some(result);
+                    if let Some(waker) = wait.waker.take() {
+                        waker.wake();
+                    }
+                });
+            }
+            Poll::Pending
+        })
+        .await
+    }
 }
 
 /// The transmitting side of a unidirectional channel.
 ///
 /// `T` is the type of the objects this side sends via the channel and the other side receives.
-#[derive(Object)]
 pub struct Sender<Stream: AsyncStream, T: Object> {
     pub(crate) fd: Stream,
+    pub(crate) options: ChannelOptions,
+    #[cfg(unix)]
+    queue: SendQueue,
+    #[cfg(unix)]
+    buffer: Vec<u8>,
+    #[cfg(windows)]
+    queue: Vec<u8>,
+    // The priority and the length of each message in the queue
+    #[cfg(windows)]
+    queued: Vec<(u8, usize)>,
     marker: PhantomData<fn(T)>,
 }
 
 /// The receiving side of a unidirectional channel.
 ///
 /// `T` is the type of the objects the other side sends via the channel and this side receives.
-#[derive(Object)]
 pub struct Receiver<Stream: AsyncStream, T: Object> {
     pub(crate) fd: Stream,
+    pub(crate) options: ChannelOptions,
+    #[cfg(unix)]
+    state: ReceiveState,
+    // Set once the stream position is lost, e.g. after an oversized message
+    #[cfg(windows)]
+    poisoned: bool,
+    // The beginning of the stream, read ahead by wait_message
+    #[cfg(windows)]
+    pending: Vec<u8>,
     marker: PhantomData<fn() -> T>,
 }
 
@@ -132,24 +345,318 @@ pub struct Receiver<Stream: AsyncStream, T: Object> {
 ///
 /// `S` is the type of the objects this side sends via the channel and the other side receives, `R`
 /// is the type of the objects the other side sends via the channel and this side receives.
-#[derive(Object)]
 pub struct Duplex<Stream: AsyncStream, S: Object, R: Object> {
     #[cfg(unix)]
     pub(crate) fd: Stream,
     #[cfg(unix)]
+    pub(crate) options: ChannelOptions,
+    #[cfg(unix)]
+    state: ReceiveState,
+    #[cfg(unix)]
+    queue: SendQueue,
+    #[cfg(unix)]
+    buffer: Vec<u8>,
+    #[cfg(unix)]
     marker: PhantomData<fn(S) -> R>,
     #[cfg(windows)]
     pub(crate) sender: Sender<Stream, S>,
     #[cfg(windows)]
     pub(crate) receiver: Receiver<Stream, R>,
+    stale_replies: usize,
+}
+
+/// An error returned by [`Duplex::request_timeout`] and
+/// [`MultiplexedDuplex::request`](crate::multiplex::MultiplexedDuplex::request).
+#[derive(Debug)]
+pub enum RequestError {
+    /// The response did not arrive in time.
+    Timeout,
+    /// The other side closed the channel before responding.
+    PeerClosed,
+    /// The response could not be deserialized.
+    Serialization(Error),
+    /// An I/O error occured.
+    Io(Error),
+}
+
+impl RequestError {
+    pub(crate) fn from_io(error: Error) -> Self {
+        match error.kind() {
+            ErrorKind::InvalidData => RequestError::Serialization(error),
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof => {
+                RequestError::PeerClosed
+            }
+            _ => RequestError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Timeout => write!(fmt, "The request timed out"),
+            RequestError::PeerClosed => write!(
+                fmt,
+                "The subprocess exitted before responding to the request"
+            ),
+            RequestError::Serialization(e) => write!(fmt, "Failed to deserialize response: {e}"),
+            RequestError::Io(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RequestError::Serialization(e) | RequestError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// An error returned by [`Child::join`].
+///
+/// # Migrating from `io::Result`
+///
+/// `join` used to return [`std::io::Result`], so this is a breaking change for code that inspects
+/// the error. Code that merely propagates it with `?` from a function returning `io::Result` keeps
+/// compiling, as `JoinError` converts to [`Error`]: [`JoinError::Io`] is unwrapped, and the other
+/// variants are wrapped into an error of kind [`ErrorKind::Other`], from which the `JoinError` can
+/// be recovered by downcasting. Where an [`Error`] is needed explicitly, use
+/// `.map_err(std::io::Error::from)`.
+///
+/// ```rust
+/// use crossmist::{func, main, JoinError};
+///
+/// #[func]
+/// fn fail() -> i32 {
+///     std::process::exit(3)
+/// }
+///
+/// fn run() -> std::io::Result<i32> {
+///     Ok(fail.spawn()?.join()?)
+/// }
+///
+/// #[main]
+/// fn main() {
+///     let error = run().unwrap_err().into_inner().unwrap();
+///     let error = error.downcast::<JoinError>().unwrap();
+///     assert!(matches!(*error, JoinError::ExitedWithCode(3)));
+/// }
+/// ```
+#[derive(Debug)]
+pub enum JoinError {
+    /// The process was terminated via [`KillHandle::kill`].
+    Killed,
+    /// The function run by the process panicked.
+    Panicked {
+        /// The panic message.
+        message: String,
+        /// The location of the panic in the source code, e.g. `src/main.rs:10:5`.
+        location: Option<String>,
+        /// The backtrace of the panic, if backtraces are enabled in the child via `RUST_BACKTRACE`
+        /// or `RUST_LIB_BACKTRACE`.
+        backtrace: Option<String>,
+    },
+    /// The process exited with a non-zero code without returning a value, e.g. via
+    /// [`std::process::exit`].
+    ExitedWithCode(i32),
+    /// The process was terminated by a signal, other than by [`KillHandle::kill`] of the parent.
+    ///
+    /// This variant is only available on Unix-like systems.
+    #[cfg(unix)]
+    KilledBySignal(i32),
+    /// The process terminated abnormally with an `NTSTATUS` error code, e.g. on access violation.
+    ///
+    /// This variant is only available on Windows.
+    #[cfg(windows)]
+    Terminated(u32),
+    /// The process was terminated for exceeding a limit set via [`SpawnOptions`].
+    ///
+    /// On Linux, this is reported if the process is killed by `SIGXCPU`, or by `SIGKILL` if it
+    /// ignores `SIGXCPU`, after consuming at least as much CPU time as its limit allows, which is
+    /// checked via `/proc/<pid>/stat` before the process is reaped. The kernel does not record
+    /// failed allocations, so exceeding the memory limit cannot be confirmed, and neither can
+    /// exceeding the CPU time limit on other Unix-like systems: these processes are reported with
+    /// [`JoinError::KilledBySignal`] instead, e.g. `SIGABRT` for a Rust program whose allocation
+    /// fails.
+    ///
+    /// On Windows, this is reported if the process fails after the job object it is assigned to
+    /// has signalled that the limit is exceeded.
+    LimitExceeded(ResourceLimit),
+    /// The process exited successfully, but did not return a value.
+    NoReturnValue,
+    /// An I/O error occured while waiting for the process.
+    Io(Error),
+}
+
+/// A resource limit that can be exceeded by a process, see [`JoinError::LimitExceeded`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResourceLimit {
+    /// The CPU time limit, set via [`SpawnOptions::cpu_time_limit`].
+    CpuTime,
+    /// The memory limit, set via [`SpawnOptions::memory_limit`].
+    Memory,
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceLimit::CpuTime => write!(fmt, "CPU time limit"),
+            ResourceLimit::Memory => write!(fmt, "memory limit"),
+        }
+    }
+}
+
+impl JoinError {
+    /// Get the exit code of the process, if it exited with a non-zero code.
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            JoinError::ExitedWithCode(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Get the signal that terminated the process, if any.
+    ///
+    /// For processes terminated via [`KillHandle::kill`], this is `SIGKILL`. For processes that
+    /// exceeded the CPU time limit, this is `SIGXCPU`, which enforces the limit, even if the process
+    /// ignored it and was killed by `SIGKILL` afterwards, see [`JoinError::LimitExceeded`].
+    ///
+    /// This method is only available on Unix-like systems.
+    #[cfg(unix)]
+    pub fn signal(&self) -> Option<i32> {
+        match self {
+            JoinError::Killed => Some(rustix::process::Signal::KILL.as_raw()),
+            JoinError::KilledBySignal(signal) => Some(*signal),
+            // Only the CPU time limit is reported on Unix-like systems
+            JoinError::LimitExceeded(_) => Some(rustix::process::Signal::XCPU.as_raw()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Killed => write!(fmt, "The subprocess was killed"),
+            JoinError::Panicked {
+                message,
+                location: Some(location),
+                ..
+            } => write!(fmt, "The subprocess panicked at {location}: {message}"),
+            JoinError::Panicked {
+                message,
+                location: None,
+                ..
+            } => write!(fmt, "The subprocess panicked: {message}"),
+            JoinError::ExitedWithCode(code) => {
+                write!(fmt, "The subprocess exited with code {code}")
+            }
+            #[cfg(unix)]
+            JoinError::KilledBySignal(signal) => {
+                write!(fmt, "The subprocess was killed by signal {signal}")
+            }
+            #[cfg(windows)]
+            JoinError::Terminated(status) => {
+                write!(fmt, "The subprocess terminated with status {status:#010x}")
+            }
+            JoinError::LimitExceeded(limit) => {
+                write!(
+                    fmt,
+                    "The subprocess was terminated for exceeding the {limit}"
+                )
+            }
+            JoinError::NoReturnValue => {
+                write!(fmt, "The subprocess terminated without returning a value")
+            }
+            JoinError::Io(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JoinError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// See [Migrating from `io::Result`](JoinError#migrating-from-ioresult).
+impl From<JoinError> for Error {
+    fn from(error: JoinError) -> Self {
+        match error {
+            JoinError::Io(e) => e,
+            _ => Error::other(error),
+        }
+    }
+}
+
+impl From<RequestError> for Error {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::Timeout => Error::new(ErrorKind::TimedOut, error.to_string()),
+            RequestError::PeerClosed => Error::new(ErrorKind::UnexpectedEof, error.to_string()),
+            RequestError::Serialization(e) | RequestError::Io(e) => e,
+        }
+    }
+}
+
+/// An error returned by [`Receiver::try_recv`].
+#[derive(Debug)]
+pub enum TryRecvError {
+    /// No message is available at the moment.
+    Empty,
+    /// The other side has dropped the channel.
+    Disconnected,
+    /// An I/O error occured.
+    Io(Error),
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(fmt, "No message is available"),
+            TryRecvError::Disconnected => write!(fmt, "The other side has dropped the channel"),
+            TryRecvError::Io(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TryRecvError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<TryRecvError> for Error {
+    fn from(error: TryRecvError) -> Self {
+        match error {
+            TryRecvError::Empty => Error::new(ErrorKind::WouldBlock, error.to_string()),
+            TryRecvError::Disconnected => Error::new(ErrorKind::UnexpectedEof, error.to_string()),
+            TryRecvError::Io(e) => e,
+        }
+    }
 }
 
 /// Create a unidirectional channel.
 pub fn channel<Stream: AsyncStream, T: Object>() -> Result<(Sender<Stream, T>, Receiver<Stream, T>)>
 {
+    channel_with(&ChannelOptions::default())
+}
+
+/// Create a unidirectional channel with custom options.
+pub fn channel_with<Stream: AsyncStream, T: Object>(
+    options: &ChannelOptions,
+) -> Result<(Sender<Stream, T>, Receiver<Stream, T>)> {
     #[cfg(unix)]
     {
-        let (tx, rx) = duplex::<Stream, T, T>()?;
+        let (tx, rx) = duplex_with::<Stream, T, T>(options)?;
         Ok((tx.into_sender(), rx.into_receiver()))
     }
     #[cfg(windows)]
@@ -161,80 +668,345 @@ pub fn channel<Stream: AsyncStream, T: Object>() -> Result<(Sender<Stream, T>, R
                 &mut rx as *mut RawHandle,
                 &mut tx as *mut RawHandle,
                 std::ptr::null(),
-                0,
+                // 0 selects the default size
+                options
+                    .buffer_size
+                    .map_or(0, |size| u32::try_from(size).unwrap_or(u32::MAX)),
             )
             .ok()?;
         }
         let tx = unsafe { SyncStream::from_raw_handle(tx) };
         let rx = unsafe { SyncStream::from_raw_handle(rx) };
-        let tx = Sender {
-            fd: Stream::try_new(tx)?,
-            marker: PhantomData,
-        };
-        let rx = Receiver {
-            fd: Stream::try_new(rx)?,
-            marker: PhantomData,
-        };
-        Ok((tx, rx))
+        unsafe {
+            Ok((
+                Sender::from_stream(Stream::try_new(tx)?, *options),
+                Receiver::from_stream(Stream::try_new(rx)?, *options),
+            ))
+        }
     }
 }
 
 /// Create a bidirectional channel.
+#[allow(clippy::type_complexity)]
 pub fn duplex<Stream: AsyncStream, A: Object, B: Object>(
+) -> Result<(Duplex<Stream, A, B>, Duplex<Stream, B, A>)> {
+    duplex_with(&ChannelOptions::default())
+}
+
+/// Create a bidirectional channel with custom options.
+#[allow(clippy::type_complexity)]
+pub fn duplex_with<Stream: AsyncStream, A: Object, B: Object>(
+    options: &ChannelOptions,
 ) -> Result<(Duplex<Stream, A, B>, Duplex<Stream, B, A>)> {
     #[cfg(unix)]
     {
         let (tx, rx) = socketpair()?;
+        if let Some(size) = options.buffer_size {
+            set_buffer_size(&tx, size)?;
+            set_buffer_size(&rx, size)?;
+        }
         unsafe {
             Ok((
-                Duplex::from_stream(Stream::try_new(tx)?),
-                Duplex::from_stream(Stream::try_new(rx)?),
+                Duplex::from_stream(Stream::try_new(tx)?, *options),
+                Duplex::from_stream(Stream::try_new(rx)?, *options),
             ))
         }
     }
     #[cfg(windows)]
     {
-        let (tx_a, rx_a) = channel::<Stream, A>()?;
-        let (tx_b, rx_b) = channel::<Stream, B>()?;
+        let (tx_a, rx_a) = channel_with::<Stream, A>(options)?;
+        let (tx_b, rx_b) = channel_with::<Stream, B>(options)?;
         let ours = Duplex {
             sender: tx_a,
             receiver: rx_b,
+            stale_replies: 0,
         };
         let theirs = Duplex {
             sender: tx_b,
             receiver: rx_a,
+            stale_replies: 0,
         };
         Ok((ours, theirs))
     }
 }
 
 impl<Stream: AsyncStream, T: Object> Sender<Stream, T> {
-    pub(crate) unsafe fn from_stream(fd: Stream) -> Self {
+    pub(crate) unsafe fn from_stream(fd: Stream, options: ChannelOptions) -> Self {
         Sender {
             fd,
+            options,
+            queue: Default::default(),
+            #[cfg(unix)]
+            buffer: Vec::new(),
+            #[cfg(windows)]
+            queued: Vec::new(),
             marker: PhantomData,
         }
     }
 
+    /// Get the options this channel was created with.
+    pub fn options(&self) -> &ChannelOptions {
+        &self.options
+    }
+
+    /// Create another sender for the same channel.
+    ///
+    /// Both senders can be used independently, e.g. passed to different processes. The order in
+    /// which messages from different senders arrive is unspecified.
+    ///
+    /// On Unix-like systems, each message is delivered whole, even if several senders send large
+    /// messages simultaneously. On Windows, the system may split a write larger than the pipe
+    /// buffer, usually 4 KiB, so large messages sent simultaneously by different senders can
+    /// interleave and corrupt the channel. Either synchronize the senders, or set
+    /// [`shared_memory_threshold`](crate::ChannelOptions::shared_memory_threshold) to at most the
+    /// size of the pipe buffer, so that only small handles are written to the pipe.
+    ///
+    /// The receiver only observes the end of the channel once all senders are dropped.
+    pub fn try_clone(&self) -> Result<Self> {
+        let fd = SyncStream::from(self.fd.as_handle().try_clone_to_owned()?);
+        unsafe { Ok(Self::from_stream(Stream::try_new(fd)?, self.options)) }
+    }
+
     /// Send a value to the other side.
+    ///
+    /// Messages queued with [`feed`](Self::feed) are flushed first.
     pub async fn send(&mut self, value: &T) -> Result<()> {
         #[cfg(unix)]
         {
-            let mut sender =
-                SingleObjectSender::new(self.fd.as_handle(), value, Stream::IS_BLOCKING);
-            self.fd.blocking_write(|| sender.send_next()).await
+            if !self.queue.is_empty() {
+                self.flush().await?;
+            }
+            let mut sender = SingleObjectSender::new(
+                self.fd.as_handle(),
+                value,
+                &self.options,
+                Stream::IS_BLOCKING,
+                &mut self.buffer,
+            );
+            let result = self.fd.blocking_write(|| sender.send_next()).await;
+            retain_buffer(&mut self.buffer, sender.into_buffer());
+            result
+        }
+        #[cfg(windows)]
+        {
+            // The prefix and the payload are written at once so that messages from different
+            // senders do not interleave, as long as the system does not split the write
+            self.feed(value)?;
+            self.flush().await
+        }
+    }
+
+    /// Queue a value to be sent to the other side.
+    ///
+    /// The value is serialized immediately, but is not necessarily delivered until
+    /// [`flush`](Self::flush) is called. This allows to send many small messages with fewer system
+    /// calls. Messages that have not been flushed are lost when the sender is dropped or passed to
+    /// another process.
+    pub fn feed(&mut self, value: &T) -> Result<()> {
+        self.feed_prioritized(value, 0)
+    }
+
+    /// Queue a value to be sent to the other side before the queued values of lower priority.
+    ///
+    /// Values fed with [`feed`](Self::feed) have priority 0. When the queue is flushed, values of
+    /// higher priority are written first, and values of the same priority are written in the order
+    /// they were queued. This lets control messages, e.g. cancellations, overtake bulk data that
+    /// has not been flushed yet.
+    ///
+    /// Only the local queue is reordered: values that have already been written to the channel are
+    /// delivered before the ones queued later, regardless of priorities.
+    pub fn feed_prioritized(&mut self, value: &T, priority: u8) -> Result<()> {
+        #[cfg(unix)]
+        {
+            self.queue.push(value, &self.options, priority)
         }
         #[cfg(windows)]
-        if implements!(T: PlainOldData) {
-            let serialized = unsafe {
-                std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
+        {
+            let serialized: Cow<'_, [u8]> = if implements!(T: PlainOldData) {
+                Cow::Borrowed(unsafe {
+                    std::slice::from_raw_parts(
+                        value as *const T as *const u8,
+                        std::mem::size_of::<T>(),
+                    )
+                })
+            } else {
+                Cow::Owned(serialize_with_handles(value)?)
             };
-            self.fd.write(&serialized.len().to_ne_bytes()).await?;
-            self.fd.write(serialized).await
+            self.feed_serialized(&serialized, priority)
+        }
+    }
+
+    /// Send a value to the other side ahead of the queued values of lower priority.
+    ///
+    /// This is [`feed_prioritized`](Self::feed_prioritized) followed by [`flush`](Self::flush).
+    pub async fn send_prioritized(&mut self, value: &T, priority: u8) -> Result<()> {
+        self.feed_prioritized(value, priority)?;
+        self.flush().await
+    }
+
+    #[cfg(windows)]
+    fn feed_serialized(&mut self, serialized: &[u8], priority: u8) -> Result<()> {
+        let start = self.queue.len();
+        if self.options.use_shared_memory(serialized.len()) {
+            // The section is announced by a length prefix that no real message can have
+            let section = create_sealed_buffer(serialized)?;
+            let announcement = serialize_with_handles(&(section, serialized.len() as u64))?;
+            self.queue
+                .extend(self.options.framing.encode_u64(SHARED_MEMORY_MARKER));
+            self.queue
+                .extend(self.options.framing.encode_len(announcement.len()));
+            self.queue.extend_from_slice(&announcement);
         } else {
-            let serialized = serialize_with_handles(value)?;
-            self.fd.write(&serialized.len().to_ne_bytes()).await?;
-            self.fd.write(&serialized).await
+            self.queue
+                .extend(self.options.framing.encode_len(serialized.len()));
+            self.queue.extend_from_slice(serialized);
+        }
+        // Keep the queue sorted by decreasing priority
+        let len = self.queue.len() - start;
+        let index = self
+            .queued
+            .iter()
+            .position(|&(queued_priority, _)| queued_priority < priority)
+            .unwrap_or(self.queued.len());
+        let offset: usize = self.queued[..index].iter().map(|&(_, len)| len).sum();
+        self.queue[offset..].rotate_right(len);
+        self.queued.insert(index, (priority, len));
+        Ok(())
+    }
+
+    /// Send all values queued with [`feed`](Self::feed) to the other side.
+    pub async fn flush(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let socket_fd = self.fd.as_handle();
+            let queue = &mut self.queue;
+            self.fd
+                .blocking_write(|| queue.send_next(socket_fd, Stream::IS_BLOCKING))
+                .await
+        }
+        #[cfg(windows)]
+        {
+            if self.queue.is_empty() {
+                return Ok(());
+            }
+            let buf = std::mem::take(&mut self.queue);
+            self.queued.clear();
+            self.fd.write(&buf).await?;
+            retain_buffer(&mut self.queue, buf);
+            Ok(())
+        }
+    }
+
+    /// Free the memory kept for serializing messages.
+    ///
+    /// To avoid an allocation per message, the buffer used to serialize a message is kept for the
+    /// next one, unless it has grown large. Call this method to release it, e.g. after a burst of
+    /// sends.
+    pub fn shrink_to_fit(&mut self) {
+        #[cfg(unix)]
+        {
+            self.buffer = Vec::new();
+        }
+        #[cfg(windows)]
+        {
+            self.queue.shrink_to_fit();
+        }
+    }
+
+    /// Send several values to the other side.
+    ///
+    /// This is equivalent to sending the values one by one, but uses fewer system calls. The other
+    /// side can receive the values with [`Receiver::recv`] or [`Receiver::recv_batch`].
+    pub async fn send_batch(&mut self, values: &[T]) -> Result<()> {
+        for value in values {
+            self.feed(value)?;
+        }
+        self.flush().await
+    }
+
+    /// Close the channel, signalling the end of the stream to the other side.
+    ///
+    /// Messages queued with [`feed`](Self::feed) are flushed first. Afterwards, the receiver gets
+    /// `Ok(None)` once it has received all messages sent before.
+    ///
+    /// Unlike dropping the sender, this reports errors that occur while flushing. On Unix-like
+    /// systems, it also ends the stream if other handles to the channel exist, e.g. senders created
+    /// with [`try_clone`](Self::try_clone), which can no longer send afterwards. On Windows, the
+    /// stream only ends once all senders are closed.
+    pub async fn close(mut self) -> Result<()> {
+        self.flush().await?;
+        #[cfg(unix)]
+        rustix::net::shutdown(self.fd.as_handle(), rustix::net::Shutdown::Write)?;
+        Ok(())
+    }
+
+    /// Poll whether the sender is ready to accept a value with [`start_send`](Self::start_send).
+    ///
+    /// Together with [`start_send`](Self::start_send) and [`poll_flush`](Self::poll_flush), this
+    /// allows to drive the sender from hand-written futures, e.g. to implement a `Sink`. Values
+    /// queued before are written first, so that at most one value is buffered at a time.
+    ///
+    /// This method is only available on Unix-like systems.
+    #[cfg(unix)]
+    pub fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+
+    /// Queue a value to be sent to the other side.
+    ///
+    /// This is the same as [`feed`](Self::feed), named after the corresponding `Sink` method. Call
+    /// [`poll_flush`](Self::poll_flush) to deliver the value.
+    ///
+    /// This method is only available on Unix-like systems.
+    #[cfg(unix)]
+    pub fn start_send(&mut self, value: &T) -> Result<()> {
+        self.feed(value)
+    }
+
+    /// Poll the delivery of the values queued with [`feed`](Self::feed) or
+    /// [`start_send`](Self::start_send).
+    ///
+    /// This is the poll-based counterpart of [`flush`](Self::flush). The progress is kept in the
+    /// sender, so a message that has been written partially is continued by the next call to
+    /// `poll_flush` or `flush`.
+    ///
+    /// This method is only available on Unix-like systems.
+    #[cfg(unix)]
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let socket_fd = self.fd.as_handle();
+        let queue = &mut self.queue;
+        self.fd
+            .poll_blocking_write(cx, || queue.send_next(socket_fd, Stream::IS_BLOCKING))
+    }
+}
+
+impl<Stream: AsyncStream> Sender<Stream, Vec<u8>> {
+    /// Send a byte slice to the other side, which receives it as a `Vec<u8>`.
+    ///
+    /// Unlike [`send`](Self::send), this does not require the bytes to be stored in a `Vec`. On
+    /// Unix-like systems, the bytes are written to the socket directly from `data`.
+    pub async fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        // This matches the serialization of Vec<u8>
+        let mut s = Serializer::new();
+        s.serialize_temporary(data.len());
+        s.write_borrowed(data);
+        #[cfg(unix)]
+        {
+            if !self.queue.is_empty() {
+                self.flush().await?;
+            }
+            let mut sender = SingleObjectSender::from_serializer(
+                self.fd.as_handle(),
+                s,
+                &self.options,
+                Stream::IS_BLOCKING,
+            );
+            self.fd.blocking_write(|| sender.send_next()).await
+        }
+        #[cfg(windows)]
+        {
+            self.feed_serialized(&s.into_vec(), 0)?;
+            self.flush().await
         }
     }
 }
@@ -245,14 +1017,39 @@ impl<Stream: AsyncStream + fmt::Debug, T: Object> fmt::Debug for Sender<Stream,
     }
 }
 
+// Queued messages are not transferred: the sender is expected to be flushed before it is passed to
+// another process.
+unsafe impl<Stream: AsyncStream, T: Object> NonTrivialObject for Sender<Stream, T> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize(&self.fd);
+        s.serialize(&self.options);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let fd = d.deserialize()?;
+        let options = d.deserialize()?;
+        Ok(Self::from_stream(fd, options))
+    }
+}
+
 impl<Stream: AsyncStream, T: Object> TryFrom<crate::Sender<T>> for Sender<Stream, T> {
     type Error = Error;
-    fn try_from(value: crate::Sender<T>) -> Result<Self> {
-        unsafe {
-            Ok(Self::from_stream(Stream::try_new(
-                SyncStream::from_raw_handle(value.into_raw_handle()),
-            )?))
+    fn try_from(mut value: crate::Sender<T>) -> Result<Self> {
+        let options = value.0.options;
+        let queue = std::mem::take(&mut value.0.queue);
+        #[cfg(windows)]
+        let queued = std::mem::take(&mut value.0.queued);
+        let mut sender = unsafe {
+            Self::from_stream(
+                Stream::try_new(SyncStream::from_raw_handle(value.into_raw_handle()))?,
+                options,
+            )
+        };
+        sender.queue = queue;
+        #[cfg(windows)]
+        {
+            sender.queued = queued;
         }
+        Ok(sender)
     }
 }
 
@@ -270,54 +1067,516 @@ impl<Stream: AsyncStream, T: Object> io::AsRawHandle for Sender<Stream, T> {
 }
 
 impl<Stream: AsyncStream, T: Object> Receiver<Stream, T> {
-    pub(crate) unsafe fn from_stream(fd: Stream) -> Self {
+    pub(crate) unsafe fn from_stream(fd: Stream, options: ChannelOptions) -> Self {
         Receiver {
             fd,
+            options,
+            #[cfg(unix)]
+            state: ReceiveState::default(),
+            #[cfg(windows)]
+            poisoned: false,
+            #[cfg(windows)]
+            pending: Vec::new(),
             marker: PhantomData,
         }
     }
 
-    /// Receive a value from the other side.
-    ///
-    /// Returns `Ok(None)` if the other side has dropped the channel.
-    pub async fn recv(&mut self) -> Result<Option<T>> {
-        #[cfg(unix)]
-        {
-            let mut receiver =
-                unsafe { SingleObjectReceiver::new(self.fd.as_handle(), Stream::IS_BLOCKING) };
+    // Reinterpret the channel as carrying values of another type
+    pub(crate) unsafe fn cast<U: Object>(self) -> Receiver<Stream, U> {
+        Receiver {
+            fd: self.fd,
+            options: self.options,
+            #[cfg(unix)]
+            state: self.state,
+            #[cfg(windows)]
+            poisoned: self.poisoned,
+            #[cfg(windows)]
+            pending: self.pending,
+            marker: PhantomData,
+        }
+    }
+
+    /// Get the options this channel was created with.
+    pub fn options(&self) -> &ChannelOptions {
+        &self.options
+    }
+
+    /// Change the maximum size of a received message.
+    ///
+    /// See [`ChannelOptions::max_message_size`] for more information. `None` disables the check.
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.options.max_message_size = limit;
+    }
+
+    /// Receive a value from the other side.
+    ///
+    /// Returns `Ok(None)` if the other side has dropped the channel, i.e. once all senders created
+    /// with [`Sender::try_clone`] are dropped.
+    pub async fn recv(&mut self) -> Result<Option<T>> {
+        self.recv_with_buffer(None).await
+    }
+
+    /// Poll for a value from the other side.
+    ///
+    /// This is the poll-based counterpart of [`recv`](Self::recv), for hand-written futures and
+    /// stream adapters. The parts of a message that have arrived are kept in the receiver, so
+    /// `poll_recv` can be interleaved with `recv` and other methods freely.
+    ///
+    /// This method is only available on Unix-like systems.
+    #[cfg(unix)]
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<T>>> {
+        let mut receiver = unsafe {
+            SingleObjectReceiver::new(
+                self.fd.as_handle(),
+                &mut self.state,
+                self.options,
+                Stream::IS_BLOCKING,
+            )
+        };
+        self.fd.poll_blocking_read(cx, || receiver.recv_next())
+    }
+
+    /// Receive a value from the other side, reusing `buf` for the serialized data.
+    ///
+    /// This behaves like [`recv`](Self::recv), but reads the message into `buf` instead of a new
+    /// buffer, which avoids an allocation per message when small messages are received at a high
+    /// rate. Pass the same buffer to each call. The contents of `buf` are unspecified afterwards.
+    pub async fn recv_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<T>> {
+        self.recv_with_buffer(Some(buf)).await
+    }
+
+    async fn recv_with_buffer(&mut self, buffer: Option<&mut Vec<u8>>) -> Result<Option<T>> {
+        #[cfg(unix)]
+        {
+            let mut receiver = unsafe {
+                SingleObjectReceiver::new(
+                    self.fd.as_handle(),
+                    &mut self.state,
+                    self.options,
+                    Stream::IS_BLOCKING,
+                )
+            };
+            if let Some(buffer) = buffer {
+                receiver = receiver.with_buffer(buffer);
+            }
             self.fd.blocking_read(|| receiver.recv_next()).await
         }
         #[cfg(windows)]
         {
-            let mut len = [0u8; std::mem::size_of::<usize>()];
-            if let Err(e) = self.fd.read(&mut len).await {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    return Ok(None);
+            let len = match self.recv_header().await? {
+                None => return Ok(None),
+                Some(Incoming::Shared(mapping)) => {
+                    return if implements!(T: PlainOldData) {
+                        Ok(Some(unsafe {
+                            std::ptr::read_unaligned(mapping.as_slice().as_ptr() as *const T)
+                        }))
+                    } else {
+                        unsafe { deserialize_mapped_with_handles(mapping).map(Some) }
+                    };
                 }
-                return Err(e);
-            }
-            let len = usize::from_ne_bytes(len);
+                Some(Incoming::Inline(len)) => len,
+            };
 
             if implements!(T: PlainOldData) {
                 struct Wrapper<T>(MaybeUninit<T>);
                 unsafe impl<T> Send for Wrapper<T> {}
                 let mut serialized = Wrapper::<T>(MaybeUninit::zeroed());
-                self.fd
-                    .read(unsafe {
-                        std::slice::from_raw_parts_mut(
-                            serialized.0.as_mut_ptr() as *mut u8,
-                            std::mem::size_of::<T>(),
-                        )
-                    })
-                    .await?;
+                self.read(unsafe {
+                    std::slice::from_raw_parts_mut(
+                        serialized.0.as_mut_ptr() as *mut u8,
+                        std::mem::size_of::<T>(),
+                    )
+                })
+                .await?;
                 Ok(Some(unsafe { serialized.0.assume_init() }))
             } else {
-                let mut serialized = vec![0u8; len];
-                self.fd.read(&mut serialized).await?;
+                let mut local_buffer = Vec::new();
+                let serialized = buffer.unwrap_or(&mut local_buffer);
+                serialized.clear();
+                serialized.resize(len, 0);
+                self.read(serialized).await?;
                 unsafe { deserialize_with_handles(serialized).map(Some) }
             }
         }
     }
+
+    /// Read exactly `buf.len()` bytes, taking the bytes read ahead by
+    /// [`wait_message`](Self::wait_message) first.
+    #[cfg(windows)]
+    async fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        if n < buf.len() {
+            self.fd.read(&mut buf[n..]).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    async fn read_len(&mut self) -> Result<Option<u64>> {
+        let framing = self.options.framing;
+        let mut prefix = Vec::with_capacity(Framing::MAX_PREFIX_LEN);
+        loop {
+            if let Some((len, _)) = framing.decode_len(&prefix) {
+                return Ok(Some(len));
+            }
+            if prefix.len() == Framing::MAX_PREFIX_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid length prefix"));
+            }
+            let mut byte = [0u8];
+            if let Err(e) = self.read(&mut byte).await {
+                if e.kind() == ErrorKind::UnexpectedEof && prefix.is_empty() {
+                    return Ok(None);
+                }
+                return Err(e);
+            }
+            prefix.push(byte[0]);
+        }
+    }
+
+    // The stream position is lost if the message cannot be skipped
+    #[cfg(windows)]
+    fn narrow_len(&mut self, len: u64) -> Result<usize> {
+        usize::try_from(len).map_err(|_| {
+            self.poisoned = true;
+            Error::new(
+                ErrorKind::InvalidData,
+                "Message is too long for this platform",
+            )
+        })
+    }
+
+    /// Wait until the next message has arrived as a whole, reading it ahead into the receiver.
+    ///
+    /// Returns `Ok(false)` if `deadline` passes before that; the bytes read so far are kept for the
+    /// next call. A broken pipe or a malformed message counts as arrived, so that the following
+    /// read reports it.
+    #[cfg(windows)]
+    pub(crate) async fn wait_message(&mut self, deadline: Instant) -> Result<bool> {
+        loop {
+            let missing = self.missing_bytes();
+            if missing == 0 {
+                return Ok(true);
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if !self.fd.wait_readable(timeout).await? {
+                return Ok(false);
+            }
+            let Some(available) = pipe_bytes_available(self.fd.as_raw_handle()) else {
+                return Ok(true);
+            };
+            // Only take what the wait is for, so that this never blocks
+            let mut chunk = vec![0u8; missing.min(available)];
+            self.fd.read(&mut chunk).await?;
+            self.pending.extend_from_slice(&chunk);
+        }
+    }
+
+    // The number of bytes that still have to arrive before the buffered message is complete. The
+    // count is a lower bound while the length prefix itself is incomplete
+    #[cfg(windows)]
+    fn missing_bytes(&self) -> usize {
+        let framing = self.options.framing;
+        let missing_prefix = |bytes: &[u8]| {
+            if bytes.len() >= Framing::MAX_PREFIX_LEN {
+                0
+            } else {
+                1
+            }
+        };
+        let Some((mut len, mut needed)) = framing.decode_len(&self.pending) else {
+            return missing_prefix(&self.pending);
+        };
+        if len == SHARED_MEMORY_MARKER {
+            let Some((announcement_len, prefix_len)) = framing.decode_len(&self.pending[needed..])
+            else {
+                return missing_prefix(&self.pending[needed..]);
+            };
+            len = announcement_len;
+            needed += prefix_len;
+        }
+        if self.options.check_message_size(len).is_err() {
+            return 0;
+        }
+        usize::try_from(len)
+            .ok()
+            .and_then(|len| len.checked_add(needed))
+            .map_or(0, |total| total.saturating_sub(self.pending.len()))
+    }
+
+    /// Read the length of the next message, or its serialized data if it is stored in shared
+    /// memory. Returns `Ok(None)` if the other side has dropped the channel.
+    #[cfg(windows)]
+    async fn recv_header(&mut self) -> Result<Option<Incoming>> {
+        if self.poisoned {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The channel is unusable after a previous oversized or malformed message",
+            ));
+        }
+
+        let Some(len) = self.read_len().await? else {
+            return Ok(None);
+        };
+
+        // Compare before narrowing, as the marker does not fit into a 32-bit usize
+        if len == SHARED_MEMORY_MARKER {
+            let Some(len) = self.read_len().await? else {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Unterminated data on stream",
+                ));
+            };
+            // The announcement is tiny, so a huge length can only mean corruption
+            if let Err(e) = self.options.check_message_size(len) {
+                self.poisoned = true;
+                return Err(e);
+            }
+            let mut announcement = vec![0u8; self.narrow_len(len)?];
+            self.read(&mut announcement).await?;
+            let (section, len): (OwnedHandle, u64) =
+                unsafe { deserialize_with_handles(&mut announcement)? };
+            self.options.check_message_size(len)?;
+            let len = usize::try_from(len).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "Message is too long for this platform",
+                )
+            })?;
+            if implements!(T: PlainOldData) && len != std::mem::size_of::<T>() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unexpected message size",
+                ));
+            }
+            // Mapping fails if the section is shorter than `len`
+            return Ok(Some(Incoming::Shared(ReadOnlyMapping::new(section, len)?)));
+        }
+
+        let len = self.narrow_len(len)?;
+        if implements!(T: PlainOldData) && len != std::mem::size_of::<T>() {
+            self.poisoned = true;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unexpected message size",
+            ));
+        }
+        if let Err(e) = self.options.check_message_size(len as u64) {
+            self.poisoned = true;
+            return Err(e);
+        }
+        Ok(Some(Incoming::Inline(len)))
+    }
+
+    /// Receive a value from the other side into `target`, reusing the allocations it owns.
+    ///
+    /// This behaves like [`recv`](Self::recv), but instead of building a new value, the message is
+    /// deserialized into the existing one. For instance, if `T` is `Vec<u8>`, `String`, or a
+    /// struct with such fields, their capacity is kept when the new value fits into it, which saves
+    /// allocations when large values of a similar shape are received repeatedly. See
+    /// [`NonTrivialObject::deserialize_into_non_trivial`] for the types that benefit; other types
+    /// are simply overwritten.
+    ///
+    /// Returns `Ok(false)` and leaves `target` untouched if the other side has dropped the
+    /// channel. If deserialization fails, `target` is left in a valid but unspecified state.
+    pub async fn recv_ref(&mut self, target: &mut T) -> Result<bool> {
+        #[cfg(unix)]
+        {
+            let mut receiver = unsafe {
+                SingleObjectReceiver::new(
+                    self.fd.as_handle(),
+                    &mut self.state,
+                    self.options,
+                    Stream::IS_BLOCKING,
+                )
+            };
+            // Deserialize outside of the closure, which must be Send
+            let Some(message) = self.fd.blocking_read(|| receiver.recv_message()).await? else {
+                return Ok(false);
+            };
+            receiver.deserialize_into(message, target)?;
+            Ok(true)
+        }
+        #[cfg(windows)]
+        {
+            let mut serialized = match self.recv_header().await? {
+                None => return Ok(false),
+                Some(Incoming::Shared(mapping)) => {
+                    if implements!(T: PlainOldData) {
+                        *target = unsafe {
+                            std::ptr::read_unaligned(mapping.as_slice().as_ptr() as *const T)
+                        };
+                    } else {
+                        unsafe { deserialize_mapped_with_handles_into(mapping, target)? };
+                    }
+                    return Ok(true);
+                }
+                Some(Incoming::Inline(len)) => {
+                    let mut serialized = vec![0u8; len];
+                    self.read(&mut serialized).await?;
+                    serialized
+                }
+            };
+            if implements!(T: PlainOldData) {
+                // Plain old data owns no allocations to reuse
+                *target = unsafe { std::ptr::read_unaligned(serialized.as_ptr() as *const T) };
+            } else {
+                unsafe { deserialize_with_handles_into(&mut serialized, target)? };
+            }
+            Ok(true)
+        }
+    }
+
+    /// Receive a value from the other side if one is available, without waiting.
+    ///
+    /// On Windows, this waits for the rest of a message if only its beginning has arrived so far.
+    pub async fn try_recv(&mut self) -> std::result::Result<T, TryRecvError> {
+        #[cfg(unix)]
+        {
+            let mut receiver = unsafe {
+                SingleObjectReceiver::new(self.fd.as_handle(), &mut self.state, self.options, false)
+            };
+            match receiver.recv_next() {
+                Ok(Some(value)) => Ok(value),
+                Ok(None) => Err(TryRecvError::Disconnected),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Err(TryRecvError::Empty),
+                Err(e) => Err(TryRecvError::Io(e)),
+            }
+        }
+        #[cfg(windows)]
+        {
+            if !self
+                .fd
+                .wait_readable(Duration::ZERO)
+                .await
+                .map_err(TryRecvError::Io)?
+            {
+                return Err(TryRecvError::Empty);
+            }
+            self.recv()
+                .await
+                .map_err(TryRecvError::Io)?
+                .ok_or(TryRecvError::Disconnected)
+        }
+    }
+
+    /// Receive up to `max` values from the other side.
+    ///
+    /// Waits until at least one value is available, then returns it together with the values that
+    /// have already arrived. Returns an empty vector if the other side has dropped the channel (or
+    /// if `max` is zero).
+    pub async fn recv_batch(&mut self, max: usize) -> Result<Vec<T>> {
+        let mut values = Vec::new();
+        if max == 0 {
+            return Ok(values);
+        }
+        let Some(value) = self.recv().await? else {
+            return Ok(values);
+        };
+        values.push(value);
+        while values.len() < max {
+            match self.try_recv().await {
+                Ok(value) => values.push(value),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Io(e)) => return Err(e),
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Receive a value from whichever of `receivers` has one available first.
+///
+/// Returns the index of the receiver and the value it received, or `None` if the other side of
+/// that receiver has dropped the channel. Receivers are checked in round-robin order across calls
+/// so that a busy receiver does not starve the rest.
+///
+/// This function is meant for asynchronous runtimes. With streams that block the thread, such as
+/// those of the `bare` module, it waits for all receivers at once with `poll(2)`.
+pub async fn select_recv<Stream: AsyncStream, T: Object>(
+    receivers: &mut [&mut Receiver<Stream, T>],
+) -> Result<(usize, Option<T>)> {
+    static NEXT_START: AtomicUsize = AtomicUsize::new(0);
+
+    if receivers.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "No receivers to select from",
+        ));
+    }
+    let start = NEXT_START.fetch_add(1, Ordering::Relaxed) % receivers.len();
+
+    #[cfg(unix)]
+    let ready = (start..receivers.len())
+        .chain(0..start)
+        .find(|&i| receivers[i].state.has_ready());
+    #[cfg(windows)]
+    let ready = None;
+
+    // Waiting on the receivers one by one would block the thread on the first of them
+    #[cfg(unix)]
+    let ready = match ready {
+        None if Stream::IS_BLOCKING => {
+            let order: Vec<usize> = (start..receivers.len()).chain(0..start).collect();
+            let fds: Vec<BorrowedHandle<'_>> =
+                order.iter().map(|&i| receivers[i].fd.as_handle()).collect();
+            Some(order[crate::internals::poll_any_readable(&fds)?])
+        }
+        ready => ready,
+    };
+
+    let index = if let Some(index) = ready {
+        index
+    } else {
+        let mut waits: Vec<_> = (start..receivers.len())
+            .chain(0..start)
+            .map(|i| {
+                let wait: Pin<Box<dyn Future<Output = Result<bool>> + Send + '_>> =
+                    Box::pin(receivers[i].fd.wait_readable(Duration::MAX));
+                (i, wait)
+            })
+            .collect();
+        poll_fn(|cx| {
+            for (i, wait) in &mut waits {
+                match wait.as_mut().poll(cx) {
+                    Poll::Ready(Ok(_)) => return Poll::Ready(Ok(*i)),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {}
+                }
+            }
+            Poll::Pending
+        })
+        .await?
+    };
+
+    // Only a part of a message might have arrived, so this may need to wait for the rest
+    let value = receivers[index].recv().await?;
+    Ok((index, value))
+}
+
+// A length prefix that announces a message stored in shared memory. No real message can be this
+// long.
+#[cfg(windows)]
+const SHARED_MEMORY_MARKER: u64 = u64::MAX;
+
+/// The beginning of a message received on Windows.
+#[cfg(windows)]
+enum Incoming {
+    /// The serialized data of this length follows in the pipe.
+    Inline(usize),
+    /// The serialized data, mapped from shared memory.
+    Shared(ReadOnlyMapping),
+}
+
+/// Values are yielded until the other side drops the channel.
+#[cfg(unix)]
+impl<Stream: AsyncStream + Unpin, T: Object> futures_core::Stream for Receiver<Stream, T> {
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx).map(Result::transpose)
+    }
 }
 
 impl<Stream: AsyncStream + fmt::Debug, T: Object> fmt::Debug for Receiver<Stream, T> {
@@ -326,13 +1585,50 @@ impl<Stream: AsyncStream + fmt::Debug, T: Object> fmt::Debug for Receiver<Stream
     }
 }
 
+// Partially received messages are not transferred: the receiver is expected to be idle when it is
+// passed to another process.
+unsafe impl<Stream: AsyncStream, T: Object> NonTrivialObject for Receiver<Stream, T> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize(&self.fd);
+        s.serialize(&self.options);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let fd = d.deserialize()?;
+        let options = d.deserialize()?;
+        Ok(Self::from_stream(fd, options))
+    }
+}
+
 impl<Stream: AsyncStream, T: Object> TryFrom<crate::Receiver<T>> for Receiver<Stream, T> {
     type Error = Error;
     fn try_from(value: crate::Receiver<T>) -> Result<Self> {
-        unsafe {
-            Ok(Self::from_stream(Stream::try_new(
-                SyncStream::from_raw_handle(value.into_raw_handle()),
-            )?))
+        #[cfg(unix)]
+        {
+            let mut value = value;
+            let options = value.0.options;
+            let state = std::mem::take(&mut value.0.state);
+            let mut receiver = unsafe {
+                Self::from_stream(
+                    Stream::try_new(SyncStream::from_raw_handle(value.into_raw_handle()))?,
+                    options,
+                )
+            };
+            receiver.state = state;
+            Ok(receiver)
+        }
+        #[cfg(windows)]
+        {
+            let mut value = value;
+            let options = value.0.options;
+            let pending = std::mem::take(&mut value.0.pending);
+            let mut receiver = unsafe {
+                Self::from_stream(
+                    Stream::try_new(SyncStream::from_raw_handle(value.into_raw_handle()))?,
+                    options,
+                )
+            };
+            receiver.pending = pending;
+            Ok(receiver)
         }
     }
 }
@@ -350,307 +1646,1924 @@ impl<Stream: AsyncStream, T: Object> io::AsRawHandle for Receiver<Stream, T> {
     }
 }
 
-impl<Stream: AsyncStream, S: Object, R: Object> Duplex<Stream, S, R> {
+impl<Stream: AsyncStream, S: Object, R: Object> Duplex<Stream, S, R> {
+    #[cfg(unix)]
+    pub(crate) unsafe fn from_stream(fd: Stream, options: ChannelOptions) -> Self {
+        Duplex {
+            fd,
+            options,
+            state: ReceiveState::default(),
+            queue: SendQueue::default(),
+            buffer: Vec::new(),
+            marker: PhantomData,
+            stale_replies: 0,
+        }
+    }
+
+    /// Get the options this channel was created with.
+    pub fn options(&self) -> &ChannelOptions {
+        #[cfg(unix)]
+        {
+            &self.options
+        }
+        #[cfg(windows)]
+        self.sender.options()
+    }
+
+    /// Change the maximum size of a received message.
+    ///
+    /// See [`ChannelOptions::max_message_size`] for more information. `None` disables the check.
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        #[cfg(unix)]
+        {
+            self.options.max_message_size = limit;
+        }
+        #[cfg(windows)]
+        {
+            self.sender.options.max_message_size = limit;
+            self.receiver.set_max_message_size(limit);
+        }
+    }
+
+    /// Send a value to the other side.
+    ///
+    /// Values queued with [`start_send`](Self::start_send) are flushed first.
+    pub async fn send(&mut self, value: &S) -> Result<()> {
+        #[cfg(unix)]
+        {
+            if !self.queue.is_empty() {
+                let socket_fd = self.fd.as_handle();
+                let queue = &mut self.queue;
+                self.fd
+                    .blocking_write(|| queue.send_next(socket_fd, Stream::IS_BLOCKING))
+                    .await?;
+            }
+            let mut sender = SingleObjectSender::new(
+                self.fd.as_handle(),
+                value,
+                &self.options,
+                Stream::IS_BLOCKING,
+                &mut self.buffer,
+            );
+            let result = self.fd.blocking_write(|| sender.send_next()).await;
+            retain_buffer(&mut self.buffer, sender.into_buffer());
+            result
+        }
+        #[cfg(windows)]
+        self.sender.send(value).await
+    }
+
+    /// Free the memory kept for serializing messages.
+    ///
+    /// See [`Sender::shrink_to_fit`].
+    pub fn shrink_to_fit(&mut self) {
+        #[cfg(unix)]
+        {
+            self.buffer = Vec::new();
+        }
+        #[cfg(windows)]
+        self.sender.shrink_to_fit()
+    }
+
+    /// Receive a value from the other side.
+    ///
+    /// Returns `Ok(None)` if the other side has dropped the channel.
+    pub async fn recv(&mut self) -> Result<Option<R>> {
+        #[cfg(unix)]
+        {
+            let mut receiver = unsafe {
+                SingleObjectReceiver::new(
+                    self.fd.as_handle(),
+                    &mut self.state,
+                    self.options,
+                    Stream::IS_BLOCKING,
+                )
+            };
+            self.fd.blocking_read(|| receiver.recv_next()).await
+        }
+        #[cfg(windows)]
+        self.receiver.recv().await
+    }
+
+    /// Poll whether the duplex is ready to accept a value with [`start_send`](Self::start_send).
+    ///
+    /// See [`Sender::poll_send_ready`].
+    #[cfg(unix)]
+    pub fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+
+    /// Queue a value to be sent to the other side.
+    ///
+    /// See [`Sender::start_send`].
+    #[cfg(unix)]
+    pub fn start_send(&mut self, value: &S) -> Result<()> {
+        self.queue.push(value, &self.options, 0)
+    }
+
+    /// Poll the delivery of the values queued with [`start_send`](Self::start_send).
+    ///
+    /// See [`Sender::poll_flush`].
+    #[cfg(unix)]
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let socket_fd = self.fd.as_handle();
+        let queue = &mut self.queue;
+        self.fd
+            .poll_blocking_write(cx, || queue.send_next(socket_fd, Stream::IS_BLOCKING))
+    }
+
+    /// Poll for a value from the other side.
+    ///
+    /// See [`Receiver::poll_recv`].
+    #[cfg(unix)]
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<R>>> {
+        let mut receiver = unsafe {
+            SingleObjectReceiver::new(
+                self.fd.as_handle(),
+                &mut self.state,
+                self.options,
+                Stream::IS_BLOCKING,
+            )
+        };
+        self.fd.poll_blocking_read(cx, || receiver.recv_next())
+    }
+
+    /// Send a value from the other side and wait for a response immediately.
+    ///
+    /// If the other side closes the channel before responding, an error is returned.
+    pub async fn request(&mut self, value: &S) -> Result<R> {
+        Ok(self.request_before(value, None).await?)
+    }
+
+    /// Send a value from the other side and wait for a response, giving up after `timeout`.
+    ///
+    /// If the response does not arrive in time, [`RequestError::Timeout`] is returned. The channel
+    /// remains usable afterwards: the late response is skipped by the next call to
+    /// [`request`](Self::request) or [`request_timeout`](Self::request_timeout). Note that
+    /// [`recv`](Self::recv) does not skip late responses.
+    pub async fn request_timeout(
+        &mut self,
+        value: &S,
+        timeout: Duration,
+    ) -> std::result::Result<R, RequestError> {
+        self.request_before(value, Some(Instant::now() + timeout))
+            .await
+    }
+
+    async fn request_before(
+        &mut self,
+        value: &S,
+        deadline: Option<Instant>,
+    ) -> std::result::Result<R, RequestError> {
+        while self.stale_replies > 0 {
+            self.recv_before(deadline).await?;
+            self.stale_replies -= 1;
+        }
+        self.send(value).await.map_err(RequestError::Io)?;
+        let result = self.recv_before(deadline).await;
+        if let Err(RequestError::Timeout) = result {
+            self.stale_replies += 1;
+        }
+        // Plain requests report I/O errors as is, only the timed variant classifies them
+        match result {
+            Err(RequestError::Io(e)) if deadline.is_some() => Err(RequestError::from_io(e)),
+            result => result,
+        }
+    }
+
+    // The deadline applies to the whole message, not just to its first byte: the parts that have
+    // arrived are kept in the receiver, so that the rest can be picked up by a later call
+    async fn recv_before(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> std::result::Result<R, RequestError> {
+        let Some(deadline) = deadline else {
+            return self
+                .recv()
+                .await
+                .map_err(RequestError::Io)?
+                .ok_or(RequestError::PeerClosed);
+        };
+        #[cfg(unix)]
+        loop {
+            let mut receiver = unsafe {
+                SingleObjectReceiver::new(self.fd.as_handle(), &mut self.state, self.options, false)
+            };
+            match receiver.recv_next() {
+                Ok(value) => return value.ok_or(RequestError::PeerClosed),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(RequestError::Io(e)),
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if !self
+                .fd
+                .wait_readable(timeout)
+                .await
+                .map_err(RequestError::Io)?
+            {
+                return Err(RequestError::Timeout);
+            }
+        }
+        #[cfg(windows)]
+        {
+            if !self
+                .receiver
+                .wait_message(deadline)
+                .await
+                .map_err(RequestError::Io)?
+            {
+                return Err(RequestError::Timeout);
+            }
+            self.recv()
+                .await
+                .map_err(RequestError::Io)?
+                .ok_or(RequestError::PeerClosed)
+        }
+    }
+
+    /// Close the sending half of the channel, keeping the receiving half open.
+    ///
+    /// The other side gets `Ok(None)` once it has received all messages sent before, while this
+    /// side can still receive messages, e.g. the final results of a computation. Sending after the
+    /// sending half is closed fails.
+    ///
+    /// On Unix-like systems, this closes the sending half of the underlying socket, so it also
+    /// affects all other handles to this side of the channel, including the sender obtained by
+    /// [`into_sender`](Self::into_sender) later. The receiver obtained by
+    /// [`into_receiver`](Self::into_receiver) keeps working. On Windows, the two halves are separate
+    /// pipes, and the sending one is closed.
+    pub fn shutdown_write(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            rustix::net::shutdown(self.fd.as_handle(), rustix::net::Shutdown::Write)?;
+            Ok(())
+        }
+        #[cfg(windows)]
+        {
+            // Replace the pipe with one that has no reader, so that further sends fail
+            let (closed, _) = channel_with::<Stream, S>(&self.sender.options)?;
+            drop(std::mem::replace(&mut self.sender, closed));
+            Ok(())
+        }
+    }
+
+    /// Split the duplex into a sender and a receiver that can be used simultaneously.
+    pub(crate) fn split(self) -> Result<(Sender<Stream, S>, Receiver<Stream, R>)> {
+        #[cfg(unix)]
+        {
+            let fd = SyncStream::from(self.fd.as_handle().try_clone_to_owned()?);
+            let mut sender = unsafe { Sender::from_stream(Stream::try_new(fd)?, self.options) };
+            let mut duplex = self;
+            sender.queue = std::mem::take(&mut duplex.queue);
+            Ok((sender, duplex.into_receiver()))
+        }
+        #[cfg(windows)]
+        Ok((self.sender, self.receiver))
+    }
+
+    /// Convert the duplex into its sending half, dropping the receiving half.
+    ///
+    /// On Unix-like systems, the two halves share a socket, so the other side does not observe the
+    /// end of the stream until the sender is closed too. Use [`shutdown_write`](Self::shutdown_write)
+    /// and [`Sender::close`] to end each direction explicitly.
+    pub fn into_sender(self) -> Sender<Stream, S> {
+        #[cfg(unix)]
+        {
+            let mut sender = unsafe { Sender::from_stream(self.fd, self.options) };
+            sender.queue = self.queue;
+            sender
+        }
+        #[cfg(windows)]
+        self.sender
+    }
+
+    /// Convert the duplex into its receiving half, dropping the sending half.
+    ///
+    /// On Unix-like systems, the two halves share a socket, so the other side does not observe the
+    /// end of the stream until the receiver is dropped too. Call
+    /// [`shutdown_write`](Self::shutdown_write) first to end the stream immediately.
+    pub fn into_receiver(self) -> Receiver<Stream, R> {
+        #[cfg(unix)]
+        {
+            let mut receiver = unsafe { Receiver::from_stream(self.fd, self.options) };
+            receiver.state = self.state;
+            receiver
+        }
+        #[cfg(windows)]
+        self.receiver
+    }
+}
+
+impl<Stream: AsyncStream + fmt::Debug, S: Object, R: Object> fmt::Debug for Duplex<Stream, S, R> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        #[cfg(unix)]
+        {
+            fmt.debug_tuple("Duplex").field(&self.fd).finish()
+        }
+        #[cfg(windows)]
+        {
+            fmt.debug_struct("Duplex")
+                .field("sender", &self.sender)
+                .field("receiver", &self.receiver)
+                .finish()
+        }
+    }
+}
+
+unsafe impl<Stream: AsyncStream, S: Object, R: Object> NonTrivialObject for Duplex<Stream, S, R> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        #[cfg(unix)]
+        {
+            s.serialize(&self.fd);
+            s.serialize(&self.options);
+        }
+        #[cfg(windows)]
+        {
+            s.serialize(&self.sender);
+            s.serialize(&self.receiver);
+        }
+        s.serialize(&self.stale_replies);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            let fd = d.deserialize()?;
+            let options = d.deserialize()?;
+            let mut duplex = Self::from_stream(fd, options);
+            duplex.stale_replies = d.deserialize()?;
+            Ok(duplex)
+        }
+        #[cfg(windows)]
+        {
+            Ok(Self {
+                sender: d.deserialize()?,
+                receiver: d.deserialize()?,
+                stale_replies: d.deserialize()?,
+            })
+        }
+    }
+}
+
+impl<Stream: AsyncStream, S: Object, R: Object> TryFrom<crate::Duplex<S, R>>
+    for Duplex<Stream, S, R>
+{
+    type Error = Error;
+    fn try_from(value: crate::Duplex<S, R>) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            let mut value = value;
+            let options = value.0.options;
+            let stale_replies = value.0.stale_replies;
+            let state = std::mem::take(&mut value.0.state);
+            let mut duplex = unsafe {
+                Self::from_stream(
+                    Stream::try_new(SyncStream::from_raw_handle(value.into_raw_handle()))?,
+                    options,
+                )
+            };
+            duplex.state = state;
+            duplex.stale_replies = stale_replies;
+            Ok(duplex)
+        }
+        #[cfg(windows)]
+        {
+            Ok(Self {
+                sender: crate::Sender(value.0.sender).try_into()?,
+                receiver: crate::Receiver(value.0.receiver).try_into()?,
+                stale_replies: value.0.stale_replies,
+            })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl<Stream: AsyncStream, S: Object, R: Object> std::os::unix::io::AsRawFd
+    for Duplex<Stream, S, R>
+{
+    fn as_raw_fd(&self) -> RawHandle {
+        self.fd.as_raw_handle()
+    }
+}
+
+/// A side of a bidirectional channel that can send and receive concurrently.
+///
+/// [`Duplex::send`] and [`Duplex::recv`] take `&mut self`, so a task waiting for a message blocks
+/// other tasks from sending, e.g. heartbeats, on the same duplex. `SharedDuplex` splits the duplex
+/// into independent halves and provides methods taking `&self`:
+///
+/// ```ignore
+/// let chan = SharedDuplex::new(duplex)?;
+/// let (sent, received) = tokio::join!(chan.send(&ping), chan.recv());
+/// ```
+///
+/// Concurrent sends are performed one at a time, and so are concurrent receives, but a send and a
+/// receive can proceed simultaneously.
+///
+/// This requires a stream that waits without blocking the thread. With the streams of the `bare`
+/// module, a receive blocks the thread it is polled on, so a send polled on the same thread cannot
+/// proceed until a message arrives.
+pub struct SharedDuplex<Stream: AsyncStream, S: Object, R: Object> {
+    sender: AsyncSlot<Sender<Stream, S>>,
+    receiver: AsyncSlot<Receiver<Stream, R>>,
+}
+
+impl<Stream: AsyncStream, S: Object, R: Object> SharedDuplex<Stream, S, R> {
+    /// Wrap a duplex.
+    pub fn new(duplex: Duplex<Stream, S, R>) -> Result<Self> {
+        let (sender, receiver) = duplex.split()?;
+        Ok(Self {
+            sender: AsyncSlot::new(sender),
+            receiver: AsyncSlot::new(receiver),
+        })
+    }
+
+    /// Send a value to the other side.
+    ///
+    /// Waits until the sends started before finish.
+    pub async fn send(&self, value: &S) -> Result<()> {
+        self.sender.take().await.send(value).await
+    }
+
+    /// Receive a value from the other side.
+    ///
+    /// Returns `Ok(None)` if the other side has dropped the channel. Waits until the receives
+    /// started before finish.
+    pub async fn recv(&self) -> Result<Option<R>> {
+        self.receiver.take().await.recv().await
+    }
+
+    /// Split into a sender and a receiver.
+    pub fn into_inner(self) -> (Sender<Stream, S>, Receiver<Stream, R>) {
+        (self.sender.into_inner(), self.receiver.into_inner())
+    }
+}
+
+impl<Stream: AsyncStream + fmt::Debug, S: Object, R: Object> fmt::Debug
+    for SharedDuplex<Stream, S, R>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SharedDuplex").finish_non_exhaustive()
+    }
+}
+
+/// A value that can be borrowed by one task at a time, with the others waiting for it.
+struct AsyncSlot<T> {
+    // The value is None while it is borrowed
+    state: Mutex<(Option<T>, Vec<Waker>)>,
+}
+
+impl<T> AsyncSlot<T> {
+    fn new(value: T) -> Self {
+        Self {
+            state: Mutex::new((Some(value), Vec::new())),
+        }
+    }
+
+    async fn take(&self) -> SlotGuard<'_, T> {
+        let value = poll_fn(|cx| {
+            let mut state = self.lock();
+            if let Some(value) = state.0.take() {
+                return Poll::Ready(value);
+            }
+            if !state.1.iter().any(|waker| waker.will_wake(cx.waker())) {
+                state.1.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await;
+        SlotGuard {
+            slot: self,
+            value: Some(value),
+        }
+    }
+
+    fn into_inner(self) -> T {
+        self.state
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner())
+            .0
+            .expect("Slot is borrowed")
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, (Option<T>, Vec<Waker>)> {
+        // The state is consistent even if a thread panicked while holding the lock
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Returns the value to the slot even if the future using it is dropped
+struct SlotGuard<'a, T> {
+    slot: &'a AsyncSlot<T>,
+    value: Option<T>,
+}
+
+impl<T> std::ops::Deref for SlotGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T> std::ops::DerefMut for SlotGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for SlotGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.slot.lock();
+        state.0 = self.value.take();
+        // Wake everyone up, one of them gets the value and the rest wait again
+        for waker in state.1.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(windows)]
+type ProcHandle = crate::handles::OwnedHandle;
+
+// On Linux, the process is additionally referred to by a pidfd, opened right after spawning, while
+// the process cannot have been reaped yet. Unlike the PID, the pidfd cannot start referring to an
+// unrelated process, and it can be polled for exit. Kernels older than 5.3 and other systems only
+// use the PID.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct ProcHandle {
+    pid: rustix::process::Pid,
+    #[cfg(target_os = "linux")]
+    pidfd: Option<OwnedHandle>,
+}
+
+#[cfg(unix)]
+#[derive(Debug)]
+enum ExitStatus {
+    #[cfg(target_os = "linux")]
+    WaitId(rustix::process::WaitIdStatus),
+    Wait(rustix::process::WaitStatus),
+}
+
+#[cfg(unix)]
+impl ExitStatus {
+    fn failure(&self) -> Option<JoinError> {
+        let (code, signal) = match self {
+            #[cfg(target_os = "linux")]
+            Self::WaitId(status) => (status.exit_status(), status.terminating_signal()),
+            Self::Wait(status) => (status.exit_status(), status.terminating_signal()),
+        };
+        match (code, signal) {
+            (Some(0), _) => None,
+            (Some(code), _) => Some(JoinError::ExitedWithCode(code)),
+            (None, Some(signal)) => Some(JoinError::KilledBySignal(signal)),
+            // Only terminated processes are waited for, so this should not happen
+            (None, None) => Some(JoinError::Io(Error::other(format!(
+                "The subprocess did not terminate successfully: {self:?}"
+            )))),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl ProcHandle {
+    fn new(pid: rustix::process::Pid) -> Self {
+        Self {
+            pid,
+            #[cfg(target_os = "linux")]
+            pidfd: rustix::process::pidfd_open(pid, rustix::process::PidfdFlags::empty()).ok(),
+        }
+    }
+
+    fn id(&self) -> ProcID {
+        self.pid.as_raw_nonzero().get()
+    }
+
+    // Move the handle out of a value that implements Drop, leaving just the PID behind
+    fn take(&mut self) -> Self {
+        Self {
+            pid: self.pid,
+            #[cfg(target_os = "linux")]
+            pidfd: self.pidfd.take(),
+        }
+    }
+
+    fn kill(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &self.pidfd {
+            rustix::process::pidfd_send_signal(pidfd, rustix::process::Signal::KILL)?;
+            return Ok(());
+        }
+        kill_process(self.id())
+    }
+
+    fn try_wait(&self) -> Result<Option<ExitStatus>> {
+        self.wait_with(true)
+    }
+
+    fn wait(&self) -> Result<ExitStatus> {
+        Ok(self
+            .wait_with(false)?
+            .expect("Waiting without WNOHANG returned no status"))
+    }
+
+    fn wait_with(&self, nohang: bool) -> Result<Option<ExitStatus>> {
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &self.pidfd {
+            use {
+                rustix::process::{WaitId, WaitIdOptions},
+                std::os::unix::io::AsFd,
+            };
+            let mut options = WaitIdOptions::EXITED;
+            options.set(WaitIdOptions::NOHANG, nohang);
+            let status = loop {
+                match rustix::process::waitid(WaitId::PidFd(pidfd.as_fd()), options) {
+                    Err(rustix::io::Errno::INTR) => {}
+                    result => break result?,
+                }
+            };
+            return Ok(status.map(ExitStatus::WaitId));
+        }
+        use rustix::process::WaitOptions;
+        let mut options = WaitOptions::empty();
+        options.set(WaitOptions::NOHANG, nohang);
+        let status = loop {
+            match rustix::process::waitpid(Some(self.pid), options) {
+                Err(rustix::io::Errno::INTR) => {}
+                result => break result?,
+            }
+        };
+        Ok(status.map(|(_, status)| ExitStatus::Wait(status)))
+    }
+}
+
+// Tells a process terminated for exceeding a resource limit from other failures. On Linux, the CPU
+// time of the process is compared to its limit; other limits and systems cannot be confirmed. On
+// Windows, the job object the process is assigned to posts notifications to a completion port.
+#[derive(Debug, Default)]
+pub(crate) struct LimitWatch {
+    // The soft CPU time limit that is actually set
+    #[cfg(unix)]
+    cpu_time_limit: Option<Duration>,
+    // An upper bound of the CPU time the process has consumed, read before it is reaped
+    #[cfg(unix)]
+    cpu_time: Option<Duration>,
+    #[cfg(windows)]
+    port: Option<OwnedHandle>,
+}
+
+impl LimitWatch {
+    #[cfg(unix)]
+    fn new(options: &SpawnOptions) -> Self {
+        Self {
+            cpu_time_limit: options.cpu_time_limit.and_then(|limit| {
+                Some(Duration::from_secs(
+                    subprocess::cpu_time_rlimit(limit).current?,
+                ))
+            }),
+            cpu_time: None,
+        }
+    }
+
+    // Record the statistics of the process needed to confirm that it exceeded a limit. This has to
+    // be called after the process exits, but before it is reaped.
+    #[cfg(unix)]
+    fn observe(&mut self, proc_handle: &ProcHandle) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.cpu_time_limit.is_some() {
+            use rustix::process::{WaitId, WaitIdOptions};
+            // Blocking channels do not wait for the process to exit beforehand
+            let options = WaitIdOptions::EXITED | WaitIdOptions::NOWAIT;
+            while let Err(rustix::io::Errno::INTR) =
+                rustix::process::waitid(WaitId::Pid(proc_handle.pid), options)
+            {}
+            self.cpu_time = read_cpu_time(proc_handle.pid);
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let _ = proc_handle;
+    }
+
+    #[cfg(unix)]
+    fn exceeded(&self, failure: &JoinError) -> Option<ResourceLimit> {
+        use rustix::process::Signal;
+        let JoinError::KilledBySignal(signal) = *failure else {
+            return None;
+        };
+        // A process that ignores SIGXCPU is killed by SIGKILL once it reaches the hard limit
+        if signal != Signal::XCPU.as_raw() && signal != Signal::KILL.as_raw() {
+            return None;
+        }
+        (self.cpu_time? >= self.cpu_time_limit?).then_some(ResourceLimit::CpuTime)
+    }
+
+    #[cfg(windows)]
+    fn exceeded(&self, _failure: &JoinError) -> Option<ResourceLimit> {
+        use windows::Win32::System::{SystemServices, IO};
+        let port = self.port.as_ref()?;
+        let mut exceeded = None;
+        // The notifications are posted before the process is terminated, so they are all queued by
+        // now
+        loop {
+            let mut message = 0u32;
+            let mut key = 0usize;
+            let mut overlapped = std::ptr::null_mut();
+            if !unsafe {
+                IO::GetQueuedCompletionStatus(
+                    port.as_raw_handle(),
+                    &mut message as *mut u32,
+                    &mut key as *mut usize,
+                    &mut overlapped as *mut *mut IO::OVERLAPPED,
+                    0,
+                )
+            }
+            .as_bool()
+            {
+                break;
+            }
+            match message {
+                SystemServices::JOB_OBJECT_MSG_END_OF_PROCESS_TIME => {
+                    exceeded = Some(ResourceLimit::CpuTime);
+                }
+                SystemServices::JOB_OBJECT_MSG_PROCESS_MEMORY_LIMIT => {
+                    exceeded = Some(ResourceLimit::Memory);
+                }
+                _ => {}
+            }
+        }
+        exceeded
+    }
+}
+
+// Read the CPU time a process has consumed, in user and kernel mode. The kernel rounds both down to
+// clock ticks, so this returns an upper bound.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn read_cpu_time(pid: rustix::process::Pid) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.as_raw_nonzero())).ok()?;
+    // The name of the process may contain spaces and parentheses, so the fields are counted from
+    // the last parenthesis. utime and stime are the 14th and 15th fields, the state being the 3rd.
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    let ticks = u64::try_from(unsafe { libc::sysconf(libc::_SC_CLK_TCK) })
+        .ok()
+        .filter(|&ticks| ticks > 0)?;
+    // Each of the two is short of the real value by less than a tick
+    let total = utime.checked_add(stime)?.checked_add(2)?;
+    Some(
+        Duration::from_secs(total / ticks)
+            + Duration::from_nanos(total % ticks * 1_000_000_000 / ticks),
+    )
+}
+
+// Wait until the process exits without reaping it, so that the runtime thread is not blocked.
+//
+// On Linux, the pidfd is polled by the runtime. Otherwise, a watcher closes a channel once the
+// process exits: the reaper thread on Unix-like systems and a thread pool wait on Windows.
+async fn wait_exited<Stream: AsyncStream>(proc_handle: &ProcHandle) -> Result<()> {
+    #[cfg(unix)]
+    {
+        // Blocking channels wait synchronously anyway
+        if Stream::IS_BLOCKING {
+            return Ok(());
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(pidfd) = &proc_handle.pidfd {
+            use rustix::process::{WaitId, WaitIdOptions};
+            use std::os::unix::io::AsFd;
+            let options = WaitIdOptions::EXITED | WaitIdOptions::NOHANG | WaitIdOptions::NOWAIT;
+            // The runtime makes the descriptor non-blocking, which would make blocking waitid fail
+            // on the original one, so a separate one is opened
+            let watcher =
+                rustix::process::pidfd_open(proc_handle.pid, rustix::process::PidfdFlags::empty())?;
+            let watcher = Stream::try_new(SyncStream::from(watcher))?;
+            return watcher
+                .blocking_read(|| {
+                    match rustix::process::waitid(WaitId::PidFd(pidfd.as_fd()), options)? {
+                        Some(_) => Ok(()),
+                        None => Err(ErrorKind::WouldBlock.into()),
+                    }
+                })
+                .await;
+        }
+
+        let (tx, rx) = crate::channel::<()>()?;
+        let mut rx: Receiver<Stream, ()> = rx.try_into()?;
+        send_to_reaper(ReaperRequest::Watch(proc_handle.pid, tx));
+        rx.recv().await?;
+    }
+    #[cfg(windows)]
+    {
+        let (tx, rx) = crate::channel::<()>()?;
+        let mut rx: Receiver<Stream, ()> = rx.try_into()?;
+        let _wait = ExitWait::register(proc_handle, tx)?;
+        rx.recv().await?;
+    }
+    Ok(())
+}
+
+// Drops the sender once the process exits, which the receiving side observes as end of stream
+#[cfg(windows)]
+struct ExitWait {
+    wait_handle: Foundation::HANDLE,
+    // Boxed so that the address passed to the callback stays valid
+    sender: Box<Mutex<Option<crate::Sender<()>>>>,
+}
+
+#[cfg(windows)]
+impl ExitWait {
+    fn register(proc_handle: &ProcHandle, sender: crate::Sender<()>) -> Result<Self> {
+        let sender = Box::new(Mutex::new(Some(sender)));
+        let mut wait_handle = Foundation::HANDLE::default();
+        unsafe {
+            Threading::RegisterWaitForSingleObject(
+                &mut wait_handle,
+                proc_handle.as_raw_handle(),
+                Some(Self::on_exit),
+                &*sender as *const Mutex<Option<crate::Sender<()>>> as *const std::ffi::c_void,
+                WindowsProgramming::INFINITE,
+                Threading::WT_EXECUTEONLYONCE,
+            )
+            .ok()?;
+        }
+        Ok(Self {
+            wait_handle,
+            sender,
+        })
+    }
+
+    unsafe extern "system" fn on_exit(
+        context: *mut std::ffi::c_void,
+        _timed_out: Foundation::BOOLEAN,
+    ) {
+        let sender = &*(context as *const Mutex<Option<crate::Sender<()>>>);
+        sender.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ExitWait {
+    fn drop(&mut self) {
+        // This waits for the callback to finish if it is running, so that the sender can be freed
+        unsafe {
+            Threading::UnregisterWaitEx(self.wait_handle, Foundation::INVALID_HANDLE_VALUE);
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) type ProcID = rustix::process::RawPid;
+#[cfg(windows)]
+pub(crate) type ProcID = RawHandle;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum KillState {
+    Running,
+    Killed,
+    Joined,
+}
+
+/// A subprocess.
+///
+/// By default, dropping a `Child` without joining it leaves the subprocess running in background,
+/// and on Unix, the process stays around as a zombie after it exits. Use [`Child::kill_on_drop`]
+/// to terminate the process on drop, or [`Child::detach`] to have it reaped automatically.
+pub struct Child<Stream: AsyncStream, T: Object> {
+    pub(crate) proc_handle: ProcHandle,
+    output_rx: Receiver<Stream, Output<T>>,
+    kill_state: Arc<Mutex<KillState>>,
+    kill_on_drop: bool,
+    pub(crate) pipes: ChildPipes,
+    limits: LimitWatch,
+}
+
+// The ends of the pipes connected to the standard streams of a child that the parent keeps
+#[derive(Debug, Default)]
+pub(crate) struct ChildPipes {
+    pub(crate) stdout: Option<PipeReader>,
+    pub(crate) stderr: Option<PipeReader>,
+}
+
+/// A handle that allows to kill the process.
+///
+/// Kill handles can be passed to other processes, e.g. to a supervisor that terminates its sibling
+/// workers. A copy in another process refers to the process object itself rather than to its ID:
+/// it is backed by a pidfd on Linux and by a duplicated process handle on Windows. Killing via a
+/// remote copy is allowed after the process has been joined by the parent, but has no effect
+/// then: it fails with an error, and never affects an unrelated process that has reused the ID.
+/// Other Unix-like systems lack such handles, so remote copies cannot kill the process there and
+/// fail with [`ErrorKind::Unsupported`].
+///
+/// Killing via a remote copy does not make [`Child::join`] return [`JoinError::Killed`] in the
+/// parent, since the parent does not know who killed the process: it returns
+/// [`JoinError::KilledBySignal`] on Unix-like systems and [`JoinError::ExitedWithCode`] on Windows
+/// instead.
+///
+/// Cloning a kill handle is cheap. Clones share the state with the original, so none of them can
+/// kill the process after it has been joined.
+#[derive(Clone)]
+pub struct KillHandle {
+    proc_id: ProcID,
+    // None for copies received from another process
+    kill_state: Option<Arc<Mutex<KillState>>>,
+    // A handle to the process object itself, if the system supports it
+    process: Option<Arc<OwnedHandle>>,
+}
+
+impl<Stream: AsyncStream, T: Object> Child<Stream, T> {
+    fn new(
+        proc_handle: ProcHandle,
+        output_rx: Receiver<Stream, Output<T>>,
+        pipes: ChildPipes,
+        limits: LimitWatch,
+    ) -> Child<Stream, T> {
+        Child {
+            proc_handle,
+            output_rx,
+            kill_state: Arc::new(Mutex::new(KillState::Running)),
+            kill_on_drop: false,
+            pipes,
+            limits,
+        }
+    }
+
+    /// Take the reading end of the pipe connected to the standard output of the process.
+    ///
+    /// Returns `None` unless the process was spawned with [`SpawnOptions::stdout`] set to
+    /// [`Stdio::piped`](crate::Stdio::piped), or if the pipe has already been taken.
+    pub fn take_stdout(&mut self) -> Result<Option<ChildPipe<Stream>>> {
+        self.pipes.stdout.take().map(ChildPipe::new).transpose()
+    }
+
+    /// Take the reading end of the pipe connected to the standard error of the process.
+    ///
+    /// Returns `None` unless the process was spawned with [`SpawnOptions::stderr`] set to
+    /// [`Stdio::piped`](crate::Stdio::piped), or if the pipe has already been taken.
+    pub fn take_stderr(&mut self) -> Result<Option<ChildPipe<Stream>>> {
+        self.pipes.stderr.take().map(ChildPipe::new).transpose()
+    }
+
+    /// Terminate the process when this `Child` is dropped without being joined.
+    ///
+    /// The process is killed as if by [`KillHandle::kill`]. Dropping does not wait for the process
+    /// to actually exit. On Unix-like systems, the process is then reaped in background, like a
+    /// [detached](Child::detach) one.
+    pub fn kill_on_drop(mut self) -> Self {
+        self.kill_on_drop = true;
+        self
+    }
+
+    /// Get a handle for process termination.
+    pub fn get_kill_handle(&self) -> crate::KillHandle {
+        // The process has not been reaped yet, so the ID refers to it. This can only fail if the
+        // system does not support pidfds or is out of resources, in which case the handle cannot
+        // be used remotely.
+        #[cfg(target_os = "linux")]
+        let process =
+            rustix::process::pidfd_open(self.proc_handle.pid, rustix::process::PidfdFlags::empty())
+                .ok();
+        #[cfg(all(unix, not(target_os = "linux")))]
+        let process = None;
+        #[cfg(windows)]
+        let process = self.proc_handle.try_clone().ok();
+        KillHandle {
+            proc_id: self.id(),
+            kill_state: Some(self.kill_state.clone()),
+            process: process.map(Arc::new),
+        }
+    }
+
+    /// Forget about the process, letting it run to completion in background.
+    ///
+    /// Unlike simply dropping the `Child`, this makes sure the process does not stay around as a
+    /// zombie after it exits. On Unix, the process is handed over to a background reaper thread,
+    /// started on first use, which polls detached processes without blocking every 100 ms and
+    /// collects the ones that have exited. On Windows, there are no zombies, so the process
+    /// handle is just closed.
+    ///
+    /// The value returned by a detached process is discarded. As nobody receives it, a process
+    /// returning anything but `()` fails to deliver its return value, so functions intended to be
+    /// detached should return `()`.
+    ///
+    /// Kill handles obtained before detaching stop working once the process is reaped.
+    /// [`Child::kill_on_drop`] has no effect on a detached process.
+    pub fn detach(mut self) {
+        self.kill_on_drop = false;
+        #[cfg(unix)]
+        reap_detached(self.proc_handle.take(), self.kill_state.clone());
+        #[cfg(windows)]
+        {
+            *self.kill_state.lock().expect("Kill mutex is poisoned") = KillState::Joined;
+        }
+    }
+
+    /// Get ID of the process.
+    pub fn id(&self) -> ProcID {
+        #[cfg(unix)]
+        {
+            self.proc_handle.id()
+        }
+        #[cfg(windows)]
+        {
+            self.proc_handle.as_raw_handle()
+        }
+    }
+
+    /// Get the current scheduling priority of the process.
+    ///
+    /// The priority is queried from the OS, so it reflects changes made after spawning, too. On
+    /// Windows, the priority class is reported as a nice value: `-20` for `REALTIME_PRIORITY_CLASS`,
+    /// `-14` for `HIGH_PRIORITY_CLASS`, `-7` for `ABOVE_NORMAL_PRIORITY_CLASS`, `0` for
+    /// `NORMAL_PRIORITY_CLASS`, `7` for `BELOW_NORMAL_PRIORITY_CLASS`, and `19` for
+    /// `IDLE_PRIORITY_CLASS`. See [`SpawnOptions::priority`] for more information.
+    pub fn get_priority(&self) -> Result<i32> {
+        #[cfg(unix)]
+        {
+            subprocess::priority(self.proc_handle.pid)
+        }
+        #[cfg(windows)]
+        {
+            subprocess::priority(self.proc_handle.as_raw_handle())
+        }
+    }
+
+    /// Get the CPUs the process is currently restricted to.
+    ///
+    /// The affinity is queried from the OS, so it reflects changes made after spawning, too. See
+    /// [`SpawnOptions::cpu_affinity`] for more information.
+    pub fn get_cpu_affinity(&self) -> Result<Vec<usize>> {
+        #[cfg(unix)]
+        {
+            subprocess::cpu_affinity(self.proc_handle.pid)
+        }
+        #[cfg(windows)]
+        {
+            subprocess::cpu_affinity(self.proc_handle.as_raw_handle())
+        }
+    }
+
+    /// Wait for the process to finish and obtain the value it returns.
+    ///
+    /// An error is returned if the process panics or is terminated. An error is also delivered if
+    /// it exits via [`std::process::exit`] or alike instead of returning a value, unless the return
+    /// type is `()`. In that case, `Ok(())` is returned.
+    ///
+    /// If the function panics, [`JoinError::Panicked`] with the panic message is returned. If the
+    /// process was terminated via [`KillHandle::kill`], [`JoinError::Killed`] is returned. A process
+    /// that finished successfully before being killed is not considered killed.
+    ///
+    /// This method used to return [`std::io::Result`], see
+    /// [Migrating from `io::Result`](JoinError#migrating-from-ioresult).
+    ///
+    /// Waiting for the process to exit does not block the runtime thread, even if the process
+    /// lingers after closing its end of the channel. On Linux, the runtime polls a pidfd of the
+    /// process. On other Unix-like systems, a helper thread shared by all children polls the process,
+    /// first every millisecond and then less often, up to every 100 ms. On Windows, the wait is
+    /// performed by the system thread pool.
+    pub async fn join(mut self) -> std::result::Result<T, JoinError> {
+        // Panics caught by the function are reported too, so the last one is kept in case the
+        // process fails
+        let mut panic = None;
+        let value = loop {
+            match self.output_rx.recv().await {
+                Ok(Some(Output::Panicked {
+                    message,
+                    location,
+                    backtrace,
+                })) => {
+                    panic = Some(JoinError::Panicked {
+                        message,
+                        location,
+                        backtrace,
+                    });
+                }
+                Ok(Some(Output::Returned(value))) => break Ok(Some(value)),
+                // Only streaming functions yield items, and they are not joined this way
+                Ok(Some(Output::Yielded(_))) => {}
+                Ok(None) => break Ok(None),
+                Err(e) => break Err(e),
+            }
+        };
+        self.wait_finished(panic).await?;
+        let mut value = value.map_err(JoinError::Io)?;
+        if let Some(void) = imp::if_void::<T>() {
+            // The value should be None at this moment
+            value = Some(void);
+        }
+        value.ok_or(JoinError::NoReturnValue)
+    }
+
+    // Wait for the process to exit once the output channel is closed, and report how it failed, if
+    // it did
+    async fn wait_finished(
+        &mut self,
+        panic: Option<JoinError>,
+    ) -> std::result::Result<(), JoinError> {
+        wait_exited::<Stream>(&self.proc_handle)
+            .await
+            .map_err(JoinError::Io)?;
+        let mut guard = self.kill_state.lock().expect("Kill mutex is poisoned");
+        let killed = *guard == KillState::Killed;
+        *guard = KillState::Joined;
+        // The process has exited by now, unless the channel is blocking, so this does not block the
+        // runtime
+        #[cfg(unix)]
+        self.limits.observe(&self.proc_handle);
+        #[cfg(unix)]
+        let failure = self.proc_handle.wait().map_err(JoinError::Io)?.failure();
+        #[cfg(windows)]
+        let failure = {
+            if unsafe {
+                Threading::WaitForSingleObject(
+                    self.proc_handle.as_raw_handle(),
+                    WindowsProgramming::INFINITE,
+                )
+            } == u32::MAX
+            {
+                return Err(JoinError::Io(Error::last_os_error()));
+            }
+            let mut code: u32 = 0;
+            unsafe {
+                Threading::GetExitCodeProcess(
+                    self.proc_handle.as_raw_handle(),
+                    &mut code as *mut u32,
+                )
+                .ok()
+                .map_err(|e| JoinError::Io(e.into()))?;
+            }
+            // Abnormal terminations are reported with NTSTATUS codes of error severity
+            match code {
+                0 => None,
+                0xc0000000.. => Some(JoinError::Terminated(code)),
+                _ => Some(JoinError::ExitedWithCode(code as i32)),
+            }
+        };
+        drop(guard);
+        match failure {
+            Some(failure) => Err(if killed {
+                JoinError::Killed
+            } else if let Some(panic) = panic {
+                panic
+            } else if let Some(limit) = self.limits.exceeded(&failure) {
+                JoinError::LimitExceeded(limit)
+            } else {
+                failure
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A subprocess running a streaming function, i.e. one returning `impl Iterator<Item = T>` or, if
+/// it is `async`, `impl Stream<Item = T>`.
+///
+/// Created by calling `spawn` (or a runtime-specific variant) on such a function annotated with
+/// [`func`](crate::func). The child sends each item to the parent as soon as it is produced, and
+/// the items are received with [`ChildStream::next`]. After the last item, the process is waited
+/// for, and an error is reported if it fails, as with [`Child::join`].
+///
+/// The child blocks if the parent does not receive the items fast enough. Dropping the
+/// `ChildStream` closes the channel, so the child panics once it tries to send the next item.
+#[derive(Debug)]
+pub struct ChildStream<Stream: AsyncStream, T: Object> {
+    child: Child<Stream, T>,
+    panic: Option<JoinError>,
+    finished: bool,
+}
+
+impl<Stream: AsyncStream, T: Object> ChildStream<Stream, T> {
+    pub(crate) fn new(child: Child<Stream, T>) -> Self {
+        Self {
+            child,
+            panic: None,
+            finished: false,
+        }
+    }
+
+    /// Get a handle for process termination.
+    pub fn get_kill_handle(&self) -> crate::KillHandle {
+        self.child.get_kill_handle()
+    }
+
+    /// Get ID of the process.
+    pub fn id(&self) -> ProcID {
+        self.child.id()
+    }
+
+    /// Receive the next item produced by the function.
+    ///
+    /// Returns `None` once the function finishes successfully. If the process fails after
+    /// producing some items, e.g. if the function panics, the error is returned as the last item,
+    /// see [`Child::join`] for the possible errors.
+    pub async fn next(&mut self) -> Option<std::result::Result<T, JoinError>> {
+        if self.finished {
+            return None;
+        }
+        let received = loop {
+            match self.child.output_rx.recv().await {
+                Ok(Some(Output::Yielded(item))) => return Some(Ok(item)),
+                Ok(Some(Output::Panicked {
+                    message,
+                    location,
+                    backtrace,
+                })) => {
+                    self.panic = Some(JoinError::Panicked {
+                        message,
+                        location,
+                        backtrace,
+                    });
+                }
+                Ok(Some(Output::Returned(_))) | Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.finished = true;
+        let result = self.child.wait_finished(self.panic.take()).await;
+        result.and(received.map_err(JoinError::Io)).err().map(Err)
+    }
+
+    /// Wait for the process to finish, discarding the items it has not produced yet.
+    ///
+    /// The items are still received, so the process runs to completion. See [`ChildStream::next`]
+    /// for the errors reported.
+    pub async fn join(mut self) -> std::result::Result<(), JoinError> {
+        while let Some(item) = self.next().await {
+            item?;
+        }
+        Ok(())
+    }
+}
+
+/// Wait for several processes to finish, yielding their results in the order they finish.
+///
+/// Each item of the returned stream contains the index of the child in `children` and the result
+/// of joining it, as returned by [`Child::join`]. This allows to process early results before the
+/// slowest child finishes. The stream ends once all children are joined.
+///
+/// The returned value implements [`futures_core::Stream`], and also provides
+/// [`JoinAllInOrder::next`] for use without stream combinators.
+pub fn join_all_in_order<Stream: AsyncStream, T: Object>(
+    children: impl IntoIterator<Item = Child<Stream, T>>,
+) -> JoinAllInOrder<impl Future<Output = std::result::Result<T, JoinError>>> {
+    let futures: Vec<_> = children
+        .into_iter()
+        .map(|child| Some(Box::pin(child.join())))
+        .collect();
+    JoinAllInOrder {
+        remaining: futures.len(),
+        futures,
+    }
+}
+
+/// A stream of results of joined processes, in the order they finish.
+///
+/// See [`join_all_in_order`] for more information.
+pub struct JoinAllInOrder<F: Future> {
+    futures: Vec<Option<Pin<Box<F>>>>,
+    remaining: usize,
+}
+
+impl<F: Future> JoinAllInOrder<F> {
+    /// Wait for the next process to finish.
+    ///
+    /// Returns `None` once all processes are joined.
+    pub async fn next(&mut self) -> Option<(usize, F::Output)> {
+        poll_fn(|cx| futures_core::Stream::poll_next(Pin::new(&mut *self), cx)).await
+    }
+
+    /// Get the number of processes that have not been joined yet.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+}
+
+impl<F: Future> futures_core::Stream for JoinAllInOrder<F> {
+    type Item = (usize, F::Output);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        // All pending joins are polled on each wakeup. This is linear in the number of children,
+        // which is cheap compared to spawning them in the first place
+        for (index, slot) in self.futures.iter_mut().enumerate() {
+            let Some(future) = slot else {
+                continue;
+            };
+            if let Poll::Ready(result) = future.as_mut().poll(cx) {
+                *slot = None;
+                self.remaining -= 1;
+                return Poll::Ready(Some((index, result)));
+            }
+        }
+        Poll::Pending
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<F: Future> fmt::Debug for JoinAllInOrder<F> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("JoinAllInOrder")
+            .field("remaining", &self.remaining)
+            .finish()
+    }
+}
+
+impl<Stream: AsyncStream + fmt::Debug, T: Object> fmt::Debug for Child<Stream, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Child")
+            .field("proc_handle", &self.proc_handle)
+            .field("output_rx", &self.output_rx)
+            .field("kill_on_drop", &self.kill_on_drop)
+            .finish()
+    }
+}
+
+impl<Stream: AsyncStream, T: Object> Drop for Child<Stream, T> {
+    fn drop(&mut self) {
+        if !self.kill_on_drop {
+            return;
+        }
+        let mut guard = self.kill_state.lock().expect("Kill mutex is poisoned");
+        if *guard == KillState::Running {
+            // There is no way to report the error from here; the process might have already exited
+            #[cfg(unix)]
+            let _ = self.proc_handle.kill();
+            #[cfg(windows)]
+            let _ = kill_process(self.id());
+            *guard = KillState::Killed;
+            drop(guard);
+            // Nobody is going to join the process, so it has to be reaped in background so as not
+            // to stay around as a zombie
+            #[cfg(unix)]
+            reap_detached(self.proc_handle.take(), self.kill_state.clone());
+        }
+    }
+}
+
+/// The reading end of a pipe connected to the standard output or error of a child.
+///
+/// Obtained via [`Child::take_stdout`] and [`Child::take_stderr`]. The pipe reports end of stream
+/// once the child and all processes that inherited the stream have exited or closed it.
+///
+/// On Unix-like systems, the pipe is polled by the runtime. On Windows, anonymous pipes do not
+/// support asynchronous I/O, so the pipe is read by a helper thread instead.
+pub struct ChildPipe<Stream: AsyncStream> {
     #[cfg(unix)]
-    pub(crate) unsafe fn from_stream(fd: Stream) -> Self {
-        Duplex {
-            fd,
-            marker: PhantomData,
-        }
-    }
+    stream: Stream,
+    #[cfg(windows)]
+    chunks: Receiver<Stream, Vec<u8>>,
+    #[cfg(windows)]
+    chunk: Vec<u8>,
+    #[cfg(windows)]
+    chunk_pos: usize,
+}
 
-    /// Send a value to the other side.
-    pub async fn send(&mut self, value: &S) -> Result<()> {
+impl<Stream: AsyncStream> ChildPipe<Stream> {
+    fn new(pipe: PipeReader) -> Result<Self> {
         #[cfg(unix)]
         {
-            let mut sender =
-                SingleObjectSender::new(self.fd.as_handle(), value, Stream::IS_BLOCKING);
-            self.fd.blocking_write(|| sender.send_next()).await
+            let fd: OwnedHandle = pipe.into();
+            Ok(Self {
+                stream: Stream::try_new(SyncStream::from(fd))?,
+            })
         }
         #[cfg(windows)]
-        self.sender.send(value).await
+        {
+            use std::io::Read;
+            let (mut tx, rx) = crate::channel::<Vec<u8>>()?;
+            let mut pipe = pipe;
+            std::thread::Builder::new()
+                .name("crossmist-pipe".to_string())
+                .spawn(move || {
+                    let mut buf = vec![0; 65536];
+                    // A broken pipe means that the child has closed the stream
+                    while let Ok(n @ 1..) = pipe.read(&mut buf) {
+                        if tx.send(&buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                })?;
+            Ok(Self {
+                chunks: rx.try_into()?,
+                chunk: Vec::new(),
+                chunk_pos: 0,
+            })
+        }
     }
 
-    /// Receive a value from the other side.
+    /// Read some bytes from the pipe into `buf`, returning how many bytes were read.
     ///
-    /// Returns `Ok(None)` if the other side has dropped the channel.
-    pub async fn recv(&mut self) -> Result<Option<R>> {
+    /// Zero is returned at end of stream.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         #[cfg(unix)]
         {
-            let mut receiver =
-                unsafe { SingleObjectReceiver::new(self.fd.as_handle(), Stream::IS_BLOCKING) };
-            self.fd.blocking_read(|| receiver.recv_next()).await
+            let fd = self.stream.as_handle();
+            self.stream
+                .blocking_read(|| Ok(rustix::io::read(fd, &mut *buf)?))
+                .await
         }
         #[cfg(windows)]
-        self.receiver.recv().await
+        {
+            if self.chunk_pos == self.chunk.len() {
+                match self.chunks.recv().await? {
+                    Some(chunk) => self.chunk = chunk,
+                    None => return Ok(0),
+                }
+                self.chunk_pos = 0;
+            }
+            let n = buf.len().min(self.chunk.len() - self.chunk_pos);
+            buf[..n].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + n]);
+            self.chunk_pos += n;
+            Ok(n)
+        }
     }
 
-    /// Send a value from the other side and wait for a response immediately.
+    /// Read all bytes until end of stream, appending them to `buf`.
     ///
-    /// If the other side closes the channel before responding, an error is returned.
-    pub async fn request(&mut self, value: &S) -> Result<R> {
-        self.send(value).await?;
-        self.recv().await?.ok_or_else(|| {
-            Error::new(
-                ErrorKind::UnexpectedEof,
-                "The subprocess exitted before responding to the request",
-            )
-        })
-    }
-
-    pub fn into_sender(self) -> Sender<Stream, S> {
-        #[cfg(unix)]
-        unsafe {
-            Sender::from_stream(self.fd)
+    /// Returns the number of bytes read.
+    pub async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        let mut chunk = [0; 8192];
+        loop {
+            let n = self.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(buf.len() - start);
+            }
+            buf.extend_from_slice(&chunk[..n]);
         }
-        #[cfg(windows)]
-        self.sender
     }
 
-    pub fn into_receiver(self) -> Receiver<Stream, R> {
-        #[cfg(unix)]
-        unsafe {
-            Receiver::from_stream(self.fd)
-        }
-        #[cfg(windows)]
-        self.receiver
+    /// Read all bytes until end of stream, appending them to `buf`.
+    ///
+    /// Returns the number of bytes read. If the data is not valid UTF-8, an error of kind
+    /// [`ErrorKind::InvalidData`] is returned, and `buf` is left unchanged.
+    pub async fn read_to_string(&mut self, buf: &mut String) -> Result<usize> {
+        let mut bytes = Vec::new();
+        let n = self.read_to_end(&mut bytes).await?;
+        buf.push_str(
+            std::str::from_utf8(&bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        );
+        Ok(n)
     }
 }
 
-impl<Stream: AsyncStream + fmt::Debug, S: Object, R: Object> fmt::Debug for Duplex<Stream, S, R> {
+impl<Stream: AsyncStream + fmt::Debug> fmt::Debug for ChildPipe<Stream> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut fmt = fmt.debug_struct("ChildPipe");
         #[cfg(unix)]
-        {
-            fmt.debug_tuple("Duplex").field(&self.fd).finish()
-        }
+        fmt.field("stream", &self.stream);
         #[cfg(windows)]
-        {
-            fmt.debug_struct("Duplex")
-                .field("sender", &self.sender)
-                .field("receiver", &self.receiver)
-                .finish()
-        }
+        fmt.field("chunks", &self.chunks);
+        fmt.finish()
     }
 }
 
-impl<Stream: AsyncStream, S: Object, R: Object> TryFrom<crate::Duplex<S, R>>
-    for Duplex<Stream, S, R>
-{
-    type Error = Error;
-    fn try_from(value: crate::Duplex<S, R>) -> Result<Self> {
-        #[cfg(unix)]
-        unsafe {
-            Ok(Self::from_stream(Stream::try_new(
-                SyncStream::from_raw_handle(value.into_raw_handle()),
-            )?))
-        }
-        #[cfg(windows)]
-        {
-            Ok(Self {
-                sender: crate::Sender(value.0.sender).try_into()?,
-                receiver: crate::Receiver(value.0.receiver).try_into()?,
-            })
-        }
-    }
+#[cfg(unix)]
+enum ReaperRequest {
+    // Reap the process once it exits
+    Reap(ProcHandle, Arc<Mutex<KillState>>),
+    // Drop the sender once the process exits, without reaping it
+    Watch(rustix::process::Pid, crate::Sender<()>),
 }
 
 #[cfg(unix)]
-impl<Stream: AsyncStream, S: Object, R: Object> std::os::unix::io::AsRawFd
-    for Duplex<Stream, S, R>
-{
-    fn as_raw_fd(&self) -> RawHandle {
-        self.fd.as_raw_handle()
-    }
+fn reap_detached(proc_handle: ProcHandle, kill_state: Arc<Mutex<KillState>>) {
+    send_to_reaper(ReaperRequest::Reap(proc_handle, kill_state));
 }
 
 #[cfg(unix)]
-type ProcHandle = rustix::process::Pid;
-#[cfg(windows)]
-type ProcHandle = crate::handles::OwnedHandle;
+fn send_to_reaper(request: ReaperRequest) {
+    static REAPER: std::sync::OnceLock<Mutex<std::sync::mpsc::Sender<ReaperRequest>>> =
+        std::sync::OnceLock::new();
+    REAPER
+        .get_or_init(|| {
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::Builder::new()
+                .name("crossmist-reaper".to_string())
+                .spawn(move || run_reaper(rx))
+                .expect("Failed to start reaper thread");
+            Mutex::new(tx)
+        })
+        .lock()
+        .expect("Reaper mutex is poisoned")
+        .send(request)
+        .expect("Reaper thread has stopped");
+}
 
 #[cfg(unix)]
-pub(crate) type ProcID = rustix::process::RawPid;
-#[cfg(windows)]
-pub(crate) type ProcID = RawHandle;
+fn run_reaper(rx: std::sync::mpsc::Receiver<ReaperRequest>) {
+    use rustix::process::{WaitId, WaitIdOptions};
+    const REAP_INTERVAL: Duration = Duration::from_millis(100);
+    // Watched processes have closed their output channel, so they usually exit right away. They
+    // are polled often at first, and then less and less often.
+    const FIRST_WATCH_INTERVAL: Duration = Duration::from_millis(1);
+    let mut detached = Vec::new();
+    let mut watched = Vec::new();
+    let mut interval = REAP_INTERVAL;
+    loop {
+        // Sleep indefinitely while there is nothing to reap
+        let request = if detached.is_empty() && watched.is_empty() {
+            match rx.recv() {
+                Ok(request) => Some(request),
+                Err(_) => return,
+            }
+        } else {
+            rx.recv_timeout(interval).ok()
+        };
+        interval = (interval * 2).min(REAP_INTERVAL);
+        for request in request.into_iter().chain(rx.try_iter()) {
+            match request {
+                ReaperRequest::Reap(proc_handle, kill_state) => {
+                    detached.push((proc_handle, kill_state))
+                }
+                ReaperRequest::Watch(pid, tx) => {
+                    watched.push((pid, tx));
+                    interval = FIRST_WATCH_INTERVAL;
+                }
+            }
+        }
+        // Reaping watched processes would free the PID while they can still be killed
+        let options = WaitIdOptions::EXITED | WaitIdOptions::NOHANG | WaitIdOptions::NOWAIT;
+        watched.retain(|(pid, _)| {
+            matches!(
+                rustix::process::waitid(WaitId::Pid(*pid), options),
+                Ok(None) | Err(rustix::io::Errno::INTR)
+            )
+        });
+        detached.retain(|(proc_handle, kill_state)| {
+            // Hold the lock so that the process is not killed after its PID is freed
+            let mut guard = kill_state.lock().expect("Kill mutex is poisoned");
+            match proc_handle.try_wait() {
+                Ok(None) => true,
+                _ => {
+                    *guard = KillState::Joined;
+                    false
+                }
+            }
+        });
+    }
+}
 
-/// A subprocess.
-pub struct Child<Stream: AsyncStream, T: Object> {
-    pub(crate) proc_handle: ProcHandle,
-    output_rx: Receiver<Stream, T>,
-    may_kill: Arc<Mutex<bool>>,
+fn kill_process(proc_id: ProcID) -> Result<()> {
+    #[cfg(unix)]
+    rustix::process::kill_process(
+        rustix::process::Pid::from_raw(proc_id).unwrap(),
+        rustix::process::Signal::KILL,
+    )?;
+    #[cfg(windows)]
+    unsafe {
+        Threading::TerminateProcess(proc_id, 1).ok()?;
+    }
+    Ok(())
 }
 
-/// A handle that allows to kill the process.
-pub struct KillHandle {
-    proc_id: ProcID,
-    may_kill: Arc<Mutex<bool>>,
+fn kill_process_group(proc_id: ProcID) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let pid = rustix::process::Pid::from_raw(proc_id).unwrap();
+        // Signalling the group of a process that does not lead one would hit someone else's group,
+        // likely the one of the parent
+        if rustix::process::getpgid(Some(pid))? != pid {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The process does not lead a process group",
+            ));
+        }
+        rustix::process::kill_process_group(pid, rustix::process::Signal::KILL)?;
+        Ok(())
+    }
+    #[cfg(windows)]
+    {
+        let _ = proc_id;
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Killing process groups is not supported on Windows",
+        ))
+    }
 }
 
-impl<Stream: AsyncStream, T: Object> Child<Stream, T> {
-    fn new(proc_handle: ProcHandle, output_rx: Receiver<Stream, T>) -> Child<Stream, T> {
-        Child {
-            proc_handle,
-            output_rx,
-            may_kill: Arc::new(Mutex::new(true)),
+impl KillHandle {
+    /// Terminate the process immediately.
+    pub fn kill(&self) -> Result<()> {
+        let Some(kill_state) = &self.kill_state else {
+            return self.kill_via_handle();
+        };
+        let mut guard = kill_state.lock().expect("Kill mutex is poisoned");
+        if *guard == KillState::Joined {
+            return Err(std::io::Error::other(
+                "This process has already been joined",
+            ));
+        }
+        // The pidfd is preferred when available, but the PID is safe to use here too, as the
+        // process cannot be reaped while the lock is held
+        if self.process.is_some() {
+            self.kill_via_handle()?;
+        } else {
+            kill_process(self.proc_id)?;
         }
+        *guard = KillState::Killed;
+        Ok(())
     }
 
-    /// Get a handle for process termination.
-    pub fn get_kill_handle(&self) -> crate::KillHandle {
-        KillHandle {
-            proc_id: self.id(),
-            may_kill: self.may_kill.clone(),
+    /// Terminate the process group led by the process immediately.
+    ///
+    /// This sends `SIGKILL` to all processes in the group, i.e. the process and the processes it
+    /// has started, unless they have moved to other groups. The process must have been spawned with
+    /// [`SpawnOptions::process_group`] set to a new group or session; otherwise, this fails with
+    /// [`ErrorKind::InvalidInput`] and sends no signals. The group stays reachable until the
+    /// process is joined, even if the process itself has exited in the meantime.
+    ///
+    /// This is only supported on Unix-like systems; on Windows, this fails with
+    /// [`ErrorKind::Unsupported`].
+    ///
+    /// Remote copies of the handle cannot kill process groups and fail with
+    /// [`ErrorKind::Unsupported`].
+    pub fn kill_group(&self) -> Result<()> {
+        let Some(kill_state) = &self.kill_state else {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Killing process groups via a KillHandle passed from another process is not \
+                 supported",
+            ));
+        };
+        let mut guard = kill_state.lock().expect("Kill mutex is poisoned");
+        if *guard == KillState::Joined {
+            return Err(std::io::Error::other(
+                "This process has already been joined",
+            ));
         }
+        kill_process_group(self.proc_id)?;
+        *guard = KillState::Killed;
+        Ok(())
+    }
+}
+
+impl KillHandle {
+    fn kill_via_handle(&self) -> Result<()> {
+        let Some(process) = &self.process else {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Killing via a KillHandle passed from another process requires pidfd support",
+            ));
+        };
+        #[cfg(target_os = "linux")]
+        rustix::process::pidfd_send_signal(process, rustix::process::Signal::KILL)?;
+        #[cfg(all(unix, not(target_os = "linux")))]
+        let _ = process;
+        #[cfg(windows)]
+        kill_process(process.as_raw_handle())?;
+        Ok(())
+    }
+}
+
+unsafe impl NonTrivialObject for KillHandle {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        #[cfg(unix)]
+        s.serialize(&self.proc_id);
+        s.serialize(&self.process);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        #[cfg(unix)]
+        let proc_id = d.deserialize()?;
+        let process: Option<Arc<OwnedHandle>> = d.deserialize()?;
+        // On Windows, the process is identified by a handle, which is only meaningful in the
+        // process that owns it
+        #[cfg(windows)]
+        let proc_id = process
+            .as_ref()
+            .map_or(RawHandle::default(), |process| process.as_raw_handle());
+        Ok(Self {
+            proc_id,
+            kill_state: None,
+            process,
+        })
+    }
+}
+
+impl fmt::Debug for KillHandle {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("KillHandle")
+            .field("proc_id", &self.proc_id)
+            .finish()
     }
+}
+
+/// A child process that has been created but has not received the function to run yet.
+///
+/// Returned by the `spawn_*_deferred_with` methods generated by [`func`](crate::func). Spawning a
+/// child normally involves creating the process and then sending it the function and its
+/// arguments, which requires waiting for the process to start reading. Deferring the second step
+/// lets the parent create many processes in a row and only then complete the handshakes, so that
+/// the startup of the children overlaps.
+///
+/// The handshake is performed by [`PendingChild::finish`], or implicitly by
+/// [`PendingChild::join`]. Errors that occur while sending the function, e.g. if the process has
+/// died in the meantime, are reported by these methods rather than by spawning. Until then, the
+/// child does not run any user code.
+///
+/// Dropping a pending child closes the channel to it, which makes the process exit without running
+/// the function. The process is then reaped in background as if by [`Child::detach`].
+pub struct PendingChild<Stream: AsyncStream, T: Object> {
+    // None once the handshake has been performed
+    inner: Option<(ProcHandle, HandshakeDuplex<Stream, T>)>,
+    handshake: Handshake,
+    verify_build: bool,
+    pipes: ChildPipes,
+    limits: LimitWatch,
+}
+
+// The serialized entry and the handles it refers to
+type Handshake = (Vec<u8>, Vec<RawHandle>);
 
+// Sends the handshake and receives the output of the child
+type HandshakeDuplex<Stream, T> = Duplex<Stream, Handshake, Output<T>>;
+
+impl<Stream: AsyncStream, T: Object> PendingChild<Stream, T> {
     /// Get ID of the process.
     pub fn id(&self) -> ProcID {
+        let (proc_handle, _) = self.inner.as_ref().expect("Handshake already performed");
         #[cfg(unix)]
         {
-            rustix::process::Pid::as_raw(Some(self.proc_handle))
+            proc_handle.id()
         }
         #[cfg(windows)]
         {
-            self.proc_handle.as_raw_handle()
+            proc_handle.as_raw_handle()
         }
     }
 
-    /// Wait for the process to finish and obtain the value it returns.
+    /// Send the function to the process and obtain a [`Child`] handle.
     ///
-    /// An error is returned if the process panics or is terminated. An error is also delivered if
-    /// it exits via [`std::process::exit`] or alike instead of returning a value, unless the return
-    /// type is `()`. In that case, `Ok(())` is returned.
-    pub async fn join(mut self) -> Result<T> {
-        let mut value = self.output_rx.recv().await?;
-        if let Some(void) = imp::if_void::<T>() {
-            // The value should be None at this moment
-            value = Some(void);
-        }
-        let mut guard = self.may_kill.lock().expect("Kill mutex is poisoned");
-        *guard = false;
-        // This is synchronous, but should be really fast
+    /// If [`SpawnOptions::verify_build`] is enabled, this also waits for the process to confirm
+    /// that it runs the same build of the program. If sending or the verification fails, the
+    /// process is killed and the error is returned. The process is killed as well if the returned
+    /// future is dropped before completion.
+    pub async fn finish(mut self) -> Result<Child<Stream, T>> {
+        let (proc_handle, mut local) = self.inner.take().expect("Handshake already performed");
+        // Until the process has received the function, it cannot do anything useful, so it is
+        // killed if the handshake fails or this future is dropped
+        let mut guard = HandshakeGuard(Some(proc_handle));
+        local.send(&self.handshake).await?;
         #[cfg(unix)]
-        {
-            let (_pid, status) = rustix::process::waitpid(
-                Some(self.proc_handle),
-                rustix::process::WaitOptions::empty(),
-            )?
-            .unwrap();
-            if status.exit_status() == Some(0) {
-                value.ok_or_else(|| {
-                    Error::other("The subprocess terminated without returning a value")
-                })
-            } else {
-                Err(Error::other(format!(
-                    "The subprocess did not terminate successfully: {status:?}"
-                )))
-            }
-        }
+        let mut receiver = unsafe { Receiver::from_stream(local.fd, local.options) };
         #[cfg(windows)]
-        {
-            if unsafe {
-                Threading::WaitForSingleObject(
-                    self.proc_handle.as_raw_handle(),
-                    WindowsProgramming::INFINITE,
-                )
-            } == u32::MAX
-            {
-                return Err(Error::last_os_error());
-            }
-            let mut code: u32 = 0;
-            unsafe {
-                Threading::GetExitCodeProcess(
-                    self.proc_handle.as_raw_handle(),
-                    &mut code as *mut u32,
-                )
-                .ok()?;
-            }
-            if code == 0 {
-                value.ok_or_else(|| {
-                    Error::other("The subprocess terminated without returning a value")
-                })
-            } else {
-                Err(Error::other(format!(
-                    "The subprocess terminated with exit code {code}"
-                )))
-            }
+        let mut receiver = local.receiver;
+        if self.verify_build {
+            let mut reply_rx = unsafe { receiver.cast::<std::result::Result<(), String>>() };
+            crate::handshake::check_reply(reply_rx.recv().await)?;
+            receiver = unsafe { reply_rx.cast() };
         }
+        Ok(Child::new(
+            guard.0.take().unwrap(),
+            receiver,
+            std::mem::take(&mut self.pipes),
+            std::mem::take(&mut self.limits),
+        ))
     }
-}
 
-impl<Stream: AsyncStream + fmt::Debug, T: Object> fmt::Debug for Child<Stream, T> {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("Child")
-            .field("proc_handle", &self.proc_handle)
-            .field("output_rx", &self.output_rx)
-            .finish()
+    /// Perform the handshake and wait for the process to finish, obtaining the value it returns.
+    ///
+    /// Errors from the handshake are reported as [`JoinError::Io`]. See [`Child::join`] for more
+    /// information.
+    pub async fn join(self) -> std::result::Result<T, JoinError> {
+        self.finish().await.map_err(JoinError::Io)?.join().await
     }
 }
 
-impl KillHandle {
-    /// Terminate the process immediately.
-    pub fn kill(&self) -> Result<()> {
-        let guard = self.may_kill.lock().expect("Kill mutex is poisoned");
-        if !*guard {
-            return Err(std::io::Error::other(
-                "This process has already been joined",
-            ));
+struct HandshakeGuard(Option<ProcHandle>);
+
+impl Drop for HandshakeGuard {
+    fn drop(&mut self) {
+        if let Some(proc_handle) = self.0.take() {
+            #[cfg(unix)]
+            {
+                // The process might have already exited
+                let _ = proc_handle.kill();
+                reap_detached(proc_handle, Arc::new(Mutex::new(KillState::Killed)));
+            }
+            #[cfg(windows)]
+            {
+                let _ = kill_process(proc_handle.as_raw_handle());
+            }
         }
-        #[cfg(unix)]
-        rustix::process::kill_process(
-            rustix::process::Pid::from_raw(self.proc_id).unwrap(),
-            rustix::process::Signal::KILL,
-        )?;
-        #[cfg(windows)]
-        unsafe {
-            Threading::TerminateProcess(self.proc_id, 1).ok()?;
+    }
+}
+
+impl<Stream: AsyncStream, T: Object> Drop for PendingChild<Stream, T> {
+    fn drop(&mut self) {
+        if let Some((proc_handle, local)) = self.inner.take() {
+            drop(local);
+            #[cfg(unix)]
+            reap_detached(proc_handle, Arc::new(Mutex::new(KillState::Running)));
+            #[cfg(windows)]
+            drop(proc_handle);
         }
-        Ok(())
     }
 }
 
-impl fmt::Debug for KillHandle {
+impl<Stream: AsyncStream + fmt::Debug, T: Object> fmt::Debug for PendingChild<Stream, T> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("KillHandle")
-            .field("proc_id", &self.proc_id)
+        let (proc_handle, local) = self.inner.as_ref().expect("Handshake already performed");
+        fmt.debug_struct("PendingChild")
+            .field("proc_handle", proc_handle)
+            .field("local", local)
             .finish()
     }
 }
 
 pub(crate) async unsafe fn spawn<Stream: AsyncStream, T: Object>(
     entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
 ) -> Result<Child<Stream, T>> {
+    spawn_deferred(entry, options)?.finish().await
+}
+
+pub(crate) unsafe fn spawn_deferred<Stream: AsyncStream, T: Object>(
+    entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
+) -> Result<PendingChild<Stream, T>> {
     imp::perform_sanity_checks();
 
     let mut s = Serializer::new();
     s.serialize(&entry);
 
     let handles = s.drain_handles();
+    #[cfg(windows)]
     let raw_handles = handles.iter().map(AsRawHandle::as_raw_handle).collect();
 
     let (local, child) = crate::duplex()?;
-    let mut local: Duplex<Stream, (Vec<u8>, Vec<RawHandle>), T> = local.try_into()?;
+    let local: HandshakeDuplex<Stream, T> = local.try_into()?;
 
-    let process_handle;
-    let receiver;
+    // The ends passed to the child are closed in the parent once the child is started
+    let (stdout, stdout_pipe) = options.stdout.open()?;
+    let (stderr, stderr_pipe) = options.stderr.open()?;
 
     #[cfg(unix)]
-    {
-        process_handle = subprocess::_spawn_child(child, &handles)?;
-        local.send(&(s.into_vec(), raw_handles)).await?;
-        receiver = Receiver::from_stream(local.fd);
-    }
+    let (process_handle, raw_handles) = {
+        use std::os::unix::io::AsFd;
+        let redirects: Vec<_> = [
+            (&stdout, libc::STDOUT_FILENO),
+            (&stderr, libc::STDERR_FILENO),
+        ]
+        .into_iter()
+        .filter_map(|(handle, target)| Some((handle.as_deref()?.as_fd(), target)))
+        .collect();
+        let (pid, raw_handles) = subprocess::_spawn_child(child, &handles, &redirects, options)?;
+        (ProcHandle::new(pid), raw_handles)
+    };
+
+    #[cfg(unix)]
+    let limits = LimitWatch::new(options);
 
     #[cfg(windows)]
-    {
-        process_handle = subprocess::_spawn_child(
+    let mut limits = LimitWatch::default();
+    #[cfg(windows)]
+    let process_handle = {
+        use std::os::windows::io::AsHandle;
+        subprocess::_spawn_child(
+            options.executable.as_deref(),
+            options.process_name.as_deref(),
+            options.environment()?,
+            options.current_dir.as_deref(),
             child.0.sender.fd.as_handle(),
             child.0.receiver.fd.as_handle(),
-            handles,
-        )?;
-        local.send(&(s.into_vec(), raw_handles)).await?;
-        receiver = local.receiver;
-    }
+            handles
+                .into_iter()
+                .chain(
+                    options
+                        .inherited
+                        .iter()
+                        .map(|inherited| inherited.handle.as_handle()),
+                )
+                .collect(),
+            [
+                stdout.as_deref().map(AsHandle::as_handle),
+                stderr.as_deref().map(AsHandle::as_handle),
+            ],
+            subprocess::creation_flags(options)?,
+            |child| {
+                limits.port = subprocess::apply_options(child, options)?;
+                Ok(())
+            },
+        )?
+    };
+
+    let mut entry_data = crate::handshake::header(options.verify_build, std::any::type_name::<T>());
+    entry_data.extend_from_slice(&s.into_vec());
 
-    Ok(Child::new(process_handle, receiver))
+    // The handles have been inherited by the child by now, so the entry does not need to be kept
+    // alive until the handshake
+    Ok(PendingChild {
+        inner: Some((process_handle, local)),
+        handshake: (entry_data, raw_handles),
+        verify_build: options.verify_build,
+        pipes: ChildPipes {
+            stdout: stdout_pipe,
+            stderr: stderr_pipe,
+        },
+        limits,
+    })
 }
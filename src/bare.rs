@@ -0,0 +1,172 @@
+//! Asynchronous implementation that does not depend on any runtime.
+//!
+//! Libraries that embed crossmist often should not dictate which async runtime their users pick.
+//! This module provides channels that can be driven by any executor, even a minimal one like
+//! [`pollster`](https://crates.io/crates/pollster):
+//!
+//! ```rust
+//! use crossmist::bare::channel;
+//! # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+//! #     let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+//! #     match std::pin::pin!(f).poll(&mut cx) {
+//! #         std::task::Poll::Ready(value) => value,
+//! #         std::task::Poll::Pending => unreachable!(),
+//! #     }
+//! # }
+//!
+//! let (mut tx, mut rx) = channel::<i32>().unwrap();
+//! block_on(tx.send(&57)).unwrap();
+//! assert_eq!(block_on(rx.recv()).unwrap(), Some(57));
+//! ```
+//!
+//! The streams are non-blocking, and when an operation would block, the current thread waits for
+//! the stream to become ready with `poll(2)`. This means that the futures returned by this module
+//! never return [`Poll::Pending`], but may block the thread they are polled on. This is fine for
+//! `block_on`-style executors, but blocks other tasks when used within a multitasking runtime, in
+//! which case the runtime-specific modules should be used instead.
+//!
+//! For the same reason, this module does not provide a `SharedDuplex`: sending and receiving
+//! concurrently on one thread is impossible when waiting blocks the thread.
+//!
+//! Child processes are started with the synchronous `spawn`; channels from this module can be
+//! passed to them like any other objects.
+//!
+//! This module is only available on Unix-like systems.
+
+use crate::{
+    asynchronous,
+    handles::{AsHandle, AsRawHandle, BorrowedHandle, RawHandle},
+    multiplex,
+    ready::{self, ReadySignal},
+    ChannelOptions, Object,
+};
+use rustix::event::{poll, PollFd, PollFlags};
+use rustix::io::Errno;
+use std::io::{ErrorKind, Result};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// Runtime-independent marker type.
+#[derive(Debug, Object)]
+pub struct Bare(std::os::unix::net::UnixStream);
+
+impl Bare {
+    fn wait(&self, flags: PollFlags) -> Result<()> {
+        loop {
+            let mut fds = [PollFd::new(&self.0, flags)];
+            match poll(&mut fds, None) {
+                Ok(_) => return Ok(()),
+                Err(Errno::INTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn retry<T>(&self, flags: PollFlags, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        loop {
+            match f() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => self.wait(flags)?,
+                result => return result,
+            }
+        }
+    }
+}
+
+unsafe impl asynchronous::AsyncStream for Bare {
+    fn try_new(stream: asynchronous::SyncStream) -> Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self(stream))
+    }
+
+    fn as_handle(&self) -> BorrowedHandle<'_> {
+        self.0.as_handle()
+    }
+
+    fn as_raw_handle(&self) -> RawHandle {
+        self.0.as_raw_handle()
+    }
+
+    // The futures block in poll(2) anyway
+    const IS_BLOCKING: bool = true;
+
+    async fn blocking_write<T>(&self, f: impl FnMut() -> Result<T> + Send) -> Result<T> {
+        self.retry(PollFlags::OUT, f)
+    }
+
+    fn poll_blocking_write<T>(
+        &self,
+        _cx: &mut Context<'_>,
+        f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>> {
+        Poll::Ready(self.retry(PollFlags::OUT, f))
+    }
+
+    async fn blocking_read<T>(&self, f: impl FnMut() -> Result<T> + Send) -> Result<T> {
+        self.retry(PollFlags::IN, f)
+    }
+
+    fn poll_blocking_read<T>(
+        &self,
+        _cx: &mut Context<'_>,
+        f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>> {
+        Poll::Ready(self.retry(PollFlags::IN, f))
+    }
+
+    async fn wait_readable(&self, timeout: Duration) -> Result<bool> {
+        crate::internals::poll_readable(self.0.as_handle(), timeout)
+    }
+}
+
+/// The transmitting side of a unidirectional channel.
+///
+/// `T` is the type of the objects this side sends via the channel and the other side receives.
+pub type Sender<T> = asynchronous::Sender<Bare, T>;
+
+/// The receiving side of a unidirectional channel.
+///
+/// `T` is the type of the objects the other side sends via the channel and this side receives.
+pub type Receiver<T> = asynchronous::Receiver<Bare, T>;
+
+/// A side of a bidirectional channel.
+///
+/// `S` is the type of the objects this side sends via the channel and the other side receives, `R`
+/// is the type of the objects the other side sends via the channel and this side receives.
+pub type Duplex<S, R> = asynchronous::Duplex<Bare, S, R>;
+
+/// A side of a bidirectional channel that supports concurrent requests.
+///
+/// See [`multiplex`] for more information.
+pub type MultiplexedDuplex<S, R> = multiplex::MultiplexedDuplex<Bare, S, R>;
+
+/// The side of a readiness notification that waits for the child.
+///
+/// See [`ready`] for more information.
+pub type ReadyWaiter<T> = ready::ReadyWaiter<Bare, T>;
+
+/// Create a unidirectional channel.
+pub fn channel<T: Object>() -> Result<(Sender<T>, Receiver<T>)> {
+    asynchronous::channel::<Bare, T>()
+}
+
+/// Create a unidirectional channel with custom options.
+pub fn channel_with<T: Object>(options: &ChannelOptions) -> Result<(Sender<T>, Receiver<T>)> {
+    asynchronous::channel_with::<Bare, T>(options)
+}
+
+/// Create a bidirectional channel.
+pub fn duplex<A: Object, B: Object>() -> Result<(Duplex<A, B>, Duplex<B, A>)> {
+    asynchronous::duplex::<Bare, A, B>()
+}
+
+/// Create a bidirectional channel with custom options.
+pub fn duplex_with<A: Object, B: Object>(
+    options: &ChannelOptions,
+) -> Result<(Duplex<A, B>, Duplex<B, A>)> {
+    asynchronous::duplex_with::<Bare, A, B>(options)
+}
+
+/// Create a readiness notification.
+pub fn ready_signal<T: Object>() -> Result<(ReadySignal<T>, ReadyWaiter<T>)> {
+    ready::ready_signal::<Bare, T>()
+}
@@ -46,13 +46,17 @@
 
 use crate::{
     asynchronous,
-    handles::{AsHandle, AsRawHandle, BorrowedHandle, RawHandle},
-    FnOnceObject, KillHandle, Object,
+    handles::{AsHandle, AsRawHandle, BorrowedHandle, FromRawHandle, RawHandle},
+    ready::{self, ReadySignal},
+    ChannelOptions, FnOnceObject, JoinError, KillHandle, Object, RequestError, SpawnOptions,
+    TryRecvError,
 };
 use std::future::Future;
-use std::io::Result;
+use std::io::{PipeReader, Result};
+use std::iter::FusedIterator;
 use std::pin::pin;
 use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 
 fn block_on<F: Future>(f: F) -> F::Output {
     let mut cx = Context::from_waker(Waker::noop());
@@ -83,8 +87,16 @@ unsafe impl asynchronous::AsyncStream for Blocking {
     const IS_BLOCKING: bool = true;
 
     #[cfg(unix)]
-    async fn blocking_write<T>(&self, mut f: impl FnMut() -> Result<T> + Send) -> Result<T> {
-        f()
+    async fn blocking_write<T>(&self, f: impl FnMut() -> Result<T> + Send) -> Result<T> {
+        retry_interrupted(f)
+    }
+    #[cfg(unix)]
+    fn poll_blocking_write<T>(
+        &self,
+        _cx: &mut Context<'_>,
+        f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>> {
+        Poll::Ready(retry_interrupted(f))
     }
     #[cfg(windows)]
     async fn write(&mut self, buf: &[u8]) -> Result<()> {
@@ -93,14 +105,38 @@ unsafe impl asynchronous::AsyncStream for Blocking {
     }
 
     #[cfg(unix)]
-    async fn blocking_read<T>(&self, mut f: impl FnMut() -> Result<T> + Send) -> Result<T> {
-        f()
+    async fn blocking_read<T>(&self, f: impl FnMut() -> Result<T> + Send) -> Result<T> {
+        retry_interrupted(f)
+    }
+    #[cfg(unix)]
+    fn poll_blocking_read<T>(
+        &self,
+        _cx: &mut Context<'_>,
+        f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>> {
+        Poll::Ready(retry_interrupted(f))
     }
     #[cfg(windows)]
     async fn read(&mut self, buf: &mut [u8]) -> Result<()> {
         use std::io::Read;
         self.0.read_exact(buf)
     }
+
+    async fn wait_readable(&self, timeout: Duration) -> Result<bool> {
+        crate::internals::poll_readable(self.0.as_handle(), timeout)
+    }
+}
+
+// Signal handlers installed without SA_RESTART make blocking calls fail with EINTR. Nothing is
+// transferred in this case, so the call is simply repeated
+#[cfg(unix)]
+fn retry_interrupted<T>(mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    loop {
+        match f() {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            result => return result,
+        }
+    }
 }
 
 /// The transmitting side of a unidirectional channel.
@@ -128,17 +164,134 @@ pub fn channel<T: Object>() -> Result<(Sender<T>, Receiver<T>)> {
     Ok((Sender(tx), Receiver(rx)))
 }
 
+/// Create a unidirectional channel with custom options.
+pub fn channel_with<T: Object>(options: &ChannelOptions) -> Result<(Sender<T>, Receiver<T>)> {
+    let (tx, rx) = asynchronous::channel_with::<Blocking, T>(options)?;
+    Ok((Sender(tx), Receiver(rx)))
+}
+
 /// Create a bidirectional channel.
 pub fn duplex<A: Object, B: Object>() -> Result<(Duplex<A, B>, Duplex<B, A>)> {
     let (tx, rx) = asynchronous::duplex::<Blocking, A, B>()?;
     Ok((Duplex(tx), Duplex(rx)))
 }
 
+/// Create a bidirectional channel with custom options.
+pub fn duplex_with<A: Object, B: Object>(
+    options: &ChannelOptions,
+) -> Result<(Duplex<A, B>, Duplex<B, A>)> {
+    let (tx, rx) = asynchronous::duplex_with::<Blocking, A, B>(options)?;
+    Ok((Duplex(tx), Duplex(rx)))
+}
+
 impl<T: Object> Sender<T> {
+    /// Create another sender for the same channel.
+    ///
+    /// Both senders can be used independently, e.g. passed to different processes. The order in
+    /// which messages from different senders arrive is unspecified.
+    ///
+    /// On Unix-like systems, each message is delivered whole, even if several senders send large
+    /// messages simultaneously. On Windows, the system may split a write larger than the pipe
+    /// buffer, usually 4 KiB, so large messages sent simultaneously by different senders can
+    /// interleave and corrupt the channel. Either synchronize the senders, or set
+    /// [`shared_memory_threshold`](crate::ChannelOptions::shared_memory_threshold) to at most the
+    /// size of the pipe buffer, so that only small handles are written to the pipe.
+    ///
+    /// The receiver only observes the end of the channel once all senders are dropped:
+    ///
+    /// ```rust
+    /// # use crossmist::channel;
+    /// let (mut sender1, mut receiver) = channel::<i32>()?;
+    /// let mut sender2 = sender1.try_clone()?;
+    /// sender1.send(&1)?;
+    /// drop(sender1);
+    /// sender2.send(&2)?;
+    /// drop(sender2);
+    /// assert_eq!(receiver.recv()?, Some(1));
+    /// assert_eq!(receiver.recv()?, Some(2));
+    /// assert_eq!(receiver.recv()?, None);
+    /// # std::io::Result::Ok(())
+    /// ```
+    pub fn try_clone(&self) -> Result<Self> {
+        self.0.try_clone().map(Self)
+    }
+
     /// Send a value to the other side.
+    ///
+    /// Messages queued with [`feed`](Self::feed) are flushed first.
     pub fn send(&mut self, value: &T) -> Result<()> {
         block_on(self.0.send(value))
     }
+
+    /// Queue a value to be sent to the other side.
+    ///
+    /// The value is serialized immediately, but is not necessarily delivered until
+    /// [`flush`](Self::flush) is called. Messages that have not been flushed are lost when the
+    /// sender is dropped or passed to another process.
+    pub fn feed(&mut self, value: &T) -> Result<()> {
+        self.0.feed(value)
+    }
+
+    /// Queue a value to be sent to the other side before the queued values of lower priority.
+    ///
+    /// Values fed with [`feed`](Self::feed) have priority 0. When the queue is flushed, values of
+    /// higher priority are written first, and values of the same priority are written in the order
+    /// they were queued. Only the local queue is reordered: values that have already been written
+    /// to the channel are delivered before the ones queued later, regardless of priorities.
+    pub fn feed_prioritized(&mut self, value: &T, priority: u8) -> Result<()> {
+        self.0.feed_prioritized(value, priority)
+    }
+
+    /// Send a value to the other side ahead of the queued values of lower priority.
+    ///
+    /// This is [`feed_prioritized`](Self::feed_prioritized) followed by [`flush`](Self::flush).
+    pub fn send_prioritized(&mut self, value: &T, priority: u8) -> Result<()> {
+        block_on(self.0.send_prioritized(value, priority))
+    }
+
+    /// Send all values queued with [`feed`](Self::feed) to the other side.
+    pub fn flush(&mut self) -> Result<()> {
+        block_on(self.0.flush())
+    }
+
+    /// Send several values to the other side.
+    ///
+    /// This is equivalent to sending the values one by one, but uses fewer system calls. The other
+    /// side can receive the values with [`Receiver::recv`] or [`Receiver::recv_batch`].
+    pub fn send_batch(&mut self, values: &[T]) -> Result<()> {
+        block_on(self.0.send_batch(values))
+    }
+
+    /// Free the memory kept for serializing messages.
+    ///
+    /// To avoid an allocation per message, the buffer used to serialize a message is kept for the
+    /// next one, unless it has grown large. Call this method to release it, e.g. after a burst of
+    /// sends.
+    pub fn shrink_to_fit(&mut self) {
+        self.0.shrink_to_fit()
+    }
+
+    /// Get the options this channel was created with.
+    pub fn options(&self) -> &ChannelOptions {
+        self.0.options()
+    }
+
+    /// Close the channel, signalling the end of the stream to the other side.
+    ///
+    /// See [`asynchronous::Sender::close`] for more information.
+    pub fn close(self) -> Result<()> {
+        block_on(self.0.close())
+    }
+}
+
+impl Sender<Vec<u8>> {
+    /// Send a byte slice to the other side, which receives it as a `Vec<u8>`.
+    ///
+    /// Unlike [`send`](Self::send), this does not require the bytes to be stored in a `Vec`. On
+    /// Unix-like systems, the bytes are written to the socket directly from `data`.
+    pub fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
+        block_on(self.0.send_bytes(data))
+    }
 }
 
 #[cfg(unix)]
@@ -156,12 +309,18 @@ impl<T: Object> std::os::windows::io::AsRawHandle for Sender<T> {
 
 #[cfg(unix)]
 impl<T: Object> std::os::unix::io::IntoRawFd for Sender<T> {
+    /// Release ownership of the underlying handle, e.g. to register it in an external event loop.
+    ///
+    /// The options of the channel are not preserved.
     fn into_raw_fd(self) -> RawHandle {
         self.0.fd.0.into_raw_fd()
     }
 }
 #[cfg(windows)]
 impl<T: Object> std::os::windows::io::IntoRawHandle for Sender<T> {
+    /// Release ownership of the underlying handle, e.g. to register it in an external event loop.
+    ///
+    /// The options of the channel are not preserved.
     fn into_raw_handle(self) -> std::os::windows::io::RawHandle {
         self.0.fd.0.into_raw_handle()
     }
@@ -169,18 +328,46 @@ impl<T: Object> std::os::windows::io::IntoRawHandle for Sender<T> {
 
 #[cfg(unix)]
 impl<T: Object> std::os::unix::io::FromRawFd for Sender<T> {
+    /// Take ownership of a handle released via `into_raw_fd`, with default options.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be the transmitting side of a channel carrying `T`, and the channel must use
+    /// the default [`ChannelOptions`]. Use [`Sender::from_raw_handle_with`] otherwise.
     unsafe fn from_raw_fd(fd: RawHandle) -> Self {
-        Self(asynchronous::Sender::from_stream(Blocking(
-            asynchronous::SyncStream::from_raw_fd(fd),
-        )))
+        Self::from_raw_handle_with(fd, &ChannelOptions::default())
     }
 }
 #[cfg(windows)]
 impl<T: Object> std::os::windows::io::FromRawHandle for Sender<T> {
+    /// Take ownership of a handle released via `into_raw_handle`, with default options.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be the transmitting side of a channel carrying `T`, and the channel must use
+    /// the default [`ChannelOptions`]. Use [`Sender::from_raw_handle_with`] otherwise.
     unsafe fn from_raw_handle(fd: std::os::windows::io::RawHandle) -> Self {
-        Self(asynchronous::Sender::from_stream(Blocking(
-            asynchronous::SyncStream::from_raw_handle(fd),
-        )))
+        Self::from_raw_handle_with(
+            windows::Win32::Foundation::HANDLE(fd as isize),
+            &ChannelOptions::default(),
+        )
+    }
+}
+
+impl<T: Object> Sender<T> {
+    /// Take ownership of a handle released via `into_raw_handle`, with custom options.
+    ///
+    /// The channel starts in a clean state, as if no objects have been transferred yet.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be the transmitting side of a channel carrying `T`, and `options` must match
+    /// the options the channel was created with.
+    pub unsafe fn from_raw_handle_with(handle: RawHandle, options: &ChannelOptions) -> Self {
+        Self(asynchronous::Sender::from_stream(
+            Blocking(<asynchronous::SyncStream as FromRawHandle>::from_raw_handle(handle)),
+            *options,
+        ))
     }
 }
 
@@ -191,6 +378,173 @@ impl<T: Object> Receiver<T> {
     pub fn recv(&mut self) -> Result<Option<T>> {
         block_on(self.0.recv())
     }
+
+    /// Receive a value from the other side, reusing `buf` for the serialized data.
+    ///
+    /// See [`asynchronous::Receiver::recv_into`] for more information.
+    pub fn recv_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<T>> {
+        block_on(self.0.recv_into(buf))
+    }
+
+    /// Receive a value from the other side into `target`, reusing the allocations it owns.
+    ///
+    /// Returns `Ok(false)` if the other side has dropped the channel. See
+    /// [`asynchronous::Receiver::recv_ref`] for more information.
+    pub fn recv_ref(&mut self, target: &mut T) -> Result<bool> {
+        block_on(self.0.recv_ref(target))
+    }
+
+    /// Iterate over the received values.
+    ///
+    /// The iterator calls [`recv`](Self::recv) repeatedly and yields the received values until the
+    /// other side drops the channel. If `recv` fails, the error is yielded and the iteration stops.
+    ///
+    /// ```rust
+    /// # use crossmist::channel;
+    /// let (mut sender, mut receiver) = channel::<i32>()?;
+    /// sender.send(&1)?;
+    /// sender.send(&2)?;
+    /// drop(sender);
+    /// let values: Vec<i32> = receiver.iter().collect::<std::io::Result<_>>()?;
+    /// assert_eq!(values, [1, 2]);
+    /// # std::io::Result::Ok(())
+    /// ```
+    pub fn iter(&mut self) -> Iter<'_, T> {
+        Iter {
+            receiver: self,
+            done: false,
+        }
+    }
+
+    /// Receive a value from the other side if one is available, without waiting.
+    pub fn try_recv(&mut self) -> std::result::Result<T, TryRecvError> {
+        block_on(self.0.try_recv())
+    }
+
+    /// Receive up to `max` values from the other side.
+    ///
+    /// Waits until at least one value is available, then returns it together with the values that
+    /// have already arrived. Returns an empty vector if the other side has dropped the channel (or
+    /// if `max` is zero).
+    pub fn recv_batch(&mut self, max: usize) -> Result<Vec<T>> {
+        block_on(self.0.recv_batch(max))
+    }
+
+    /// Iterate over the values that have already been received, without waiting.
+    ///
+    /// The iterator calls [`try_recv`](Self::try_recv) repeatedly and yields the received values
+    /// until no more values are available or the other side drops the channel. If `try_recv` fails,
+    /// the error is yielded and the iteration stops.
+    pub fn try_iter(&mut self) -> TryIter<'_, T> {
+        TryIter {
+            receiver: self,
+            done: false,
+        }
+    }
+
+    /// Get the options this channel was created with.
+    pub fn options(&self) -> &ChannelOptions {
+        self.0.options()
+    }
+
+    /// Change the maximum size of a received message.
+    ///
+    /// See [`ChannelOptions::max_message_size`] for more information. `None` disables the check.
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.0.set_max_message_size(limit)
+    }
+}
+
+fn next_value<T: Object>(receiver: &mut Receiver<T>, done: &mut bool) -> Option<Result<T>> {
+    if *done {
+        return None;
+    }
+    let result = receiver.recv().transpose();
+    if !matches!(result, Some(Ok(_))) {
+        *done = true;
+    }
+    result
+}
+
+/// A borrowing iterator over the values received from a [`Receiver`].
+///
+/// This type is created by [`Receiver::iter`].
+#[derive(Debug)]
+pub struct Iter<'a, T: Object> {
+    receiver: &'a mut Receiver<T>,
+    done: bool,
+}
+
+impl<T: Object> Iterator for Iter<'_, T> {
+    type Item = Result<T>;
+    fn next(&mut self) -> Option<Result<T>> {
+        next_value(self.receiver, &mut self.done)
+    }
+}
+
+impl<T: Object> FusedIterator for Iter<'_, T> {}
+
+/// An iterator over the values that have already been received by a [`Receiver`].
+///
+/// This type is created by [`Receiver::try_iter`].
+#[derive(Debug)]
+pub struct TryIter<'a, T: Object> {
+    receiver: &'a mut Receiver<T>,
+    done: bool,
+}
+
+impl<T: Object> Iterator for TryIter<'_, T> {
+    type Item = Result<T>;
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.done {
+            return None;
+        }
+        match self.receiver.try_recv() {
+            Ok(value) => Some(Ok(value)),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => None,
+            Err(TryRecvError::Io(e)) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// An owning iterator over the values received from a [`Receiver`].
+///
+/// This type is created by [`Receiver::into_iter`].
+#[derive(Debug)]
+pub struct IntoIter<T: Object> {
+    receiver: Receiver<T>,
+    done: bool,
+}
+
+impl<T: Object> Iterator for IntoIter<T> {
+    type Item = Result<T>;
+    fn next(&mut self) -> Option<Result<T>> {
+        next_value(&mut self.receiver, &mut self.done)
+    }
+}
+
+impl<T: Object> FusedIterator for IntoIter<T> {}
+
+impl<'a, T: Object> IntoIterator for &'a mut Receiver<T> {
+    type Item = Result<T>;
+    type IntoIter = Iter<'a, T>;
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<T: Object> IntoIterator for Receiver<T> {
+    type Item = Result<T>;
+    type IntoIter = IntoIter<T>;
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter {
+            receiver: self,
+            done: false,
+        }
+    }
 }
 
 #[cfg(unix)]
@@ -208,12 +562,20 @@ impl<T: Object> std::os::windows::io::AsRawHandle for Receiver<T> {
 
 #[cfg(unix)]
 impl<T: Object> std::os::unix::io::IntoRawFd for Receiver<T> {
+    /// Release ownership of the underlying handle, e.g. to register it in an external event loop.
+    ///
+    /// Any part of an object that has been received, but not returned yet, is discarded, so this
+    /// should only be done between objects. The options of the channel are not preserved either.
     fn into_raw_fd(self) -> RawHandle {
         self.0.fd.0.into_raw_fd()
     }
 }
 #[cfg(windows)]
 impl<T: Object> std::os::windows::io::IntoRawHandle for Receiver<T> {
+    /// Release ownership of the underlying handle, e.g. to register it in an external event loop.
+    ///
+    /// Any part of an object that has been received, but not returned yet, is discarded, so this
+    /// should only be done between objects. The options of the channel are not preserved either.
     fn into_raw_handle(self) -> std::os::windows::io::RawHandle {
         self.0.fd.0.into_raw_handle()
     }
@@ -221,18 +583,46 @@ impl<T: Object> std::os::windows::io::IntoRawHandle for Receiver<T> {
 
 #[cfg(unix)]
 impl<T: Object> std::os::unix::io::FromRawFd for Receiver<T> {
+    /// Take ownership of a handle released via `into_raw_fd`, with default options.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be the receiving side of a channel carrying `T`, and the channel must use
+    /// the default [`ChannelOptions`]. Use [`Receiver::from_raw_handle_with`] otherwise.
     unsafe fn from_raw_fd(fd: RawHandle) -> Self {
-        Self(asynchronous::Receiver::from_stream(Blocking(
-            asynchronous::SyncStream::from_raw_fd(fd),
-        )))
+        Self::from_raw_handle_with(fd, &ChannelOptions::default())
     }
 }
 #[cfg(windows)]
 impl<T: Object> std::os::windows::io::FromRawHandle for Receiver<T> {
+    /// Take ownership of a handle released via `into_raw_handle`, with default options.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be the receiving side of a channel carrying `T`, and the channel must use
+    /// the default [`ChannelOptions`]. Use [`Receiver::from_raw_handle_with`] otherwise.
     unsafe fn from_raw_handle(fd: std::os::windows::io::RawHandle) -> Self {
-        Self(asynchronous::Receiver::from_stream(Blocking(
-            asynchronous::SyncStream::from_raw_handle(fd),
-        )))
+        Self::from_raw_handle_with(
+            windows::Win32::Foundation::HANDLE(fd as isize),
+            &ChannelOptions::default(),
+        )
+    }
+}
+
+impl<T: Object> Receiver<T> {
+    /// Take ownership of a handle released via `into_raw_handle`, with custom options.
+    ///
+    /// The channel starts in a clean state, as if no objects have been transferred yet.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be the receiving side of a channel carrying `T`, and `options` must match
+    /// the options the channel was created with.
+    pub unsafe fn from_raw_handle_with(handle: RawHandle, options: &ChannelOptions) -> Self {
+        Self(asynchronous::Receiver::from_stream(
+            Blocking(<asynchronous::SyncStream as FromRawHandle>::from_raw_handle(handle)),
+            *options,
+        ))
     }
 }
 
@@ -256,10 +646,56 @@ impl<S: Object, R: Object> Duplex<S, R> {
         block_on(self.0.request(value))
     }
 
+    /// Send a value from the other side and wait for a response, giving up after `timeout`.
+    ///
+    /// If the response does not arrive in time, [`RequestError::Timeout`] is returned. The channel
+    /// remains usable afterwards: the late response is skipped by the next call to
+    /// [`request`](Self::request) or [`request_timeout`](Self::request_timeout). Note that
+    /// [`recv`](Self::recv) does not skip late responses.
+    pub fn request_timeout(
+        &mut self,
+        value: &S,
+        timeout: Duration,
+    ) -> std::result::Result<R, RequestError> {
+        block_on(self.0.request_timeout(value, timeout))
+    }
+
+    /// Free the memory kept for serializing messages.
+    ///
+    /// See [`Sender::shrink_to_fit`].
+    pub fn shrink_to_fit(&mut self) {
+        self.0.shrink_to_fit()
+    }
+
+    /// Get the options this channel was created with.
+    pub fn options(&self) -> &ChannelOptions {
+        self.0.options()
+    }
+
+    /// Change the maximum size of a received message.
+    ///
+    /// See [`ChannelOptions::max_message_size`] for more information. `None` disables the check.
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.0.set_max_message_size(limit)
+    }
+
+    /// Close the sending half of the channel, keeping the receiving half open.
+    ///
+    /// See [`asynchronous::Duplex::shutdown_write`] for more information.
+    pub fn shutdown_write(&mut self) -> Result<()> {
+        self.0.shutdown_write()
+    }
+
+    /// Convert the duplex into its sending half, dropping the receiving half.
+    ///
+    /// See [`asynchronous::Duplex::into_sender`] for more information.
     pub fn into_sender(self) -> Sender<S> {
         Sender(self.0.into_sender())
     }
 
+    /// Convert the duplex into its receiving half, dropping the sending half.
+    ///
+    /// See [`asynchronous::Duplex::into_receiver`] for more information.
     pub fn into_receiver(self) -> Receiver<R> {
         Receiver(self.0.into_receiver())
     }
@@ -282,9 +718,32 @@ impl<S: Object, R: Object> std::os::unix::io::IntoRawFd for Duplex<S, R> {
 #[cfg(unix)]
 impl<S: Object, R: Object> std::os::unix::io::FromRawFd for Duplex<S, R> {
     unsafe fn from_raw_fd(fd: RawHandle) -> Self {
-        Self(asynchronous::Duplex::from_stream(Blocking(
-            asynchronous::SyncStream::from_raw_fd(fd),
-        )))
+        Self(asynchronous::Duplex::from_stream(
+            Blocking(asynchronous::SyncStream::from_raw_fd(fd)),
+            Default::default(),
+        ))
+    }
+}
+
+/// The side of a readiness notification that waits for the child.
+///
+/// See [`ready`] for more information.
+#[derive(Debug)]
+pub struct ReadyWaiter<T: Object>(ready::ReadyWaiter<Blocking, T>);
+
+/// Create a readiness notification.
+pub fn ready_signal<T: Object>() -> Result<(ReadySignal<T>, ReadyWaiter<T>)> {
+    let (signal, waiter) = ready::ready_signal::<Blocking, T>()?;
+    Ok((signal, ReadyWaiter(waiter)))
+}
+
+impl<T: Object> ReadyWaiter<T> {
+    /// Wait until the child signals readiness and return the value it passed.
+    ///
+    /// An error is returned if the signal is dropped without being used, e.g. if the child
+    /// terminates.
+    pub fn wait(self) -> Result<T> {
+        block_on(self.0.wait())
     }
 }
 
@@ -303,12 +762,137 @@ impl<T: Object> Child<T> {
         self.0.id()
     }
 
+    /// Get the current scheduling priority of the process.
+    ///
+    /// See [`asynchronous::Child::get_priority`] for more information.
+    pub fn get_priority(&self) -> Result<i32> {
+        self.0.get_priority()
+    }
+
+    /// Get the CPUs the process is currently restricted to.
+    ///
+    /// See [`asynchronous::Child::get_cpu_affinity`] for more information.
+    pub fn get_cpu_affinity(&self) -> Result<Vec<usize>> {
+        self.0.get_cpu_affinity()
+    }
+
+    /// Terminate the process when this `Child` is dropped without being joined.
+    ///
+    /// By default, dropping a `Child` leaves the process running in background. With this option,
+    /// the process is killed as if by [`KillHandle::kill`]. Dropping does not wait for the process
+    /// to actually exit.
+    pub fn kill_on_drop(self) -> Self {
+        Child(self.0.kill_on_drop())
+    }
+
+    /// Take the reading end of the pipe connected to the standard output of the process.
+    ///
+    /// Returns `None` unless the process was spawned with [`SpawnOptions::stdout`] set to
+    /// [`Stdio::piped`](crate::Stdio::piped), or if the pipe has already been taken.
+    pub fn take_stdout(&mut self) -> Option<PipeReader> {
+        self.0.pipes.stdout.take()
+    }
+
+    /// Take the reading end of the pipe connected to the standard error of the process.
+    ///
+    /// Returns `None` unless the process was spawned with [`SpawnOptions::stderr`] set to
+    /// [`Stdio::piped`](crate::Stdio::piped), or if the pipe has already been taken.
+    pub fn take_stderr(&mut self) -> Option<PipeReader> {
+        self.0.pipes.stderr.take()
+    }
+
+    /// Forget about the process, letting it run to completion in background.
+    ///
+    /// Unlike simply dropping the `Child`, this makes sure the process does not stay around as a
+    /// zombie after it exits. See [`asynchronous::Child::detach`] for how this is implemented.
+    ///
+    /// The value returned by a detached process is discarded, so functions intended to be detached
+    /// should return `()`.
+    pub fn detach(self) {
+        self.0.detach()
+    }
+
     /// Wait for the process to finish and obtain the value it returns.
     ///
     /// An error is returned if the process panics or is terminated. An error is also delivered if
     /// it exits via [`std::process::exit`] or alike instead of returning a value, unless the return
     /// type is `()`. In that case, `Ok(())` is returned.
-    pub fn join(self) -> Result<T> {
+    ///
+    /// If the function panics, [`JoinError::Panicked`] with the panic message is returned. If the
+    /// process was terminated via [`KillHandle::kill`], [`JoinError::Killed`] is returned. A process
+    /// that finished successfully before being killed is not considered killed.
+    ///
+    /// This method used to return [`std::io::Result`], see
+    /// [Migrating from `io::Result`](JoinError#migrating-from-ioresult).
+    pub fn join(self) -> std::result::Result<T, JoinError> {
+        block_on(self.0.join())
+    }
+}
+
+/// The subprocess object created by calling `spawn` on a streaming function annotated with
+/// `#[func]`.
+///
+/// The items produced by the function are obtained by iterating over this object. See
+/// [`asynchronous::ChildStream`] for more information.
+#[derive(Debug)]
+pub struct ChildStream<T: Object>(asynchronous::ChildStream<Blocking, T>);
+
+impl<T: Object> ChildStream<T> {
+    /// Get a handle for process termination.
+    pub fn get_kill_handle(&self) -> KillHandle {
+        self.0.get_kill_handle()
+    }
+
+    /// Get ID of the process.
+    pub fn id(&self) -> asynchronous::ProcID {
+        self.0.id()
+    }
+
+    /// Wait for the process to finish, discarding the items it has not produced yet.
+    ///
+    /// See [`asynchronous::ChildStream::join`] for more information.
+    pub fn join(self) -> std::result::Result<(), JoinError> {
+        block_on(self.0.join())
+    }
+}
+
+impl<T: Object> Iterator for ChildStream<T> {
+    type Item = std::result::Result<T, JoinError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        block_on(self.0.next())
+    }
+}
+
+impl<T: Object> FusedIterator for ChildStream<T> {}
+
+/// A subprocess that has been created but has not received the function to run yet.
+///
+/// Created by calling `spawn_deferred_with` on a function annotated with `#[func]`. See
+/// [`asynchronous::PendingChild`] for more information.
+#[derive(Debug)]
+pub struct PendingChild<T: Object>(asynchronous::PendingChild<Blocking, T>);
+
+impl<T: Object> PendingChild<T> {
+    /// Get ID of the process.
+    pub fn id(&self) -> asynchronous::ProcID {
+        self.0.id()
+    }
+
+    /// Send the function to the process and obtain a [`Child`] handle.
+    ///
+    /// If [`SpawnOptions::verify_build`] is enabled, this also waits for the process to confirm
+    /// that it runs the same build of the program. If sending or the verification fails, the
+    /// process is killed and the error is returned.
+    pub fn finish(self) -> Result<Child<T>> {
+        block_on(self.0.finish()).map(Child)
+    }
+
+    /// Perform the handshake and wait for the process to finish, obtaining the value it returns.
+    ///
+    /// Errors from the handshake are reported as [`JoinError::Io`]. See [`Child::join`] for more
+    /// information.
+    pub fn join(self) -> std::result::Result<T, JoinError> {
         block_on(self.0.join())
     }
 }
@@ -316,6 +900,24 @@ impl<T: Object> Child<T> {
 #[doc(hidden)]
 pub unsafe fn spawn<T: Object>(
     entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
 ) -> Result<Child<T>> {
-    block_on(asynchronous::spawn::<Blocking, T>(entry)).map(Child)
+    block_on(asynchronous::spawn::<Blocking, T>(entry, options)).map(Child)
+}
+
+#[doc(hidden)]
+pub unsafe fn spawn_stream<T: Object>(
+    entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
+) -> Result<ChildStream<T>> {
+    block_on(asynchronous::spawn::<Blocking, T>(entry, options))
+        .map(|child| ChildStream(asynchronous::ChildStream::new(child)))
+}
+
+#[doc(hidden)]
+pub unsafe fn spawn_deferred<T: Object>(
+    entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
+) -> Result<PendingChild<T>> {
+    asynchronous::spawn_deferred::<Blocking, T>(entry, options).map(PendingChild)
 }
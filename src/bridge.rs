@@ -0,0 +1,70 @@
+//! Bridges between [`std::sync::mpsc`] channels and crossmist channels.
+//!
+//! Code that passes data between threads via [`std::sync::mpsc`] can be moved to child processes
+//! incrementally: the thread side keeps using the std channel, and the bridged crossmist channel is
+//! passed to the child:
+//!
+//! ```rust
+//! use crossmist::{bridge_mpsc_receiver, func, main, Receiver};
+//!
+//! #[func]
+//! fn sum(rx: Receiver<u32>) -> u32 {
+//!     rx.into_iter().map(Result::unwrap).sum()
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let (tx, rx) = std::sync::mpsc::channel();
+//!     let child = sum.spawn(bridge_mpsc_receiver(rx).unwrap()).unwrap();
+//!     for i in 1..=10 {
+//!         tx.send(i).unwrap();
+//!     }
+//!     drop(tx);
+//!     assert_eq!(child.join().unwrap(), 55);
+//! }
+//! ```
+//!
+//! Each bridge runs a forwarding thread in the current process, which exits once either side of
+//! the bridge is closed. Values are serialized on the way, so `T` has to implement [`Object`].
+
+use crate::{Object, Receiver, Sender};
+use std::io::Result;
+use std::sync::mpsc;
+
+/// Forward values from a std receiver to a new crossmist channel.
+///
+/// The returned receiver gets the values sent to the std channel, and reports end of stream once
+/// all std senders are dropped.
+pub fn bridge_mpsc_receiver<T: Object + Send + 'static>(
+    rx: mpsc::Receiver<T>,
+) -> Result<Receiver<T>> {
+    let (mut sender, receiver) = crate::channel()?;
+    std::thread::Builder::new()
+        .name("crossmist-bridge".to_string())
+        .spawn(move || {
+            for value in rx {
+                if sender.send(&value).is_err() {
+                    break;
+                }
+            }
+        })?;
+    Ok(receiver)
+}
+
+/// Forward values from a new crossmist channel to a std sender.
+///
+/// Values sent via the returned sender are delivered to the std channel. Forwarding stops once the
+/// crossmist sender is dropped or the std receiver is dropped.
+pub fn bridge_mpsc_sender<T: Object + Send + 'static>(tx: mpsc::Sender<T>) -> Result<Sender<T>> {
+    let (sender, mut receiver) = crate::channel()?;
+    std::thread::Builder::new()
+        .name("crossmist-bridge".to_string())
+        .spawn(move || {
+            while let Ok(Some(value)) = receiver.recv() {
+                if tx.send(value).is_err() {
+                    break;
+                }
+            }
+        })?;
+    Ok(sender)
+}
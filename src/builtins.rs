@@ -4,17 +4,23 @@ use crate::handles::RawHandle;
 use crate::handles::{FromRawHandle, IntoRawHandle};
 use crate::{
     handles::{AsHandle, OwnedHandle},
+    imp::{implements, sum_size_hints},
     pod::PlainOldData,
     Deserializer, NonTrivialObject, Object, Serializer,
 };
 use paste::paste;
+use std::borrow::Cow;
+use std::cmp::Reverse;
 use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, LinkedList, VecDeque};
 use std::hash::{BuildHasher, Hash};
 use std::io::Result;
 use std::mem::MaybeUninit;
+use std::num::{Saturating, Wrapping};
+use std::ops::{Bound, ControlFlow};
 use std::os::raw::c_void;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::task::Poll;
 
 macro_rules! impl_pod {
     ([$($generics:tt)*] for $t:ty) => {
@@ -41,6 +47,11 @@ macro_rules! impl_pod {
     };
 }
 
+// Hints of containers that serialize their length before the contents
+fn length_prefixed(contents: Option<usize>) -> Option<usize> {
+    contents?.checked_add(std::mem::size_of::<usize>())
+}
+
 impl_pod!(for bool);
 impl_pod!(for char);
 impl_pod!([T] for std::marker::PhantomData<T>);
@@ -61,30 +72,224 @@ impl_pod!(for u128);
 impl_pod!(for usize);
 impl_pod!(for f32);
 impl_pod!(for f64);
-impl_pod!(for std::num::NonZeroI8);
-impl_pod!(for std::num::NonZeroI16);
-impl_pod!(for std::num::NonZeroI32);
-impl_pod!(for std::num::NonZeroI64);
-impl_pod!(for std::num::NonZeroI128);
-impl_pod!(for std::num::NonZeroIsize);
-impl_pod!(for std::num::NonZeroU8);
-impl_pod!(for std::num::NonZeroU16);
-impl_pod!(for std::num::NonZeroU32);
-impl_pod!(for std::num::NonZeroU64);
-impl_pod!(for std::num::NonZeroU128);
-impl_pod!(for std::num::NonZeroUsize);
 impl_pod!(for std::time::Duration);
 impl_pod!(for std::time::Instant);
-impl_pod!(for std::time::SystemTime);
+impl_pod!(for std::net::Ipv4Addr);
+impl_pod!(for std::net::Ipv6Addr);
+impl_pod!(for std::net::SocketAddrV4);
+
+// The enums and SocketAddrV6 contain padding, so they are encoded field by field rather than copied
+unsafe impl NonTrivialObject for std::net::IpAddr {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        match self {
+            std::net::IpAddr::V4(addr) => {
+                s.serialize_temporary(0u8);
+                s.serialize_temporary(*addr);
+            }
+            std::net::IpAddr::V6(addr) => {
+                s.serialize_temporary(1u8);
+                s.serialize_temporary(*addr);
+            }
+        }
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(match d.deserialize::<u8>()? {
+            0 => std::net::IpAddr::V4(d.deserialize()?),
+            1 => std::net::IpAddr::V6(d.deserialize()?),
+            tag => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unknown IpAddr variant {tag}"),
+                ))
+            }
+        })
+    }
+}
+
+unsafe impl NonTrivialObject for std::net::SocketAddrV6 {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize_temporary(*self.ip());
+        s.serialize_temporary(self.port());
+        s.serialize_temporary(self.flowinfo());
+        s.serialize_temporary(self.scope_id());
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(Self::new(
+            d.deserialize()?,
+            d.deserialize()?,
+            d.deserialize()?,
+            d.deserialize()?,
+        ))
+    }
+}
+
+unsafe impl NonTrivialObject for std::net::SocketAddr {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        match self {
+            std::net::SocketAddr::V4(addr) => {
+                s.serialize_temporary(0u8);
+                s.serialize_temporary(*addr);
+            }
+            std::net::SocketAddr::V6(addr) => {
+                s.serialize_temporary(1u8);
+                s.serialize_temporary(*addr);
+            }
+        }
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(match d.deserialize::<u8>()? {
+            0 => std::net::SocketAddr::V4(d.deserialize()?),
+            1 => std::net::SocketAddr::V6(d.deserialize()?),
+            tag => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unknown SocketAddr variant {tag}"),
+                ))
+            }
+        })
+    }
+}
+
+// SystemTime is encoded relative to the Unix epoch rather than as its platform-specific
+// representation
+unsafe impl NonTrivialObject for std::time::SystemTime {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        match self.duration_since(std::time::UNIX_EPOCH) {
+            Ok(after) => {
+                s.serialize_temporary(false);
+                s.serialize_temporary(after);
+            }
+            Err(err) => {
+                s.serialize_temporary(true);
+                s.serialize_temporary(err.duration());
+            }
+        }
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let before_epoch = d.deserialize::<bool>()?;
+        let offset = d.deserialize::<std::time::Duration>()?;
+        if before_epoch {
+            std::time::UNIX_EPOCH.checked_sub(offset)
+        } else {
+            std::time::UNIX_EPOCH.checked_add(offset)
+        }
+        .ok_or_else(|| std::io::Error::other("SystemTime is out of range"))
+    }
+}
+
+// Not plain old data: a zero received from a misbehaving peer must not produce an invalid value
+macro_rules! impl_serialize_for_nonzero {
+    ($($ty:ident),*) => {
+        $(
+            unsafe impl NonTrivialObject for std::num::$ty {
+                fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+                    s.serialize_temporary(self.get());
+                }
+                fn serialized_size_hint(&self) -> Option<usize> {
+                    Some(std::mem::size_of::<Self>())
+                }
+                unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+                    Self::new(d.deserialize()?).ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            concat!("Received zero for ", stringify!($ty)),
+                        )
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_serialize_for_nonzero!(
+    NonZeroI8,
+    NonZeroI16,
+    NonZeroI32,
+    NonZeroI64,
+    NonZeroI128,
+    NonZeroIsize,
+    NonZeroU8,
+    NonZeroU16,
+    NonZeroU32,
+    NonZeroU64,
+    NonZeroU128,
+    NonZeroUsize
+);
+
+macro_rules! impl_serialize_for_wrapper {
+    ($($ty:ident),*) => {
+        $(
+            unsafe impl<T: Object> NonTrivialObject for $ty<T> {
+                fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+                    s.serialize(&self.0);
+                }
+                fn serialized_size_hint(&self) -> Option<usize> {
+                    Object::serialized_size_hint(&self.0)
+                }
+                unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+                    Ok($ty(d.deserialize()?))
+                }
+            }
+            unsafe impl<T: PlainOldData> PlainOldData for $ty<T> {}
+        )*
+    };
+}
+
+impl_serialize_for_wrapper!(Wrapping, Saturating, Reverse);
+
+// Atomics are sent as a snapshot of their value. Reading the value non-atomically, as plain old data
+// would, races with concurrent updates
+macro_rules! impl_serialize_for_atomic {
+    ($($ty:ident: $width:literal),*) => {
+        $(
+            #[cfg(target_has_atomic = $width)]
+            unsafe impl NonTrivialObject for std::sync::atomic::$ty {
+                fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+                    s.serialize_temporary(self.load(std::sync::atomic::Ordering::SeqCst));
+                }
+                fn serialized_size_hint(&self) -> Option<usize> {
+                    Some(std::mem::size_of::<Self>())
+                }
+                unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+                    Ok(Self::new(d.deserialize()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_serialize_for_atomic!(
+    AtomicBool: "8",
+    AtomicI8: "8",
+    AtomicI16: "16",
+    AtomicI32: "32",
+    AtomicI64: "64",
+    AtomicIsize: "ptr",
+    AtomicU8: "8",
+    AtomicU16: "16",
+    AtomicU32: "32",
+    AtomicU64: "64",
+    AtomicUsize: "ptr"
+);
 
 unsafe impl NonTrivialObject for String {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         s.serialize_temporary(self.len());
         s.serialize_slice(self.as_bytes());
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.len()))
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         Ok(unsafe { String::from_utf8_unchecked(d.deserialize::<Vec<u8>>()?) })
     }
+    unsafe fn deserialize_into_non_trivial(&mut self, d: &mut Deserializer) -> Result<()> {
+        let mut bytes = std::mem::take(self).into_bytes();
+        let result = d.deserialize_into(&mut bytes);
+        // The bytes are only modified on success, and are valid UTF-8 in either case
+        *self = unsafe { String::from_utf8_unchecked(bytes) };
+        result
+    }
 }
 
 unsafe impl NonTrivialObject for std::ffi::CString {
@@ -93,22 +298,101 @@ unsafe impl NonTrivialObject for std::ffi::CString {
         s.serialize_temporary(bytes.len());
         s.serialize_slice(bytes);
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.as_bytes().len()))
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         Ok(unsafe { Self::from_vec_unchecked(d.deserialize::<Vec<u8>>()?) })
     }
 }
 
+// OS strings are sent in the platform-specific encoding returned by as_encoded_bytes: raw bytes on
+// Unix-like systems and WTF-8 on Windows. Both preserve non-Unicode strings exactly, and both ends
+// of a channel always run on the same platform
 unsafe impl NonTrivialObject for std::ffi::OsString {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         let bytes = self.as_encoded_bytes();
         s.serialize_temporary(bytes.len());
         s.serialize_slice(bytes);
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.as_encoded_bytes().len()))
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         Ok(unsafe { Self::from_encoded_bytes_unchecked(d.deserialize()?) })
     }
 }
 
+// Borrowed and owned values are serialized identically to the owned types. A borrow cannot outlive
+// the message, so the other side always receives Cow::Owned
+unsafe impl NonTrivialObject for Cow<'static, str> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize_temporary(self.len());
+        s.serialize_slice(self.as_bytes());
+    }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.len()))
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(Cow::Owned(d.deserialize()?))
+    }
+}
+
+unsafe impl<T: Object + Clone> NonTrivialObject for Cow<'static, [T]> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize_temporary(self.len());
+        s.serialize_slice(self);
+    }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(T::serialized_slice_size_hint(self))
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(Cow::Owned(d.deserialize()?))
+    }
+}
+
+unsafe impl NonTrivialObject for Cow<'static, std::ffi::CStr> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        let bytes = self.to_bytes();
+        s.serialize_temporary(bytes.len());
+        s.serialize_slice(bytes);
+    }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.to_bytes().len()))
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(Cow::Owned(d.deserialize()?))
+    }
+}
+
+unsafe impl NonTrivialObject for Cow<'static, std::ffi::OsStr> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        let bytes = self.as_encoded_bytes();
+        s.serialize_temporary(bytes.len());
+        s.serialize_slice(bytes);
+    }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.as_encoded_bytes().len()))
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(Cow::Owned(d.deserialize()?))
+    }
+}
+
+unsafe impl NonTrivialObject for Cow<'static, std::path::Path> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        let bytes = self.as_os_str().as_encoded_bytes();
+        s.serialize_temporary(bytes.len());
+        s.serialize_slice(bytes);
+    }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.as_os_str().as_encoded_bytes().len()))
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(Cow::Owned(d.deserialize()?))
+    }
+}
+
 macro_rules! serialize_rev {
     ($s:tt, $self:tt,) => {};
 
@@ -146,6 +430,9 @@ macro_rules! impl_serialize_for_tuple {
                 fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
                     serialize_rev!(s, self, $($tail)*);
                 }
+                fn serialized_size_hint(&self) -> Option<usize> {
+                    sum_size_hints(&[$(Object::serialized_size_hint(&self.$tail)),*])
+                }
                 #[allow(unused_variables)]
                 #[allow(clippy::unused_unit)]
                 unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
@@ -170,6 +457,12 @@ unsafe impl<T: Object> NonTrivialObject for Option<T> {
             }
         }
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        match self {
+            None => Some(1),
+            Some(ref x) => Object::serialized_size_hint(x)?.checked_add(1),
+        }
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         if d.deserialize::<bool>()? {
             d.deserialize().map(Some)
@@ -180,62 +473,91 @@ unsafe impl<T: Object> NonTrivialObject for Option<T> {
 }
 unsafe impl<T: PlainOldData> PlainOldData for Option<T> {}
 
-unsafe impl<T: 'static + Object> NonTrivialObject for Rc<T> {
-    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
-        match s.learn_cyclic(Rc::as_ptr(self) as *const c_void) {
-            None => {
-                s.serialize_temporary(0usize);
-                s.serialize(&**self);
+// Pointers to the same allocation are sent once and deduplicated on the receiving side. The
+// contents are deserialized after the index is reserved, so that nested pointers are numbered in
+// the same order as by the serializer
+macro_rules! impl_serialize_for_shared {
+    ($($ptr:ident),*) => {
+        $(
+            unsafe impl<T: 'static + Object> NonTrivialObject for $ptr<T> {
+                fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+                    if learn_shared(s, $ptr::as_ptr(self) as *const c_void) {
+                        s.serialize(&**self);
+                    }
+                }
+                unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+                    deserialize_shared(d, |d| Ok($ptr::new(d.deserialize()?)))
+                }
             }
-            Some(id) => {
-                s.serialize_temporary(id);
+
+            unsafe impl NonTrivialObject for $ptr<str> {
+                fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+                    if learn_shared(s, $ptr::as_ptr(self) as *const c_void) {
+                        s.serialize_temporary(self.len());
+                        s.serialize_slice(self.as_bytes());
+                    }
+                }
+                unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+                    deserialize_shared(d, |d| Ok($ptr::from(d.deserialize::<String>()?)))
+                }
             }
+
+            unsafe impl<T: 'static + Object> NonTrivialObject for $ptr<[T]> {
+                fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+                    if learn_shared(s, $ptr::as_ptr(self) as *const c_void) {
+                        s.serialize_temporary(self.len());
+                        s.serialize_slice(self);
+                    }
+                }
+                unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+                    deserialize_shared(d, |d| Ok($ptr::from(d.deserialize::<Vec<T>>()?)))
+                }
+            }
+        )*
+    };
+}
+
+// Returns whether the contents have to be serialized
+fn learn_shared(s: &mut Serializer, ptr: *const c_void) -> bool {
+    match s.learn_cyclic(ptr) {
+        None => {
+            s.serialize_temporary(0usize);
+            true
         }
-    }
-    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
-        let id = d.deserialize::<usize>()?;
-        match std::num::NonZeroUsize::new(id) {
-            None => {
-                let rc = Self::new(d.deserialize()?);
-                d.learn_cyclic(rc.clone());
-                Ok(rc)
-            }
-            Some(id) => Ok(d.get_cyclic::<Rc<T>>(id).clone()),
+        Some(id) => {
+            s.serialize_temporary(id);
+            false
         }
     }
 }
 
-unsafe impl<T: 'static + Object> NonTrivialObject for Arc<T> {
-    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
-        match s.learn_cyclic(Arc::as_ptr(self) as *const c_void) {
-            None => {
-                s.serialize_temporary(0usize);
-                s.serialize(&**self);
-            }
-            Some(id) => {
-                s.serialize_temporary(id);
-            }
-        }
-    }
-    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
-        let id = d.deserialize::<usize>()?;
-        match std::num::NonZeroUsize::new(id) {
-            None => {
-                let rc = Self::new(d.deserialize()?);
-                d.learn_cyclic(rc.clone());
-                Ok(rc)
-            }
-            Some(id) => Ok(d.get_cyclic::<Arc<T>>(id).clone()),
+unsafe fn deserialize_shared<P: 'static + Clone>(
+    d: &mut Deserializer,
+    build: impl FnOnce(&mut Deserializer) -> Result<P>,
+) -> Result<P> {
+    let id = d.deserialize::<usize>()?;
+    match std::num::NonZeroUsize::new(id) {
+        None => {
+            let id = d.reserve_cyclic();
+            let ptr = build(d)?;
+            d.fill_cyclic(id, ptr.clone());
+            Ok(ptr)
         }
+        Some(id) => d.try_get_cyclic::<P>(id).cloned(),
     }
 }
 
+impl_serialize_for_shared!(Rc, Arc);
+
 unsafe impl NonTrivialObject for std::path::PathBuf {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         let bytes = self.as_os_str().as_encoded_bytes();
         s.serialize_temporary(bytes.len());
         s.serialize_slice(bytes);
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(Some(self.as_os_str().as_encoded_bytes().len()))
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         Ok(d.deserialize::<std::ffi::OsString>()?.into())
     }
@@ -245,6 +567,9 @@ unsafe impl<T: Object, const N: usize> NonTrivialObject for [T; N] {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         s.serialize_slice(self);
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        T::serialized_slice_size_hint(self)
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         // array::try_map is not stabilized yet
         let mut array: MaybeUninit<[T; N]> = MaybeUninit::uninit();
@@ -265,19 +590,73 @@ unsafe impl<T: Object, const N: usize> NonTrivialObject for [T; N] {
 }
 unsafe impl<T: PlainOldData, const N: usize> PlainOldData for [T; N] {}
 
+// The length of a container is read from the message, so a corrupted length must not cause a huge
+// allocation before the elements are read. Containers grow as usual past this limit
+const MAX_PREALLOCATION_BYTES: usize = 1 << 20;
+
+fn preallocation<T>(size: usize) -> usize {
+    size.min(MAX_PREALLOCATION_BYTES / std::mem::size_of::<T>().max(1))
+}
+
 unsafe impl<T: Object> NonTrivialObject for Vec<T> {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         s.serialize_temporary(self.len());
         s.serialize_slice(self.as_slice())
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        length_prefixed(T::serialized_slice_size_hint(self))
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         let size: usize = d.deserialize()?;
-        let mut seq = Vec::with_capacity(size);
+        if implements!(T: PlainOldData) {
+            // Copy the elements at once instead of one by one
+            let n_bytes = size
+                .checked_mul(std::mem::size_of::<T>())
+                .filter(|&n_bytes| n_bytes <= d.remaining())
+                .ok_or_else(|| std::io::Error::other("Vec is too long"))?;
+            let mut seq = Vec::<T>::with_capacity(size);
+            d.read(std::slice::from_raw_parts_mut(
+                seq.as_mut_ptr() as *mut u8,
+                n_bytes,
+            ));
+            seq.set_len(size);
+            return Ok(seq);
+        }
+        let mut seq = Vec::with_capacity(preallocation::<T>(size));
         for _ in 0..size {
             seq.push(d.deserialize()?);
         }
         Ok(seq)
     }
+    unsafe fn deserialize_into_non_trivial(&mut self, d: &mut Deserializer) -> Result<()> {
+        let size: usize = d.deserialize()?;
+        if implements!(T: PlainOldData) {
+            let n_bytes = size
+                .checked_mul(std::mem::size_of::<T>())
+                .filter(|&n_bytes| n_bytes <= d.remaining())
+                .ok_or_else(|| std::io::Error::other("Vec is too long"))?;
+            // Plain old data has no drop glue, so the old elements can simply be overwritten
+            self.clear();
+            self.reserve(size);
+            d.read(std::slice::from_raw_parts_mut(
+                self.as_mut_ptr() as *mut u8,
+                n_bytes,
+            ));
+            self.set_len(size);
+            return Ok(());
+        }
+        // Deserialize into the existing elements, so that their allocations are reused too
+        self.truncate(size);
+        for element in self.iter_mut() {
+            d.deserialize_into(element)?;
+        }
+        let rest = size - self.len();
+        self.reserve(preallocation::<T>(rest));
+        for _ in 0..rest {
+            self.push(d.deserialize()?);
+        }
+        Ok(())
+    }
 }
 
 macro_rules! impl_serialize_for_sequence {
@@ -297,6 +676,11 @@ macro_rules! impl_serialize_for_sequence {
                     s.serialize(item);
                 }
             }
+            fn serialized_size_hint(&self) -> Option<usize> {
+                length_prefixed(self.iter().try_fold(0usize, |size, item| {
+                    size.checked_add(Object::serialized_size_hint(item)?)
+                }))
+            }
             unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
                 let $size: usize = d.deserialize()?;
                 let mut $seq = $with_capacity;
@@ -333,6 +717,12 @@ macro_rules! impl_serialize_for_map {
                     s.serialize(value);
                 }
             }
+            fn serialized_size_hint(&self) -> Option<usize> {
+                length_prefixed(self.iter().try_fold(0usize, |size, (key, value)| {
+                    size.checked_add(Object::serialized_size_hint(key)?)?
+                        .checked_add(Object::serialized_size_hint(value)?)
+                }))
+            }
             unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
                 let $size: usize = d.deserialize()?;
                 let mut map = $with_capacity;
@@ -349,7 +739,7 @@ impl_serialize_for_sequence!(
     BinaryHeap<T: Ord>,
     seq,
     size,
-    BinaryHeap::with_capacity(size),
+    BinaryHeap::with_capacity(preallocation::<T>(size)),
     BinaryHeap::push
 );
 impl_serialize_for_sequence!(
@@ -370,21 +760,21 @@ impl_serialize_for_sequence!(
     HashSet<T: Eq + Hash, S: BuildHasher + Default>,
     seq,
     size,
-    HashSet::with_capacity_and_hasher(size, S::default()),
+    HashSet::with_capacity_and_hasher(preallocation::<T>(size), S::default()),
     HashSet::insert
 );
 impl_serialize_for_sequence!(
     VecDeque<T>,
     seq,
     size,
-    VecDeque::with_capacity(size),
+    VecDeque::with_capacity(preallocation::<T>(size)),
     VecDeque::push_back
 );
 impl_serialize_for_map!(BTreeMap<K: Ord, V>, size, BTreeMap::new());
 impl_serialize_for_map!(
     HashMap<K: Eq + Hash, V, S: BuildHasher + Default>,
     size,
-    HashMap::with_capacity_and_hasher(size, S::default())
+    HashMap::with_capacity_and_hasher(preallocation::<(K, V)>(size), S::default())
 );
 
 unsafe impl<T: Object, E: Object> NonTrivialObject for std::result::Result<T, E> {
@@ -400,6 +790,12 @@ unsafe impl<T: Object, E: Object> NonTrivialObject for std::result::Result<T, E>
             }
         }
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        match self {
+            Ok(ref ok) => Object::serialized_size_hint(ok)?.checked_add(1),
+            Err(ref err) => Object::serialized_size_hint(err)?.checked_add(1),
+        }
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         Ok(if d.deserialize::<bool>()? {
             Ok(d.deserialize()?)
@@ -410,6 +806,179 @@ unsafe impl<T: Object, E: Object> NonTrivialObject for std::result::Result<T, E>
 }
 unsafe impl<T: PlainOldData, E: PlainOldData> PlainOldData for std::result::Result<T, E> {}
 
+// ErrorKind is non-exhaustive and has no stable numeric representation, so kinds are transferred
+// as indices into this list. Kinds missing from it are transferred as Other
+const ERROR_KINDS: &[std::io::ErrorKind] = {
+    use std::io::ErrorKind::*;
+    &[
+        NotFound,
+        PermissionDenied,
+        ConnectionRefused,
+        ConnectionReset,
+        HostUnreachable,
+        NetworkUnreachable,
+        ConnectionAborted,
+        NotConnected,
+        AddrInUse,
+        AddrNotAvailable,
+        NetworkDown,
+        BrokenPipe,
+        AlreadyExists,
+        WouldBlock,
+        NotADirectory,
+        IsADirectory,
+        DirectoryNotEmpty,
+        ReadOnlyFilesystem,
+        StaleNetworkFileHandle,
+        InvalidInput,
+        InvalidData,
+        TimedOut,
+        WriteZero,
+        StorageFull,
+        NotSeekable,
+        QuotaExceeded,
+        FileTooLarge,
+        ResourceBusy,
+        ExecutableFileBusy,
+        Deadlock,
+        CrossesDevices,
+        TooManyLinks,
+        ArgumentListTooLong,
+        Interrupted,
+        Unsupported,
+        UnexpectedEof,
+        OutOfMemory,
+        Other,
+    ]
+};
+
+unsafe impl NonTrivialObject for std::io::ErrorKind {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        let index = ERROR_KINDS
+            .iter()
+            .position(|kind| kind == self)
+            .unwrap_or(ERROR_KINDS.len() - 1);
+        s.serialize_temporary(index as u8);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let index = d.deserialize::<u8>()?;
+        ERROR_KINDS.get(index as usize).copied().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unknown error kind {index}"),
+            )
+        })
+    }
+}
+
+// OS errors are transferred as the error code, which determines both the kind and the message.
+// Other errors keep their kind and, if present, the message of the inner error; the inner error
+// itself is not transferred, as it is not required to implement Object
+unsafe impl NonTrivialObject for std::io::Error {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        if let Some(code) = self.raw_os_error() {
+            s.serialize_temporary(0u8);
+            s.serialize_temporary(code);
+        } else if let Some(inner) = self.get_ref() {
+            s.serialize_temporary(1u8);
+            s.serialize_temporary(self.kind());
+            s.serialize_temporary(inner.to_string());
+        } else {
+            s.serialize_temporary(2u8);
+            s.serialize_temporary(self.kind());
+        }
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(match d.deserialize::<u8>()? {
+            0 => Self::from_raw_os_error(d.deserialize()?),
+            1 => Self::new(
+                d.deserialize::<std::io::ErrorKind>()?,
+                d.deserialize::<String>()?,
+            ),
+            2 => Self::from(d.deserialize::<std::io::ErrorKind>()?),
+            tag => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unknown io::Error representation {tag}"),
+                ))
+            }
+        })
+    }
+}
+
+unsafe impl<T: Object> NonTrivialObject for Bound<T> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        match self {
+            Bound::Included(ref x) => {
+                s.serialize_temporary(0u8);
+                s.serialize(x);
+            }
+            Bound::Excluded(ref x) => {
+                s.serialize_temporary(1u8);
+                s.serialize(x);
+            }
+            Bound::Unbounded => s.serialize_temporary(2u8),
+        }
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(match d.deserialize::<u8>()? {
+            0 => Bound::Included(d.deserialize()?),
+            1 => Bound::Excluded(d.deserialize()?),
+            2 => Bound::Unbounded,
+            tag => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unknown Bound variant {tag}"),
+                ))
+            }
+        })
+    }
+}
+unsafe impl<T: PlainOldData> PlainOldData for Bound<T> {}
+
+unsafe impl<B: Object, C: Object> NonTrivialObject for ControlFlow<B, C> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        match self {
+            ControlFlow::Continue(ref x) => {
+                s.serialize_temporary(true);
+                s.serialize(x);
+            }
+            ControlFlow::Break(ref x) => {
+                s.serialize_temporary(false);
+                s.serialize(x);
+            }
+        }
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        Ok(if d.deserialize::<bool>()? {
+            ControlFlow::Continue(d.deserialize()?)
+        } else {
+            ControlFlow::Break(d.deserialize()?)
+        })
+    }
+}
+unsafe impl<B: PlainOldData, C: PlainOldData> PlainOldData for ControlFlow<B, C> {}
+
+unsafe impl<T: Object> NonTrivialObject for Poll<T> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        match self {
+            Poll::Pending => s.serialize_temporary(false),
+            Poll::Ready(ref x) => {
+                s.serialize_temporary(true);
+                s.serialize(x);
+            }
+        }
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        if d.deserialize::<bool>()? {
+            d.deserialize().map(Poll::Ready)
+        } else {
+            Ok(Poll::Pending)
+        }
+    }
+}
+unsafe impl<T: PlainOldData> PlainOldData for Poll<T> {}
+
 unsafe impl NonTrivialObject for OwnedHandle {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         s.serialize_handle(self.as_handle());
@@ -454,16 +1023,61 @@ unsafe impl NonTrivialObject for async_fs::File {
     }
 }
 
-#[cfg(unix)]
-unsafe impl NonTrivialObject for std::os::unix::net::UnixStream {
+#[cfg(feature = "async-std")]
+unsafe impl NonTrivialObject for async_std::fs::File {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         s.serialize_handle(self.as_handle());
     }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
-        Ok(d.deserialize::<OwnedHandle>()?.into())
+        Ok(d.deserialize::<std::fs::File>()?.into())
     }
 }
 
+// On Windows, sockets are duplicated with WSADuplicateSocketW rather than as handles, as sockets of
+// layered service providers are not plain kernel handles
+macro_rules! impl_serialize_for_socket {
+    ($($ty:ty),*) => {
+        $(
+            unsafe impl NonTrivialObject for $ty {
+                fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+                    #[cfg(unix)]
+                    s.serialize_handle(self.as_handle());
+                    #[cfg(windows)]
+                    s.serialize_temporary(crate::internals::duplicate_socket_for_broker(
+                        std::os::windows::io::AsRawSocket::as_raw_socket(self),
+                    ));
+                }
+                unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+                    #[cfg(unix)]
+                    return Ok(d.deserialize::<OwnedHandle>()?.into());
+                    #[cfg(windows)]
+                    {
+                        let info = d
+                            .deserialize::<std::result::Result<Vec<u8>, i32>>()?
+                            .map_err(std::io::Error::from_raw_os_error)?;
+                        return Ok(<Self as std::os::windows::io::FromRawSocket>::from_raw_socket(
+                            crate::internals::claim_socket(info)?,
+                        ));
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_serialize_for_socket!(
+    std::net::TcpStream,
+    std::net::TcpListener,
+    std::net::UdpSocket
+);
+
+#[cfg(unix)]
+impl_serialize_for_socket!(
+    std::os::unix::net::UnixStream,
+    std::os::unix::net::UnixListener,
+    std::os::unix::net::UnixDatagram
+);
+
 #[cfg(all(unix, feature = "tokio"))]
 unsafe impl NonTrivialObject for tokio::net::UnixStream {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
@@ -474,7 +1088,7 @@ unsafe impl NonTrivialObject for tokio::net::UnixStream {
     }
 }
 
-#[cfg(all(unix, feature = "smol"))]
+#[cfg(all(unix, any(feature = "smol", feature = "async-std")))]
 unsafe impl<T: 'static + std::os::fd::AsFd + Object> NonTrivialObject for async_io::Async<T> {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         s.serialize(self.get_ref())
@@ -0,0 +1,319 @@
+//! Channels over arbitrary byte streams.
+//!
+//! The channels in [`asynchronous`](crate::asynchronous) are built on OS streams, which allows them
+//! to pass file descriptors and handles, but ties them to runtimes that can poll such streams. This
+//! module provides channels over any stream implementing [`AsyncRead`] and [`AsyncWrite`] from the
+//! `futures-io` crate, e.g. a TCP connection or an in-memory pipe, regardless of the runtime:
+//!
+//! ```rust
+//! use crossmist::byte_stream::Duplex;
+//! use smol::net::{TcpListener, TcpStream};
+//!
+//! smol::block_on(async {
+//!     let listener = TcpListener::bind("127.0.0.1:0").await?;
+//!     let client = TcpStream::connect(listener.local_addr()?).await?;
+//!     let (server, _) = listener.accept().await?;
+//!
+//!     // Both sides are run by this very program
+//!     let mut client = unsafe { Duplex::<_, String, usize>::new(client) };
+//!     let mut server = unsafe { Duplex::<_, usize, String>::new(server) };
+//!
+//!     client.send(&"hello".to_string()).await?;
+//!     let message = server.recv().await?.unwrap();
+//!     server.send(&message.len()).await?;
+//!     assert_eq!(client.recv().await?, Some(5));
+//!     std::io::Result::Ok(())
+//! })
+//! .unwrap();
+//! ```
+//!
+//! A byte stream cannot carry file descriptors or handles, so sending an object that contains them,
+//! e.g. a file or a channel, fails with [`SendError::HandlesNotSupported`].
+//!
+//! Each message is sent as a little-endian `u64` length followed by the serialized object. The
+//! objects are deserialized without validation, so both sides have to be the same build of the
+//! program, and creating a channel is `unsafe`. Dropping the future returned by `send` or `recv`
+//! before it completes leaves the stream in an unspecified state.
+
+use crate::{ChannelOptions, Deserializer, Object, Serializer};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::fmt;
+use std::future::poll_fn;
+use std::io::{Error, ErrorKind, Result};
+use std::marker::PhantomData;
+use std::pin::Pin;
+
+/// An error returned by the `send` methods of this module.
+#[derive(Debug)]
+pub enum SendError {
+    /// The object contains file descriptors or handles, which a byte stream cannot transfer.
+    HandlesNotSupported,
+    /// An I/O error occured.
+    Io(Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::HandlesNotSupported => write!(
+                fmt,
+                "Objects with file descriptors or handles cannot be sent over a byte stream"
+            ),
+            SendError::Io(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for SendError {
+    fn from(error: Error) -> Self {
+        SendError::Io(error)
+    }
+}
+
+impl From<SendError> for Error {
+    fn from(error: SendError) -> Self {
+        match error {
+            SendError::HandlesNotSupported => Error::new(ErrorKind::Unsupported, error),
+            SendError::Io(e) => e,
+        }
+    }
+}
+
+async fn write_all<S: AsyncWrite + Unpin>(stream: &mut S, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match poll_fn(|cx| Pin::new(&mut *stream).poll_write(cx, buf)).await {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => buf = &buf[n..],
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Returns the number of bytes read, which is less than the length of `buf` only at end of stream
+async fn read_full<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match poll_fn(|cx| Pin::new(&mut *stream).poll_read(cx, &mut buf[filled..])).await {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+async fn send_on<S: AsyncWrite + Unpin, T: Object>(
+    stream: &mut S,
+    value: &T,
+) -> std::result::Result<(), SendError> {
+    let mut s = Serializer::new();
+    s.serialize(value);
+    if !s.drain_handles().is_empty() {
+        return Err(SendError::HandlesNotSupported);
+    }
+    let data = s.into_vec();
+    write_all(stream, &(data.len() as u64).to_le_bytes()).await?;
+    write_all(stream, &data).await?;
+    poll_fn(|cx| Pin::new(&mut *stream).poll_flush(cx)).await?;
+    Ok(())
+}
+
+async fn recv_on<S: AsyncRead + Unpin, T: Object>(
+    stream: &mut S,
+    options: &ChannelOptions,
+) -> Result<Option<T>> {
+    let truncated = || {
+        Error::new(
+            ErrorKind::UnexpectedEof,
+            "The stream ends in the middle of a message",
+        )
+    };
+    let mut prefix = [0u8; 8];
+    match read_full(stream, &mut prefix).await? {
+        0 => return Ok(None),
+        8 => {}
+        _ => return Err(truncated()),
+    }
+    let len = u64::from_le_bytes(prefix);
+    options.check_message_size(len)?;
+    let len = usize::try_from(len).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "Message is too long for this platform",
+        )
+    })?;
+    let mut data = vec![0u8; len];
+    if read_full(stream, &mut data).await? < len {
+        return Err(truncated());
+    }
+    unsafe { Deserializer::new(data, Vec::new()).deserialize().map(Some) }
+}
+
+/// The transmitting side of a unidirectional channel over a byte stream.
+///
+/// `T` is the type of the objects this side sends via the channel and the other side receives.
+pub struct Sender<S: AsyncWrite + Unpin, T: Object> {
+    stream: S,
+    marker: PhantomData<fn(T)>,
+}
+
+impl<S: AsyncWrite + Unpin, T: Object> Sender<S, T> {
+    /// Create a channel over a stream.
+    ///
+    /// # Safety
+    ///
+    /// The other end of the stream must be a [`Receiver`] or a [`Duplex`] for the same type `T` in
+    /// the same build of the program.
+    pub unsafe fn new(stream: S) -> Self {
+        Self {
+            stream,
+            marker: PhantomData,
+        }
+    }
+
+    /// Send a value to the other side.
+    pub async fn send(&mut self, value: &T) -> std::result::Result<(), SendError> {
+        send_on(&mut self.stream, value).await
+    }
+
+    /// Get the underlying stream back.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncWrite + Unpin, T: Object> fmt::Debug for Sender<S, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+/// The receiving side of a unidirectional channel over a byte stream.
+///
+/// `T` is the type of the objects the other side sends via the channel and this side receives.
+pub struct Receiver<S: AsyncRead + Unpin, T: Object> {
+    stream: S,
+    options: ChannelOptions,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<S: AsyncRead + Unpin, T: Object> Receiver<S, T> {
+    /// Create a channel over a stream.
+    ///
+    /// # Safety
+    ///
+    /// The other end of the stream must be a [`Sender`] or a [`Duplex`] for the same type `T` in
+    /// the same build of the program.
+    pub unsafe fn new(stream: S) -> Self {
+        Self {
+            stream,
+            options: ChannelOptions::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Change the maximum size of a received message.
+    ///
+    /// See [`ChannelOptions::max_message_size`] for more information. `None` disables the check.
+    /// An oversized message cannot be skipped, so all further receives fail.
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.options = self.options.max_message_size(limit);
+    }
+
+    /// Receive a value from the other side.
+    ///
+    /// Returns `Ok(None)` if the stream ends between messages.
+    pub async fn recv(&mut self) -> Result<Option<T>> {
+        recv_on(&mut self.stream, &self.options).await
+    }
+
+    /// Get the underlying stream back.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin, T: Object> fmt::Debug for Receiver<S, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+/// A side of a bidirectional channel over a byte stream.
+///
+/// `A` is the type of the objects this side sends via the channel and the other side receives, `B`
+/// is the type of the objects the other side sends via the channel and this side receives.
+pub struct Duplex<S: AsyncRead + AsyncWrite + Unpin, A: Object, B: Object> {
+    stream: S,
+    options: ChannelOptions,
+    marker: PhantomData<fn(A) -> B>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, A: Object, B: Object> Duplex<S, A, B> {
+    /// Create a channel over a stream.
+    ///
+    /// # Safety
+    ///
+    /// The other end of the stream must be a `Duplex<_, B, A>` in the same build of the program.
+    pub unsafe fn new(stream: S) -> Self {
+        Self {
+            stream,
+            options: ChannelOptions::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Change the maximum size of a received message.
+    ///
+    /// See [`ChannelOptions::max_message_size`] for more information. `None` disables the check.
+    /// An oversized message cannot be skipped, so all further receives fail.
+    pub fn set_max_message_size(&mut self, limit: Option<usize>) {
+        self.options = self.options.max_message_size(limit);
+    }
+
+    /// Send a value to the other side.
+    pub async fn send(&mut self, value: &A) -> std::result::Result<(), SendError> {
+        send_on(&mut self.stream, value).await
+    }
+
+    /// Receive a value from the other side.
+    ///
+    /// Returns `Ok(None)` if the stream ends between messages.
+    pub async fn recv(&mut self) -> Result<Option<B>> {
+        recv_on(&mut self.stream, &self.options).await
+    }
+
+    /// Send a value and receive the response.
+    pub async fn request(&mut self, value: &A) -> Result<B> {
+        self.send(value).await?;
+        self.recv().await?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                "The other side closed the stream before responding to the request",
+            )
+        })
+    }
+
+    /// Get the underlying stream back.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, A: Object, B: Object> fmt::Debug for Duplex<S, A, B> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Duplex").finish_non_exhaustive()
+    }
+}
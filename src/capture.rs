@@ -0,0 +1,111 @@
+//! Capturing messages that fail to deserialize.
+//!
+//! When a message received from another process cannot be deserialized, e.g. due to a protocol
+//! mismatch or a corrupted stream, the offending bytes are normally lost. With the `capture`
+//! feature enabled, such errors carry an [`InvalidFrame`] with the raw bytes of the message:
+//!
+//! ```rust
+//! use crossmist::capture::InvalidFrame;
+//! use crossmist::handles::{FromRawHandle, IntoRawHandle};
+//! use crossmist::{channel, Receiver};
+//! use std::num::NonZeroU32;
+//!
+//! let (mut tx, rx) = channel::<u32>().unwrap();
+//! let mut rx = unsafe { Receiver::<NonZeroU32>::from_raw_handle(rx.into_raw_handle()) };
+//! tx.send(&0).unwrap();
+//! let error = rx.recv().unwrap_err();
+//! let frame = error.get_ref().unwrap().downcast_ref::<InvalidFrame>().unwrap();
+//! assert_eq!(frame.frame(), 0u32.to_ne_bytes());
+//! ```
+//!
+//! Only the first [`MAX_CAPTURED_BYTES`] bytes of a message are attached to the error. If the
+//! `CROSSMIST_CAPTURE_DIR` environment variable is set, the whole message is additionally saved to
+//! a file in that directory, named `crossmist-frame-<pid>-<index>.bin`.
+//!
+//! Handles passed along with the message are not captured. As messages may contain sensitive data,
+//! this feature is intended for debugging only and is disabled by default.
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The maximum number of bytes attached to an error.
+pub const MAX_CAPTURED_BYTES: usize = 4096;
+
+/// A deserialization error along with the message that caused it.
+#[derive(Debug)]
+pub struct InvalidFrame {
+    error: Error,
+    frame: Vec<u8>,
+    len: usize,
+}
+
+impl InvalidFrame {
+    /// Get the original deserialization error.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Get the raw bytes of the message, truncated to [`MAX_CAPTURED_BYTES`].
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Get the length of the whole message.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the message was empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check if [`InvalidFrame::frame`] contains only a prefix of the message.
+    pub fn is_truncated(&self) -> bool {
+        self.frame.len() < self.len
+    }
+}
+
+impl fmt::Display for InvalidFrame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} (message of {} bytes:", self.error, self.len)?;
+        for byte in &self.frame {
+            write!(fmt, " {byte:02x}")?;
+        }
+        if self.is_truncated() {
+            write!(fmt, " ...")?;
+        }
+        write!(fmt, ")")
+    }
+}
+
+impl std::error::Error for InvalidFrame {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+pub(crate) fn capture(error: Error, frame: &[u8]) -> Error {
+    if error.kind() != ErrorKind::InvalidData {
+        return error;
+    }
+    if let Some(dir) = std::env::var_os("CROSSMIST_CAPTURE_DIR") {
+        static INDEX: AtomicUsize = AtomicUsize::new(0);
+        let index = INDEX.fetch_add(1, Ordering::Relaxed);
+        let path = std::path::Path::new(&dir).join(format!(
+            "crossmist-frame-{}-{index}.bin",
+            std::process::id()
+        ));
+        // Failing to save the message must not hide the original error
+        let _ = std::fs::write(path, frame);
+    }
+    Error::new(
+        ErrorKind::InvalidData,
+        InvalidFrame {
+            error,
+            frame: frame[..frame.len().min(MAX_CAPTURED_BYTES)].to_vec(),
+            len: frame.len(),
+        },
+    )
+}
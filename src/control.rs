@@ -0,0 +1,84 @@
+//! Control channels between a parent and a child.
+//!
+//! Passing a [`Duplex`] to a child explicitly requires adding it to the signature of the function.
+//! Alternatively, `spawn_with_channel` creates a bidirectional channel alongside the child, and the
+//! child retrieves its side with [`control_channel`]:
+//!
+//! ```rust
+//! use crossmist::{control::control_channel, func, main};
+//!
+//! #[func]
+//! fn count(n: u32) -> u32 {
+//!     let mut chan = control_channel::<u32, ()>().unwrap();
+//!     for i in 0..n {
+//!         chan.send(&i).unwrap();
+//!     }
+//!     n
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let (child, mut chan) = count.spawn_with_channel::<(), u32>(3).unwrap();
+//!     for i in 0..3 {
+//!         assert_eq!(chan.recv().unwrap(), Some(i));
+//!     }
+//!     assert_eq!(child.join().unwrap(), 3);
+//! }
+//! ```
+//!
+//! The parent side has type `Duplex<ParentToChild, ChildToParent>`, and the child side has type
+//! `Duplex<ChildToParent, ParentToChild>`.
+
+use crate::{
+    blocking::spawn, handles::RawHandle, CallWrapper, Child, Duplex, FnOnceObject, InternalFnOnce,
+    Object, SpawnOptions,
+};
+use std::any::Any;
+use std::io::Result;
+use std::sync::Mutex;
+
+static CONTROL_CHANNEL: Mutex<Option<Box<dyn Any + Send>>> = Mutex::new(None);
+
+/// Take the control channel of the current process.
+///
+/// Returns `None` if the process was not started with `spawn_with_channel`, if the channel has
+/// already been taken, or if the types do not match the ones the parent used. In the latter case,
+/// the channel is kept, so that it can be retrieved with the right types.
+pub fn control_channel<S: Object + 'static, R: Object + 'static>() -> Option<Duplex<S, R>> {
+    let mut slot = CONTROL_CHANNEL.lock().unwrap_or_else(|e| e.into_inner());
+    match slot.take()?.downcast() {
+        Ok(chan) => Some(*chan),
+        Err(chan) => {
+            *slot = Some(chan);
+            None
+        }
+    }
+}
+
+#[derive(Object)]
+struct ControlEntry<S: Object, R: Object> {
+    entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    chan: Duplex<R, S>,
+}
+
+impl<S: Object + 'static, R: Object + 'static> InternalFnOnce<(RawHandle,)> for ControlEntry<S, R> {
+    type Output = i32;
+    fn call_object_once(self, args: (RawHandle,)) -> Self::Output {
+        *CONTROL_CHANNEL.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(self.chan));
+        self.entry.call_object_box(args)
+    }
+}
+
+#[doc(hidden)]
+pub unsafe fn spawn_with_channel<T: Object, S: Object + 'static, R: Object + 'static>(
+    entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
+) -> Result<(Child<T>, Duplex<S, R>)> {
+    let (local, remote) = crate::duplex()?;
+    let entry = ControlEntry {
+        entry,
+        chan: remote,
+    };
+    let child = spawn(Box::new(CallWrapper(entry)), options)?;
+    Ok((child, local))
+}
@@ -0,0 +1,118 @@
+//! Incremental updates of maps.
+//!
+//! When a large [`HashMap`] is mirrored in another process, sending the whole map after each
+//! modification is wasteful. [`MapDelta`] records the changes instead, and the other side applies
+//! them to its copy:
+//!
+//! ```rust
+//! use crossmist::MapDelta;
+//! use std::collections::HashMap;
+//!
+//! let old = HashMap::from([(1, 10), (2, 20)]);
+//! let new = HashMap::from([(1, 11), (3, 30)]);
+//!
+//! let delta = MapDelta::diff(&old, &new);
+//! assert_eq!(delta.len(), 3);
+//!
+//! let mut copy = old.clone();
+//! delta.apply(&mut copy);
+//! assert_eq!(copy, new);
+//! ```
+
+use crate::Object;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+/// A single change of a map.
+#[derive(Clone, Debug, PartialEq, Eq, Object)]
+pub enum MapChange<K: Object, V: Object> {
+    /// Insert a value, replacing the previous one if present.
+    Insert(K, V),
+    /// Remove a key if present.
+    Remove(K),
+}
+
+/// A sequence of changes of a map.
+///
+/// Changes are applied in the order they were added.
+#[derive(Clone, Debug, PartialEq, Eq, Object)]
+pub struct MapDelta<K: Object, V: Object> {
+    changes: Vec<MapChange<K, V>>,
+}
+
+impl<K: Object, V: Object> MapDelta<K, V> {
+    /// Create an empty delta.
+    pub fn new() -> Self {
+        Self {
+            changes: Vec::new(),
+        }
+    }
+
+    /// Record an insertion or an update.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.changes.push(MapChange::Insert(key, value));
+    }
+
+    /// Record a removal.
+    pub fn remove(&mut self, key: K) {
+        self.changes.push(MapChange::Remove(key));
+    }
+
+    /// The recorded changes.
+    pub fn changes(&self) -> &[MapChange<K, V>] {
+        &self.changes
+    }
+
+    /// The number of recorded changes.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Whether no changes are recorded.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Apply the changes to a map.
+    pub fn apply<S: BuildHasher>(self, map: &mut HashMap<K, V, S>)
+    where
+        K: Eq + Hash,
+    {
+        for change in self.changes {
+            match change {
+                MapChange::Insert(key, value) => {
+                    map.insert(key, value);
+                }
+                MapChange::Remove(key) => {
+                    map.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl<K: Object + Clone + Eq + Hash, V: Object + Clone + PartialEq> MapDelta<K, V> {
+    /// Compute the changes that turn `old` into `new`.
+    ///
+    /// Only the keys that were added, removed, or whose values changed are recorded.
+    pub fn diff<S: BuildHasher>(old: &HashMap<K, V, S>, new: &HashMap<K, V, S>) -> Self {
+        let mut delta = Self::new();
+        for key in old.keys() {
+            if !new.contains_key(key) {
+                delta.remove(key.clone());
+            }
+        }
+        for (key, value) in new {
+            if old.get(key) != Some(value) {
+                delta.insert(key.clone(), value.clone());
+            }
+        }
+        delta
+    }
+}
+
+impl<K: Object, V: Object> Default for MapDelta<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
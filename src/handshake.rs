@@ -0,0 +1,185 @@
+//! Verification that the parent and the child are compatible.
+//!
+//! The function a child runs is sent to it in a form that is only meaningful to the very same
+//! build of the program, e.g. function pointers are transferred as offsets. If the child executes
+//! a different build, deserializing the function produces garbage instead of an error. To catch
+//! this early, the serialized function is prefixed with a header:
+//!
+//! - 8 bytes: magic number,
+//! - 4 bytes: protocol version,
+//! - 4 bytes: flags,
+//! - 8 bytes: build ID of the parent, see `relocation::build_id`,
+//! - 8 bytes: reading of the system-wide monotonic clock in the parent, in nanoseconds,
+//! - 8 bytes: wall-clock time that the parent maps the moment of the reading to, in nanoseconds
+//!   since the Unix epoch,
+//! - 4 bytes + UTF-8 string: name of the type the child returns, for diagnostics.
+//!
+//! The first three fields are never going to change, so that mismatching versions are always
+//! reported as such. All integers are little-endian.
+//!
+//! The clock reading and the time let the child derive the mapping between
+//! [`Instant`](std::time::Instant) and wall-clock time from that of the parent, see
+//! [`MonotonicInstant`](crate::MonotonicInstant). Both are zero if the parent cannot provide them,
+//! in which case the child captures its own mapping.
+//!
+//! The child compares the build ID to its own before deserializing the function. If
+//! [`SpawnOptions::verify_build`](crate::SpawnOptions::verify_build) is enabled, the child sends the
+//! result back as a `Result<(), String>`, and the parent waits for it. Otherwise, the child reports
+//! a mismatch on stderr and exits.
+
+use crate::relocation::build_id;
+use crate::{
+    handles::{FromRawHandle, RawHandle},
+    Sender,
+};
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: [u8; 8] = *b"crossmst";
+const PROTOCOL_VERSION: u32 = 2;
+const FLAG_REPLY: u32 = 1;
+
+/// Build the header for a child returning `type_name`.
+pub(crate) fn header(reply: bool, type_name: &str) -> Vec<u8> {
+    let (clock, time) = crate::time::reference_for_child()
+        .and_then(|(clock, time)| {
+            let clock = u64::try_from(clock.as_nanos()).ok()?;
+            let time = u64::try_from(time.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()?;
+            Some((clock, time))
+        })
+        .unwrap_or((0, 0));
+    let mut header = Vec::with_capacity(44 + type_name.len());
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    header.extend_from_slice(&(if reply { FLAG_REPLY } else { 0 }).to_le_bytes());
+    header.extend_from_slice(&build_id().to_le_bytes());
+    header.extend_from_slice(&clock.to_le_bytes());
+    header.extend_from_slice(&time.to_le_bytes());
+    header.extend_from_slice(&(type_name.len() as u32).to_le_bytes());
+    header.extend_from_slice(type_name.as_bytes());
+    header
+}
+
+struct Header<'a> {
+    reply: bool,
+    build_id: u64,
+    reference: Option<(Duration, SystemTime)>,
+    type_name: &'a str,
+    len: usize,
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (head, tail) = data.split_at_checked(len)?;
+    *data = tail;
+    Some(head)
+}
+
+fn take_u32(data: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(data, 4)?.try_into().unwrap()))
+}
+
+fn take_u64(data: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(take(data, 8)?.try_into().unwrap()))
+}
+
+fn parse_header(data: &[u8]) -> std::result::Result<Header<'_>, (bool, String)> {
+    let mut rest = data;
+    if take(&mut rest, 8) != Some(&MAGIC[..]) {
+        return Err((
+            false,
+            "the parent is not a crossmist program, or uses an incompatible version of crossmist"
+                .to_string(),
+        ));
+    }
+    let malformed = |reply| (reply, "the handshake is malformed".to_string());
+    let version = take_u32(&mut rest).ok_or(malformed(false))?;
+    let reply = take_u32(&mut rest).ok_or(malformed(false))? & FLAG_REPLY != 0;
+    if version != PROTOCOL_VERSION {
+        return Err((
+            reply,
+            format!(
+                "the parent uses crossmist protocol version {version}, the child uses version \
+                 {PROTOCOL_VERSION}"
+            ),
+        ));
+    }
+    let build_id = take_u64(&mut rest).ok_or(malformed(reply))?;
+    let clock = take_u64(&mut rest).ok_or(malformed(reply))?;
+    let time = take_u64(&mut rest).ok_or(malformed(reply))?;
+    let reference = (clock != 0 || time != 0).then(|| {
+        (
+            Duration::from_nanos(clock),
+            UNIX_EPOCH + Duration::from_nanos(time),
+        )
+    });
+    let type_name_len = take_u32(&mut rest).ok_or(malformed(reply))? as usize;
+    let type_name = take(&mut rest, type_name_len)
+        .and_then(|bytes| std::str::from_utf8(bytes).ok())
+        .ok_or(malformed(reply))?;
+    Ok(Header {
+        reply,
+        build_id,
+        reference,
+        type_name,
+        len: data.len() - rest.len(),
+    })
+}
+
+/// Check the header at the beginning of `entry_data` in the child and strip it, and capture the
+/// time reference of the child.
+///
+/// If the parent has requested a reply, the result is reported via `reply_handle`. On
+/// failure, the process exits.
+pub(crate) fn accept(entry_data: &mut Vec<u8>, reply_handle: RawHandle) {
+    let (reply, result, len, reference) = match parse_header(entry_data) {
+        Ok(header) => {
+            let own = build_id();
+            // An unknown ID on either side, e.g. if /proc is not mounted, cannot be verified
+            let result = if header.build_id == own || header.build_id == 0 || own == 0 {
+                Ok(())
+            } else {
+                Err(format!(
+                    "the child executable is a different build than the parent, so it cannot run \
+                     a function returning `{}`",
+                    header.type_name,
+                ))
+            };
+            (header.reply, result, header.len, header.reference)
+        }
+        Err((reply, message)) => (reply, Err(message), 0, None),
+    };
+
+    if reply {
+        let mut reply_tx =
+            unsafe { Sender::<std::result::Result<(), String>>::from_raw_handle(reply_handle) };
+        let sent = reply_tx.send(&result);
+        // The handle is still used to send the return value
+        std::mem::forget(reply_tx);
+        sent.expect("Failed to reply to the handshake");
+    }
+
+    if let Err(message) = result {
+        if !reply {
+            eprintln!("crossmist: cannot start the child: {message}");
+        }
+        std::process::exit(1);
+    }
+
+    crate::time::capture_reference(reference);
+    entry_data.drain(..len);
+}
+
+/// Interpret the reply of the child in the parent.
+pub(crate) fn check_reply(reply: Result<Option<std::result::Result<(), String>>>) -> Result<()> {
+    match reply? {
+        Some(Ok(())) => Ok(()),
+        Some(Err(message)) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Handshake with the child failed: {message}"),
+        )),
+        None => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "The child exited during the handshake",
+        )),
+    }
+}
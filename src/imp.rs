@@ -2,8 +2,16 @@ pub use crate::pod::PlainOldData;
 
 #[cfg(feature = "smol")]
 pub use async_io;
+#[cfg(feature = "async-std")]
+pub use async_std;
+pub use futures_core;
 
-use crate::entry;
+use crate::{
+    entry,
+    handles::{FromRawHandle, RawHandle},
+    Object, Sender,
+};
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 pub static INITIALIZED: AtomicBool = AtomicBool::new(false);
@@ -29,24 +37,37 @@ impl<T: ?Sized> IdentityImpl<'_> for T {
     type Type = Self;
 }
 
+/// Add up size hints of the parts of an object, returning `None` if any of them is unknown.
+pub fn sum_size_hints(hints: &[Option<usize>]) -> Option<usize> {
+    hints
+        .iter()
+        .try_fold(0usize, |size, hint| size.checked_add((*hint)?))
+}
+
 macro_rules! implements {
     ($type:ty: $($trait:tt)*) => {{
         // Workaround for a false positive "trait is never used" warning
         #[allow(dead_code)]
         fn use_trait<T: $($trait)*>(_: T) {}
 
-        // https://stackoverflow.com/a/71721609
-        struct Probe<'a, T: ?Sized>(&'a std::cell::Cell<bool>, std::marker::PhantomData<T>);
-        impl<T: ?Sized> Clone for Probe<'_, T> {
-            fn clone(&self) -> Self {
-                self.0.set(false);
-                Self(self.0, self.1)
+        // This used to rely on arrays specializing Clone for Copy types, see
+        // https://stackoverflow.com/a/71721609, but std no longer specializes on Copy. Fuse still
+        // specializes on FusedIterator: a fused inner iterator is polled again after it returns
+        // None, while a non-fused one is dropped.
+        struct Probe<'a, T: ?Sized>(&'a std::cell::Cell<usize>, std::marker::PhantomData<T>);
+        impl<T: ?Sized> Iterator for Probe<'_, T> {
+            type Item = ();
+            fn next(&mut self) -> Option<()> {
+                self.0.set(self.0.get() + 1);
+                None
             }
         }
-        impl<T: $($trait)*> Copy for Probe<'_, T> {}
-        let cell = std::cell::Cell::new(true);
-        let _ = [Probe(&cell, std::marker::PhantomData::<$type>)].clone();
-        cell.get()
+        impl<T: ?Sized + $($trait)*> std::iter::FusedIterator for Probe<'_, T> {}
+        let cell = std::cell::Cell::new(0);
+        let mut probe = Probe(&cell, std::marker::PhantomData::<$type>).fuse();
+        probe.next();
+        probe.next();
+        cell.get() == 2
     }};
 }
 pub(crate) use implements;
@@ -88,6 +109,143 @@ pub fn if_void<T>() -> Option<T> {
 trait IsVoid {}
 impl IsVoid for () {}
 
+/// Make this process send a wrong build ID to the children it spawns.
+///
+/// This is only meant for testing the detection of incompatible children.
+pub fn corrupt_build_id() {
+    crate::relocation::corrupt_build_id();
+}
+
+/// The message a child sends to the parent via the output channel.
+///
+/// A value is only sent if the function returns, and is not sent at all if it returns `()`. A
+/// streaming function sends each item it produces as `Yielded` instead, and nothing on completion.
+/// A panic on the thread running the function is reported before the process exits. Panics that
+/// are caught by the function are reported too, so the parent only considers the last one, and
+/// only if the child fails.
+#[derive(Debug, Object)]
+pub enum Output<T: Object> {
+    Returned(T),
+    Panicked {
+        message: String,
+        location: Option<String>,
+        backtrace: Option<String>,
+    },
+    Yielded(T),
+}
+
+// Set while a message is being written to the output channel. A panic in the meantime, e.g. in
+// the serialization code, leaves the channel in the middle of a message, so it is not reported
+static SENDING_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+// Backtraces of deep recursion can be huge, and the parent might not read the output channel
+// until the child exits
+const MAX_BACKTRACE_LEN: usize = 65536;
+
+/// Report panics on the current thread to the parent via the output channel.
+///
+/// The default hook still runs afterwards, so the message and the backtrace are printed to stderr
+/// as usual. A backtrace is only sent if it is enabled via `RUST_BACKTRACE` or
+/// `RUST_LIB_BACKTRACE`.
+pub fn report_panics<T: Object>(output_tx_handle: RawHandle) {
+    // A function pointer does not capture T, so the hook is 'static even if T is not
+    let send: fn(RawHandle, String, Option<String>, Option<String>) = send_panic::<T>;
+    let thread = std::thread::current().id();
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().id() == thread && !SENDING_OUTPUT.swap(true, Ordering::AcqRel) {
+            let payload = info.payload();
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                message.to_string()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "Box<dyn Any>".to_string()
+            };
+            let backtrace = std::backtrace::Backtrace::capture();
+            let backtrace =
+                (backtrace.status() == std::backtrace::BacktraceStatus::Captured).then(|| {
+                    let mut backtrace = backtrace.to_string();
+                    if backtrace.len() > MAX_BACKTRACE_LEN {
+                        let mut len = MAX_BACKTRACE_LEN;
+                        while !backtrace.is_char_boundary(len) {
+                            len -= 1;
+                        }
+                        backtrace.truncate(len);
+                        backtrace.push_str("\n[truncated]");
+                    }
+                    backtrace
+                });
+            send(
+                output_tx_handle,
+                message,
+                info.location().map(|location| location.to_string()),
+                backtrace,
+            );
+            SENDING_OUTPUT.store(false, Ordering::Release);
+        }
+        previous(info);
+    }));
+}
+
+fn send_panic<T: Object>(
+    output_tx_handle: RawHandle,
+    message: String,
+    location: Option<String>,
+    backtrace: Option<String>,
+) {
+    let mut output_tx = unsafe { Sender::<Output<T>>::from_raw_handle(output_tx_handle) };
+    // Nothing can be done if the parent is gone
+    let _ = output_tx.send(&Output::Panicked {
+        message,
+        location,
+        backtrace,
+    });
+    // The handle is still used if the panic is caught
+    std::mem::forget(output_tx);
+}
+
+/// Send the return value of the function to the parent via the output channel.
+pub fn send_output<T: Object>(output_tx_handle: RawHandle, value: T) {
+    let mut output_tx = unsafe { Sender::<Output<T>>::from_raw_handle(output_tx_handle) };
+    // The process exits right after this, so the flag is never reset
+    SENDING_OUTPUT.store(true, Ordering::Release);
+    output_tx
+        .send(&Output::Returned(value))
+        .expect("Failed to send subprocess output");
+}
+
+/// Send the items produced by a streaming function to the parent via the output channel.
+pub fn send_items<T: Object>(output_tx_handle: RawHandle, items: impl Iterator<Item = T>) {
+    for item in items {
+        send_item(output_tx_handle, item);
+    }
+}
+
+/// Send the items produced by an asynchronous streaming function to the parent via the output
+/// channel.
+///
+/// The items are sent synchronously, so other tasks are blocked while the parent is not reading
+/// the channel.
+pub async fn send_stream<T: Object>(
+    output_tx_handle: RawHandle,
+    mut items: Pin<Box<dyn futures_core::Stream<Item = T>>>,
+) {
+    while let Some(item) = std::future::poll_fn(|cx| items.as_mut().poll_next(cx)).await {
+        send_item(output_tx_handle, item);
+    }
+}
+
+fn send_item<T: Object>(output_tx_handle: RawHandle, item: T) {
+    let mut output_tx = unsafe { Sender::<Output<T>>::from_raw_handle(output_tx_handle) };
+    SENDING_OUTPUT.store(true, Ordering::Release);
+    let sent = output_tx.send(&Output::Yielded(item));
+    SENDING_OUTPUT.store(false, Ordering::Release);
+    // The handle is still used for the next item
+    std::mem::forget(output_tx);
+    sent.expect("Failed to send an item to the parent");
+}
+
 /// Initialize the crossmist runtime.
 ///
 /// This function should always be called at the beginning of the program. It is automatically
@@ -100,13 +258,19 @@ pub fn init() {
         return;
     }
 
-    let mut args = std::env::args();
-    if let Some(s) = args.next() {
-        if s == "_crossmist_" {
-            entry::crossmist_main(args);
-        }
+    #[cfg(unix)]
+    crate::executable::capture();
+    crate::relocation::capture_executable();
+
+    // The name of the process is cosmetic, see SpawnOptions::process_name
+    let mut args = std::env::args_os();
+    let name = args.next();
+    if args.next().is_some_and(|arg| arg == "_crossmist_") {
+        entry::crossmist_main(name, args);
     }
 
+    // Children capture the reference during the handshake instead
+    crate::time::capture_reference(None);
     entry::start_root();
 }
 
@@ -135,3 +299,16 @@ macro_rules! if_smol {
 macro_rules! if_smol {
     ($($a:tt)*) => {};
 }
+
+#[cfg(feature = "async-std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! if_async_std {
+    ($($a:tt)*) => { $($a)* };
+}
+#[cfg(not(feature = "async-std"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! if_async_std {
+    ($($a:tt)*) => {};
+}
@@ -47,19 +47,39 @@
 //!
 //! For numeric types, strings, vectors, hashmaps, other common containers, and files/sockets, the
 //! [`Object`] trait is implemented automatically. For user-defined structures and enums, use
-//! `#[derive(Object)]`. You may use generics, but make sure to add `: Object` constraint to stored
-//! types:
+//! `#[derive(Object)]`. Generic types are supported as well:
 //!
 //! ```rust
 //! use crossmist::Object;
 //!
 //! #[derive(Object)]
-//! struct MyPair<T: Object, U: Object> {
+//! struct MyPair<T, U> {
 //!     first: T,
 //!     second: U,
 //! }
 //! ```
 //!
+//! Files are passed by transferring their handles: [`std::fs::File`], as well as `OwnedFd` on
+//! Unix-like systems and `OwnedHandle` on Windows, arrive as handles referring to the same open
+//! file. In particular, the file offset is shared, so writes from both processes are appended one
+//! after another rather than overwriting each other. crossmist makes sure the handles are inherited
+//! by the child during spawn and are not leaked to other processes.
+//!
+//! [`Rc`](std::rc::Rc) and [`Arc`](std::sync::Arc) pointers to the same allocation within a single
+//! message are sent once, and the receiver gets pointers sharing a single allocation too. Unlike
+//! files, however, the allocation is copied rather than shared between processes: if it is
+//! mutated via interior mutability, e.g. via an `Arc<Mutex<_>>`, the changes are not visible to
+//! the other side. Pointers sent in different messages are never deduplicated: each message
+//! produces fresh allocations. Shared strings and slices, such as `Arc<str>` and `Rc<[T]>`, are
+//! supported as well.
+//!
+//! Paths and OS strings ([`PathBuf`](std::path::PathBuf), [`OsString`](std::ffi::OsString), and
+//! [`CString`](std::ffi::CString)) are passed losslessly, including paths that are not valid
+//! Unicode. They are sent in the platform-native encoding rather than as UTF-8, so their wire format
+//! differs between platforms. The wire format of crossmist is not portable in general: both sides
+//! are expected to run the same executable on the same machine, and data serialized on one platform
+//! must not be deserialized on another.
+//!
 //! Occasionally, e.g. for custom hash tables or externally defined types, you might have to
 //! implement [`Object`] manually. Check out the documentation for [`Object`] for more information.
 //!
@@ -77,6 +97,9 @@
 //! as arguments (they wouldn't be useful otherwise), but you can pass channels across other
 //! channels, just like you can pass files across channels.
 //!
+//! Channels are created with sensible defaults. If you need to tweak the wire format, e.g. the width
+//! of length prefixes, use [`channel_with`] and [`duplex_with`], see [`options`].
+//!
 //! Channels are trusted. This means that if one side reads from [`Receiver`] and another side
 //! writes garbage to the corresponding file descriptor instead of using [`Sender`], the receiver
 //! side may crash and burn, potentially leading to arbitrary code execution.
@@ -118,6 +141,14 @@
 //! This crate provides the following features:
 //! - `tokio`: enable [Tokio](https://tokio.rs) async runtime support.
 //! - `smol`: enable [smol](https://crates.io/crates/smol) async runtime support.
+//! - `async-std`: enable [async-std](https://crates.io/crates/async-std) async runtime support.
+//! - `futures-io`: enable channels over any stream implementing the `futures-io` traits via the
+//!   `byte_stream` module, for runtimes crossmist has no module for.
+//! - `replay`: enable recording and replaying interactions with child processes via the `replay`
+//!   module.
+//! - `capture`: attach the raw bytes of messages that fail to deserialize to the returned errors,
+//!   see the `capture` module. Meant for debugging only.
+//! - `serde`: enable passing types implementing serde traits via the `SerdeObject` wrapper.
 //! - `nightly`: make use of nightly features. This enables crossmist to be more performant and
 //!   provide better API, but requires a nightly compiler to be used.
 
@@ -157,13 +188,31 @@ extern crate self as crossmist;
 ///
 /// ```ignore
 /// pub fn spawn(&self, arg1: Type1, ...) -> std::io::Result<crossmist::Child<Output>>;
+/// pub fn spawn_with(&self, options: &crossmist::SpawnOptions, arg1: Type1, ...) ->
+///     std::io::Result<crossmist::Child<Output>>;
 /// pub fn run(&self, arg1: Type1, ...) -> std::io::Result<Output>;
+/// pub fn spawn_deferred_with(&self, options: &crossmist::SpawnOptions, arg1: Type1, ...) ->
+///     std::io::Result<crossmist::PendingChild<Output>>;
+/// pub fn spawn_with_channel<S: Object, R: Object>(&self, arg1: Type1, ...) ->
+///     std::io::Result<(crossmist::Child<Output>, crossmist::Duplex<S, R>)>;
+/// pub fn spawn_with_channel_with<S: Object, R: Object>(&self, options: &crossmist::SpawnOptions,
+///     arg1: Type1, ...) -> std::io::Result<(crossmist::Child<Output>, crossmist::Duplex<S, R>)>;
+/// pub fn spawn_with_kill_handle(&self, arg1: Type1, ...) ->
+///     std::io::Result<(crossmist::Child<Output>, crossmist::KillHandle)>;
+/// pub fn spawn_with_kill_handle_with(&self, options: &crossmist::SpawnOptions, arg1: Type1, ...) ->
+///     std::io::Result<(crossmist::Child<Output>, crossmist::KillHandle)>;
 /// ```
 ///
 /// `spawn` runs the function in a subprocess and returns a [`Child`] instance which can be used to
 /// monitor the process and retrieve its return value when it finishes via [`Child::join`]. `run`
 /// combines the two operations into one, which may be useful if a new process is needed for a
-/// reason other than parallel execution.
+/// reason other than parallel execution. `spawn_with` is like `spawn`, but configures the child
+/// with [`SpawnOptions`]. `spawn_deferred_with` only creates the process and returns a
+/// [`PendingChild`], which sends the function to the process later, so that many processes can be
+/// started concurrently. `spawn_with_channel` additionally creates a bidirectional channel to the
+/// child, which the child retrieves with [`control::control_channel`]. `spawn_with_kill_handle`
+/// returns a [`KillHandle`] alongside the child, which is handy when the child is moved elsewhere,
+/// e.g. into a thread that joins it, while the handle stays with a supervisor.
 ///
 /// For example:
 ///
@@ -312,11 +361,17 @@ extern crate self as crossmist;
 /// ```ignore
 /// pub async fn spawn_tokio(&self, arg1: Type1, ...) ->
 ///     std::io::Result<crossmist::tokio::Child<Output>>;
+/// pub async fn spawn_tokio_with(&self, options: &crossmist::SpawnOptions, arg1: Type1, ...) ->
+///     std::io::Result<crossmist::tokio::Child<Output>>;
 /// pub async fn run_tokio(&self, arg1: Type1, ...) -> std::io::Result<Output>;
+/// pub fn spawn_tokio_deferred_with(&self, options: &crossmist::SpawnOptions, arg1: Type1, ...) ->
+///     std::io::Result<crossmist::tokio::PendingChild<Output>>;
 /// ```
 ///
-/// If `smol` is enabled, the functions `spawn_smol` and `run_smol` with matching signatures are
-/// generated.
+/// If `smol` is enabled, the functions `spawn_smol`, `spawn_smol_with`, `run_smol`, and
+/// `spawn_smol_deferred_with` with matching signatures are generated. Similarly, `async-std`
+/// enables `spawn_async_std`, `spawn_async_std_with`, `run_async_std`, and
+/// `spawn_async_std_deferred_with`.
 ///
 /// Additionally, the function may be `async`. In this case, you have to indicate which runtime to
 /// use as follows:
@@ -327,11 +382,15 @@ extern crate self as crossmist;
 ///
 /// #[crossmist::func(smol)]
 /// async fn example_smol() {}
+///
+/// #[crossmist::func(async_std)]
+/// async fn example_async_std() {}
 /// ```
 ///
 /// You may pass operands to forward to `tokio::main` like this:
 ///
-/// ```rust
+#[cfg_attr(feature = "tokio", doc = "```rust")]
+#[cfg_attr(not(feature = "tokio"), doc = "```ignore")]
 /// #[crossmist::func(tokio(flavor = "current_thread"))]
 /// async fn example() {}
 /// ```
@@ -340,7 +399,8 @@ extern crate self as crossmist;
 /// function is `async`: you can start a synchronous function in a child process asynchronously, or
 /// vice versa:
 ///
-/// ```rust
+#[cfg_attr(feature = "tokio", doc = "```rust")]
+#[cfg_attr(not(feature = "tokio"), doc = "```ignore")]
 /// use crossmist::{func, main};
 ///
 /// #[func]
@@ -355,7 +415,8 @@ extern crate self as crossmist;
 /// }
 /// ```
 ///
-/// ```rust
+#[cfg_attr(feature = "tokio", doc = "```rust")]
+#[cfg_attr(not(feature = "tokio"), doc = "```ignore")]
 /// use crossmist::{func, main};
 ///
 /// #[func(tokio(flavor = "current_thread"))]
@@ -368,6 +429,36 @@ extern crate self as crossmist;
 ///     assert_eq!(example.run(5, 7).unwrap(), 12);
 /// }
 /// ```
+///
+/// ## Streaming
+///
+/// A function returning `impl Iterator<Item = T>` produces a stream of values instead of a single
+/// one. Each item is sent to the parent as soon as the iterator yields it, and `spawn` and
+/// `spawn_with` return a [`ChildStream`], which is an iterator over the items:
+///
+/// ```rust
+/// use crossmist::{func, main};
+///
+/// #[func]
+/// fn squares(n: u32) -> impl Iterator<Item = u32> {
+///     (0..n).map(|i| i * i)
+/// }
+///
+/// #[main]
+/// fn main() {
+///     let items: Vec<u32> = squares.spawn(4).unwrap().map(Result::unwrap).collect();
+///     assert_eq!(items, [0, 1, 4, 9]);
+/// }
+/// ```
+///
+/// The stream ends once the iterator is exhausted and the process exits successfully. If the
+/// process fails, e.g. if the iterator panics, the error is delivered as the last item. An `async`
+/// function may similarly return `impl Stream<Item = T>`, where `Stream` is
+/// [`futures_core::Stream`]. Asynchronous streams are obtained with `spawn_tokio` and the like, see
+/// [`asynchronous::ChildStream`].
+///
+/// Only `spawn`, `spawn_with`, and their asynchronous counterparts are generated for streaming
+/// functions. The items must be `'static`.
 pub use crossmist_derive::func;
 
 /// Setup an entrypoint.
@@ -386,7 +477,8 @@ pub use crossmist_derive::func;
 /// This attribute may be mixed with other attributes, e.g. `#[tokio::main]`. In this case, this
 /// attribute should be the first in the list:
 ///
-/// ```rust
+#[cfg_attr(feature = "tokio", doc = "```rust")]
+#[cfg_attr(not(feature = "tokio"), doc = "```ignore")]
 /// #[crossmist::main]
 /// #[tokio::main(flavor = "current_thread")]
 /// async fn main() {
@@ -421,23 +513,54 @@ pub use crossmist_derive::main;
 /// struct Test(String, i32, NotObject);
 /// ```
 ///
-/// Generics are supported. In this case, to ensure that all fields implement [`Object`],
-/// constraints might be necessary:
-///
-/// This is okay:
+/// Generics are supported. The type implements [`Object`] whenever the fields that depend on
+/// generic parameters do, so constraints are not necessary:
 ///
 /// ```rust
 /// # use crossmist::Object;
 /// #[derive(Object)]
-/// struct MyPair<T: Object>(T, T);
-/// ```
+/// struct MyPair<T>(T, T);
 ///
-/// This is not okay:
+/// fn is_object<T: Object>() {}
+/// is_object::<MyPair<String>>();
+/// ```
 ///
 /// ```compile_fail
 /// # use crossmist::Object;
+/// # #[derive(Object)]
+/// # struct MyPair<T>(T, T);
+/// # fn is_object<T: Object>() {}
+/// struct NotObject;
+///
+/// is_object::<MyPair<NotObject>>();
+/// ```
+///
+/// If all fields are plain data, i.e. numbers, booleans, characters, and arrays, tuples, and derived
+/// types of those, the type is transferred by copying its bytes instead of serializing the fields
+/// one by one. No attribute is necessary for this. In particular, vectors and slices of such types
+/// are sent with a single copy, which is much faster than serializing the elements separately:
+///
+/// ```rust
+/// # use crossmist::Object;
+/// #[derive(Clone, Copy, Object)]
+/// struct Point {
+///     x: f64,
+///     y: f64,
+/// }
+///
+/// let points = vec![Point { x: 1.0, y: 2.0 }; 1000]; // Sent as a 16000-byte block
+/// ```
+///
+/// Enums are serialized as the index of the variant followed by its fields. The index takes as few
+/// bytes as possible and is omitted for enums with a single variant. Recursive types are supported:
+///
+/// ```rust
+/// # use crossmist::Object;
 /// #[derive(Object)]
-/// struct MyPair<T>(T, T);
+/// enum List<T> {
+///     Cons(T, Box<List<T>>),
+///     Nil,
+/// }
 /// ```
 pub use crossmist_derive::Object;
 
@@ -453,6 +576,7 @@ mod platform {
     #[cfg(unix)]
     pub mod unix {
         pub(crate) mod entry;
+        pub(crate) mod executable;
         pub mod handles;
         pub(crate) mod internals;
         pub(crate) mod subprocess;
@@ -471,17 +595,61 @@ pub use crate::platform::unix::*;
 #[cfg(windows)]
 pub use crate::platform::windows::*;
 
+#[cfg(feature = "async-std")]
+pub mod async_std;
 pub mod asynchronous;
+#[cfg(unix)]
+pub mod bare;
 pub mod blocking;
+#[cfg(feature = "futures-io")]
+pub mod byte_stream;
 #[cfg(feature = "smol")]
 pub mod smol;
 #[cfg(feature = "tokio")]
 pub mod tokio;
 
 #[doc(inline)]
-pub use asynchronous::KillHandle;
-pub use blocking::{channel, duplex, Child, Duplex, Receiver, Sender};
+pub use asynchronous::{
+    join_all_in_order, JoinError, KillHandle, RequestError, ResourceLimit, TryRecvError,
+};
+pub use blocking::{
+    channel, channel_with, duplex, duplex_with, ready_signal, Child, ChildStream, Duplex,
+    PendingChild, ReadyWaiter, Receiver, Sender,
+};
+
+pub mod options;
+pub use options::{ChannelOptions, Framing, Namespaces, ProcessGroup, SpawnOptions, Stdio};
+
+pub mod multiplex;
+pub use multiplex::RequestId;
+
+pub mod ready;
+pub use ready::ReadySignal;
+
+pub mod shm;
+
+pub mod time;
+pub use time::MonotonicInstant;
+
+pub mod worker;
 
+pub mod control;
+
+pub mod bridge;
+pub use bridge::{bridge_mpsc_receiver, bridge_mpsc_sender};
+
+#[cfg(feature = "replay")]
+pub mod replay;
+
+#[cfg(feature = "capture")]
+pub mod capture;
+
+#[cfg(feature = "serde")]
+pub mod serde_object;
+#[cfg(feature = "serde")]
+pub use serde_object::SerdeObject;
+
+pub(crate) mod handshake;
 pub(crate) mod relocation;
 
 mod builtins;
@@ -490,11 +658,16 @@ mod unsized_builtins;
 pub mod delayed;
 pub use delayed::Delayed;
 
+pub mod delta;
+pub use delta::{MapChange, MapDelta};
+
 pub mod fns;
 pub use fns::*;
 
 pub mod static_ref;
 pub use static_ref::StaticRef;
 
+pub mod registry;
+
 mod pod;
 pub use pod::Object;
@@ -0,0 +1,230 @@
+//! Pipelined requests over a bidirectional channel.
+//!
+//! [`Duplex::request`] allows a single outstanding request at a time. [`MultiplexedDuplex`] tags
+//! each request with a [`RequestId`], so that several tasks can wait for responses simultaneously,
+//! and the other side can respond in any order.
+//!
+//! The other side uses an ordinary duplex that receives `(RequestId, S)` and sends
+//! `(RequestId, R)`, echoing the ID of the request in the response:
+//!
+//! ```ignore
+//! #[func]
+//! fn serve(mut chan: Duplex<(RequestId, i32), (RequestId, i32)>) {
+//!     while let Some((id, value)) = chan.recv().unwrap() {
+//!         chan.send(&(id, value * 2)).unwrap();
+//!     }
+//! }
+//!
+//! let (local, remote) = duplex::<(RequestId, i32), (RequestId, i32)>()?;
+//! let child = serve.spawn_tokio(remote).await?;
+//! let local = MultiplexedDuplex::new(local.try_into()?)?;
+//! assert_eq!(local.request(5).await?, 10);
+//! ```
+
+use crate::{
+    asynchronous::{AsyncStream, Duplex, Receiver, Sender},
+    Object, RequestError,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::poll_fn;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, MutexGuard,
+};
+use std::task::{Poll, Waker};
+
+/// The ID that matches a response to its request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Object)]
+pub struct RequestId(u64);
+
+/// A side of a bidirectional channel that supports concurrent requests.
+///
+/// `S` is the type of the requests and `R` is the type of the responses.
+pub struct MultiplexedDuplex<Stream: AsyncStream, S: Object, R: Object> {
+    sender: Sender<Stream, (RequestId, S)>,
+    idle_senders: Mutex<Vec<Sender<Stream, (RequestId, S)>>>,
+    next_id: AtomicU64,
+    state: Mutex<State<Stream, R>>,
+}
+
+struct State<Stream: AsyncStream, R: Object> {
+    // None while some request is reading from the channel
+    receiver: Option<Receiver<Stream, (RequestId, R)>>,
+    pending: HashMap<RequestId, Pending<R>>,
+    failure: Option<Failure>,
+}
+
+struct Pending<R> {
+    response: Option<R>,
+    waker: Option<Waker>,
+}
+
+enum Failure {
+    PeerClosed,
+    Io(ErrorKind, String),
+}
+
+enum Step<Stream: AsyncStream, R: Object> {
+    Done(std::result::Result<R, RequestError>),
+    Read(Receiver<Stream, (RequestId, R)>),
+}
+
+impl<Stream: AsyncStream, S: Object, R: Object> MultiplexedDuplex<Stream, S, R> {
+    /// Wrap a duplex.
+    ///
+    /// The duplex should not have been used for requests before, as a response to an earlier
+    /// request would be misinterpreted.
+    pub fn new(duplex: Duplex<Stream, (RequestId, S), (RequestId, R)>) -> Result<Self> {
+        let (sender, receiver) = duplex.split()?;
+        Ok(Self {
+            sender,
+            idle_senders: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+            state: Mutex::new(State {
+                receiver: Some(receiver),
+                pending: HashMap::new(),
+                failure: None,
+            }),
+        })
+    }
+
+    /// Send a request to the other side and wait for the response to it.
+    ///
+    /// This method can be called concurrently. If the other side closes the channel before
+    /// responding, [`RequestError::PeerClosed`] is returned by all pending requests.
+    pub async fn request(&self, value: S) -> std::result::Result<R, RequestError> {
+        let id = RequestId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        self.lock().pending.insert(
+            id,
+            Pending {
+                response: None,
+                waker: None,
+            },
+        );
+        let _pending = PendingGuard { owner: self, id };
+
+        self.send(&(id, value))
+            .await
+            .map_err(RequestError::from_io)?;
+
+        loop {
+            // Wait until either the response is available or nobody is reading from the channel
+            let step = poll_fn(|cx| {
+                let mut state = self.lock();
+                let state = &mut *state;
+                let pending = state.pending.get_mut(&id).unwrap();
+                if let Some(response) = pending.response.take() {
+                    return Poll::Ready(Step::Done(Ok(response)));
+                }
+                if let Some(failure) = &state.failure {
+                    return Poll::Ready(Step::Done(Err(failure.to_error())));
+                }
+                if let Some(receiver) = state.receiver.take() {
+                    return Poll::Ready(Step::Read(receiver));
+                }
+                pending.waker = Some(cx.waker().clone());
+                Poll::Pending
+            })
+            .await;
+
+            let receiver = match step {
+                Step::Done(result) => return result,
+                Step::Read(receiver) => receiver,
+            };
+
+            // Read a single response and hand it over to the request it belongs to. The guard
+            // returns the receiver even if this future is dropped while reading.
+            let mut reader = ReaderGuard {
+                owner: self,
+                receiver: Some(receiver),
+            };
+            let result = reader.receiver.as_mut().unwrap().recv().await;
+            let mut state = self.lock();
+            match result {
+                Ok(Some((response_id, response))) => {
+                    // The request might have been cancelled
+                    if let Some(pending) = state.pending.get_mut(&response_id) {
+                        pending.response = Some(response);
+                    }
+                }
+                Ok(None) => state.failure = Some(Failure::PeerClosed),
+                Err(e) => state.failure = Some(Failure::Io(e.kind(), e.to_string())),
+            }
+        }
+    }
+
+    async fn send(&self, value: &(RequestId, S)) -> Result<()> {
+        // Messages are sent atomically even when several senders share a channel, so concurrent
+        // requests can use their own copies of the sender
+        let idle_sender = self
+            .idle_senders
+            .lock()
+            .expect("Sender pool mutex is poisoned")
+            .pop();
+        let mut sender = match idle_sender {
+            Some(sender) => sender,
+            None => self.sender.try_clone()?,
+        };
+        sender.send(value).await?;
+        self.idle_senders
+            .lock()
+            .expect("Sender pool mutex is poisoned")
+            .push(sender);
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<Stream, R>> {
+        self.state.lock().expect("Multiplexer mutex is poisoned")
+    }
+}
+
+impl<Stream: AsyncStream + fmt::Debug, S: Object, R: Object> fmt::Debug
+    for MultiplexedDuplex<Stream, S, R>
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MultiplexedDuplex")
+            .field("sender", &self.sender)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Failure {
+    fn to_error(&self) -> RequestError {
+        match self {
+            Failure::PeerClosed => RequestError::PeerClosed,
+            Failure::Io(kind, message) => RequestError::from_io(Error::new(*kind, message.clone())),
+        }
+    }
+}
+
+struct PendingGuard<'a, Stream: AsyncStream, S: Object, R: Object> {
+    owner: &'a MultiplexedDuplex<Stream, S, R>,
+    id: RequestId,
+}
+
+impl<Stream: AsyncStream, S: Object, R: Object> Drop for PendingGuard<'_, Stream, S, R> {
+    fn drop(&mut self) {
+        self.owner.lock().pending.remove(&self.id);
+    }
+}
+
+struct ReaderGuard<'a, Stream: AsyncStream, S: Object, R: Object> {
+    owner: &'a MultiplexedDuplex<Stream, S, R>,
+    receiver: Option<Receiver<Stream, (RequestId, R)>>,
+}
+
+impl<Stream: AsyncStream, S: Object, R: Object> Drop for ReaderGuard<'_, Stream, S, R> {
+    fn drop(&mut self) {
+        let mut state = self.owner.lock();
+        state.receiver = self.receiver.take();
+        // Wake everyone up: the requests that got a response return, and one of the rest continues
+        // reading
+        for pending in state.pending.values_mut() {
+            if let Some(waker) = pending.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
@@ -0,0 +1,1117 @@
+//! Configuration of channels and child processes.
+//!
+//! Most programs can use the defaults and create channels with [`channel`](crate::channel) and
+//! [`duplex`](crate::duplex). If you need to tweak the wire format, build a [`ChannelOptions`] and
+//! pass it to [`channel_with`](crate::channel_with) or [`duplex_with`](crate::duplex_with) (or
+//! their asynchronous counterparts):
+//!
+//! ```rust
+//! use crossmist::{channel_with, ChannelOptions, Framing};
+//!
+//! let options = ChannelOptions::new().framing(Framing::Varint);
+//! let (mut sender, mut receiver) = channel_with::<i32>(&options)?;
+//! sender.send(&57)?;
+//! assert_eq!(receiver.recv()?, Some(57));
+//! # std::io::Result::Ok(())
+//! ```
+//!
+//! Both ends of a channel are created at once, so they always agree on the options. The options are
+//! stored inside the channel objects and travel with them when they are passed to other processes.
+//!
+//! Similarly, child processes are configured with [`SpawnOptions`], passed to the `spawn_with`
+//! family of methods generated by [`func`](crate::func):
+//!
+//! ```rust
+//! use crossmist::{func, main, SpawnOptions};
+//! use std::time::Duration;
+//!
+//! #[func]
+//! fn compute(n: u64) -> u64 {
+//!     (0..n).sum()
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let options = SpawnOptions::new().cpu_time_limit(Some(Duration::from_secs(10)));
+//!     let child = compute.spawn_with(&options, 1000).unwrap();
+//!     assert_eq!(child.join().unwrap(), 499500);
+//! }
+//! ```
+
+#[cfg(windows)]
+use crate::handles::BorrowedHandle;
+use crate::{handles::OwnedHandle, Object};
+use std::ffi::{OsStr, OsString};
+#[cfg(unix)]
+use std::fmt;
+use std::fs::File;
+use std::io::PipeReader;
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, BorrowedFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::AsHandle;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The format of message length prefixes.
+///
+/// Length prefixes are only written to the wire on platforms where channels are byte streams, i.e.
+/// on Windows. On Unix-like systems, channels use `SOCK_SEQPACKET` sockets that preserve message
+/// boundaries, so no prefix is transmitted and this option has no effect.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Object)]
+pub enum Framing {
+    /// A fixed-width prefix: a little-endian `u64`, regardless of the width of `usize`.
+    #[default]
+    Fixed,
+    /// A variable-width LEB128 prefix, taking one byte for messages shorter than 128 bytes.
+    Varint,
+}
+
+impl Framing {
+    /// The maximum number of bytes a length prefix can occupy.
+    pub const MAX_PREFIX_LEN: usize = 10;
+
+    /// Encode a message length as a prefix.
+    pub fn encode_len(self, len: usize) -> Vec<u8> {
+        self.encode_u64(len as u64)
+    }
+
+    pub(crate) fn encode_u64(self, mut len: u64) -> Vec<u8> {
+        match self {
+            Framing::Fixed => len.to_le_bytes().to_vec(),
+            Framing::Varint => {
+                let mut prefix = Vec::with_capacity(Self::MAX_PREFIX_LEN);
+                loop {
+                    let byte = (len & 0x7f) as u8;
+                    len >>= 7;
+                    if len == 0 {
+                        prefix.push(byte);
+                        return prefix;
+                    }
+                    prefix.push(byte | 0x80);
+                }
+            }
+        }
+    }
+
+    /// Decode a length prefix from the beginning of `bytes`.
+    ///
+    /// Returns the length and the number of bytes the prefix occupies, or `None` if `bytes` does not
+    /// contain a complete prefix yet. The length is returned as `u64`, as the peer might have a wider
+    /// `usize`.
+    pub fn decode_len(self, bytes: &[u8]) -> Option<(u64, usize)> {
+        match self {
+            Framing::Fixed => {
+                let prefix = bytes.get(..8)?;
+                Some((u64::from_le_bytes(prefix.try_into().unwrap()), prefix.len()))
+            }
+            Framing::Varint => {
+                let mut len: u64 = 0;
+                for (i, byte) in bytes.iter().take(Self::MAX_PREFIX_LEN).enumerate() {
+                    len |= ((byte & 0x7f) as u64) << (7 * i);
+                    if byte & 0x80 == 0 {
+                        return Some((len, i + 1));
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+/// The default limit on the size of received messages, 64 MiB.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Options for creating a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Object)]
+pub struct ChannelOptions {
+    pub(crate) framing: Framing,
+    pub(crate) shared_memory_threshold: Option<usize>,
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) buffer_size: Option<usize>,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> Self {
+        Self {
+            framing: Framing::default(),
+            shared_memory_threshold: None,
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            buffer_size: None,
+        }
+    }
+}
+
+impl ChannelOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the format of message length prefixes.
+    ///
+    /// The default is [`Framing::Fixed`].
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Get the format of message length prefixes.
+    pub fn get_framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Transfer messages of at least `threshold` bytes via shared memory.
+    ///
+    /// Large messages are normally split into many small writes. With this option, a message that
+    /// is large enough is written to a shared memory region (a memfd on Linux and FreeBSD, a POSIX
+    /// shared memory object on other Unix-like systems, a section on Windows) instead, and only a
+    /// handle to the region is sent over the channel. The receiving side handles both kinds of
+    /// messages transparently and deserializes the message from the mapped region without copying
+    /// it first.
+    ///
+    /// The region cannot be modified once it is sent. On Linux and FreeBSD, the memfd is sealed,
+    /// and the receiver rejects regions that are not. Elsewhere, the sender only passes a read-only
+    /// descriptor or handle and drops the writable one; on Unix-like systems, the receiver also
+    /// rejects writable descriptors.
+    ///
+    /// By default, shared memory is not used.
+    pub fn shared_memory_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.shared_memory_threshold = threshold;
+        self
+    }
+
+    /// Get the minimal size of messages transferred via shared memory.
+    pub fn get_shared_memory_threshold(&self) -> Option<usize> {
+        self.shared_memory_threshold
+    }
+
+    /// Limit the size of messages the receiving side accepts.
+    ///
+    /// A corrupted stream or a misbehaving peer may announce a message of many gigabytes.
+    /// Receiving a message larger than the limit fails with
+    /// [`ErrorKind::InvalidData`](std::io::ErrorKind::InvalidData) before the message is buffered,
+    /// instead of exhausting memory. The limit applies to the serialized size of a message, as
+    /// transferred over the channel or via shared memory.
+    ///
+    /// On Unix-like systems, the rest of the oversized message is discarded, and the channel
+    /// remains usable. On Windows, the stream cannot be resynchronized, so all further receives
+    /// fail.
+    ///
+    /// The limit can also be changed for an existing receiver, e.g. via
+    /// [`Receiver::set_max_message_size`](crate::Receiver::set_max_message_size). `None` disables
+    /// the check. The default is [`DEFAULT_MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, limit: Option<usize>) -> Self {
+        self.max_message_size = limit;
+        self
+    }
+
+    /// Get the maximal size of received messages.
+    pub fn get_max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// Set the size of the kernel buffer that holds messages sent but not received yet.
+    ///
+    /// Channels are bounded by this buffer. Once it is full, sending waits until the other side
+    /// receives enough data: a synchronous [`send`](crate::Sender::send) blocks, an asynchronous
+    /// one waits without blocking the thread, and
+    /// [`poll_send_ready`](crate::asynchronous::Sender::poll_send_ready) returns
+    /// [`Poll::Pending`](std::task::Poll::Pending), which lets poll-based producers stop producing.
+    /// Messages larger than the buffer are still delivered, in parts. A smaller buffer makes
+    /// producers slow down earlier and limits the memory held by a slow receiver, while a larger
+    /// buffer lets producers run ahead of bursts.
+    ///
+    /// The bound is enforced by the kernel, so it is approximate and counted in bytes, not in
+    /// messages:
+    ///
+    /// - The buffer also holds the bookkeeping of the kernel, e.g. on Linux, a few hundred bytes
+    ///   per packet, so fewer bytes of messages fit into it than its size.
+    /// - Messages sent via shared memory, see
+    ///   [`shared_memory_threshold`](Self::shared_memory_threshold), only occupy a packet in the
+    ///   buffer, regardless of their size.
+    /// - The receiving side assembles a message in its memory as its parts arrive, so messages
+    ///   being received are held there in addition to the buffer.
+    ///
+    /// crossmist does not count messages in flight itself: a sender can be cloned and passed to
+    /// other processes, and the receiving side has no way to tell which of them should be allowed to
+    /// send more.
+    ///
+    /// `None`, the default, keeps the system default:
+    ///
+    /// - On Linux, the buffer is `net.core.wmem_default` bytes, usually 208 KiB, doubled by the
+    ///   kernel. A requested size is not doubled: it is passed to the kernel halved, so that the
+    ///   resulting buffer is of the requested size, capped by twice `net.core.wmem_max`.
+    /// - On other Unix-like systems, see `SO_SNDBUF` in the documentation of the system.
+    /// - On Windows, the pipe buffer is chosen by the system, usually 4 KiB.
+    ///
+    /// On Unix-like systems, sizes below 32 KiB are rounded up, as a packet has to fit into the
+    /// buffer.
+    pub fn buffer_size(mut self, size: Option<usize>) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Get the size of the buffer for messages in flight.
+    pub fn get_buffer_size(&self) -> Option<usize> {
+        self.buffer_size
+    }
+
+    pub(crate) fn check_message_size(&self, len: u64) -> std::io::Result<()> {
+        match self.max_message_size {
+            Some(limit) if len > limit as u64 => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Message of {len} bytes exceeds the limit of {limit} bytes"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn use_shared_memory(&self, len: usize) -> bool {
+        len > 0
+            && self
+                .shared_memory_threshold
+                .is_some_and(|threshold| len >= threshold)
+    }
+}
+
+/// The process group a child is placed in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProcessGroup {
+    /// Stay in the process group of the parent.
+    #[default]
+    Inherit,
+    /// Become the leader of a new process group.
+    ///
+    /// On Unix-like systems, this calls `setpgid(0, 0)`. On Windows, the process is created with
+    /// `CREATE_NEW_PROCESS_GROUP`, which, among other things, makes it ignore Ctrl+C sent to the
+    /// console of the parent.
+    NewGroup,
+    /// Become the leader of a new session and of a new process group in it, detaching from the
+    /// controlling terminal.
+    ///
+    /// On Unix-like systems, this calls `setsid()`. This is not supported on Windows.
+    NewSession,
+}
+
+/// A set of Linux namespaces a child is placed in, see [`SpawnOptions::unshare`].
+///
+/// Sets are combined with `|`, e.g. `Namespaces::USER | Namespaces::NET`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Namespaces(u32);
+
+impl Namespaces {
+    /// A new cgroup namespace, `CLONE_NEWCGROUP`.
+    pub const CGROUP: Self = Self(1 << 0);
+    /// A new System V IPC namespace, `CLONE_NEWIPC`.
+    pub const IPC: Self = Self(1 << 1);
+    /// A new mount namespace, `CLONE_NEWNS`.
+    pub const MOUNT: Self = Self(1 << 2);
+    /// A new network namespace, `CLONE_NEWNET`. It only contains a loopback interface, which is
+    /// down.
+    pub const NET: Self = Self(1 << 3);
+    /// A new PID namespace, `CLONE_NEWPID`. The child becomes its init process.
+    pub const PID: Self = Self(1 << 4);
+    /// A new user namespace, `CLONE_NEWUSER`. The user and the group of the parent are mapped to
+    /// root in it.
+    pub const USER: Self = Self(1 << 5);
+    /// A new UTS namespace, `CLONE_NEWUTS`, which isolates the host name.
+    pub const UTS: Self = Self(1 << 6);
+
+    /// The empty set: the child shares all namespaces with the parent.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Check whether the set is empty.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check whether the set contains all namespaces from `other`.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Namespaces {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for Namespaces {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+/// Where a standard stream of a child is connected to.
+///
+/// This mirrors [`std::process::Stdio`], see [`SpawnOptions::stdout`] and
+/// [`SpawnOptions::stderr`]. A [`File`] or any other owned handle can be converted into `Stdio` to
+/// redirect the stream to it, e.g. to a log file opened in append mode.
+#[derive(Clone, Debug, Default)]
+pub struct Stdio(StdioKind);
+
+#[derive(Clone, Debug, Default)]
+enum StdioKind {
+    #[default]
+    Inherit,
+    Null,
+    Piped,
+    Handle(Arc<OwnedHandle>),
+}
+
+impl Stdio {
+    /// Share the stream of the parent with the child.
+    pub fn inherit() -> Self {
+        Self(StdioKind::Inherit)
+    }
+
+    /// Discard the output of the child, as if it was written to `/dev/null`.
+    pub fn null() -> Self {
+        Self(StdioKind::Null)
+    }
+
+    /// Connect the stream to a pipe, the other end of which is available to the parent via
+    /// [`Child::take_stdout`](crate::Child::take_stdout) or
+    /// [`Child::take_stderr`](crate::Child::take_stderr).
+    pub fn piped() -> Self {
+        Self(StdioKind::Piped)
+    }
+
+    /// Open the end of the stream that is passed to the child. The second element is the end of
+    /// the pipe kept by the parent.
+    pub(crate) fn open(&self) -> std::io::Result<(Option<Arc<OwnedHandle>>, Option<PipeReader>)> {
+        match &self.0 {
+            StdioKind::Inherit => Ok((None, None)),
+            StdioKind::Null => {
+                #[cfg(unix)]
+                let path = "/dev/null";
+                #[cfg(windows)]
+                let path = "NUL";
+                let file = File::options().write(true).open(path)?;
+                Ok((Some(Arc::new(file.into())), None))
+            }
+            StdioKind::Piped => {
+                let (reader, writer) = std::io::pipe()?;
+                Ok((Some(Arc::new(writer.into())), Some(reader)))
+            }
+            StdioKind::Handle(handle) => Ok((Some(handle.clone()), None)),
+        }
+    }
+}
+
+impl PartialEq for Stdio {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (StdioKind::Inherit, StdioKind::Inherit)
+            | (StdioKind::Null, StdioKind::Null)
+            | (StdioKind::Piped, StdioKind::Piped) => true,
+            (StdioKind::Handle(a), StdioKind::Handle(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Stdio {}
+
+impl From<File> for Stdio {
+    fn from(file: File) -> Self {
+        Self(StdioKind::Handle(Arc::new(file.into())))
+    }
+}
+
+impl From<OwnedHandle> for Stdio {
+    fn from(handle: OwnedHandle) -> Self {
+        Self(StdioKind::Handle(Arc::new(handle)))
+    }
+}
+
+/// Options for spawning a child process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpawnOptions {
+    pub(crate) cpu_affinity: Option<Vec<usize>>,
+    pub(crate) cpu_time_limit: Option<Duration>,
+    pub(crate) current_dir: Option<PathBuf>,
+    pub(crate) env: Vec<(OsString, Option<OsString>)>,
+    pub(crate) env_clear: bool,
+    pub(crate) executable: Option<PathBuf>,
+    pub(crate) inherited: Vec<InheritedHandle>,
+    pub(crate) memory_limit: Option<u64>,
+    pub(crate) open_files_limit: Option<u64>,
+    pub(crate) oom_score_adj: Option<i32>,
+    pub(crate) posix_spawn: bool,
+    pub(crate) priority: Option<i32>,
+    pub(crate) process_group: ProcessGroup,
+    pub(crate) process_name: Option<OsString>,
+    pub(crate) stderr: Stdio,
+    pub(crate) stdout: Stdio,
+    pub(crate) unshare: Namespaces,
+    pub(crate) verify_build: bool,
+    #[cfg(unix)]
+    pub(crate) pre_exec: PreExec,
+}
+
+impl Default for SpawnOptions {
+    fn default() -> Self {
+        Self {
+            cpu_affinity: None,
+            cpu_time_limit: None,
+            current_dir: None,
+            env: Vec::new(),
+            env_clear: false,
+            executable: None,
+            inherited: Vec::new(),
+            memory_limit: None,
+            open_files_limit: None,
+            oom_score_adj: None,
+            posix_spawn: false,
+            priority: None,
+            process_group: ProcessGroup::Inherit,
+            process_name: None,
+            stderr: Stdio::inherit(),
+            stdout: Stdio::inherit(),
+            unshare: Namespaces::empty(),
+            verify_build: true,
+            #[cfg(unix)]
+            pre_exec: PreExec::default(),
+        }
+    }
+}
+
+/// A handle passed to the child explicitly, compared by identity.
+#[derive(Clone, Debug)]
+pub(crate) struct InheritedHandle {
+    pub(crate) handle: Arc<OwnedHandle>,
+    // The descriptor the handle is duplicated onto in the child
+    #[cfg(unix)]
+    pub(crate) target: RawFd,
+}
+
+impl PartialEq for InheritedHandle {
+    fn eq(&self, other: &Self) -> bool {
+        #[cfg(unix)]
+        if self.target != other.target {
+            return false;
+        }
+        Arc::ptr_eq(&self.handle, &other.handle)
+    }
+}
+
+impl Eq for InheritedHandle {}
+
+#[cfg(unix)]
+fn env_key_eq(a: &OsStr, b: &OsStr) -> bool {
+    a == b
+}
+
+#[cfg(windows)]
+fn env_key_eq(a: &OsStr, b: &OsStr) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+#[cfg(unix)]
+pub(crate) type PreExecCallback = dyn Fn() -> std::io::Result<()> + Send + Sync;
+
+/// Callbacks run in the child between fork and exec, compared by identity.
+#[cfg(unix)]
+#[derive(Clone, Default)]
+pub(crate) struct PreExec(pub(crate) Vec<Arc<PreExecCallback>>);
+
+#[cfg(unix)]
+impl fmt::Debug for PreExec {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_list()
+            .entries(self.0.iter().map(|_| format_args!("<callback>")))
+            .finish()
+    }
+}
+
+#[cfg(unix)]
+impl PartialEq for PreExec {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(&other.0)
+                .all(|(a, b)| std::ptr::addr_eq(Arc::as_ptr(a), Arc::as_ptr(b)))
+    }
+}
+
+#[cfg(unix)]
+impl Eq for PreExec {}
+
+impl SpawnOptions {
+    /// Create the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restrict the child to run on the given CPUs.
+    ///
+    /// CPUs are identified by their indices, as in `/proc/cpuinfo` or Task Manager. On Linux, this
+    /// sets the affinity with `sched_setaffinity` before the child executes the binary, or right
+    /// after with [`posix_spawn`](Self::posix_spawn). On Windows, the process is created without
+    /// inheriting the affinity of the parent, and `SetProcessAffinityMask` is applied before it
+    /// starts running, so only the CPUs of the current processor group, i.e. the first 64, can be
+    /// used. Other platforms are not supported.
+    ///
+    /// The affinity is inherited by the processes and threads the child spawns. Spawning fails
+    /// with [`ErrorKind::InvalidInput`](std::io::ErrorKind::InvalidInput) if the list is empty or
+    /// one of the CPUs does not exist, and with the error reported by the OS if none of the CPUs
+    /// are available to the child. The affinity the child actually runs with can be queried with
+    /// [`Child::get_cpu_affinity`](crate::Child::get_cpu_affinity).
+    ///
+    /// By default, the child inherits the affinity of the parent.
+    pub fn cpu_affinity(mut self, cpus: Option<Vec<usize>>) -> Self {
+        self.cpu_affinity = cpus;
+        self
+    }
+
+    /// Get the CPUs the child is restricted to.
+    pub fn get_cpu_affinity(&self) -> Option<&[usize]> {
+        self.cpu_affinity.as_deref()
+    }
+
+    /// Limit the CPU time the child may consume.
+    ///
+    /// On Unix-like systems, this sets `RLIMIT_CPU`, rounded up to whole seconds. Once the limit is
+    /// exceeded, the child receives `SIGXCPU`, which terminates it unless handled. If the child
+    /// handles or ignores the signal, the kernel kills it with `SIGKILL` after one more second of
+    /// CPU time. The limit cannot exceed the hard limit of the parent.
+    ///
+    /// On Windows, the child is assigned to a job object limiting its user-mode time and is
+    /// terminated once the limit is exceeded.
+    ///
+    /// [`Child::join`](crate::Child::join) reports a child terminated for exceeding the limit with
+    /// [`JoinError::LimitExceeded`](crate::JoinError::LimitExceeded) on Linux and Windows. Other
+    /// Unix-like systems cannot confirm that the limit was reached, so the child is reported as
+    /// killed by the signal. The limit is inherited by the processes the child spawns, but is
+    /// counted separately for each of them.
+    ///
+    /// By default, the CPU time is not limited.
+    pub fn cpu_time_limit(mut self, limit: Option<Duration>) -> Self {
+        self.cpu_time_limit = limit;
+        self
+    }
+
+    /// Get the CPU time limit.
+    pub fn get_cpu_time_limit(&self) -> Option<Duration> {
+        self.cpu_time_limit
+    }
+
+    /// Limit the memory the child may use, in bytes.
+    ///
+    /// On Unix-like systems, this sets `RLIMIT_AS`, which limits the size of the virtual address
+    /// space of the child, including reserved but unused memory, mapped files and thread stacks. Once
+    /// the limit is reached, allocations fail, which usually makes a Rust program abort. Keep in
+    /// mind that the child needs a few dozen megabytes of address space just to start. macOS accepts
+    /// `RLIMIT_AS` but does not enforce it.
+    ///
+    /// On Windows, the child is assigned to a job object limiting the memory it commits.
+    ///
+    /// On Windows, if the child fails after reaching the limit, [`Child::join`](crate::Child::join)
+    /// returns [`JoinError::LimitExceeded`](crate::JoinError::LimitExceeded). On Unix-like systems,
+    /// a failed allocation is not recorded anywhere, so a child aborting because of it cannot be
+    /// told apart from one aborting for another reason, and is reported with
+    /// [`JoinError::KilledBySignal`](crate::JoinError::KilledBySignal).
+    ///
+    /// In both cases, the limit cannot exceed the one of the parent, and is inherited by the
+    /// processes the child spawns, but is counted separately for each of them. By default, the
+    /// memory is not limited.
+    pub fn memory_limit(mut self, limit: Option<u64>) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Get the memory limit.
+    pub fn get_memory_limit(&self) -> Option<u64> {
+        self.memory_limit
+    }
+
+    /// Limit the number of files the child may have open at once.
+    ///
+    /// This sets `RLIMIT_NOFILE`, so more precisely, the child cannot create file descriptors
+    /// greater than or equal to `limit`. The descriptors inherited from the parent count as well.
+    /// Opening a file beyond the limit fails with `EMFILE`.
+    ///
+    /// This option is only supported on Unix-like systems; spawning fails with
+    /// [`ErrorKind::Unsupported`](std::io::ErrorKind::Unsupported) on Windows. By default, the
+    /// limit of the parent is inherited.
+    pub fn open_files_limit(mut self, limit: Option<u64>) -> Self {
+        self.open_files_limit = limit;
+        self
+    }
+
+    /// Get the limit on the number of open files.
+    pub fn get_open_files_limit(&self) -> Option<u64> {
+        self.open_files_limit
+    }
+
+    /// Adjust the badness score the OOM killer assigns to the child.
+    ///
+    /// The value is written to `/proc/<pid>/oom_score_adj` of the child before it starts executing
+    /// the function, and must lie in `-1000..=1000`. Positive values make the child a preferred
+    /// victim under memory pressure, e.g. for best-effort workers, so that the parent survives;
+    /// `1000` makes it the first one to be killed. Negative values protect the child, but setting
+    /// a value lower than the one of the parent requires `CAP_SYS_RESOURCE`.
+    ///
+    /// Spawning fails with [`ErrorKind::InvalidInput`](std::io::ErrorKind::InvalidInput) if the
+    /// value is out of range, and with the error reported by the kernel if the value cannot be set.
+    /// This option is only supported on Linux; spawning fails with
+    /// [`ErrorKind::Unsupported`](std::io::ErrorKind::Unsupported) on other platforms.
+    ///
+    /// By default, the child inherits the adjustment of the parent.
+    pub fn oom_score_adj(mut self, adj: Option<i32>) -> Self {
+        self.oom_score_adj = adj;
+        self
+    }
+
+    /// Get the OOM score adjustment.
+    pub fn get_oom_score_adj(&self) -> Option<i32> {
+        self.oom_score_adj
+    }
+
+    /// Set the scheduling priority of the child.
+    ///
+    /// The priority is a nice value in `-20..=19`, lower values meaning higher priority. On
+    /// Unix-like systems, it is set with `setpriority` before the child starts executing the
+    /// function. Lowering the priority is always allowed, but raising it above the one of the
+    /// parent usually requires privileges, e.g. `CAP_SYS_NICE` on Linux.
+    ///
+    /// On Windows, the value is mapped to a priority class, which is set with `SetPriorityClass`
+    /// before the child starts running: `-20..=-8` to `HIGH_PRIORITY_CLASS`, `-7..=-1` to
+    /// `ABOVE_NORMAL_PRIORITY_CLASS`, `0..=6` to `NORMAL_PRIORITY_CLASS`, `7..=18` to
+    /// `BELOW_NORMAL_PRIORITY_CLASS`, and `19` to `IDLE_PRIORITY_CLASS`.
+    ///
+    /// The priority is inherited by the processes and threads the child spawns. Spawning fails
+    /// with [`ErrorKind::InvalidInput`](std::io::ErrorKind::InvalidInput) if the value is out of
+    /// range, and with the error reported by the OS if the priority cannot be set.
+    ///
+    /// By default, the child inherits the priority of the parent.
+    pub fn priority(mut self, priority: Option<i32>) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Get the scheduling priority.
+    pub fn get_priority(&self) -> Option<i32> {
+        self.priority
+    }
+
+    /// Get the scheduling priority, checking that it is in range.
+    pub(crate) fn checked_priority(&self) -> std::io::Result<Option<i32>> {
+        match self.priority {
+            Some(priority) if !(-20..=19).contains(&priority) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Priority {priority} is out of range -20..=19"),
+            )),
+            priority => Ok(priority),
+        }
+    }
+
+    /// Launch the child with `posix_spawn` instead of `clone` and `execv`.
+    ///
+    /// By default, the child is started on Linux by a `vfork`-like `clone` call, and on other
+    /// Unix-like systems by `fork`, which run a small amount of code in the address space of the
+    /// parent before executing the current binary. This is fast, but is known to trip up some
+    /// sanitizers, tracers and seccomp filters. With this option, the child is started by
+    /// `posix_spawn`, which sets up the inherited file descriptors via file actions, and no
+    /// crossmist code runs between fork and exec. The CPU time, memory and open files limits are
+    /// then applied to the child right after it is spawned, before it starts executing the
+    /// function, which is only supported on Linux. Enable this option in builds instrumented with
+    /// ThreadSanitizer or run under Valgrind.
+    ///
+    /// Inheriting file descriptors relies on `posix_spawn_file_actions_adddup2` clearing
+    /// `FD_CLOEXEC` when the source and the target coincide, which requires glibc 2.29 or later. On
+    /// macOS and other Apple platforms, `posix_spawn_file_actions_addinherit_np` is used instead,
+    /// and all other file descriptors are closed in the child via `POSIX_SPAWN_CLOEXEC_DEFAULT`.
+    ///
+    /// This option has no effect on Windows. By default, `clone` is used.
+    pub fn posix_spawn(mut self, posix_spawn: bool) -> Self {
+        self.posix_spawn = posix_spawn;
+        self
+    }
+
+    /// Check whether the child is launched with `posix_spawn`.
+    pub fn get_posix_spawn(&self) -> bool {
+        self.posix_spawn
+    }
+
+    /// Place the child in a new process group or session.
+    ///
+    /// This lets the parent signal the child together with all processes it starts via
+    /// [`KillHandle::kill_group`](crate::KillHandle::kill_group), and detaches the child from job
+    /// control of the terminal, e.g. a Ctrl+C in the terminal is not delivered to the new group.
+    ///
+    /// On Unix-like systems, the group is set up in the child before it executes the binary, and
+    /// spawning only returns after that, both with and without [`posix_spawn`](Self::posix_spawn).
+    /// Thus the group already exists by the time the parent can signal it.
+    ///
+    /// Spawning fails with [`ErrorKind::Unsupported`](std::io::ErrorKind::Unsupported) if the
+    /// requested kind of group is not supported on the platform. By default, the child stays in
+    /// the group of the parent.
+    pub fn process_group(mut self, group: ProcessGroup) -> Self {
+        self.process_group = group;
+        self
+    }
+
+    /// Get the process group the child is placed in.
+    pub fn get_process_group(&self) -> ProcessGroup {
+        self.process_group
+    }
+
+    /// Report a child running a different build of the program as a spawn error.
+    ///
+    /// The function a child runs is transferred in a form that only the same build of the program
+    /// can interpret, e.g. function pointers are sent as offsets. To avoid misinterpreting it, the
+    /// parent sends an ID of its build along, and the child compares it to its own ID before
+    /// deserializing anything. The ID covers the size of the executable and the parts of it that
+    /// describe its layout, so copies of the executable are considered compatible. On Windows, the
+    /// ID is taken from the executable opened at startup, so replacing the file on disk while the
+    /// program is running is detected too.
+    ///
+    /// When this option is enabled, the child reports the result of the check back, and the
+    /// handshake (see [`PendingChild::finish`](crate::PendingChild::finish)) fails with
+    /// [`ErrorKind::InvalidData`](std::io::ErrorKind::InvalidData) on mismatch, naming the type the
+    /// function returns. This costs a round-trip to the child during spawning. When it is disabled,
+    /// the child reports a mismatch by printing a message to stderr and exiting, so it is only
+    /// noticed on [`Child::join`](crate::Child::join).
+    ///
+    /// Enabled by default.
+    pub fn verify_build(mut self, verify: bool) -> Self {
+        self.verify_build = verify;
+        self
+    }
+
+    /// Check whether a child running a different build is reported as a spawn error.
+    pub fn get_verify_build(&self) -> bool {
+        self.verify_build
+    }
+
+    /// Execute a different file in the child instead of the current executable.
+    ///
+    /// This is useful if the program is installed to several locations, or if the current
+    /// executable cannot be executed directly, e.g. because it has been replaced on disk. The file
+    /// must be a copy of the current executable: the child interprets the function it runs
+    /// according to its own layout, so a different build, even one compiled from the same
+    /// sources, cannot be used.
+    ///
+    /// A mismatching file is detected by the build check described in
+    /// [`verify_build`](Self::verify_build), which is performed regardless of that option, and
+    /// the child exits without running the function. If the file does not exist or cannot be
+    /// executed, spawning fails with the error reported by the OS.
+    ///
+    /// By default, `/proc/self/exe` is executed on Linux, the path to the executable determined at
+    /// startup on other Unix-like systems, and the file returned by `GetModuleFileNameW` on
+    /// Windows.
+    pub fn executable(mut self, path: Option<PathBuf>) -> Self {
+        self.executable = path;
+        self
+    }
+
+    /// Get the file executed in the child, if it differs from the current executable.
+    pub fn get_executable(&self) -> Option<&Path> {
+        self.executable.as_deref()
+    }
+
+    /// Set the name the child is displayed with, e.g. by `ps` or Task Manager.
+    ///
+    /// The name is passed as `argv[0]` on Unix-like systems and as the first token of the command
+    /// line on Windows. On Linux, it is also used as the thread name of the child, which `top`
+    /// and `ps -e` show, truncated to 15 bytes. The arguments crossmist uses to start the child
+    /// follow the name, so the child is recognized regardless of it.
+    ///
+    /// By default, the name of the current process, i.e. its own `argv[0]`, is used. On Windows,
+    /// the name may not contain double quotes.
+    pub fn process_name(mut self, name: Option<OsString>) -> Self {
+        self.process_name = name;
+        self
+    }
+
+    /// Get the name the child is displayed with, if it differs from the name of the current
+    /// process.
+    pub fn get_process_name(&self) -> Option<&OsStr> {
+        self.process_name.as_deref()
+    }
+
+    /// Set an environment variable in the child.
+    ///
+    /// The child starts with the environment of the parent at the time of spawning, modified by
+    /// the calls to [`env`](Self::env), [`env_remove`](Self::env_remove) and
+    /// [`env_clear`](Self::env_clear) in the order they were made. The environment of the parent
+    /// is left as is, so unlike calling [`std::env::set_var`] around spawning, this is safe to use
+    /// while other threads are running. On Windows, the names of variables are compared
+    /// case-insensitively.
+    ///
+    /// Names may not be empty or contain `=`, and neither names nor values may contain null
+    /// characters. Otherwise, spawning fails with
+    /// [`ErrorKind::InvalidInput`](std::io::ErrorKind::InvalidInput).
+    pub fn env(mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> Self {
+        self.set_env(key.as_ref(), Some(value.as_ref()));
+        self
+    }
+
+    /// Remove an environment variable from the environment of the child.
+    ///
+    /// See [`env`](Self::env) for more information.
+    pub fn env_remove(mut self, key: impl AsRef<OsStr>) -> Self {
+        self.set_env(key.as_ref(), None);
+        self
+    }
+
+    /// Start the child with an empty environment, apart from the variables set with
+    /// [`env`](Self::env) afterwards.
+    ///
+    /// See [`env`](Self::env) for more information.
+    pub fn env_clear(mut self) -> Self {
+        self.env.clear();
+        self.env_clear = true;
+        self
+    }
+
+    fn set_env(&mut self, key: &OsStr, value: Option<&OsStr>) {
+        self.env.retain(|(other, _)| !env_key_eq(other, key));
+        self.env.push((key.to_owned(), value.map(OsStr::to_owned)));
+    }
+
+    /// Get the environment variables set or removed in the child.
+    ///
+    /// Removed variables are reported with a value of `None`. Variables inherited from the parent
+    /// are not included.
+    pub fn get_envs(&self) -> impl Iterator<Item = (&OsStr, Option<&OsStr>)> {
+        self.env
+            .iter()
+            .map(|(key, value)| (key.as_os_str(), value.as_deref()))
+    }
+
+    /// Check whether the child starts with an empty environment.
+    pub fn get_env_clear(&self) -> bool {
+        self.env_clear
+    }
+
+    /// Compute the environment of the child, or `None` if it is inherited as is.
+    pub(crate) fn environment(&self) -> std::io::Result<Option<Vec<(OsString, OsString)>>> {
+        if self.env.is_empty() && !self.env_clear {
+            return Ok(None);
+        }
+        let mut vars: Vec<(OsString, OsString)> = if self.env_clear {
+            Vec::new()
+        } else {
+            std::env::vars_os().collect()
+        };
+        for (key, value) in &self.env {
+            let key_bytes = key.as_encoded_bytes();
+            if key_bytes.is_empty() || key_bytes.contains(&b'=') || key_bytes.contains(&0) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid environment variable name {key:?}"),
+                ));
+            }
+            vars.retain(|(other, _)| !env_key_eq(other, key));
+            if let Some(value) = value {
+                if value.as_encoded_bytes().contains(&0) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "The value of the environment variable {key:?} contains a null byte"
+                        ),
+                    ));
+                }
+                vars.push((key.clone(), value.clone()));
+            }
+        }
+        Ok(Some(vars))
+    }
+
+    /// Set the working directory of the child.
+    ///
+    /// The directory is changed before the child executes the binary. On Unix-like systems, a
+    /// relative path, including one passed to [`executable`](Self::executable), is resolved
+    /// relative to the working directory of the parent. If the directory does not exist, spawning
+    /// fails with the error reported by the OS. With [`posix_spawn`](Self::posix_spawn), this
+    /// option is only supported on Linux and Apple platforms.
+    ///
+    /// By default, the child inherits the working directory of the parent.
+    pub fn current_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.current_dir = dir;
+        self
+    }
+
+    /// Get the working directory of the child, if it differs from the one of the parent.
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    /// Connect the standard output of the child to a file, a pipe or `/dev/null`.
+    ///
+    /// The stream is set up before the child executes the binary, so everything the child prints
+    /// is redirected, including messages printed by crossmist itself and by the panic hook. With
+    /// [`Stdio::piped`], the reading end of the pipe is obtained via
+    /// [`Child::take_stdout`](crate::Child::take_stdout). The child blocks once the pipe is full,
+    /// so the pipe should be read before joining the child.
+    ///
+    /// By default, the child inherits the standard output of the parent.
+    pub fn stdout(mut self, stdio: Stdio) -> Self {
+        self.stdout = stdio;
+        self
+    }
+
+    /// Get where the standard output of the child is connected to.
+    pub fn get_stdout(&self) -> &Stdio {
+        &self.stdout
+    }
+
+    /// Connect the standard error of the child to a file, a pipe or `/dev/null`.
+    ///
+    /// See [`stdout`](Self::stdout) for more information. With [`Stdio::piped`], the reading end
+    /// of the pipe is obtained via [`Child::take_stderr`](crate::Child::take_stderr).
+    ///
+    /// By default, the child inherits the standard error of the parent.
+    pub fn stderr(mut self, stdio: Stdio) -> Self {
+        self.stderr = stdio;
+        self
+    }
+
+    /// Get where the standard error of the child is connected to.
+    pub fn get_stderr(&self) -> &Stdio {
+        &self.stderr
+    }
+
+    /// Pass a file descriptor to the child as descriptor `target`.
+    ///
+    /// This is meant for descriptors that the child uses without receiving them as an argument,
+    /// e.g. a log file expected at a fixed number. The descriptor is duplicated onto `target`
+    /// before the child executes the binary, and stays open in the child.
+    ///
+    /// The descriptors crossmist passes to the child itself, i.e. the channel to the parent and
+    /// the handles contained in the arguments, are moved to other numbers if they coincide with a
+    /// target, so any target is allowed. However, spawning fails with
+    /// [`ErrorKind::InvalidInput`](std::io::ErrorKind::InvalidInput) if a target is negative, is
+    /// used twice, or is the standard output or error redirected with [`stdout`](Self::stdout) or
+    /// [`stderr`](Self::stderr).
+    ///
+    /// This method is only available on Unix-like systems.
+    #[cfg(unix)]
+    pub fn inherit_fd(mut self, fd: impl Into<OwnedHandle>, target: RawFd) -> Self {
+        self.inherited.push(InheritedHandle {
+            handle: Arc::new(fd.into()),
+            target,
+        });
+        self
+    }
+
+    /// Get the descriptors passed to the child explicitly, along with their target numbers.
+    ///
+    /// This method is only available on Unix-like systems.
+    #[cfg(unix)]
+    pub fn get_inherited_fds(&self) -> impl Iterator<Item = (BorrowedFd<'_>, RawFd)> {
+        self.inherited
+            .iter()
+            .map(|inherited| (inherited.handle.as_fd(), inherited.target))
+    }
+
+    /// Pass a handle to the child.
+    ///
+    /// This is meant for handles that the child uses without receiving them as an argument. The
+    /// handle is made inheritable only for this child, and has the same value in the child as in
+    /// the parent, so its value has to be communicated to the child separately, e.g. via an
+    /// environment variable.
+    ///
+    /// This method is only available on Windows.
+    #[cfg(windows)]
+    pub fn inherit_handle(mut self, handle: impl Into<OwnedHandle>) -> Self {
+        self.inherited.push(InheritedHandle {
+            handle: Arc::new(handle.into()),
+        });
+        self
+    }
+
+    /// Get the handles passed to the child explicitly.
+    ///
+    /// This method is only available on Windows.
+    #[cfg(windows)]
+    pub fn get_inherited_handles(&self) -> impl Iterator<Item = BorrowedHandle<'_>> {
+        self.inherited
+            .iter()
+            .map(|inherited| inherited.handle.as_handle())
+    }
+
+    /// Place the child in new Linux namespaces, isolating it from the parent.
+    ///
+    /// The namespaces are created by the `clone` call that starts the child. This is the same
+    /// `clone(CLONE_VM | CLONE_VFORK)` as usual: the parent stays suspended until the child
+    /// executes the binary, so the child sets up the namespaces itself. With
+    /// [`Namespaces::USER`], it maps the user and the group of the parent to root in the new
+    /// namespace by writing `/proc/self/uid_map` and `/proc/self/gid_map`, after disabling
+    /// `setgroups`. This works without privileges if the system allows unprivileged user
+    /// namespaces, and also grants the privileges necessary to create the other namespaces.
+    ///
+    /// Further setup, e.g. mounting file systems in a new mount namespace, can be performed with
+    /// [`pre_exec`](Self::pre_exec), which runs inside the new namespaces, after the mappings are
+    /// written. Note that unless the mounts of the parent are private, mount events propagate
+    /// between the namespaces; use `MS_REC | MS_PRIVATE` on `/` to prevent that.
+    ///
+    /// With [`Namespaces::PID`], the child is the init process of the new namespace. The parent
+    /// still sees it under its usual PID, so [`Child::id`](crate::Child::id), killing and joining
+    /// the child work as usual. However, as an init process, the child ignores signals it does not
+    /// handle, except for `SIGKILL` and `SIGSTOP` sent from the parent namespace. In particular,
+    /// it is not terminated by `SIGXCPU` when it exceeds [`cpu_time_limit`](Self::cpu_time_limit),
+    /// but only killed after the grace period. Once it exits, all other processes in the namespace
+    /// are killed.
+    ///
+    /// This option is only supported on Linux and cannot be combined with
+    /// [`posix_spawn`](Self::posix_spawn); spawning fails with
+    /// [`ErrorKind::Unsupported`](std::io::ErrorKind::Unsupported) otherwise, and with the error
+    /// reported by the kernel if the namespaces cannot be created. By default, the child shares
+    /// all namespaces with the parent.
+    pub fn unshare(mut self, namespaces: Namespaces) -> Self {
+        self.unshare = namespaces;
+        self
+    }
+
+    /// Get the namespaces the child is placed in.
+    pub fn get_unshare(&self) -> Namespaces {
+        self.unshare
+    }
+
+    /// Schedule a closure to be run in the child right before it executes the current binary.
+    ///
+    /// This is an escape hatch for setup that crossmist does not support directly, e.g. moving the
+    /// child to a cgroup. The closure runs after the file descriptors are prepared for inheritance,
+    /// the namespaces are set up, the process group is set up, the resource limits are set, and the
+    /// working directory is changed. Multiple closures can be registered, and they run in the order
+    /// they were registered.
+    ///
+    /// If a closure returns an error, the child exits without executing the binary, and spawning
+    /// fails with that error.
+    ///
+    /// This option is only available on Unix-like systems, and cannot be combined with
+    /// [`posix_spawn`](Self::posix_spawn): spawning fails with
+    /// [`ErrorKind::Unsupported`](std::io::ErrorKind::Unsupported) in that case.
+    ///
+    /// # Safety
+    ///
+    /// The closure runs in a `vfork`-like child that shares the address space of the parent, with
+    /// the parent thread suspended and other threads of the parent still running. As with
+    /// [`CommandExt::pre_exec`](std::os::unix::process::CommandExt::pre_exec), the closure must
+    /// only call async-signal-safe functions: it must not allocate memory, take locks, or access
+    /// state that other threads may be modifying. Errors should be constructed with
+    /// [`Error::from_raw_os_error`](std::io::Error::from_raw_os_error) or
+    /// [`Error::last_os_error`](std::io::Error::last_os_error), which do not allocate. The closure
+    /// must not modify the memory of the parent, except through its own return value, and must not
+    /// unwind.
+    #[cfg(unix)]
+    pub unsafe fn pre_exec<F>(mut self, f: F) -> Self
+    where
+        F: Fn() -> std::io::Result<()> + Send + Sync + 'static,
+    {
+        self.pre_exec.0.push(Arc::new(f));
+        self
+    }
+}
@@ -3,10 +3,27 @@ use crate::{
     Deserializer, FnOnceObject, Receiver,
 };
 use rustix::io::{fcntl_setfd, FdFlags};
+use std::ffi::{OsStr, OsString};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use {std::ffi::CString, std::os::unix::ffi::OsStrExt, std::path::Path};
 
 pub(crate) fn start_root() {}
 
-pub(crate) fn crossmist_main(mut args: std::env::Args) -> ! {
+pub(crate) fn crossmist_main(name: Option<OsString>, mut args: std::env::ArgsOs) -> ! {
+    // exec names the process after the executable, e.g. "exe" for /proc/self/exe
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = name
+        .as_deref()
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| CString::new(name.as_bytes()).ok())
+    {
+        unsafe {
+            libc::prctl(libc::PR_SET_NAME, name.as_ptr());
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = name;
+
     let handle = unsafe {
         parse_handle(
             &args
@@ -20,10 +37,14 @@ pub(crate) fn crossmist_main(mut args: std::env::Args) -> ! {
     let mut entry_rx =
         unsafe { Receiver::<(Vec<u8>, Vec<RawHandle>)>::from_raw_handle(handle.as_raw_handle()) };
 
-    let (entry_data, entry_handles) = entry_rx
-        .recv()
-        .expect("Failed to read entry for crossmist")
-        .expect("No entry passed");
+    let Some((mut entry_data, entry_handles)) =
+        entry_rx.recv().expect("Failed to read entry for crossmist")
+    else {
+        // The parent has dropped a pending child without performing the handshake
+        std::process::exit(1);
+    };
+
+    crate::handshake::accept(&mut entry_data, handle.as_raw_handle());
 
     std::mem::forget(entry_rx);
 
@@ -43,8 +64,12 @@ pub(crate) fn crossmist_main(mut args: std::env::Args) -> ! {
     std::process::exit(entry.call_object_once((handle.as_raw_handle(),)))
 }
 
-unsafe fn parse_handle(s: &str) -> OwnedHandle {
-    OwnedHandle::from_raw_handle(s.parse().expect("Failed to parse fd"))
+unsafe fn parse_handle(s: &OsStr) -> OwnedHandle {
+    OwnedHandle::from_raw_handle(
+        s.to_str()
+            .and_then(|s| s.parse().ok())
+            .expect("Failed to parse fd"),
+    )
 }
 
 pub(crate) fn disable_cloexec(fd: BorrowedHandle<'_>) -> std::io::Result<()> {
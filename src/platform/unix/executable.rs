@@ -0,0 +1,42 @@
+//! Resolution of the path to the current executable.
+//!
+//! On Linux, `/proc/self/exe` always refers to the running image, even if the file has been
+//! renamed or replaced on disk, so it is used directly. Other systems have no such link, so the
+//! path is resolved once at startup, before the program has a chance to change the working
+//! directory, and cached. [`std::env::current_exe`] queries `_NSGetExecutablePath` on macOS and the
+//! `KERN_PROC_PATHNAME` sysctl on FreeBSD.
+
+use std::ffi::CStr;
+use std::io::Result;
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+static EXECUTABLE: std::sync::OnceLock<Option<std::ffi::CString>> = std::sync::OnceLock::new();
+
+/// Resolve the path to the current executable. Called by [`init`](crate::init).
+pub(crate) fn capture() {
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    EXECUTABLE.get_or_init(|| {
+        use std::os::unix::ffi::OsStringExt;
+        let path = std::env::current_exe()
+            .and_then(std::fs::canonicalize)
+            .ok()?;
+        std::ffi::CString::new(path.into_os_string().into_vec()).ok()
+    });
+}
+
+/// Get the path to the current executable.
+pub(crate) fn path() -> Result<&'static CStr> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        Ok(c"/proc/self/exe")
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        EXECUTABLE.get().and_then(Option::as_deref).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "The path to the executable could not be determined at startup",
+            )
+        })
+    }
+}
@@ -7,6 +7,11 @@
 use std::os::unix::io;
 
 pub trait FromRawHandle: io::FromRawFd {
+    /// Construct an owned object from a raw handle.
+    ///
+    /// # Safety
+    ///
+    /// The handle must be open and owned by the caller, as with [`io::FromRawFd::from_raw_fd`].
     unsafe fn from_raw_handle(handle: RawHandle) -> Self
     where
         Self: Sized,
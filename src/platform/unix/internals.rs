@@ -1,22 +1,64 @@
-use crate::{imp::implements, pod::PlainOldData, Deserializer, Object, Serializer};
+use crate::{
+    imp::implements, pod::PlainOldData, serde::Segments, shm::ReadOnlyMapping, ChannelOptions,
+    Deserializer, Object, Serializer,
+};
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+use rustix::fs::{fcntl_add_seals, fcntl_get_seals, memfd_create, MemfdFlags, SealFlags};
 use rustix::{
     cmsg_space,
+    event::{poll, PollFd, PollFlags, Timespec},
+    fs::{fstat, ftruncate},
+    io::Errno,
+    mm::{mmap, munmap, MapFlags, ProtFlags},
     net::{
         self, recvmsg, sendmsg, AddressFamily, RecvAncillaryBuffer, RecvAncillaryMessage,
-        RecvFlags, SendAncillaryBuffer, SendAncillaryMessage, SendFlags, SocketFlags, SocketType,
+        RecvFlags, ReturnFlags, SendAncillaryBuffer, SendAncillaryMessage, SendFlags, SocketFlags,
+        SocketType,
     },
 };
+use std::collections::{hash_map::RandomState, VecDeque};
+use std::hash::{BuildHasher, Hasher};
 use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Result};
 use std::marker::PhantomData;
 use std::mem::MaybeUninit;
 use std::os::unix::{
-    io::{BorrowedFd, OwnedFd},
+    io::{AsFd, BorrowedFd, OwnedFd},
     net::UnixStream,
 };
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+use std::time::{Duration, Instant};
 
 pub(crate) const MAX_PACKET_SIZE: usize = 16 * 1024;
 pub(crate) const MAX_PACKET_FDS: usize = 253; // SCM_MAX_FD
 
+// Each packet starts with a marker byte and the ID of the message the packet belongs to. Several
+// senders may share a socket, so packets of large messages can interleave; the ID lets the receiver
+// reassemble them.
+const HEADER_SIZE: usize = 9;
+const MARKER_FIRST: u8 = 2;
+const MARKER_LAST: u8 = 1;
+// A batch packet contains several complete messages, each prefixed with the length of its data and
+// the number of its fds as little-endian u32s. The fds of all messages are attached in order.
+const MARKER_BATCH: u8 = 4;
+const BATCH_ENTRY_HEADER_SIZE: usize = 8;
+// A shared memory packet contains the length of the message data as a little-endian u64. The data
+// itself is stored in a memfd, which is attached before the fds of the message.
+const MARKER_SHARED: u8 = 8;
+// A sender that dies in the middle of a message leaves its beginning behind. The receiver cannot
+// tell this from a slow sender, so it only keeps this many partial messages, dropping the oldest.
+const MAX_PARTIAL_MESSAGES: usize = 64;
+
+fn next_message_id() -> u64 {
+    // Randomize the IDs so that messages from different processes do not collide
+    static BASE: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let base = *BASE.get_or_init(|| RandomState::new().build_hasher().finish());
+    base.wrapping_add(COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
 pub(crate) fn socketpair() -> Result<(UnixStream, UnixStream)> {
     // UnixStream creates a SOCK_STREAM by default, while we need SOCK_SEQPACKET
     let (tx, rx) = net::socketpair(
@@ -28,99 +70,657 @@ pub(crate) fn socketpair() -> Result<(UnixStream, UnixStream)> {
     Ok((tx.into(), rx.into()))
 }
 
+/// Set the size of the send buffer of a socket created by [`socketpair`].
+pub(crate) fn set_buffer_size(fd: impl AsFd, size: usize) -> Result<()> {
+    // A packet larger than the buffer fails with EMSGSIZE instead of waiting for space
+    let size = size.max(2 * MAX_PACKET_SIZE);
+    // Linux doubles the requested size, which then covers the bookkeeping of the kernel as well
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let size = size / 2;
+    net::sockopt::set_socket_send_buffer_size(fd, size)?;
+    Ok(())
+}
+
+/// Read the system-wide monotonic clock. Unlike [`Instant`], its readings can be compared across
+/// processes.
+pub(crate) fn monotonic_clock() -> Option<Duration> {
+    let time = rustix::time::clock_gettime(rustix::time::ClockId::Monotonic);
+    Some(Duration::new(
+        time.tv_sec.try_into().ok()?,
+        time.tv_nsec.try_into().ok()?,
+    ))
+}
+
+/// Wait until `fd` becomes readable or hung up. Returns `Ok(false)` if `timeout` elapses first.
+pub(crate) fn poll_readable(fd: BorrowedFd<'_>, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now().checked_add(timeout);
+    loop {
+        let timeout = deadline.map(|deadline| {
+            Timespec::try_from(deadline.saturating_duration_since(Instant::now())).unwrap_or(
+                Timespec {
+                    tv_sec: i64::MAX as _,
+                    tv_nsec: 0,
+                },
+            )
+        });
+        let mut fds = [PollFd::new(&fd, PollFlags::IN)];
+        match poll(&mut fds, timeout.as_ref()) {
+            Ok(n) => return Ok(n > 0),
+            Err(Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Wait until any of `fds` becomes readable or hung up, and return the index of the first such one.
+pub(crate) fn poll_any_readable(fds: &[BorrowedFd<'_>]) -> Result<usize> {
+    let mut poll_fds: Vec<PollFd<'_>> = fds
+        .iter()
+        .map(|fd| PollFd::new(fd, PollFlags::IN))
+        .collect();
+    loop {
+        match poll(&mut poll_fds, None) {
+            Ok(_) => break,
+            Err(Errno::INTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    poll_fds
+        .iter()
+        .position(|fd| !fd.revents().is_empty())
+        .ok_or_else(|| std::io::Error::other("poll returned without any ready descriptor"))
+}
+
+/// The progress of sending a single message, which might be split into several packets.
+struct MessageProgress {
+    id: u64,
+    // The position in the data, both as an offset and as a slice index and an offset in that slice
+    data_pos: usize,
+    slice_index: usize,
+    slice_pos: usize,
+    fds_pos: usize,
+}
+
+impl MessageProgress {
+    fn new() -> Self {
+        Self {
+            id: next_message_id(),
+            data_pos: 0,
+            slice_index: 0,
+            slice_pos: 0,
+            fds_pos: 0,
+        }
+    }
+
+    /// Send the message, which consists of `data` concatenated. The slices are written directly to
+    /// the socket without copying them into a single buffer.
+    fn send(
+        &mut self,
+        socket_fd: BorrowedFd<'_>,
+        data: &[&[u8]],
+        fds: &[BorrowedFd<'_>],
+        marker: u8,
+        flags: SendFlags,
+    ) -> Result<()> {
+        let mut space = [MaybeUninit::uninit(); cmsg_space!(ScmRights(MAX_PACKET_FDS))];
+        let mut cmsg_buffer = SendAncillaryBuffer::new(&mut space);
+
+        let data_len: usize = data.iter().map(|slice| slice.len()).sum();
+
+        loop {
+            let buffer_end = data_len.min(self.data_pos + MAX_PACKET_SIZE - HEADER_SIZE);
+            let fds_end = fds.len().min(self.fds_pos + MAX_PACKET_FDS);
+
+            let is_first = self.data_pos == 0 && self.fds_pos == 0;
+            let is_last = buffer_end == data_len && fds_end == fds.len();
+
+            let mut header = [0u8; HEADER_SIZE];
+            header[0] = marker
+                | if is_first { MARKER_FIRST } else { 0 }
+                | if is_last { MARKER_LAST } else { 0 };
+            header[1..].copy_from_slice(&self.id.to_le_bytes());
+
+            cmsg_buffer.clear();
+            assert!(cmsg_buffer.push(SendAncillaryMessage::ScmRights(&fds[self.fds_pos..fds_end],)));
+
+            let mut iovecs = vec![IoSlice::new(&header)];
+            let (mut slice_index, mut slice_pos) = (self.slice_index, self.slice_pos);
+            let mut remaining = buffer_end - self.data_pos;
+            while remaining > 0 {
+                let slice = &data[slice_index][slice_pos..];
+                let n = slice.len().min(remaining);
+                iovecs.push(IoSlice::new(&slice[..n]));
+                remaining -= n;
+                slice_pos += n;
+                if slice_pos == data[slice_index].len() {
+                    slice_index += 1;
+                    slice_pos = 0;
+                }
+            }
+
+            // Packets are sent atomically, so the whole packet is written on success
+            sendmsg(socket_fd, &iovecs, &mut cmsg_buffer, flags)?;
+
+            self.data_pos = buffer_end;
+            (self.slice_index, self.slice_pos) = (slice_index, slice_pos);
+            self.fds_pos = fds_end;
+
+            if is_last {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn send_flags(blocking: bool) -> SendFlags {
+    if blocking {
+        SendFlags::empty()
+    } else {
+        SendFlags::DONTWAIT
+    }
+}
+
+// The memfd is sealed, so that the receiver can deserialize the message from it in place without
+// the sender modifying it in the meantime
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn create_shared_memory(data: &[&[u8]]) -> Result<OwnedFd> {
+    use std::{fs::File, io::Write};
+    let mut file = File::from(memfd_create(
+        "crossmist",
+        MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING,
+    )?);
+    for slice in data {
+        file.write_all(slice)?;
+    }
+    fcntl_add_seals(
+        &file,
+        SealFlags::SHRINK | SealFlags::GROW | SealFlags::WRITE | SealFlags::SEAL,
+    )?;
+    Ok(file.into())
+}
+
+// Without memfd, the object is created with shm_open. It is filled via a mapping, as not all
+// systems support writing to shared memory objects, and reopened read-only before its name is
+// unlinked, so that no writable descriptor remains once the original one is dropped.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn create_shared_memory(data: &[&[u8]]) -> Result<OwnedFd> {
+    let (fd, name) = open_shm_object()?;
+    let fill = || -> Result<OwnedFd> {
+        let len: usize = data.iter().map(|slice| slice.len()).sum();
+        ftruncate(&fd, len as u64)?;
+        if len > 0 {
+            let ptr = map_shared_buffer(&fd, len)?;
+            let mut offset = 0;
+            for slice in data {
+                unsafe {
+                    ptr.add(offset)
+                        .copy_from_nonoverlapping(slice.as_ptr(), slice.len());
+                }
+                offset += slice.len();
+            }
+            unsafe {
+                unmap_shared_buffer(ptr, len);
+            }
+        }
+        Ok(rustix::shm::open(
+            name.as_str(),
+            rustix::shm::OFlags::RDONLY,
+            rustix::fs::Mode::empty(),
+        )?)
+    };
+    let result = fill();
+    let _ = rustix::shm::unlink(name.as_str());
+    result
+}
+
+// Create a new shared memory object with a unique name, which the caller has to unlink
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn open_shm_object() -> Result<(OwnedFd, String)> {
+    use rustix::{fs::Mode, shm::OFlags};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    loop {
+        // macOS limits names to 31 bytes
+        let name = format!(
+            "/crossmist-{:x}-{:x}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        match rustix::shm::open(
+            name.as_str(),
+            OFlags::RDWR | OFlags::CREATE | OFlags::EXCL,
+            Mode::RUSR | Mode::WUSR,
+        ) {
+            Ok(fd) => return Ok((fd, name)),
+            // Left behind by a process with the same PID that has crashed
+            Err(Errno::EXIST) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Create a zero-filled shared memory object of the given size.
+pub(crate) fn create_shared_buffer(len: usize) -> Result<OwnedFd> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    let fd = memfd_create("crossmist", MemfdFlags::CLOEXEC)?;
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let fd = {
+        let (fd, name) = open_shm_object()?;
+        rustix::shm::unlink(name.as_str())?;
+        fd
+    };
+    ftruncate(&fd, len as u64)?;
+    Ok(fd)
+}
+
+/// Map the first `len` bytes of a shared memory object into memory for reading and writing.
+///
+/// `len` must not be zero.
+pub(crate) fn map_shared_buffer(fd: &OwnedFd, len: usize) -> Result<*mut u8> {
+    if (fstat(fd)?.st_size as u64) < len as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Shared memory object is too short",
+        ));
+    }
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            ProtFlags::READ | ProtFlags::WRITE,
+            MapFlags::SHARED,
+            fd,
+            0,
+        )?
+    };
+    Ok(ptr as *mut u8)
+}
+
+/// Create a shared memory object holding a copy of `data` that cannot be modified.
+pub(crate) fn create_sealed_buffer(data: &[u8]) -> Result<OwnedFd> {
+    create_shared_memory(&[data])
+}
+
+/// Map the first `len` bytes of a shared memory object into memory privately for reading.
+///
+/// `len` must not be zero.
+pub(crate) fn map_private_buffer(fd: &OwnedFd, len: usize) -> Result<*mut u8> {
+    if (fstat(fd)?.st_size as u64) < len as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Shared memory object is too short",
+        ));
+    }
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            ProtFlags::READ,
+            MapFlags::PRIVATE,
+            fd,
+            0,
+        )?
+    };
+    Ok(ptr as *mut u8)
+}
+
+/// Unmap memory mapped by [`map_shared_buffer`] or [`map_private_buffer`].
+pub(crate) unsafe fn unmap_shared_buffer(ptr: *mut u8, len: usize) {
+    let _ = munmap(ptr as *mut _, len);
+}
+
+fn read_shared_memory(mut message: RawMessage, options: &ChannelOptions) -> Result<RawMessage> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Malformed shared memory message");
+    let len = u64::from_le_bytes(message.data.as_slice().try_into().map_err(|_| invalid())?);
+    options.check_message_size(len)?;
+    if message.fds.is_empty() {
+        return Err(invalid());
+    }
+    let fd = message.fds.remove(0);
+    // Without the seals, the sender could change the data while it is being deserialized
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    if !fcntl_get_seals(&fd)?.contains(SealFlags::SHRINK | SealFlags::WRITE) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Shared memory object is not sealed",
+        ));
+    }
+    // Objects cannot be sealed here, but the sender only passes a read-only descriptor and drops
+    // the writable one
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    if rustix::fs::fcntl_getfl(&fd)? & rustix::fs::OFlags::ACCMODE != rustix::fs::OFlags::RDONLY {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Shared memory object is writable",
+        ));
+    }
+    let mapping = ReadOnlyMapping::new(fd, usize::try_from(len).map_err(|_| invalid())?)?;
+    Ok(RawMessage {
+        data: Vec::new(),
+        fds: message.fds,
+        mapped: Some(mapping),
+    })
+}
+
 pub(crate) struct SingleObjectSender<'a> {
     socket_fd: BorrowedFd<'a>,
-    bytes: &'a [u8],
+    data: Segments<'a>,
     fds: Vec<BorrowedFd<'a>>,
-    buffer: Vec<u8>,
-    data_pos: usize,
-    fds_pos: usize,
+    shared: Option<OwnedFd>,
+    progress: MessageProgress,
     flags: SendFlags,
 }
 
 impl<'a> SingleObjectSender<'a> {
-    pub(crate) fn new<T: Object>(socket_fd: BorrowedFd<'a>, value: &'a T, blocking: bool) -> Self {
-        let bytes;
-        let fds;
-        let buffer;
+    pub(crate) fn new<T: Object>(
+        socket_fd: BorrowedFd<'a>,
+        value: &'a T,
+        options: &ChannelOptions,
+        blocking: bool,
+        buffer: &mut Vec<u8>,
+    ) -> Self {
         if implements!(T: PlainOldData) {
-            bytes = unsafe {
+            let bytes = unsafe {
                 std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>())
             };
-            fds = Vec::new();
-            buffer = Vec::new();
+            Self::from_segments(
+                socket_fd,
+                Segments::borrowed(bytes),
+                Vec::new(),
+                options,
+                blocking,
+            )
         } else {
-            bytes = &[];
-            let mut s = Serializer::new();
-            s.serialize(value);
-            fds = s.drain_handles();
-            buffer = s.into_vec();
+            let mut s = Serializer::with_buffer(std::mem::take(buffer));
+            // Not Serializer::serialize: reserving space for the whole value would copy large
+            // chunks that can be sent directly from the value
+            value.serialize_self(&mut s);
+            Self::from_serializer(socket_fd, s, options, blocking)
         }
+    }
+
+    pub(crate) fn from_serializer(
+        socket_fd: BorrowedFd<'a>,
+        mut s: Serializer<'a>,
+        options: &ChannelOptions,
+        blocking: bool,
+    ) -> Self {
+        let fds = s.drain_handles();
+        Self::from_segments(socket_fd, s.into_segments(), fds, options, blocking)
+    }
+
+    fn from_segments(
+        socket_fd: BorrowedFd<'a>,
+        mut data: Segments<'a>,
+        fds: Vec<BorrowedFd<'a>>,
+        options: &ChannelOptions,
+        blocking: bool,
+    ) -> Self {
+        let len = data.len();
+        let mut shared = None;
+        if options.use_shared_memory(len) && fds.len() < MAX_PACKET_FDS {
+            // If shared memory cannot be allocated, fall back to sending the data inline
+            shared = create_shared_memory(&data.slices()).ok();
+            if shared.is_some() {
+                data = (len as u64).to_le_bytes().to_vec().into();
+            }
+        }
+
         Self {
             socket_fd,
-            bytes,
+            data,
             fds,
-            buffer,
-            data_pos: 0,
-            fds_pos: 0,
-            flags: if blocking {
-                SendFlags::empty()
-            } else {
-                SendFlags::DONTWAIT
-            },
+            shared,
+            progress: MessageProgress::new(),
+            flags: send_flags(blocking),
         }
     }
 
+    /// Take the serialization buffer to reuse it for the next message.
+    pub(crate) fn into_buffer(self) -> Vec<u8> {
+        self.data.into_buffer()
+    }
+
     pub(crate) fn send_next(&mut self) -> Result<()> {
+        if let Some(shared) = &self.shared {
+            let fds: Vec<BorrowedFd<'_>> = std::iter::once(shared.as_fd())
+                .chain(self.fds.iter().copied())
+                .collect();
+            return self.progress.send(
+                self.socket_fd,
+                &self.data.slices(),
+                &fds,
+                MARKER_SHARED,
+                self.flags,
+            );
+        }
+        self.progress.send(
+            self.socket_fd,
+            &self.data.slices(),
+            &self.fds,
+            0,
+            self.flags,
+        )
+    }
+}
+
+/// Messages that have been serialized but not sent yet.
+#[derive(Default)]
+pub(crate) struct SendQueue {
+    messages: VecDeque<QueuedMessage>,
+    progress: Option<MessageProgress>,
+}
+
+struct QueuedMessage {
+    data: Vec<u8>,
+    fds: Vec<OwnedFd>,
+    shared: bool,
+    priority: u8,
+}
+
+impl SendQueue {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Queue a message after all messages of the same or higher priority.
+    pub(crate) fn push<T: Object>(
+        &mut self,
+        value: &T,
+        options: &ChannelOptions,
+        priority: u8,
+    ) -> Result<()> {
+        let mut message = if implements!(T: PlainOldData) {
+            QueuedMessage {
+                data: unsafe {
+                    std::slice::from_raw_parts(
+                        value as *const T as *const u8,
+                        std::mem::size_of::<T>(),
+                    )
+                }
+                .to_vec(),
+                fds: Vec::new(),
+                shared: false,
+                priority,
+            }
+        } else {
+            let mut s = Serializer::new();
+            s.serialize(value);
+            // The handles are only borrowed from the value, so they have to be duplicated to
+            // outlive it
+            let fds = s
+                .drain_handles()
+                .into_iter()
+                .map(|fd| fd.try_clone_to_owned())
+                .collect::<Result<_>>()?;
+            QueuedMessage {
+                data: s.into_vec(),
+                fds,
+                shared: false,
+                priority,
+            }
+        };
+        if options.use_shared_memory(message.data.len()) && message.fds.len() < MAX_PACKET_FDS {
+            // If shared memory cannot be allocated, fall back to sending the data inline
+            if let Ok(fd) = create_shared_memory(&[&message.data]) {
+                message.data = (message.data.len() as u64).to_le_bytes().to_vec();
+                message.fds.insert(0, fd);
+                message.shared = true;
+            }
+        }
+        // The queue is sorted by decreasing priority, except that a partially sent message stays in
+        // front
+        let start = usize::from(self.progress.is_some());
+        let index = self
+            .messages
+            .iter()
+            .skip(start)
+            .position(|queued| queued.priority < priority)
+            .map_or(self.messages.len(), |index| start + index);
+        self.messages.insert(index, message);
+        Ok(())
+    }
+
+    /// Send the queued messages in order. On error, the messages that have not been sent yet stay
+    /// in the queue.
+    ///
+    /// Consecutive small messages are coalesced into batch packets.
+    pub(crate) fn send_next(&mut self, socket_fd: BorrowedFd<'_>, blocking: bool) -> Result<()> {
+        while let Some(message) = self.messages.front() {
+            if self.progress.is_none() {
+                let count = self.batch_len();
+                if count >= 2 {
+                    self.send_batch(socket_fd, count, send_flags(blocking))?;
+                    self.messages.drain(..count);
+                    continue;
+                }
+            }
+            let fds: Vec<BorrowedFd<'_>> = message.fds.iter().map(|fd| fd.as_fd()).collect();
+            self.progress
+                .get_or_insert_with(MessageProgress::new)
+                .send(
+                    socket_fd,
+                    &[&message.data],
+                    &fds,
+                    if message.shared { MARKER_SHARED } else { 0 },
+                    send_flags(blocking),
+                )?;
+            self.messages.pop_front();
+            self.progress = None;
+        }
+        Ok(())
+    }
+
+    /// The number of messages at the front of the queue that fit into a single packet together.
+    fn batch_len(&self) -> usize {
+        let mut size = HEADER_SIZE;
+        let mut n_fds = 0;
+        self.messages
+            .iter()
+            .take_while(|message| {
+                if message.shared {
+                    return false;
+                }
+                size += BATCH_ENTRY_HEADER_SIZE + message.data.len();
+                n_fds += message.fds.len();
+                size <= MAX_PACKET_SIZE && n_fds <= MAX_PACKET_FDS
+            })
+            .count()
+    }
+
+    fn send_batch(&self, socket_fd: BorrowedFd<'_>, count: usize, flags: SendFlags) -> Result<()> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0] = MARKER_FIRST | MARKER_LAST | MARKER_BATCH;
+
+        let mut body = Vec::new();
+        let mut fds = Vec::new();
+        for message in self.messages.iter().take(count) {
+            body.extend_from_slice(&(message.data.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(message.fds.len() as u32).to_le_bytes());
+            body.extend_from_slice(&message.data);
+            fds.extend(message.fds.iter().map(|fd| fd.as_fd()));
+        }
+
         let mut space = [MaybeUninit::uninit(); cmsg_space!(ScmRights(MAX_PACKET_FDS))];
         let mut cmsg_buffer = SendAncillaryBuffer::new(&mut space);
+        assert!(cmsg_buffer.push(SendAncillaryMessage::ScmRights(&fds)));
 
-        loop {
-            let buffer_end = self.data().len().min(self.data_pos + MAX_PACKET_SIZE - 1);
-            let fds_end = self.fds.len().min(self.fds_pos + MAX_PACKET_FDS);
-
-            let is_last = buffer_end == self.data().len() && fds_end == self.fds.len();
+        sendmsg(
+            socket_fd,
+            &[IoSlice::new(&header), IoSlice::new(&body)],
+            &mut cmsg_buffer,
+            flags,
+        )?;
+        Ok(())
+    }
+}
 
-            cmsg_buffer.clear();
-            assert!(cmsg_buffer.push(SendAncillaryMessage::ScmRights(
-                &self.fds[self.fds_pos..fds_end],
-            )));
+/// Messages that have been received partially, indexed by message ID, and complete messages from
+/// batches that have not been returned yet.
+///
+/// This has to outlive a single `recv` call, as packets of a message sent by one sender may be
+/// received while waiting for a message from another sender.
+#[derive(Default)]
+pub(crate) struct ReceiveState {
+    packet: Vec<u8>,
+    // Oldest first, at most MAX_PARTIAL_MESSAGES entries
+    partial: VecDeque<(u64, RawMessage)>,
+    // IDs of dropped messages whose remaining packets are to be skipped, bounded likewise
+    discarded: VecDeque<u64>,
+    ready: VecDeque<RawMessage>,
+}
 
-            let n_written = sendmsg(
-                self.socket_fd,
-                &[
-                    IoSlice::new(&[is_last as u8]),
-                    IoSlice::new(&self.data()[self.data_pos..buffer_end]),
-                ],
-                &mut cmsg_buffer,
-                self.flags,
-            )?;
+impl ReceiveState {
+    /// Whether a message can be received without reading from the socket.
+    pub(crate) fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
 
-            self.data_pos += n_written - 1;
-            self.fds_pos = fds_end;
+    fn remove_partial(&mut self, id: u64) -> Option<RawMessage> {
+        let index = self
+            .partial
+            .iter()
+            .position(|(partial_id, _)| *partial_id == id)?;
+        self.partial.remove(index).map(|(_, partial)| partial)
+    }
 
-            if is_last {
-                return Ok(());
-            }
+    /// Start receiving a message. Returns `true` if the oldest partial message had to be dropped
+    /// to make room for it.
+    fn start_partial(&mut self, id: u64) -> bool {
+        if self.partial.iter().any(|(partial_id, _)| *partial_id == id) {
+            return false;
         }
+        let evict = self.partial.len() == MAX_PARTIAL_MESSAGES;
+        if evict {
+            let (evicted_id, _) = self.partial.pop_front().unwrap();
+            self.discard(evicted_id);
+        }
+        self.partial.push_back((id, RawMessage::default()));
+        evict
     }
 
-    fn data(&self) -> &[u8] {
-        if self.bytes.is_empty() {
-            &self.buffer
-        } else {
-            self.bytes
+    fn discard(&mut self, id: u64) {
+        if self.discarded.len() == MAX_PARTIAL_MESSAGES {
+            self.discarded.pop_front();
         }
+        self.discarded.push_back(id);
     }
 }
 
+#[derive(Default)]
+pub(crate) struct RawMessage {
+    data: Vec<u8>,
+    fds: Vec<OwnedFd>,
+    // The data, if it is passed via shared memory
+    mapped: Option<ReadOnlyMapping>,
+}
+
 pub(crate) struct SingleObjectReceiver<'a, T: Object> {
     socket_fd: BorrowedFd<'a>,
-    buffer: Vec<u8>,
-    data_pos: usize,
-    value: MaybeUninit<T>,
-    fds: Vec<OwnedFd>,
+    state: &'a mut ReceiveState,
+    options: ChannelOptions,
     flags: RecvFlags,
+    // Reused for the serialized data of single-packet messages, if provided
+    buffer: Option<&'a mut Vec<u8>>,
     terminated: bool,
     marker: PhantomData<fn() -> T>,
 }
@@ -128,52 +728,74 @@ pub(crate) struct SingleObjectReceiver<'a, T: Object> {
 unsafe impl<T: Object> Send for SingleObjectReceiver<'_, T> {}
 
 impl<'a, T: Object> SingleObjectReceiver<'a, T> {
-    pub(crate) unsafe fn new(socket_fd: BorrowedFd<'a>, blocking: bool) -> Self {
+    pub(crate) unsafe fn new(
+        socket_fd: BorrowedFd<'a>,
+        state: &'a mut ReceiveState,
+        options: ChannelOptions,
+        blocking: bool,
+    ) -> Self {
         Self {
             socket_fd,
-            buffer: Vec::new(),
-            data_pos: 0,
-            value: MaybeUninit::zeroed(),
-            fds: Vec::new(),
+            state,
+            options,
             flags: if blocking {
                 RecvFlags::empty()
             } else {
                 RecvFlags::DONTWAIT
             },
+            buffer: None,
             terminated: false,
             marker: PhantomData,
         }
     }
 
+    /// Use `buffer` for the serialized data instead of allocating a new buffer for each message.
+    ///
+    /// After a message is received, `buffer` contains its serialized data.
+    pub(crate) fn with_buffer(mut self, buffer: &'a mut Vec<u8>) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
     pub(crate) fn recv_next(&mut self) -> Result<Option<T>> {
+        let Some(message) = self.recv_message()? else {
+            return Ok(None);
+        };
+        deserialize_message(message, self.buffer.as_deref_mut(), |d| unsafe {
+            d.deserialize()
+        })
+        .map(Some)
+    }
+
+    /// Deserialize a message returned by [`recv_message`](Self::recv_message) into `target`.
+    pub(crate) fn deserialize_into(&mut self, message: RawMessage, target: &mut T) -> Result<()> {
+        deserialize_message(message, self.buffer.as_deref_mut(), |d| unsafe {
+            d.deserialize_into(target)
+        })
+    }
+
+    /// Receive the next message without deserializing it.
+    pub(crate) fn recv_message(&mut self) -> Result<Option<RawMessage>> {
         assert!(
             !self.terminated,
-            "Calling recv_next after it returned Ok(Some(...)) or Err(...) is undefined behavior",
+            "Calling recv_message after it returned Ok(Some(...)) or Err(...) is undefined behavior",
         );
 
         let mut space = [MaybeUninit::uninit(); cmsg_space!(ScmRights(MAX_PACKET_FDS))];
         let mut cmsg_buffer = RecvAncillaryBuffer::new(&mut space);
 
         loop {
-            if !implements!(T: PlainOldData) {
-                self.buffer.resize(self.data_pos + MAX_PACKET_SIZE - 1, 0);
+            if let Some(message) = self.state.ready.pop_front() {
+                self.terminated = true;
+                return Ok(Some(message));
             }
 
-            let data = if implements!(T: PlainOldData) {
-                unsafe {
-                    std::slice::from_raw_parts_mut(
-                        self.value.as_mut_ptr() as *mut u8,
-                        std::mem::size_of::<T>(),
-                    )
-                }
-            } else {
-                &mut self.buffer
-            };
+            self.state.packet.resize(MAX_PACKET_SIZE - HEADER_SIZE, 0);
 
-            let mut marker = [0];
+            let mut header = [0u8; HEADER_SIZE];
             let mut iovecs = [
-                IoSliceMut::new(&mut marker),
-                IoSliceMut::new(&mut data[self.data_pos..]),
+                IoSliceMut::new(&mut header),
+                IoSliceMut::new(&mut self.state.packet),
             ];
 
             let message = recvmsg(
@@ -183,48 +805,168 @@ impl<'a, T: Object> SingleObjectReceiver<'a, T> {
                 self.flags | RecvFlags::CMSG_CLOEXEC,
             )?;
 
+            let mut fds = Vec::new();
             for cmsg in cmsg_buffer.drain() {
                 let RecvAncillaryMessage::ScmRights(rights) = cmsg else {
                     return Err(Error::other("Unexpected kind of cmsg on stream"));
                 };
-                self.fds.extend(rights);
+                fds.extend(rights);
             }
 
             if message.bytes == 0 {
-                if self.data_pos == 0 && self.fds.is_empty() {
+                if self.state.partial.is_empty() && fds.is_empty() {
                     return Ok(None);
                 } else {
                     return Err(Error::other("Unterminated data on stream"));
                 }
             }
 
-            if message.bytes == 0 {
-                return Err(Error::other("Unexpected empty message on stream"));
+            if message.bytes < HEADER_SIZE {
+                return Err(Error::other("Truncated packet header on stream"));
             }
 
-            self.data_pos += message.bytes - 1;
-            if marker[0] != 1 {
+            let id = u64::from_le_bytes(header[1..].try_into().unwrap());
+            let len = message.bytes - HEADER_SIZE;
+            // The kernel drops the fds that do not fit into the fd table of this process, and the
+            // message cannot be deserialized without them
+            let truncated = message.flags.contains(ReturnFlags::CTRUNC);
+
+            if header[0] & MARKER_BATCH != 0 {
+                if truncated {
+                    return Err(fds_truncated());
+                }
+                let state = &mut *self.state;
+                split_batch(&state.packet[..len], fds, &mut state.ready)?;
                 continue;
             }
 
-            self.terminated = true;
+            let (data, fds) = if header[0] & !MARKER_SHARED == MARKER_FIRST | MARKER_LAST {
+                if truncated {
+                    return Err(fds_truncated());
+                }
+                let data = match self.buffer {
+                    Some(ref mut buffer) => {
+                        buffer.clear();
+                        buffer.extend_from_slice(&self.state.packet[..len]);
+                        std::mem::take(*buffer)
+                    }
+                    None => self.state.packet[..len].to_vec(),
+                };
+                (data, fds)
+            } else {
+                if let Some(index) = self.state.discarded.iter().position(|&x| x == id) {
+                    if header[0] & MARKER_LAST != 0 {
+                        self.state.discarded.remove(index);
+                    }
+                    continue;
+                }
+                if truncated {
+                    self.state.remove_partial(id);
+                    if header[0] & MARKER_LAST == 0 {
+                        self.state.discard(id);
+                    }
+                    return Err(fds_truncated());
+                }
+                let evicted = header[0] & MARKER_FIRST != 0 && self.state.start_partial(id);
+                let state = &mut *self.state;
+                let (_, partial) = state
+                    .partial
+                    .iter_mut()
+                    .find(|(partial_id, _)| *partial_id == id)
+                    .ok_or_else(|| {
+                        Error::other("Received a part of a message without its beginning")
+                    })?;
+                partial.data.extend_from_slice(&state.packet[..len]);
+                partial.fds.extend(fds);
+                if let Err(e) = self.options.check_message_size(partial.data.len() as u64) {
+                    self.state.remove_partial(id);
+                    if header[0] & MARKER_LAST == 0 {
+                        self.state.discard(id);
+                    }
+                    return Err(e);
+                }
+                if evicted {
+                    return Err(Error::other(
+                        "Dropped a partially received message, as too many messages were being \
+                         received at once; its sender has likely died in the middle of it",
+                    ));
+                }
+                if header[0] & MARKER_LAST == 0 {
+                    continue;
+                }
+                let partial = self.state.remove_partial(id).unwrap();
+                (partial.data, partial.fds)
+            };
 
-            if implements!(T: PlainOldData) {
-                return Ok(Some(unsafe { self.value.assume_init_read() }));
+            self.terminated = true;
+            let mut message = RawMessage {
+                data,
+                fds,
+                mapped: None,
+            };
+            if header[0] & MARKER_SHARED != 0 {
+                message = read_shared_memory(message, &self.options)?;
             }
+            return Ok(Some(message));
+        }
+    }
+}
 
-            self.buffer.truncate(self.data_pos);
-            let buffer = std::mem::take(&mut self.buffer);
-            let fds = std::mem::take(&mut self.fds);
-            let mut d = Deserializer::new(buffer, fds);
-            return match unsafe { d.deserialize() } {
-                Ok(value) => Ok(Some(value)),
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    // Prevent this error from being interpreted as a "wait for socket" signal
-                    Err(std::io::Error::other("Unexpected blocking event"))
-                }
-                Err(e) => Err(e),
-            };
+fn fds_truncated() -> Error {
+    Error::other(
+        "File descriptors attached to the message were discarded, likely because the limit on \
+         the number of open files was reached",
+    )
+}
+
+fn deserialize_message<U>(
+    message: RawMessage,
+    buffer: Option<&mut Vec<u8>>,
+    deserialize: impl FnOnce(&mut Deserializer) -> Result<U>,
+) -> Result<U> {
+    let mut d = match message.mapped {
+        Some(mapping) => Deserializer::from_mapping(mapping, message.fds),
+        None => Deserializer::new(message.data, message.fds),
+    };
+    let result = match deserialize(&mut d) {
+        Ok(value) => Ok(value),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+            // Prevent this error from being interpreted as a "wait for socket" signal
+            Err(std::io::Error::other("Unexpected blocking event"))
+        }
+        #[cfg(feature = "capture")]
+        Err(e) => Err(crate::capture::capture(e, d.data())),
+        #[cfg(not(feature = "capture"))]
+        Err(e) => Err(e),
+    };
+    if let Some(buffer) = buffer {
+        *buffer = d.into_data();
+    }
+    result
+}
+
+fn split_batch(mut body: &[u8], fds: Vec<OwnedFd>, ready: &mut VecDeque<RawMessage>) -> Result<()> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Malformed batch on stream");
+    let mut fds = fds.into_iter();
+    while !body.is_empty() {
+        let entry_header = body.get(..BATCH_ENTRY_HEADER_SIZE).ok_or_else(invalid)?;
+        let data_len = u32::from_le_bytes(entry_header[..4].try_into().unwrap()) as usize;
+        let n_fds = u32::from_le_bytes(entry_header[4..].try_into().unwrap()) as usize;
+        body = &body[BATCH_ENTRY_HEADER_SIZE..];
+        let data = body.get(..data_len).ok_or_else(invalid)?.to_vec();
+        body = &body[data_len..];
+        let message_fds: Vec<OwnedFd> = fds.by_ref().take(n_fds).collect();
+        if message_fds.len() != n_fds {
+            return Err(invalid());
         }
+        ready.push_back(RawMessage {
+            data,
+            fds: message_fds,
+            mapped: None,
+        });
+    }
+    if fds.next().is_some() {
+        return Err(invalid());
     }
+    Ok(())
 }
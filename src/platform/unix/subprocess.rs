@@ -1,73 +1,884 @@
-use crate::{asynchronous::AsyncStream, entry, Duplex, Object};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::Namespaces;
+use crate::{
+    asynchronous::AsyncStream, entry, executable, options::PreExecCallback, Duplex, Object,
+    ProcessGroup, SpawnOptions,
+};
 use libc::{c_char, c_int, c_void};
-use rustix::process::Pid;
-use std::ffi::{CStr, CString};
-use std::io::Result;
-use std::os::unix::io::{AsRawFd, BorrowedFd};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use rustix::mm::{mmap_anonymous, mprotect, munmap, MapFlags, MprotectFlags, ProtFlags};
+use rustix::process::{Pid, Resource, Rlimit};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use rustix::thread::CpuSet;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::cell::Cell;
+use std::ffi::{CStr, CString, OsString};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Large enough for fork_child_main, including formatting an error message on failure
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const CLONE_STACK_SIZE: usize = 64 * 1024;
+
+// How long a child that handles SIGXCPU may keep running before it is killed
+const CPU_TIME_GRACE: u64 = 1;
 
 struct CloneArg<'a> {
+    executable: &'a CStr,
+    process_name: &'a CStr,
+    // Null-terminated, or None to inherit the environment of the parent
+    envp: Option<&'a [*const c_char]>,
+    current_dir: Option<&'a CStr>,
     child_fd: BorrowedFd<'a>,
     child_fd_str: &'a CStr,
     inherited_fds: &'a [BorrowedFd<'a>],
+    redirects: &'a [(BorrowedFd<'a>, c_int)],
+    process_group: ProcessGroup,
+    rlimits: &'a [(Resource, Rlimit)],
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    affinity: Option<CpuSet>,
+    // CLONE_NEW* flags, and the contents of uid_map and gid_map for a new user namespace
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    namespaces: c_int,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    id_maps: Option<(&'a [u8], &'a [u8])>,
+    pre_exec: &'a [Arc<PreExecCallback>],
+    // Set by the child on failure. The child shares memory with the parent, so this is visible to
+    // the parent once clone returns
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    error: Cell<Option<Error>>,
 }
 
+/// Start a child process.
+///
+/// `redirects` lists descriptors to be duplicated onto the given descriptors in the child, e.g. to
+/// redirect its standard output. The descriptors passed via [`SpawnOptions::inherit_fd`] are
+/// redirected as well.
+///
+/// Returns the PID and the numbers `inherited_fds` have in the child, which differ from the ones in
+/// the parent if they coincide with the targets of redirections.
 pub(crate) unsafe fn _spawn_child<S: Object, R: Object>(
     child_fd: Duplex<S, R>,
     inherited_fds: &[BorrowedFd<'_>],
-) -> Result<Pid> {
+    redirects: &[(BorrowedFd<'_>, c_int)],
+    options: &SpawnOptions,
+) -> Result<(Pid, Vec<RawFd>)> {
+    let redirects: Vec<_> = redirects
+        .iter()
+        .copied()
+        .chain(
+            options
+                .inherited
+                .iter()
+                .map(|inherited| (inherited.handle.as_fd(), inherited.target)),
+        )
+        .collect();
+    let targets = redirect_targets(&redirects)?;
+    // The descriptors the child needs are moved out of the way of the redirections beforehand, so
+    // that the child can perform the redirections in any order without overwriting one of them
+    let moved_child_fd = move_out_of(child_fd.0.fd.as_handle(), None, &targets)?;
+    let moved_inherited = inherited_fds
+        .iter()
+        .map(|&fd| move_out_of(fd, None, &targets))
+        .collect::<Result<Vec<_>>>()?;
+    let moved_redirects = redirects
+        .iter()
+        .map(|&(fd, target)| move_out_of(fd, Some(target), &targets))
+        .collect::<Result<Vec<_>>>()?;
+    let child_fd = moved_child_fd
+        .as_ref()
+        .map_or(child_fd.0.fd.as_handle(), AsFd::as_fd);
+    let inherited_fds: Vec<_> = inherited_fds
+        .iter()
+        .zip(&moved_inherited)
+        .map(|(&fd, moved)| moved.as_ref().map_or(fd, AsFd::as_fd))
+        .collect();
+    let redirects: Vec<_> = redirects
+        .iter()
+        .zip(&moved_redirects)
+        .map(|(&(fd, target), moved)| (moved.as_ref().map_or(fd, AsFd::as_fd), target))
+        .collect();
+
+    if let Some(adj) = options.oom_score_adj {
+        check_oom_score_adj(adj)?;
+    }
+    let priority = options.checked_priority()?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let affinity = options.cpu_affinity.as_deref().map(cpu_set).transpose()?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    if options.cpu_affinity.is_some() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "CPU affinity is not supported on this platform",
+        ));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let namespaces = clone_flags(options.unshare);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let id_maps = options.unshare.contains(Namespaces::USER).then(|| {
+        // Unprivileged processes can only map their own effective IDs
+        (
+            format!("0 {} 1", rustix::process::geteuid().as_raw()),
+            format!("0 {} 1", rustix::process::getegid().as_raw()),
+        )
+    });
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    if !options.unshare.is_empty() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Namespaces are only supported on Linux",
+        ));
+    }
+
     let child_fd_str = CString::new(child_fd.as_raw_fd().to_string()).unwrap();
-    let clone_arg = CloneArg {
-        child_fd: child_fd.0.fd.as_handle(),
+    let executable = match options.executable {
+        Some(ref path) => {
+            // The working directory is changed before exec, so a relative path has to be resolved
+            // in advance
+            let path = if options.current_dir.is_some() {
+                std::path::absolute(path)?
+            } else {
+                path.clone()
+            };
+            CString::new(path.into_os_string().into_vec()).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "The path to the executable contains a null byte",
+                )
+            })?
+        }
+        None => executable::path()?.to_owned(),
+    };
+    let process_name = match options.process_name {
+        Some(ref name) => CString::new(name.clone().into_vec()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "The name of the process contains a null byte",
+            )
+        })?,
+        None => std::env::args_os()
+            .next()
+            .and_then(|name| CString::new(name.into_vec()).ok())
+            .unwrap_or_default(),
+    };
+    let env = options
+        .environment()?
+        .map(|vars| env_entries(vars.into_iter()))
+        .transpose()?;
+    let envp = env.as_deref().map(null_terminated);
+    let current_dir = match options.current_dir {
+        Some(ref dir) => Some(
+            CString::new(dir.clone().into_os_string().into_vec()).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "The path to the working directory contains a null byte",
+                )
+            })?,
+        ),
+        None => None,
+    };
+    let rlimits = resource_limits(options);
+    let arg = CloneArg {
+        executable: &executable,
+        process_name: &process_name,
+        envp: envp.as_deref(),
+        current_dir: current_dir.as_deref(),
+        child_fd,
         child_fd_str: &child_fd_str,
-        inherited_fds,
+        inherited_fds: &inherited_fds,
+        redirects: &redirects,
+        process_group: options.process_group,
+        rlimits: &rlimits,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        affinity,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        namespaces,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        id_maps: id_maps
+            .as_ref()
+            .map(|(uid_map, gid_map)| (uid_map.as_bytes(), gid_map.as_bytes())),
+        pre_exec: &options.pre_exec.0,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        error: Cell::new(None),
+    };
+    let pid = if options.posix_spawn {
+        if !options.pre_exec.0.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "pre_exec callbacks cannot be used with posix_spawn",
+            ));
+        }
+        if !options.unshare.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Namespaces cannot be used with posix_spawn",
+            ));
+        }
+        posix_spawn_child(&arg)?
+    } else {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let pid = clone_child(&arg)?;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let pid = fork_child(&arg)?;
+        pid
     };
 
-    let mut stack = [0u8; 4096];
+    // The child does not run any user code until it receives the entry, so there is no race here
+    if let Some(adj) = options.oom_score_adj {
+        if let Err(e) = set_oom_score_adj(pid, adj) {
+            let _ = rustix::process::kill_process(pid, rustix::process::Signal::KILL);
+            let _ = rustix::process::waitpid(Some(pid), rustix::process::WaitOptions::empty());
+            return Err(e);
+        }
+    }
+    if let Some(priority) = priority {
+        if let Err(e) = set_priority(pid, priority) {
+            let _ = rustix::process::kill_process(pid, rustix::process::Signal::KILL);
+            let _ = rustix::process::waitpid(Some(pid), rustix::process::WaitOptions::empty());
+            return Err(e);
+        }
+    }
+
+    // The moved descriptors are closed in the parent, but the child has its own copies by now
+    Ok((pid, inherited_fds.iter().map(AsRawFd::as_raw_fd).collect()))
+}
+
+// Check that each descriptor is redirected at most once and list the targets
+fn redirect_targets(redirects: &[(BorrowedFd<'_>, c_int)]) -> Result<Vec<c_int>> {
+    let mut targets = Vec::with_capacity(redirects.len());
+    for &(_, target) in redirects {
+        if target < 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Descriptor {target} is invalid"),
+            ));
+        }
+        if targets.contains(&target) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Descriptor {target} is redirected more than once"),
+            ));
+        }
+        targets.push(target);
+    }
+    Ok(targets)
+}
+
+// Duplicate a descriptor to a number above all targets, unless its number is not among them or is
+// its own target
+fn move_out_of(
+    fd: BorrowedFd<'_>,
+    own_target: Option<c_int>,
+    targets: &[c_int],
+) -> Result<Option<OwnedFd>> {
+    let raw_fd = fd.as_raw_fd();
+    if Some(raw_fd) == own_target || !targets.contains(&raw_fd) {
+        return Ok(None);
+    }
+    let above = targets.iter().max().map_or(0, |&max| max.saturating_add(1));
+    Ok(Some(rustix::io::fcntl_dupfd_cloexec(fd, above)?))
+}
+
+/// Format environment variables as `KEY=VALUE` strings.
+fn env_entries(vars: impl Iterator<Item = (OsString, OsString)>) -> Result<Vec<CString>> {
+    vars.map(|(key, value)| {
+        let mut entry = key.into_vec();
+        entry.push(b'=');
+        entry.extend(value.into_vec());
+        CString::new(entry).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "An environment variable contains a null byte",
+            )
+        })
+    })
+    .collect()
+}
+
+fn null_terminated(strings: &[CString]) -> Vec<*const c_char> {
+    strings
+        .iter()
+        .map(|string| string.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
+}
+
+// The stack of the fork child. It is mapped separately rather than borrowed from the stack of the
+// parent so that an overflow hits a guard page instead of silently corrupting adjacent memory
+#[cfg(any(target_os = "linux", target_os = "android"))]
+struct CloneStack {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl CloneStack {
+    fn new() -> Result<Self> {
+        let guard_len = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = guard_len + CLONE_STACK_SIZE;
+        let ptr = unsafe {
+            mmap_anonymous(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::PRIVATE | MapFlags::STACK,
+            )?
+        };
+        let stack = Self { ptr, len };
+        // The stack grows down, so the guard page is at the lowest address
+        unsafe {
+            mprotect(ptr, guard_len, MprotectFlags::empty())?;
+        }
+        Ok(stack)
+    }
+
+    fn top(&self) -> *mut c_void {
+        unsafe { self.ptr.byte_add(self.len) }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for CloneStack {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn clone_child(clone_arg: &CloneArg) -> Result<Pid> {
+    // With CLONE_VFORK, clone only returns once the child has exec'd or exited, so the stack can
+    // be freed right after that
+    let stack = CloneStack::new()?;
     let result = libc::clone(
         clone_callback,
-        stack.as_mut_ptr_range().end as *mut c_void,
-        libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD,
-        &clone_arg as *const CloneArg as *mut c_void,
+        stack.top(),
+        libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD | clone_arg.namespaces,
+        clone_arg as *const CloneArg as *mut c_void,
     );
 
     if result < 0 {
-        Err(std::io::Error::last_os_error())
+        return Err(std::io::Error::last_os_error());
+    }
+    let pid = Pid::from_raw(result as i32).unwrap();
+
+    // With CLONE_VFORK, the child has either exec'd or exited by now
+    if let Some(e) = clone_arg.error.take() {
+        let _ = rustix::process::waitpid(Some(pid), rustix::process::WaitOptions::empty());
+        return Err(e);
+    }
+    Ok(pid)
+}
+
+// clone with CLONE_VM | CLONE_VFORK is Linux-specific, so other systems use a plain fork. The child
+// does not share memory with the parent, so it reports errors through a pipe that is closed on exec
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn fork_child(fork_arg: &CloneArg) -> Result<Pid> {
+    // Creating the pipe with O_CLOEXEC atomically prevents it from leaking into children forked
+    // concurrently by other threads
+    #[cfg(not(any(
+        target_vendor = "apple",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto",
+    )))]
+    let (error_rx, error_tx) = rustix::pipe::pipe_with(rustix::pipe::PipeFlags::CLOEXEC)?;
+    // These systems lack pipe2, so there is a window in which the pipe can leak, as in the standard
+    // library
+    #[cfg(any(
+        target_vendor = "apple",
+        target_os = "aix",
+        target_os = "haiku",
+        target_os = "nto",
+    ))]
+    let (error_rx, error_tx) = {
+        use std::os::unix::io::AsFd;
+        let (error_rx, error_tx) = rustix::pipe::pipe()?;
+        entry::enable_cloexec(error_rx.as_fd())?;
+        entry::enable_cloexec(error_tx.as_fd())?;
+        (error_rx, error_tx)
+    };
+
+    let result = libc::fork();
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if result == 0 {
+        // As in clone_callback, the child must not unwind or free resources of the parent
+        let error = fork_child_main(fork_arg).unwrap_err();
+        let code: c_int = error.raw_os_error().unwrap_or(libc::EINVAL);
+        libc::write(
+            error_tx.as_raw_fd(),
+            &code as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>(),
+        );
+        libc::_exit(127);
+    }
+    let pid = Pid::from_raw(result).unwrap();
+    drop(error_tx);
+
+    // The pipe is closed without any data once the child has exec'd
+    let mut code: c_int = 0;
+    let n_read = loop {
+        let n_read = libc::read(
+            error_rx.as_raw_fd(),
+            &mut code as *mut c_int as *mut c_void,
+            std::mem::size_of::<c_int>(),
+        );
+        if n_read >= 0 || std::io::Error::last_os_error().kind() != ErrorKind::Interrupted {
+            break n_read;
+        }
+    };
+    if n_read == std::mem::size_of::<c_int>() as isize {
+        let _ = rustix::process::waitpid(Some(pid), rustix::process::WaitOptions::empty());
+        return Err(Error::from_raw_os_error(code));
+    }
+    Ok(pid)
+}
+
+struct FileActions(libc::posix_spawn_file_actions_t);
+
+impl FileActions {
+    fn new() -> Result<Self> {
+        let mut actions = std::mem::MaybeUninit::uninit();
+        check_spawn_error(unsafe { libc::posix_spawn_file_actions_init(actions.as_mut_ptr()) })?;
+        Ok(Self(unsafe { actions.assume_init() }))
+    }
+
+    fn inherit(&mut self, fd: BorrowedFd<'_>) -> Result<()> {
+        let fd = fd.as_raw_fd();
+        // dup2 onto the same fd only clears FD_CLOEXEC. This is specified by POSIX.1-2024 and
+        // implemented by glibc 2.29+ and musl, but not by Apple, which provides a dedicated action
+        #[cfg(not(target_vendor = "apple"))]
+        let result = unsafe { libc::posix_spawn_file_actions_adddup2(&mut self.0, fd, fd) };
+        #[cfg(target_vendor = "apple")]
+        let result = unsafe { posix_spawn_file_actions_addinherit_np(&mut self.0, fd) };
+        check_spawn_error(result)
+    }
+
+    fn redirect(&mut self, fd: BorrowedFd<'_>, target: c_int) -> Result<()> {
+        if fd.as_raw_fd() == target {
+            return self.inherit(fd);
+        }
+        check_spawn_error(unsafe {
+            libc::posix_spawn_file_actions_adddup2(&mut self.0, fd.as_raw_fd(), target)
+        })
+    }
+
+    // The action is not standardized, but glibc 2.29+, musl and Apple provide it
+    #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+    fn chdir(&mut self, dir: &CStr) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        use libc::posix_spawn_file_actions_addchdir_np;
+        check_spawn_error(unsafe {
+            posix_spawn_file_actions_addchdir_np(&mut self.0, dir.as_ptr())
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+    fn chdir(&mut self, _dir: &CStr) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Changing the working directory is not supported with posix_spawn on this platform",
+        ))
+    }
+}
+
+// Not bound by the libc versions we support. Both are declared in <spawn.h> since macOS 10.15
+#[cfg(target_vendor = "apple")]
+extern "C" {
+    fn posix_spawn_file_actions_addinherit_np(
+        actions: *mut libc::posix_spawn_file_actions_t,
+        fd: c_int,
+    ) -> c_int;
+    fn posix_spawn_file_actions_addchdir_np(
+        actions: *mut libc::posix_spawn_file_actions_t,
+        path: *const c_char,
+    ) -> c_int;
+}
+
+impl Drop for FileActions {
+    fn drop(&mut self) {
+        unsafe {
+            libc::posix_spawn_file_actions_destroy(&mut self.0);
+        }
+    }
+}
+
+struct SpawnAttr(libc::posix_spawnattr_t);
+
+impl SpawnAttr {
+    fn new() -> Result<Self> {
+        let mut attr = std::mem::MaybeUninit::uninit();
+        check_spawn_error(unsafe { libc::posix_spawnattr_init(attr.as_mut_ptr()) })?;
+        Ok(Self(unsafe { attr.assume_init() }))
+    }
+
+    fn set_flags(&mut self, group: ProcessGroup) -> Result<()> {
+        // On Apple platforms, descriptors that are not explicitly inherited are closed in the
+        // child, so that descriptors created by other threads without FD_CLOEXEC do not leak
+        #[cfg(target_vendor = "apple")]
+        let mut flags = libc::POSIX_SPAWN_CLOEXEC_DEFAULT;
+        #[cfg(not(target_vendor = "apple"))]
+        let mut flags = 0;
+        match group {
+            ProcessGroup::Inherit => {}
+            ProcessGroup::NewGroup => {
+                // A zero group ID means a group with the same ID as the child
+                check_spawn_error(unsafe { libc::posix_spawnattr_setpgroup(&mut self.0, 0) })?;
+                flags |= libc::POSIX_SPAWN_SETPGROUP;
+            }
+            #[cfg(target_os = "linux")]
+            // Declared as c_short by newer libc versions
+            ProcessGroup::NewSession => flags |= libc::POSIX_SPAWN_SETSID as c_int,
+            #[cfg(not(target_os = "linux"))]
+            ProcessGroup::NewSession => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "New session is not supported with posix_spawn on this platform",
+                ));
+            }
+        }
+        if flags == 0 {
+            return Ok(());
+        }
+        check_spawn_error(unsafe { libc::posix_spawnattr_setflags(&mut self.0, flags as _) })
+    }
+}
+
+impl Drop for SpawnAttr {
+    fn drop(&mut self) {
+        unsafe {
+            libc::posix_spawnattr_destroy(&mut self.0);
+        }
+    }
+}
+
+fn check_spawn_error(result: c_int) -> Result<()> {
+    if result == 0 {
+        Ok(())
     } else {
-        Ok(Pid::from_raw(result as i32).unwrap())
+        Err(Error::from_raw_os_error(result))
+    }
+}
+
+fn posix_spawn_child(arg: &CloneArg) -> Result<Pid> {
+    // The limits cannot be set between fork and exec, so they are applied to the spawned process
+    if !arg.rlimits.is_empty() && !cfg!(any(target_os = "linux", target_os = "android")) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Resource limits are not supported with posix_spawn on this platform",
+        ));
+    }
+
+    let mut actions = FileActions::new()?;
+    actions.inherit(arg.child_fd)?;
+    for fd in arg.inherited_fds {
+        actions.inherit(*fd)?;
+    }
+    for &(fd, target) in arg.redirects {
+        actions.redirect(fd, target)?;
+    }
+    if let Some(dir) = arg.current_dir {
+        actions.chdir(dir)?;
+    }
+
+    // Like with clone, posix_spawn only returns once the attributes are applied and the child has
+    // exec'd, so the group exists by the time the parent can signal it
+    let mut attr = SpawnAttr::new()?;
+    attr.set_flags(arg.process_group)?;
+
+    // Going through std takes the environment lock, unlike reading environ directly
+    let inherited_env;
+    let inherited_envp;
+    let envp = match arg.envp {
+        Some(envp) => envp,
+        None => {
+            inherited_env = env_entries(std::env::vars_os())?;
+            inherited_envp = null_terminated(&inherited_env);
+            &inherited_envp
+        }
+    };
+
+    let argv = [
+        arg.process_name.as_ptr() as *mut c_char,
+        c"_crossmist_".as_ptr() as *mut c_char,
+        arg.child_fd_str.as_ptr() as *mut c_char,
+        std::ptr::null_mut(),
+    ];
+
+    let mut pid = 0;
+    check_spawn_error(unsafe {
+        libc::posix_spawn(
+            &mut pid,
+            arg.executable.as_ptr(),
+            &actions.0,
+            &attr.0,
+            argv.as_ptr(),
+            envp.as_ptr() as *const *mut c_char,
+        )
+    })?;
+    let pid = Pid::from_raw(pid).unwrap();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    for &(resource, limit) in arg.rlimits {
+        // As with the OOM score, the child is blocked until it receives the entry
+        if let Err(e) = rustix::process::prlimit(Some(pid), resource, limit) {
+            let _ = rustix::process::kill_process(pid, rustix::process::Signal::KILL);
+            let _ = rustix::process::waitpid(Some(pid), rustix::process::WaitOptions::empty());
+            return Err(e.into());
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(ref affinity) = arg.affinity {
+        // The child is single-threaded until it receives the entry, so setting the affinity of its
+        // main thread suffices
+        if let Err(e) = rustix::thread::sched_setaffinity(Some(pid), affinity) {
+            let _ = rustix::process::kill_process(pid, rustix::process::Signal::KILL);
+            let _ = rustix::process::waitpid(Some(pid), rustix::process::WaitOptions::empty());
+            return Err(e.into());
+        }
+    }
+
+    Ok(pid)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn cpu_set(cpus: &[usize]) -> Result<CpuSet> {
+    if cpus.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The CPU affinity must contain at least one CPU",
+        ));
+    }
+    // The kernel silently ignores CPUs that do not exist as long as one of the CPUs does
+    let count = match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } {
+        count if count > 0 => (count as usize).min(CpuSet::MAX_CPU),
+        _ => CpuSet::MAX_CPU,
+    };
+    let mut set = CpuSet::new();
+    for &cpu in cpus {
+        if cpu >= count {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("CPU {cpu} is out of range 0..{count}"),
+            ));
+        }
+        set.set(cpu);
+    }
+    Ok(set)
+}
+
+fn set_priority(pid: Pid, priority: i32) -> Result<()> {
+    // On Linux, this only affects the main thread, which is the only one until the child receives
+    // the entry
+    rustix::process::setpriority_process(Some(pid), priority).map_err(|e| {
+        let e = Error::from(e);
+        Error::new(
+            e.kind(),
+            format!("Failed to set priority to {priority}: {e}"),
+        )
+    })
+}
+
+/// Get the scheduling priority of a process.
+pub(crate) fn priority(pid: Pid) -> Result<i32> {
+    Ok(rustix::process::getpriority_process(Some(pid))?)
+}
+
+/// Get the CPUs a process is restricted to.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn cpu_affinity(pid: Pid) -> Result<Vec<usize>> {
+    let set = rustix::thread::sched_getaffinity(Some(pid))?;
+    Ok((0..CpuSet::MAX_CPU)
+        .filter(|&cpu| set.is_set(cpu))
+        .collect())
+}
+
+/// Get the CPUs a process is restricted to.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn cpu_affinity(_pid: Pid) -> Result<Vec<usize>> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "CPU affinity is not supported on this platform",
+    ))
+}
+
+fn check_oom_score_adj(adj: i32) -> Result<()> {
+    if !cfg!(target_os = "linux") {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "OOM score adjustment is only supported on Linux",
+        ));
+    }
+    if !(-1000..=1000).contains(&adj) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("OOM score adjustment {adj} is out of range -1000..=1000"),
+        ));
+    }
+    Ok(())
+}
+
+fn set_oom_score_adj(pid: Pid, adj: i32) -> Result<()> {
+    std::fs::write(
+        format!("/proc/{}/oom_score_adj", pid.as_raw_nonzero()),
+        adj.to_string(),
+    )
+    .map_err(|e| {
+        let hint = if e.kind() == ErrorKind::PermissionDenied {
+            " (lowering the adjustment requires CAP_SYS_RESOURCE)"
+        } else {
+            ""
+        };
+        Error::new(
+            e.kind(),
+            format!("Failed to set OOM score adjustment to {adj}: {e}{hint}"),
+        )
+    })
+}
+
+fn resource_limits(options: &SpawnOptions) -> Vec<(Resource, Rlimit)> {
+    let mut limits = Vec::new();
+    if let Some(limit) = options.cpu_time_limit {
+        limits.push((Resource::Cpu, cpu_time_rlimit(limit)));
+    }
+    if let Some(limit) = options.memory_limit {
+        limits.push((Resource::As, fixed_rlimit(Resource::As, limit)));
+    }
+    if let Some(limit) = options.open_files_limit {
+        limits.push((Resource::Nofile, fixed_rlimit(Resource::Nofile, limit)));
+    }
+    limits
+}
+
+// Both limits are set, so that the child cannot raise the soft limit
+fn fixed_rlimit(resource: Resource, limit: u64) -> Rlimit {
+    let max = rustix::process::getrlimit(resource)
+        .maximum
+        .unwrap_or(u64::MAX);
+    Rlimit {
+        current: Some(limit.min(max)),
+        maximum: Some(limit.min(max)),
+    }
+}
+
+pub(crate) fn cpu_time_rlimit(limit: Duration) -> Rlimit {
+    // RLIMIT_CPU is measured in seconds, and a zero limit would be effectively ignored
+    let soft = (limit.as_secs() + u64::from(limit.subsec_nanos() > 0)).max(1);
+    let hard = soft.saturating_add(CPU_TIME_GRACE);
+    // Unprivileged processes cannot raise the hard limit
+    let max = rustix::process::getrlimit(Resource::Cpu)
+        .maximum
+        .unwrap_or(u64::MAX);
+    Rlimit {
+        current: Some(soft.min(max)),
+        maximum: Some(hard.min(max)),
     }
 }
 
 // XXX: The signature of libc::clone forces this function to be safe when in reality it isn't
 // (calling it with an arbitrary arg may be unsound). libc 1.0 is going to fix that, see
 // https://github.com/rust-lang/libc/issues/2198.
+#[cfg(any(target_os = "linux", target_os = "android"))]
 extern "C" fn clone_callback(arg: *mut c_void) -> c_int {
-    // Use abort() instead of panic!() to prevent stack unwinding, as unwinding in the fork child
-    // may free resources that would later be freed in the original process
-    match fork_child_main(unsafe { &*(arg as *mut CloneArg) }) {
+    let arg = unsafe { &*(arg as *mut CloneArg) };
+    // Use _exit() instead of panic!() to prevent stack unwinding, as unwinding in the fork child
+    // may free resources that would later be freed in the original process. The error is reported
+    // by the parent
+    match fork_child_main(arg) {
         Ok(()) => unreachable!(),
         Err(e) => {
-            eprintln!("{e}");
-            std::process::abort();
+            arg.error.set(Some(e));
+            unsafe { libc::_exit(127) }
         }
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn clone_flags(namespaces: Namespaces) -> c_int {
+    [
+        (Namespaces::CGROUP, libc::CLONE_NEWCGROUP),
+        (Namespaces::IPC, libc::CLONE_NEWIPC),
+        (Namespaces::MOUNT, libc::CLONE_NEWNS),
+        (Namespaces::NET, libc::CLONE_NEWNET),
+        (Namespaces::PID, libc::CLONE_NEWPID),
+        (Namespaces::USER, libc::CLONE_NEWUSER),
+        (Namespaces::UTS, libc::CLONE_NEWUTS),
+    ]
+    .into_iter()
+    .filter(|&(namespace, _)| namespaces.contains(namespace))
+    .fold(0, |flags, (_, flag)| flags | flag)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn write_file(path: &CStr, data: &[u8]) -> Result<()> {
+    use rustix::fs::{Mode, OFlags};
+    let fd = rustix::fs::open(path, OFlags::WRONLY | OFlags::CLOEXEC, Mode::empty())?;
+    rustix::io::write(&fd, data)?;
+    Ok(())
+}
+
 fn fork_child_main(arg: &CloneArg) -> Result<()> {
     // No heap allocations are allowed here.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some((uid_map, gid_map)) = arg.id_maps {
+        // The group can only be mapped without privileges once setgroups is disabled
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", uid_map)?;
+        write_file(c"/proc/self/gid_map", gid_map)?;
+    }
     entry::disable_cloexec(arg.child_fd)?;
     for fd in arg.inherited_fds {
         entry::disable_cloexec(*fd)?;
     }
+    for &(fd, target) in arg.redirects {
+        // dup2 clears FD_CLOEXEC on the new descriptor, unless it is the same one
+        if fd.as_raw_fd() == target {
+            entry::disable_cloexec(fd)?;
+        } else if unsafe { libc::dup2(fd.as_raw_fd(), target) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    match arg.process_group {
+        ProcessGroup::Inherit => {}
+        ProcessGroup::NewGroup => rustix::process::setpgid(None, None)?,
+        ProcessGroup::NewSession => {
+            rustix::process::setsid()?;
+        }
+    }
+    for &(resource, limit) in arg.rlimits {
+        rustix::process::setrlimit(resource, limit)?;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(ref affinity) = arg.affinity {
+        rustix::thread::sched_setaffinity(None, affinity)?;
+    }
+    if let Some(dir) = arg.current_dir {
+        if unsafe { libc::chdir(dir.as_ptr()) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    for callback in arg.pre_exec {
+        callback()?;
+    }
 
+    let argv = [
+        arg.process_name.as_ptr(),
+        c"_crossmist_".as_ptr(),
+        arg.child_fd_str.as_ptr(),
+        std::ptr::null(),
+    ];
     unsafe {
-        libc::execv(
-            c"/proc/self/exe".as_ptr(),
-            &[
-                c"_crossmist_".as_ptr(),
-                arg.child_fd_str.as_ptr(),
-                std::ptr::null(),
-            ] as *const *const c_char,
-        );
+        match arg.envp {
+            Some(envp) => libc::execve(arg.executable.as_ptr(), argv.as_ptr(), envp.as_ptr()),
+            None => libc::execv(arg.executable.as_ptr(), argv.as_ptr()),
+        };
     }
 
     Err(std::io::Error::last_os_error())
@@ -6,39 +6,62 @@ use crate::{
     Deserializer, FnOnceObject, Receiver, Sender,
 };
 use std::default::Default;
-use std::sync::OnceLock;
+use std::ffi::{OsStr, OsString};
+use std::sync::{Mutex, OnceLock};
+
+/// A request to pass a socket to another process.
+///
+/// The socket is described by a `WSAPROTOCOL_INFOW` structure addressed to the broker, as returned
+/// by `WSADuplicateSocketW`. The broker opens the socket and duplicates it for the process with the
+/// given ID, replying with the new structure or a Winsock error code.
+pub(crate) type BrokerRequest = (Vec<u8>, u32, Sender<Result<Vec<u8>, i32>>);
 
 pub(crate) struct HandleBroker {
     pub(crate) process: OwnedHandle,
-    pub(crate) holder: Sender<()>,
+    // Every process holds a copy of the sending side, so the broker gets EOF once all are dead
+    pub(crate) holder: Mutex<Sender<BrokerRequest>>,
+    pub(crate) holder_handle: BorrowedHandle<'static>,
 }
 
 pub(crate) static HANDLE_BROKER: OnceLock<HandleBroker> = OnceLock::new();
 
+impl HandleBroker {
+    fn new(process: OwnedHandle, holder: Sender<BrokerRequest>) -> Self {
+        // The broker is stored in a static and never dropped
+        let holder_handle = unsafe { BorrowedHandle::borrow_raw(holder.as_raw_handle().0 as _) };
+        Self {
+            process,
+            holder: Mutex::new(holder),
+            holder_handle,
+        }
+    }
+}
+
 pub(crate) fn start_root() {
     let (ours, theirs) = channel().expect("Failed to create holder channel for handle broker");
     let broker = handle_broker
         .spawn(theirs)
         .expect("Failed to start handle broker");
     HANDLE_BROKER
-        .set(HandleBroker {
-            process: broker.0.proc_handle,
-            holder: ours,
-        })
+        .set(HandleBroker::new(broker.0.proc_handle, ours))
         .ok()
         .expect("HANDLE_BROKER has already been initialized");
 }
 
 #[func]
-fn handle_broker(mut holder: Receiver<()>) {
-    holder
+fn handle_broker(mut holder: Receiver<BrokerRequest>) {
+    while let Some((info, process_id, mut reply)) = holder
         .recv()
-        .expect("Failed to receive from holder in handle broker");
+        .expect("Failed to receive from holder in handle broker")
+    {
+        // The requester might have died in the meantime
+        let _ = reply.send(&crate::internals::redirect_socket(&info, process_id));
+    }
     // Everyone is dead by now, there is nobody to report to
     std::process::exit(0);
 }
 
-pub(crate) fn crossmist_main(mut args: std::env::Args) -> ! {
+pub(crate) fn crossmist_main(_name: Option<OsString>, mut args: std::env::ArgsOs) -> ! {
     let handle_broker_id = unsafe {
         parse_handle(
             &args
@@ -69,10 +92,9 @@ pub(crate) fn crossmist_main(mut args: std::env::Args) -> ! {
     };
 
     HANDLE_BROKER
-        .set(HandleBroker {
-            process: handle_broker_id,
-            holder: unsafe { Sender::from_raw_handle(handle_broker_holder_id.into_raw_handle()) },
-        })
+        .set(HandleBroker::new(handle_broker_id, unsafe {
+            Sender::from_raw_handle(handle_broker_holder_id.into_raw_handle())
+        }))
         .ok()
         .expect("HANDLE_BROKER has already been initialized");
 
@@ -83,10 +105,14 @@ pub(crate) fn crossmist_main(mut args: std::env::Args) -> ! {
         Receiver::<(Vec<u8>, Vec<RawHandle>)>::from_raw_handle(handle_rx.into_raw_handle())
     };
 
-    let (entry_data, entry_handles) = entry_rx
-        .recv()
-        .expect("Failed to read entry for crossmist")
-        .expect("No entry passed");
+    let Some((mut entry_data, entry_handles)) =
+        entry_rx.recv().expect("Failed to read entry for crossmist")
+    else {
+        // The parent has dropped a pending child without performing the handshake
+        std::process::exit(1);
+    };
+
+    crate::handshake::accept(&mut entry_data, handle_tx.as_raw_handle());
 
     drop(entry_rx);
 
@@ -106,10 +132,12 @@ pub(crate) fn crossmist_main(mut args: std::env::Args) -> ! {
     std::process::exit(entry.call_object_once((handle_tx.into_raw_handle(),)))
 }
 
-unsafe fn parse_handle(s: &str) -> OwnedHandle {
+unsafe fn parse_handle(s: &OsStr) -> OwnedHandle {
     use windows::Win32::Foundation;
     OwnedHandle::from_raw_handle(Foundation::HANDLE(
-        s.parse::<isize>().expect("Failed to parse handle"),
+        s.to_str()
+            .and_then(|s| s.parse::<isize>().ok())
+            .expect("Failed to parse handle"),
     ))
 }
 
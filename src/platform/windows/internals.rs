@@ -1,11 +1,22 @@
 use crate::{
     entry,
-    handles::{AsRawHandle, FromRawHandle, OwnedHandle, RawHandle},
+    handles::{AsRawHandle, BorrowedHandle, FromRawHandle, OwnedHandle, RawHandle},
+    shm::ReadOnlyMapping,
     Deserializer, Object, Serializer,
 };
 use std::default::Default;
-use std::io::Result;
-use windows::Win32::{Foundation, System::Threading};
+use std::io::{Error, Result};
+use std::os::windows::io::RawSocket;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Foundation,
+        Networking::WinSock,
+        System::{Memory, Performance, Pipes, Threading},
+    },
+};
 
 pub(crate) fn serialize_with_handles<T: Object>(value: &T) -> Result<Vec<u8>> {
     let mut s = Serializer::new();
@@ -20,7 +31,7 @@ pub(crate) fn serialize_with_handles<T: Object>(value: &T) -> Result<Vec<u8>> {
 
         for handle in handles {
             let mut dup_handle: RawHandle = Default::default();
-            unsafe {
+            let result = unsafe {
                 Foundation::DuplicateHandle(
                     Threading::GetCurrentProcess(),
                     handle.as_raw_handle(),
@@ -30,7 +41,12 @@ pub(crate) fn serialize_with_handles<T: Object>(value: &T) -> Result<Vec<u8>> {
                     false,
                     Foundation::DUPLICATE_SAME_ACCESS,
                 )
-                .ok()?;
+                .ok()
+            };
+            if let Err(e) = result {
+                // Nobody is going to claim the handles moved so far
+                close_in_broker(handle_broker, &dup_handles);
+                return Err(e.into());
             }
             dup_handles.push(dup_handle);
         }
@@ -42,10 +58,70 @@ pub(crate) fn serialize_with_handles<T: Object>(value: &T) -> Result<Vec<u8>> {
     Ok(s1.into_vec())
 }
 
-pub(crate) unsafe fn deserialize_with_handles<T: Object>(serialized: Vec<u8>) -> Result<T> {
-    let mut d = Deserializer::new(serialized, Vec::new());
+/// Close handles owned by the handle broker that are not going to be claimed.
+fn close_in_broker(handle_broker: &entry::HandleBroker, handles: &[RawHandle]) {
+    for &handle in handles {
+        unsafe {
+            // There is nothing to do on failure
+            let _ = Foundation::DuplicateHandle(
+                handle_broker.process.as_raw_handle(),
+                handle,
+                Foundation::HANDLE::default(),
+                std::ptr::null_mut(),
+                0,
+                false,
+                Foundation::DUPLICATE_CLOSE_SOURCE,
+            );
+        }
+    }
+}
+
+pub(crate) unsafe fn deserialize_with_handles<T: Object>(serialized: &mut Vec<u8>) -> Result<T> {
+    deserialize_with_handles_by(serialized, |d| d.deserialize())
+}
+
+/// Like [`deserialize_with_handles`], but deserializes into an existing object.
+pub(crate) unsafe fn deserialize_with_handles_into<T: Object>(
+    serialized: &mut Vec<u8>,
+    target: &mut T,
+) -> Result<()> {
+    deserialize_with_handles_by(serialized, |d| d.deserialize_into(target))
+}
+
+/// Like [`deserialize_with_handles`], but reads the data from shared memory in place.
+pub(crate) unsafe fn deserialize_mapped_with_handles<T: Object>(
+    mapping: ReadOnlyMapping,
+) -> Result<T> {
+    let mut d = Deserializer::from_mapping(mapping, Vec::new());
+    deserialize_claiming_handles(&mut d, |d| d.deserialize())
+}
+
+/// Like [`deserialize_mapped_with_handles`], but deserializes into an existing object.
+pub(crate) unsafe fn deserialize_mapped_with_handles_into<T: Object>(
+    mapping: ReadOnlyMapping,
+    target: &mut T,
+) -> Result<()> {
+    let mut d = Deserializer::from_mapping(mapping, Vec::new());
+    deserialize_claiming_handles(&mut d, |d| d.deserialize_into(target))
+}
+
+unsafe fn deserialize_with_handles_by<U>(
+    serialized: &mut Vec<u8>,
+    deserialize: impl FnOnce(&mut Deserializer) -> Result<U>,
+) -> Result<U> {
+    let mut d = Deserializer::new(std::mem::take(serialized), Vec::new());
+    let result = deserialize_claiming_handles(&mut d, deserialize);
+    // Hand the buffer back, so that the caller can reuse it
+    *serialized = d.into_data();
+    result
+}
+
+// Claim the handles listed at the beginning of the data from the broker, then deserialize the rest
+unsafe fn deserialize_claiming_handles<U>(
+    d: &mut Deserializer,
+    deserialize: impl FnOnce(&mut Deserializer) -> Result<U>,
+) -> Result<U> {
     let handles: Vec<RawHandle> = d.deserialize()?;
-    let serialized_contents: Vec<u8> = Vec::from(d.get_rest());
 
     let mut dup_handles = Vec::new();
     if !handles.is_empty() {
@@ -53,9 +129,10 @@ pub(crate) unsafe fn deserialize_with_handles<T: Object>(serialized: Vec<u8>) ->
             .get()
             .expect("HANDLE_BROKER has not been initialized yet");
 
-        for handle in handles {
+        for (i, &handle) in handles.iter().enumerate() {
             let mut dup_handle: RawHandle = Default::default();
-            unsafe {
+            // With DUPLICATE_CLOSE_SOURCE, the handle is closed in the broker even on failure
+            let result = unsafe {
                 Foundation::DuplicateHandle(
                     handle_broker.process.as_raw_handle(),
                     handle,
@@ -65,12 +142,272 @@ pub(crate) unsafe fn deserialize_with_handles<T: Object>(serialized: Vec<u8>) ->
                     false,
                     Foundation::DUPLICATE_CLOSE_SOURCE | Foundation::DUPLICATE_SAME_ACCESS,
                 )
-                .ok()?;
+                .ok()
+            };
+            if let Err(e) = result {
+                close_in_broker(handle_broker, &handles[i + 1..]);
+                return Err(e.into());
             }
             let dup_handle = unsafe { OwnedHandle::from_raw_handle(dup_handle) };
             dup_handles.push(dup_handle);
         }
     }
+    d.handles = dup_handles.into_iter();
+
+    // The captured frame does not include the list of handles
+    #[cfg(feature = "capture")]
+    let start = d.data().len() - d.get_rest().len();
+    match deserialize(d) {
+        #[cfg(feature = "capture")]
+        Err(e) => Err(crate::capture::capture(e, &d.data()[start..])),
+        result => result,
+    }
+}
+
+/// Read the system-wide performance counter. Unlike [`Instant`](std::time::Instant), its readings
+/// can be compared across processes.
+pub(crate) fn monotonic_clock() -> Option<std::time::Duration> {
+    let (mut counter, mut frequency) = (0i64, 0i64);
+    unsafe {
+        if !Performance::QueryPerformanceCounter(&mut counter as *mut i64).as_bool()
+            || !Performance::QueryPerformanceFrequency(&mut frequency as *mut i64).as_bool()
+        {
+            return None;
+        }
+    }
+    let counter = u128::try_from(counter).ok()?;
+    let frequency = u128::try_from(frequency)
+        .ok()
+        .filter(|&frequency| frequency > 0)?;
+    let nanos = u64::try_from(counter * 1_000_000_000 / frequency).ok()?;
+    Some(std::time::Duration::from_nanos(nanos))
+}
+
+/// Get the number of bytes that can be read from a pipe without blocking, without consuming any
+/// data. Returns `None` if the pipe is broken.
+pub(crate) fn pipe_bytes_available(handle: RawHandle) -> Option<usize> {
+    let mut available: u32 = 0;
+    let success = unsafe {
+        Pipes::PeekNamedPipe(
+            handle,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            &mut available,
+            std::ptr::null_mut(),
+        )
+    };
+    success.as_bool().then_some(available as usize)
+}
+
+/// Check if reading from a pipe would not block, without consuming any data.
+pub(crate) fn is_pipe_readable(handle: RawHandle) -> bool {
+    // If peeking fails, the pipe is most likely broken, and reading from it reports EOF immediately
+    pipe_bytes_available(handle) != Some(0)
+}
+
+/// Wait until reading from a pipe would not block, or until `timeout` elapses.
+///
+/// Anonymous pipes cannot be waited for, so the pipe is checked every millisecond.
+pub(crate) fn poll_readable(handle: BorrowedHandle<'_>, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now().checked_add(timeout);
+    while !is_pipe_readable(handle.as_raw_handle()) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    Ok(true)
+}
+
+/// Create a zero-filled section of the given size.
+pub(crate) fn create_shared_buffer(len: usize) -> Result<OwnedHandle> {
+    let len = len as u64;
+    Ok(unsafe {
+        OwnedHandle::from_raw_handle(Memory::CreateFileMappingW(
+            Foundation::INVALID_HANDLE_VALUE,
+            std::ptr::null(),
+            Memory::PAGE_READWRITE,
+            (len >> 32) as u32,
+            len as u32,
+            PCWSTR::null(),
+        )?)
+    })
+}
+
+/// Map the first `len` bytes of a section into memory for reading and writing.
+///
+/// `len` must not be zero.
+pub(crate) fn map_shared_buffer(section: &OwnedHandle, len: usize) -> Result<*mut u8> {
+    // Mapping fails if the section is shorter than `len`
+    let view = unsafe {
+        Memory::MapViewOfFile(
+            section.as_raw_handle(),
+            Memory::FILE_MAP_READ | Memory::FILE_MAP_WRITE,
+            0,
+            0,
+            len,
+        )
+    };
+    if view.is_null() {
+        return Err(Error::last_os_error());
+    }
+    Ok(view as *mut u8)
+}
+
+/// Create a section holding a copy of `data` that can only be mapped for reading.
+pub(crate) fn create_sealed_buffer(data: &[u8]) -> Result<OwnedHandle> {
+    // Empty sections cannot be created
+    let len = data.len().max(1);
+    let section = create_shared_buffer(len)?;
+    let ptr = map_shared_buffer(&section, len)?;
+    unsafe {
+        std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+        unmap_shared_buffer(ptr, len);
+    }
+    // Handles passed to other processes are duplicated with the same access rights
+    let mut read_only: RawHandle = Default::default();
+    unsafe {
+        Foundation::DuplicateHandle(
+            Threading::GetCurrentProcess(),
+            section.as_raw_handle(),
+            Threading::GetCurrentProcess(),
+            &mut read_only,
+            Memory::FILE_MAP_READ.0,
+            false,
+            Foundation::DUPLICATE_HANDLE_OPTIONS(0),
+        )
+        .ok()?;
+        Ok(OwnedHandle::from_raw_handle(read_only))
+    }
+}
+
+/// Map the first `len` bytes of a section into memory for reading.
+///
+/// `len` must not be zero.
+pub(crate) fn map_private_buffer(section: &OwnedHandle, len: usize) -> Result<*mut u8> {
+    let view =
+        unsafe { Memory::MapViewOfFile(section.as_raw_handle(), Memory::FILE_MAP_READ, 0, 0, len) };
+    if view.is_null() {
+        return Err(Error::last_os_error());
+    }
+    Ok(view as *mut u8)
+}
+
+/// Unmap memory mapped by [`map_shared_buffer`] or [`map_private_buffer`].
+pub(crate) unsafe fn unmap_shared_buffer(ptr: *mut u8, _len: usize) {
+    Memory::UnmapViewOfFile(ptr as *const _);
+}
+
+// Sockets cannot be passed via DuplicateHandle in general: sockets of layered service providers
+// are not plain kernel handles. WSADuplicateSocketW works for all sockets, but needs the ID of the
+// receiving process, which is not known when the socket is serialized. So the socket is duplicated
+// for the handle broker instead, and the receiver asks the broker to duplicate it once more for
+// the receiving process.
+
+fn init_winsock() -> Result<()> {
+    static INIT: OnceLock<i32> = OnceLock::new();
+    let mut data = WinSock::WSAData::default();
+    match *INIT.get_or_init(|| unsafe { WinSock::WSAStartup(0x202, &mut data) }) {
+        0 => Ok(()),
+        code => Err(Error::from_raw_os_error(code)),
+    }
+}
+
+fn last_socket_error() -> i32 {
+    unsafe { WinSock::WSAGetLastError().0 }
+}
+
+fn protocol_info_bytes(info: &WinSock::WSAPROTOCOL_INFOW) -> Vec<u8> {
+    unsafe {
+        std::slice::from_raw_parts(
+            info as *const WinSock::WSAPROTOCOL_INFOW as *const u8,
+            std::mem::size_of::<WinSock::WSAPROTOCOL_INFOW>(),
+        )
+    }
+    .to_vec()
+}
+
+fn parse_protocol_info(bytes: &[u8]) -> std::result::Result<WinSock::WSAPROTOCOL_INFOW, i32> {
+    if bytes.len() != std::mem::size_of::<WinSock::WSAPROTOCOL_INFOW>() {
+        return Err(WinSock::WSAEINVAL.0);
+    }
+    Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const WinSock::WSAPROTOCOL_INFOW) })
+}
+
+fn socket_from_protocol_info(
+    info: &WinSock::WSAPROTOCOL_INFOW,
+) -> std::result::Result<WinSock::SOCKET, i32> {
+    let socket = unsafe {
+        WinSock::WSASocketW(
+            WinSock::FROM_PROTOCOL_INFO,
+            WinSock::FROM_PROTOCOL_INFO,
+            WinSock::FROM_PROTOCOL_INFO,
+            info,
+            0,
+            WinSock::WSA_FLAG_OVERLAPPED | WinSock::WSA_FLAG_NO_HANDLE_INHERIT,
+        )
+    };
+    if socket == WinSock::INVALID_SOCKET {
+        return Err(last_socket_error());
+    }
+    Ok(socket)
+}
+
+/// Duplicate a socket for the handle broker. Returns the serialized `WSAPROTOCOL_INFOW` structure
+/// or a Winsock error code.
+pub(crate) fn duplicate_socket_for_broker(socket: RawSocket) -> std::result::Result<Vec<u8>, i32> {
+    let Some(handle_broker) = entry::HANDLE_BROKER.get() else {
+        return Err(WinSock::WSAEINVAL.0);
+    };
+    let broker_id = unsafe { Threading::GetProcessId(handle_broker.process.as_raw_handle()) };
+    let mut info = WinSock::WSAPROTOCOL_INFOW::default();
+    if unsafe {
+        WinSock::WSADuplicateSocketW(WinSock::SOCKET(socket as usize), broker_id, &mut info)
+    } != 0
+    {
+        return Err(last_socket_error());
+    }
+    Ok(protocol_info_bytes(&info))
+}
+
+/// Open a socket duplicated for the broker and duplicate it for the process `process_id`.
+///
+/// Called in the handle broker. Returns the serialized `WSAPROTOCOL_INFOW` structure or a Winsock
+/// error code.
+pub(crate) fn redirect_socket(info: &[u8], process_id: u32) -> std::result::Result<Vec<u8>, i32> {
+    init_winsock().map_err(|e| e.raw_os_error().unwrap_or(WinSock::WSAEINVAL.0))?;
+    let socket = socket_from_protocol_info(&parse_protocol_info(info)?)?;
+    let mut info = WinSock::WSAPROTOCOL_INFOW::default();
+    let result = unsafe { WinSock::WSADuplicateSocketW(socket, process_id, &mut info) };
+    let error = last_socket_error();
+    // Closing this copy does not affect the duplicate
+    unsafe {
+        WinSock::closesocket(socket);
+    }
+    if result != 0 {
+        return Err(error);
+    }
+    Ok(protocol_info_bytes(&info))
+}
 
-    Deserializer::new(serialized_contents, dup_handles).deserialize()
+/// Claim a socket duplicated by [`duplicate_socket_for_broker`] in another process.
+pub(crate) fn claim_socket(info: Vec<u8>) -> Result<RawSocket> {
+    init_winsock()?;
+    let handle_broker = entry::HANDLE_BROKER
+        .get()
+        .expect("HANDLE_BROKER has not been initialized yet");
+    let (tx, mut rx) = crate::channel()?;
+    handle_broker
+        .holder
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .send(&(info, std::process::id(), tx))?;
+    let info = rx
+        .recv()?
+        .ok_or_else(|| Error::other("The handle broker has exited"))?
+        .map_err(Error::from_raw_os_error)?;
+    let info = parse_protocol_info(&info).map_err(Error::from_raw_os_error)?;
+    let socket = socket_from_protocol_info(&info).map_err(Error::from_raw_os_error)?;
+    Ok(socket.0 as RawSocket)
 }
@@ -2,21 +2,361 @@ use crate::{
     asynchronous::AsyncStream,
     entry,
     handles::{AsHandle, AsRawHandle, BorrowedHandle, FromRawHandle, OwnedHandle, RawHandle},
+    ProcessGroup, SpawnOptions,
 };
-use std::ffi::c_void;
+use std::ffi::{c_void, OsStr, OsString};
 use std::io::Result;
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Mutex;
 use windows::{
     core::{PCWSTR, PWSTR},
     Win32::{
         Foundation,
-        System::{LibraryLoader, Threading},
+        System::{Console, JobObjects, LibraryLoader, Threading, IO},
     },
 };
 
+/// A child process that has been created but has not started executing yet.
+pub(crate) struct SuspendedChild {
+    process: OwnedHandle,
+    thread: OwnedHandle,
+}
+
+// The accessors are meant for configuration hooks
+#[allow(dead_code)]
+impl SuspendedChild {
+    pub(crate) fn process(&self) -> BorrowedHandle<'_> {
+        self.process.as_handle()
+    }
+
+    pub(crate) fn thread(&self) -> BorrowedHandle<'_> {
+        self.thread.as_handle()
+    }
+
+    fn resume(self) -> Result<OwnedHandle> {
+        if unsafe { Threading::ResumeThread(self.thread.as_raw_handle()) } == u32::MAX {
+            let err = std::io::Error::last_os_error();
+            self.terminate();
+            return Err(err);
+        }
+        Ok(self.process)
+    }
+
+    fn terminate(self) {
+        // The process has not run any code, so there is nothing to clean up
+        unsafe {
+            let _ = Threading::TerminateProcess(self.process.as_raw_handle(), 1);
+        }
+    }
+}
+
+/// Compute the process creation flags requested by the spawn options.
+pub(crate) fn creation_flags(options: &SpawnOptions) -> Result<Threading::PROCESS_CREATION_FLAGS> {
+    let mut flags = match options.process_group {
+        ProcessGroup::Inherit => Threading::PROCESS_CREATION_FLAGS(0),
+        ProcessGroup::NewGroup => Threading::CREATE_NEW_PROCESS_GROUP,
+        ProcessGroup::NewSession => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "New session is not supported on Windows",
+            ));
+        }
+    };
+    // A custom affinity is set by apply_options instead
+    if options.cpu_affinity.is_none() {
+        flags |= Threading::INHERIT_PARENT_AFFINITY;
+    }
+    Ok(flags)
+}
+
+/// Apply the spawn options to a child that has not started yet.
+///
+/// If the child is assigned to a job object, the completion port receiving the notifications of
+/// the job is returned.
+pub(crate) fn apply_options(
+    child: &SuspendedChild,
+    options: &SpawnOptions,
+) -> Result<Option<OwnedHandle>> {
+    if options.oom_score_adj.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "OOM score adjustment is only supported on Linux",
+        ));
+    }
+    if !options.unshare.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Namespaces are only supported on Linux",
+        ));
+    }
+    if options.open_files_limit.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Limiting the number of open files is not supported on Windows",
+        ));
+    }
+
+    if let Some(ref cpus) = options.cpu_affinity {
+        if cpus.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The CPU affinity must contain at least one CPU",
+            ));
+        }
+        let mut mask = 0usize;
+        for &cpu in cpus {
+            if cpu >= usize::BITS as usize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("CPU {cpu} is out of range 0..{}", usize::BITS),
+                ));
+            }
+            mask |= 1 << cpu;
+        }
+        let mut process_mask = 0usize;
+        let mut system_mask = 0usize;
+        unsafe {
+            Threading::GetProcessAffinityMask(
+                Threading::GetCurrentProcess(),
+                &mut process_mask as *mut usize,
+                &mut system_mask as *mut usize,
+            )
+            .ok()?;
+        }
+        if mask & !system_mask != 0 {
+            let cpu = (mask & !system_mask).trailing_zeros();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("CPU {cpu} does not exist"),
+            ));
+        }
+        unsafe {
+            Threading::SetProcessAffinityMask(child.process().as_raw_handle(), mask).ok()?;
+        }
+    }
+
+    if let Some(priority) = options.checked_priority()? {
+        let class = match priority {
+            ..=-8 => Threading::HIGH_PRIORITY_CLASS,
+            -7..=-1 => Threading::ABOVE_NORMAL_PRIORITY_CLASS,
+            0..=6 => Threading::NORMAL_PRIORITY_CLASS,
+            7..=18 => Threading::BELOW_NORMAL_PRIORITY_CLASS,
+            19.. => Threading::IDLE_PRIORITY_CLASS,
+        };
+        unsafe {
+            Threading::SetPriorityClass(child.process().as_raw_handle(), class).ok()?;
+        }
+    }
+
+    let mut info = JobObjects::JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+    if let Some(limit) = options.cpu_time_limit {
+        // Measured in 100-nanosecond intervals
+        info.BasicLimitInformation.PerProcessUserTimeLimit =
+            i64::try_from(limit.as_nanos() / 100).unwrap_or(i64::MAX);
+        info.BasicLimitInformation.LimitFlags |= JobObjects::JOB_OBJECT_LIMIT_PROCESS_TIME;
+    }
+    if let Some(limit) = options.memory_limit {
+        info.ProcessMemoryLimit = usize::try_from(limit).unwrap_or(usize::MAX);
+        info.BasicLimitInformation.LimitFlags |= JobObjects::JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+    }
+    if info.BasicLimitInformation.LimitFlags.0 == 0 {
+        return Ok(None);
+    }
+
+    // The job is kept alive by the process assigned to it, so the handle can be closed
+    let job = unsafe {
+        OwnedHandle::from_raw_handle(JobObjects::CreateJobObjectW(
+            std::ptr::null(),
+            PCWSTR::null(),
+        )?)
+    };
+    unsafe {
+        JobObjects::SetInformationJobObject(
+            job.as_raw_handle(),
+            JobObjects::JobObjectExtendedLimitInformation,
+            &info as *const JobObjects::JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *const c_void,
+            std::mem::size_of::<JobObjects::JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        )
+        .ok()?;
+    }
+
+    // The job reports limit violations to the port, so that the parent can tell why the child has
+    // been terminated
+    let port = unsafe {
+        OwnedHandle::from_raw_handle(IO::CreateIoCompletionPort(
+            Foundation::INVALID_HANDLE_VALUE,
+            Foundation::HANDLE(0),
+            0,
+            1,
+        )?)
+    };
+    let association = JobObjects::JOBOBJECT_ASSOCIATE_COMPLETION_PORT {
+        CompletionKey: std::ptr::null_mut(),
+        CompletionPort: port.as_raw_handle(),
+    };
+    unsafe {
+        JobObjects::SetInformationJobObject(
+            job.as_raw_handle(),
+            JobObjects::JobObjectAssociateCompletionPortInformation,
+            &association as *const JobObjects::JOBOBJECT_ASSOCIATE_COMPLETION_PORT as *const c_void,
+            std::mem::size_of::<JobObjects::JOBOBJECT_ASSOCIATE_COMPLETION_PORT>() as u32,
+        )
+        .ok()?;
+        JobObjects::AssignProcessToJobObject(job.as_raw_handle(), child.process().as_raw_handle())
+            .ok()?;
+    }
+    Ok(Some(port))
+}
+
+/// Get the scheduling priority of a process, see [`SpawnOptions::priority`].
+pub(crate) fn priority(process: RawHandle) -> Result<i32> {
+    let class = unsafe { Threading::GetPriorityClass(process) };
+    Ok(match Threading::PROCESS_CREATION_FLAGS(class) {
+        Threading::REALTIME_PRIORITY_CLASS => -20,
+        Threading::HIGH_PRIORITY_CLASS => -14,
+        Threading::ABOVE_NORMAL_PRIORITY_CLASS => -7,
+        Threading::NORMAL_PRIORITY_CLASS => 0,
+        Threading::BELOW_NORMAL_PRIORITY_CLASS => 7,
+        Threading::IDLE_PRIORITY_CLASS => 19,
+        _ => return Err(std::io::Error::last_os_error()),
+    })
+}
+
+/// Get the CPUs a process is restricted to.
+pub(crate) fn cpu_affinity(process: RawHandle) -> Result<Vec<usize>> {
+    let mut process_mask = 0usize;
+    let mut system_mask = 0usize;
+    unsafe {
+        Threading::GetProcessAffinityMask(
+            process,
+            &mut process_mask as *mut usize,
+            &mut system_mask as *mut usize,
+        )
+        .ok()?;
+    }
+    Ok((0..usize::BITS as usize)
+        .filter(|&cpu| process_mask & (1 << cpu) != 0)
+        .collect())
+}
+
+/// A thread attribute list for `CreateProcessW`, deleted on drop.
+struct ProcThreadAttributeList {
+    list: Threading::LPPROC_THREAD_ATTRIBUTE_LIST,
+    _buffer: Vec<u8>,
+}
+
+impl ProcThreadAttributeList {
+    fn new(n_attrs: u32) -> Result<Self> {
+        let mut size = 0;
+        unsafe {
+            Threading::InitializeProcThreadAttributeList(
+                Threading::LPPROC_THREAD_ATTRIBUTE_LIST::default(),
+                n_attrs,
+                0,
+                &mut size as *mut usize,
+            );
+        }
+        let mut buffer = vec![0u8; size];
+        let list = Threading::LPPROC_THREAD_ATTRIBUTE_LIST(buffer.as_mut_ptr() as *mut c_void);
+        unsafe {
+            Threading::InitializeProcThreadAttributeList(list, n_attrs, 0, &mut size as *mut usize)
+                .ok()?;
+        }
+        // Moving the buffer does not move its contents, so the list stays valid
+        Ok(Self {
+            list,
+            _buffer: buffer,
+        })
+    }
+}
+
+impl Drop for ProcThreadAttributeList {
+    fn drop(&mut self) {
+        unsafe {
+            Threading::DeleteProcThreadAttributeList(self.list);
+        }
+    }
+}
+
+/// Serializes the window during which handles are inheritable.
+///
+/// Inheritance flags are process-wide state. Without the lock, a concurrent spawn could make a
+/// handle shared by all children, e.g. the broker process handle, non-inheritable again while
+/// `CreateProcessW` is running in another thread, or start a child while handles meant for another
+/// child are inheritable.
+static SPAWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Handles temporarily made inheritable for `CreateProcessW`. They are made non-inheritable again on
+/// drop, so that an early return does not leak them into processes spawned by other threads.
+struct InheritableHandles<'a>(Vec<BorrowedHandle<'a>>);
+
+impl Drop for InheritableHandles<'_> {
+    fn drop(&mut self) {
+        for &handle in &self.0 {
+            // There is nothing to do on failure; at worst, the handle leaks into other children
+            let _ = entry::enable_cloexec(handle);
+        }
+    }
+}
+
+fn current_module_name() -> Result<Vec<u16>> {
+    let mut module_name = vec![0u16; 256];
+    loop {
+        let module_name_len =
+            unsafe { LibraryLoader::GetModuleFileNameW(None, &mut module_name) } as usize;
+        if module_name_len == 0 {
+            return Err(std::io::Error::last_os_error());
+        } else if module_name_len == module_name.len() {
+            module_name.resize(module_name.len() * 2, 0);
+        } else {
+            module_name.truncate(module_name_len + 1);
+            return Ok(module_name);
+        }
+    }
+}
+
+/// Build a Unicode environment block for `CreateProcessW`.
+fn environment_block(mut vars: Vec<(OsString, OsString)>) -> Vec<u16> {
+    // The block is expected to be sorted by name, case-insensitively
+    vars.sort_by_cached_key(|(key, _)| key.to_ascii_uppercase());
+    let mut block = Vec::new();
+    for (key, value) in vars {
+        block.extend(key.encode_wide());
+        block.push(b'=' as u16);
+        block.extend(value.encode_wide());
+        block.push(0);
+    }
+    // An empty block still consists of two null characters
+    if block.is_empty() {
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
+/// Start a child process.
+///
+/// The process is created suspended, so that `configure` can adjust it (e.g. set its affinity or
+/// priority, or assign it to a job) before it executes its first instruction. If `configure` fails,
+/// the process is terminated. `creation_flags` are passed to `CreateProcessW` in addition to the
+/// flags crossmist relies on. If `executable` is `None`, the current executable is started. If
+/// `process_name` is `None`, the child is named after the current process. `env` and
+/// `current_dir` replace the environment and the working directory of the child if set. `stdio`
+/// lists the handles to use as the standard output and error of the child, `None` meaning the ones
+/// of the parent.
+#[allow(clippy::too_many_arguments)]
 pub(crate) unsafe fn _spawn_child<'a>(
+    executable: Option<&Path>,
+    process_name: Option<&OsStr>,
+    env: Option<Vec<(OsString, OsString)>>,
+    current_dir: Option<&Path>,
     child_tx: BorrowedHandle<'a>,
     child_rx: BorrowedHandle<'a>,
     mut inherited_handles: Vec<BorrowedHandle<'a>>,
+    stdio: [Option<BorrowedHandle<'a>>; 2],
+    creation_flags: Threading::PROCESS_CREATION_FLAGS,
+    configure: impl FnOnce(&SuspendedChild) -> Result<()>,
 ) -> Result<OwnedHandle> {
     inherited_handles.push(child_tx);
     inherited_handles.push(child_rx);
@@ -24,10 +364,10 @@ pub(crate) unsafe fn _spawn_child<'a>(
     let (broker_process, holder_handle) = match entry::HANDLE_BROKER.get() {
         Some(handle_broker) => {
             inherited_handles.push(handle_broker.process.as_handle());
-            inherited_handles.push(handle_broker.holder.0.fd.as_handle());
+            inherited_handles.push(handle_broker.holder_handle);
             (
                 handle_broker.process.as_raw_handle().0,
-                handle_broker.holder.as_raw_handle().0,
+                handle_broker.holder_handle.as_raw_handle().0,
             )
         }
         None => {
@@ -36,44 +376,96 @@ pub(crate) unsafe fn _spawn_child<'a>(
         }
     };
 
-    let mut module_name = vec![0u16; 256];
-    let mut module_name_len;
-    loop {
-        module_name_len = LibraryLoader::GetModuleFileNameW(None, &mut module_name) as usize;
-        if module_name_len == 0 {
-            return Err(std::io::Error::last_os_error());
-        } else if module_name_len == module_name.len() {
-            module_name.resize(module_name.len() * 2, 0);
-        } else {
-            module_name.truncate(module_name_len + 1);
-            break;
+    let module_name = match executable {
+        Some(path) => {
+            let mut module_name: Vec<u16> = path.as_os_str().encode_wide().collect();
+            if module_name.contains(&0) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "The path to the executable contains a null character",
+                ));
+            }
+            module_name.push(0);
+            module_name
         }
-    }
+        None => current_module_name()?,
+    };
 
-    let mut cmd_line: Vec<u16> = format!(
-        "_crossmist_ {} {} {} {}\0",
-        broker_process,
-        holder_handle,
-        child_tx.as_raw_handle().0,
-        child_rx.as_raw_handle().0,
-    )
-    .encode_utf16()
-    .collect();
-
-    let n_attrs = 1;
-    let mut size = 0;
-    Threading::InitializeProcThreadAttributeList(
-        Threading::LPPROC_THREAD_ATTRIBUTE_LIST::default(),
-        n_attrs,
-        0,
-        &mut size as *mut usize,
+    // The first token is quoted, so it cannot contain quotes itself
+    let process_name = match process_name {
+        Some(name) => name.to_owned(),
+        None => std::env::args_os().next().unwrap_or_default(),
+    };
+    let mut cmd_line: Vec<u16> = vec![b'"' as u16];
+    cmd_line.extend(process_name.encode_wide());
+    if cmd_line[1..].iter().any(|&c| c == b'"' as u16 || c == 0) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "The name of the process contains a double quote or a null character",
+        ));
+    }
+    cmd_line.push(b'"' as u16);
+    cmd_line.extend(
+        format!(
+            " _crossmist_ {} {} {} {}\0",
+            broker_process,
+            holder_handle,
+            child_tx.as_raw_handle().0,
+            child_rx.as_raw_handle().0,
+        )
+        .encode_utf16(),
     );
-    let mut attrs = vec![0u8; size];
-    let attrs = Threading::LPPROC_THREAD_ATTRIBUTE_LIST(attrs.as_mut_ptr() as *mut c_void);
-    Threading::InitializeProcThreadAttributeList(attrs, n_attrs, 0, &mut size as *mut usize)
-        .ok()?;
+
+    let env_block = env.map(environment_block);
+    let current_dir = match current_dir {
+        Some(dir) => {
+            // CreateProcessW expects a full path
+            let mut current_dir: Vec<u16> = std::path::absolute(dir)?
+                .as_os_str()
+                .encode_wide()
+                .collect();
+            if current_dir.contains(&0) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "The path to the working directory contains a null character",
+                ));
+            }
+            current_dir.push(0);
+            Some(current_dir)
+        }
+        None => None,
+    };
+
+    let mut std_handles = None;
+    // Once any standard handle is specified, all of them have to be, so the remaining ones are
+    // taken from the parent
+    if stdio.iter().any(Option::is_some) {
+        let std_handle = |handle: Option<BorrowedHandle<'a>>, kind| match handle {
+            Some(handle) => handle.as_raw_handle(),
+            None => Console::GetStdHandle(kind).unwrap_or_default(),
+        };
+        let [stdout, stderr] = stdio;
+        std_handles = Some([
+            std_handle(None, Console::STD_INPUT_HANDLE),
+            std_handle(stdout, Console::STD_OUTPUT_HANDLE),
+            std_handle(stderr, Console::STD_ERROR_HANDLE),
+        ]);
+        for handle in std_handles.into_iter().flatten() {
+            // Missing handles cannot be inherited, and a handle may only be listed once
+            if handle.is_invalid()
+                || inherited_handles
+                    .iter()
+                    .any(|inherited| inherited.as_raw_handle() == handle)
+            {
+                continue;
+            }
+            inherited_handles.push(BorrowedHandle::borrow_raw(handle.0 as _));
+        }
+    }
+
+    let attrs = ProcThreadAttributeList::new(1)?;
     Threading::UpdateProcThreadAttribute(
-        attrs,
+        attrs.list,
         0,
         Threading::PROC_THREAD_ATTRIBUTE_HANDLE_LIST as usize,
         inherited_handles.as_ptr() as *const c_void,
@@ -85,15 +477,23 @@ pub(crate) unsafe fn _spawn_child<'a>(
 
     let mut startup_info = Threading::STARTUPINFOEXW::default();
     startup_info.StartupInfo.cb = std::mem::size_of::<Threading::STARTUPINFOEXW>() as u32;
-    startup_info.lpAttributeList = attrs;
+    startup_info.lpAttributeList = attrs.list;
+    if let Some([stdin, stdout, stderr]) = std_handles {
+        let info = &mut startup_info.StartupInfo;
+        info.dwFlags |= Threading::STARTF_USESTDHANDLES;
+        info.hStdInput = stdin;
+        info.hStdOutput = stdout;
+        info.hStdError = stderr;
+    }
 
     let mut process_info = Threading::PROCESS_INFORMATION::default();
 
-    let mut enabled_handles = Vec::new();
+    let spawn_guard = SPAWN_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut inheritable = InheritableHandles(Vec::new());
     for &handle in &inherited_handles {
         if entry::is_cloexec(handle)? {
-            enabled_handles.push(handle);
             entry::disable_cloexec(handle)?;
+            inheritable.0.push(handle);
         }
     }
 
@@ -103,19 +503,33 @@ pub(crate) unsafe fn _spawn_child<'a>(
         std::ptr::null(),
         std::ptr::null(),
         true,
-        Threading::EXTENDED_STARTUPINFO_PRESENT | Threading::INHERIT_PARENT_AFFINITY,
-        std::ptr::null(),
-        None,
+        Threading::EXTENDED_STARTUPINFO_PRESENT
+            | Threading::CREATE_SUSPENDED
+            | Threading::CREATE_UNICODE_ENVIRONMENT
+            | creation_flags,
+        env_block
+            .as_ref()
+            .map_or(std::ptr::null(), |block| block.as_ptr() as *const c_void),
+        current_dir
+            .as_ref()
+            .map_or(PCWSTR::null(), |dir| PCWSTR::from_raw(dir.as_ptr())),
         &startup_info as *const Threading::STARTUPINFOEXW as *const Threading::STARTUPINFOW,
         &mut process_info as *mut Threading::PROCESS_INFORMATION,
     );
 
-    for handle in enabled_handles {
-        entry::enable_cloexec(handle)?;
-    }
+    drop(inheritable);
+    drop(spawn_guard);
+    drop(attrs);
 
     res.ok()?;
 
-    Foundation::CloseHandle(process_info.hThread);
-    Ok(OwnedHandle::from_raw_handle(process_info.hProcess))
+    let child = SuspendedChild {
+        process: OwnedHandle::from_raw_handle(process_info.hProcess),
+        thread: OwnedHandle::from_raw_handle(process_info.hThread),
+    };
+    if let Err(err) = configure(&child) {
+        child.terminate();
+        return Err(err);
+    }
+    child.resume()
 }
@@ -1,6 +1,15 @@
 use crate::{imp::implements, Deserializer, NonTrivialObject, Serializer};
 use std::io::Result;
 
+/// A type that can be serialized by copying its bytes.
+///
+/// Arrays, vectors and boxed slices of such types are serialized with a single copy of the whole
+/// buffer rather than element by element.
+///
+/// # Safety
+///
+/// The type must be valid for any bit pattern produced by copying a value of this type, must not
+/// contain pointers or handles, and must not have side effects on drop.
 pub unsafe trait PlainOldData: NonTrivialObject {}
 
 mod private {
@@ -13,6 +22,11 @@ mod private {
 /// reasonably be implemented, and if you need it for your structs and enums, you can use
 /// `#[derive(Object)]`.
 ///
+/// Atomic types, such as [`AtomicU64`](std::sync::atomic::AtomicU64), are transferred as a snapshot
+/// of their value, loaded with [`Ordering::SeqCst`](std::sync::atomic::Ordering::SeqCst). The
+/// receiver gets a new atomic with that value, so updates made by one process are not visible to
+/// the other.
+///
 /// If you need a custom implementation that `#[derive(Object)]` doesn't cover (e.g.: a library type
 /// crossmist has no information about), implement [`NonTrivialObject`]. [`Object`] will be
 /// implemented automatically in this case.
@@ -25,6 +39,15 @@ pub trait Object: private::Sealed {
     fn serialize_self<'a>(&'a self, s: &mut Serializer<'a>);
     /// Serialize an array of objects into a serializer.
     fn serialize_slice<'a>(elements: &'a [Self], s: &mut Serializer<'a>)
+    where
+        Self: Sized;
+    /// Estimate the number of bytes [`Self::serialize_self`] is going to write.
+    ///
+    /// This is exact for plain old data. See [`NonTrivialObject::serialized_size_hint`] for more
+    /// information.
+    fn serialized_size_hint(&self) -> Option<usize>;
+    /// Estimate the number of bytes [`Self::serialize_slice`] is going to write.
+    fn serialized_slice_size_hint(elements: &[Self]) -> Option<usize>
     where
         Self: Sized;
     /// Deserialize a single object from a deserializer.
@@ -35,6 +58,19 @@ pub trait Object: private::Sealed {
     /// deserialization matches, up to serialization layout. See the documentation of
     /// [`Deserializer::deserialize`] for more details.
     unsafe fn deserialize_self(d: &mut Deserializer) -> Result<Self>
+    where
+        Self: Sized;
+    /// Deserialize a single object from a deserializer into an existing object, reusing its
+    /// allocations where possible.
+    ///
+    /// See [`NonTrivialObject::deserialize_into_non_trivial`] for more information.
+    ///
+    /// # Safety
+    ///
+    /// This function is safe to call if the order of serialized types during serialization and
+    /// deserialization matches, up to serialization layout. See the documentation of
+    /// [`Deserializer::deserialize`] for more details.
+    unsafe fn deserialize_into(&mut self, d: &mut Deserializer) -> Result<()>
     where
         Self: Sized;
     #[doc(hidden)]
@@ -53,7 +89,7 @@ impl<T: NonTrivialObject> private::Sealed for T {}
 impl<T: NonTrivialObject> Object for T {
     fn serialize_self<'a>(&'a self, s: &mut Serializer<'a>) {
         if implements!(T: PlainOldData) {
-            s.write(unsafe {
+            s.write_borrowed(unsafe {
                 std::slice::from_raw_parts(self as *const T as *const u8, std::mem::size_of::<T>())
             });
         } else {
@@ -66,7 +102,7 @@ impl<T: NonTrivialObject> Object for T {
         Self: Sized,
     {
         if implements!(T: PlainOldData) {
-            s.write(unsafe {
+            s.write_borrowed(unsafe {
                 std::slice::from_raw_parts(
                     elements.as_ptr() as *const u8,
                     std::mem::size_of_val(elements),
@@ -79,6 +115,27 @@ impl<T: NonTrivialObject> Object for T {
         }
     }
 
+    fn serialized_size_hint(&self) -> Option<usize> {
+        if implements!(T: PlainOldData) {
+            Some(std::mem::size_of::<T>())
+        } else {
+            NonTrivialObject::serialized_size_hint(self)
+        }
+    }
+
+    fn serialized_slice_size_hint(elements: &[Self]) -> Option<usize>
+    where
+        Self: Sized,
+    {
+        if implements!(T: PlainOldData) {
+            Some(std::mem::size_of_val(elements))
+        } else {
+            elements.iter().try_fold(0usize, |size, element| {
+                size.checked_add(NonTrivialObject::serialized_size_hint(element)?)
+            })
+        }
+    }
+
     unsafe fn deserialize_self(d: &mut Deserializer) -> Result<Self>
     where
         Self: Sized,
@@ -95,6 +152,21 @@ impl<T: NonTrivialObject> Object for T {
         }
     }
 
+    unsafe fn deserialize_into(&mut self, d: &mut Deserializer) -> Result<()>
+    where
+        Self: Sized,
+    {
+        if implements!(T: PlainOldData) {
+            d.read(std::slice::from_raw_parts_mut(
+                self as *mut T as *mut u8,
+                std::mem::size_of::<T>(),
+            ));
+            Ok(())
+        } else {
+            self.deserialize_into_non_trivial(d)
+        }
+    }
+
     unsafe fn deserialize_on_heap(d: &mut Deserializer) -> Result<*mut ()>
     where
         Self: Sized,
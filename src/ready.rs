@@ -0,0 +1,90 @@
+//! Waiting for a child to finish initialization.
+//!
+//! A common pattern is to start a server in a child process and wait until it is ready to accept
+//! connections, learning e.g. the port it has bound to. [`ReadySignal`] is passed to the child,
+//! which calls [`ReadySignal::signal`] once it is initialized, and the parent waits for the value
+//! with [`ReadyWaiter::wait`]:
+//!
+//! ```rust
+//! use crossmist::{func, main, ready_signal, ReadySignal};
+//! use std::net::TcpListener;
+//!
+//! #[func]
+//! fn server(ready: ReadySignal<u16>) {
+//!     let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+//!     ready.signal(listener.local_addr().unwrap().port()).unwrap();
+//!     // Accept connections...
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let (signal, waiter) = ready_signal::<u16>().unwrap();
+//!     let child = server.spawn(signal).unwrap();
+//!     let port = waiter.wait().unwrap();
+//!     println!("Listening on port {port}");
+//!     child.join().unwrap();
+//! }
+//! ```
+//!
+//! If the child exits or drops the signal without calling [`ReadySignal::signal`], the wait fails
+//! instead of hanging.
+
+use crate::{
+    asynchronous::{self, AsyncStream},
+    Object,
+};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+
+/// The side of a readiness notification that is passed to the child.
+#[derive(Debug, Object)]
+pub struct ReadySignal<T: Object> {
+    sender: crate::Sender<T>,
+}
+
+impl<T: Object> ReadySignal<T> {
+    /// Notify the parent that the child is ready, passing a value to it.
+    pub fn signal(mut self, value: T) -> Result<()> {
+        self.sender.send(&value)
+    }
+}
+
+/// The side of a readiness notification that waits for the child.
+pub struct ReadyWaiter<Stream: AsyncStream, T: Object> {
+    receiver: asynchronous::Receiver<Stream, T>,
+}
+
+impl<Stream: AsyncStream, T: Object> ReadyWaiter<Stream, T> {
+    /// Wait until the child signals readiness and return the value it passed.
+    ///
+    /// An error is returned if the signal is dropped without being used, e.g. if the child
+    /// terminates.
+    pub async fn wait(mut self) -> Result<T> {
+        self.receiver.recv().await?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                "The subprocess did not signal readiness",
+            )
+        })
+    }
+}
+
+impl<Stream: AsyncStream + fmt::Debug, T: Object> fmt::Debug for ReadyWaiter<Stream, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ReadyWaiter")
+            .field("receiver", &self.receiver)
+            .finish()
+    }
+}
+
+/// Create a readiness notification.
+pub fn ready_signal<Stream: AsyncStream, T: Object>(
+) -> Result<(ReadySignal<T>, ReadyWaiter<Stream, T>)> {
+    let (sender, receiver) = crate::channel()?;
+    Ok((
+        ReadySignal { sender },
+        ReadyWaiter {
+            receiver: receiver.try_into()?,
+        },
+    ))
+}
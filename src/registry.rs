@@ -0,0 +1,146 @@
+//! Serializing trait objects via a registry of implementors.
+//!
+//! `Box<dyn Trait>` implements [`Object`] out of the box if `Trait` has [`Object`] as a supertrait.
+//! This transfers a pointer to the vtable, so any implementor can be passed, but the trait is then
+//! forced to depend on crossmist, and the value is only meaningful to the very same build.
+//!
+//! Alternatively, a closed set of implementors can be registered with [`register_object!`]. Each
+//! type is assigned a tag, which is written before the value, and the receiving side looks up the
+//! type by its tag:
+//!
+//! ```rust
+//! use crossmist::{func, main, register_object, registry::AnyObject, Object};
+//!
+//! trait Shape: AnyObject {
+//!     fn area(&self) -> f64;
+//! }
+//!
+//! #[derive(Object)]
+//! struct Circle {
+//!     radius: f64,
+//! }
+//! impl Shape for Circle {
+//!     fn area(&self) -> f64 {
+//!         std::f64::consts::PI * self.radius * self.radius
+//!     }
+//! }
+//!
+//! #[derive(Object)]
+//! struct Square {
+//!     side: f64,
+//! }
+//! impl Shape for Square {
+//!     fn area(&self) -> f64 {
+//!         self.side * self.side
+//!     }
+//! }
+//!
+//! register_object!(dyn Shape: Circle = "circle", Square = "square");
+//!
+//! #[func]
+//! fn total_area(shapes: Vec<Box<dyn Shape>>) -> f64 {
+//!     shapes.iter().map(|shape| shape.area()).sum()
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let shapes: Vec<Box<dyn Shape>> =
+//!         vec![Box::new(Square { side: 2.0 }), Box::new(Circle { radius: 1.0 })];
+//!     assert_eq!(total_area.run(shapes).unwrap(), 4.0 + std::f64::consts::PI);
+//! }
+//! ```
+//!
+//! If the tag is omitted, the name of the type as written in the macro invocation is used. Tags do
+//! not depend on addresses or on the order of registration. Serializing an unregistered implementor
+//! panics, and receiving an unknown tag fails with [`ErrorKind::InvalidData`].
+
+use crate::{Object, Serializer};
+use std::any::TypeId;
+use std::io::{Error, ErrorKind};
+
+/// A supertrait for traits whose objects are serialized via [`register_object!`].
+///
+/// This trait is implemented automatically for all `'static` types implementing [`Object`].
+pub trait AnyObject {
+    #[doc(hidden)]
+    fn registered_type_id(&self) -> TypeId;
+    #[doc(hidden)]
+    fn registered_type_name(&self) -> &'static str;
+    #[doc(hidden)]
+    fn serialize_registered<'a>(&'a self, s: &mut Serializer<'a>);
+}
+
+impl<T: Object + 'static> AnyObject for T {
+    fn registered_type_id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+    fn registered_type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+    fn serialize_registered<'a>(&'a self, s: &mut Serializer<'a>) {
+        self.serialize_self(s);
+    }
+}
+
+#[doc(hidden)]
+pub fn unregistered_type(type_name: &str, trait_name: &str) -> ! {
+    panic!("{type_name} is not registered as an implementor of {trait_name}")
+}
+
+#[doc(hidden)]
+pub fn unknown_tag(tag: &str, trait_name: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Unknown tag {tag:?} for {trait_name}"),
+    )
+}
+
+/// Implement [`Object`] for a boxed trait object by registering its implementors.
+///
+/// The syntax is `register_object!(dyn Trait: Type1, Type2 = "tag", ...)`. `Trait` must have
+/// [`AnyObject`] as a supertrait, and each type must implement [`Object`]. Tags must be unique
+/// within a trait.
+///
+/// See the documentation for [`mod@crossmist::registry`] for an example.
+#[macro_export]
+macro_rules! register_object {
+    ($trait:ty: $($type:ty $(= $tag:literal)?),+ $(,)?) => {
+        unsafe impl $crate::NonTrivialObject for ::std::boxed::Box<$trait> {
+            fn serialize_self_non_trivial<'a>(&'a self, s: &mut $crate::Serializer<'a>) {
+                let value: &'a $trait = &**self;
+                let id = $crate::registry::AnyObject::registered_type_id(value);
+                $(
+                    if id == ::std::any::TypeId::of::<$type>() {
+                        s.serialize_temporary(::std::string::String::from(
+                            $crate::register_object!(@tag $type $(= $tag)?),
+                        ));
+                        $crate::registry::AnyObject::serialize_registered(value, s);
+                        return;
+                    }
+                )+
+                $crate::registry::unregistered_type(
+                    $crate::registry::AnyObject::registered_type_name(value),
+                    ::std::stringify!($trait),
+                )
+            }
+            unsafe fn deserialize_self_non_trivial(
+                d: &mut $crate::Deserializer,
+            ) -> ::std::io::Result<Self> {
+                let tag: ::std::string::String = d.deserialize()?;
+                $(
+                    if tag == $crate::register_object!(@tag $type $(= $tag)?) {
+                        return Ok(::std::boxed::Box::new(d.deserialize::<$type>()?));
+                    }
+                )+
+                Err($crate::registry::unknown_tag(&tag, ::std::stringify!($trait)))
+            }
+        }
+    };
+    (@tag $type:ty) => {
+        ::std::stringify!($type)
+    };
+    (@tag $type:ty = $tag:literal) => {
+        $tag
+    };
+}
+pub use register_object;
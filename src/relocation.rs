@@ -1,5 +1,9 @@
 use crate::{Deserializer, NonTrivialObject, Serializer};
-use std::io::Result;
+use std::fs::File;
+use std::hash::{DefaultHasher, Hasher};
+use std::io::{Read, Result, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
 
 // This needs to be a singleton to prevent different codegen units from using different copies of
 // the function. See also: https://github.com/alecmocatta/relative/pull/2
@@ -22,9 +26,81 @@ unsafe impl<T> NonTrivialObject for RelocatablePtr<T> {
     fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
         s.serialize_temporary((self.0 as usize).wrapping_sub(BASE_ADDRESS as usize));
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        Some(std::mem::size_of::<usize>())
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         Ok(Self(
             (BASE_ADDRESS as usize).wrapping_add(d.deserialize()?) as *const T
         ))
     }
 }
+
+// Relocatable pointers are only meaningful to the same build of the program, so the parent and the
+// child compare build IDs during the handshake, see handshake.rs. Executables are compared by their
+// size, their headers and their tail, which contains the section table on ELF. This is cheap and
+// detects any change in the layout of the code, while considering copies of the same executable
+// compatible.
+const BUILD_ID_HEAD_LEN: u64 = 4096;
+const BUILD_ID_TAIL_LEN: u64 = 65536;
+
+// On Windows, the executable is opened at startup, so that the ID describes the running image even
+// if the file is replaced later. On Unix-like systems, the path resolved at startup is used, which
+// on Linux is /proc/self/exe and thus always refers to the running image
+#[cfg(windows)]
+static EXECUTABLE: OnceLock<Option<File>> = OnceLock::new();
+
+static CORRUPT_BUILD_ID: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn capture_executable() {
+    #[cfg(windows)]
+    EXECUTABLE.get_or_init(|| std::env::current_exe().and_then(File::open).ok());
+}
+
+fn open_executable() -> Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let path = crate::executable::path()?;
+        File::open(std::ffi::OsStr::from_bytes(path.to_bytes()))
+    }
+    #[cfg(windows)]
+    {
+        EXECUTABLE
+            .get()
+            .and_then(Option::as_ref)
+            .ok_or_else(|| std::io::Error::other("The executable could not be opened at startup"))?
+            .try_clone()
+    }
+}
+
+fn compute_build_id() -> Result<u64> {
+    let mut file = open_executable()?;
+    let len = file.metadata()?.len();
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u64(len);
+    let mut buf = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    (&mut file).take(BUILD_ID_HEAD_LEN).read_to_end(&mut buf)?;
+    file.seek(SeekFrom::Start(len.saturating_sub(BUILD_ID_TAIL_LEN)))?;
+    file.take(BUILD_ID_TAIL_LEN).read_to_end(&mut buf)?;
+    hasher.write(&buf);
+    // Zero means that the ID is unknown
+    Ok(hasher.finish().max(1))
+}
+
+/// Get the ID of the build of the running executable, or zero if it cannot be determined.
+pub(crate) fn build_id() -> u64 {
+    static BUILD_ID: OnceLock<u64> = OnceLock::new();
+    let id = *BUILD_ID.get_or_init(|| compute_build_id().unwrap_or(0));
+    if CORRUPT_BUILD_ID.load(Ordering::Relaxed) {
+        id.wrapping_add(1).max(1)
+    } else {
+        id
+    }
+}
+
+/// Make [`build_id`] return a wrong value in this process, for testing.
+pub(crate) fn corrupt_build_id() {
+    CORRUPT_BUILD_ID.store(true, Ordering::Relaxed);
+}
@@ -0,0 +1,507 @@
+//! Recording and replaying the messages exchanged with child processes.
+//!
+//! Bugs in multi-process programs are often hard to reproduce, as they depend on the order in which
+//! the children happen to respond. This module allows to record the messages a process receives
+//! from (and sends to) its children once, and then replay the exact same interaction without
+//! spawning the children at all.
+//!
+//! Channels to be recorded are opened through a [`Session`]. In replay mode, the closure that
+//! connects to the child is not invoked, and the messages are read from the recording instead:
+//!
+//! ```rust
+//! use crossmist::{channel, func, main, replay::Session, Sender};
+//!
+//! #[func]
+//! fn worker(mut tx: Sender<i32>) {
+//!     for i in 0..3 {
+//!         tx.send(&i).unwrap();
+//!     }
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     // The recording, if any, is made by this very program
+//!     let session = unsafe { Session::from_env() }.unwrap();
+//!     let mut rx = session
+//!         .receiver("worker", || {
+//!             let (tx, rx) = channel::<i32>()?;
+//!             worker.spawn(tx)?;
+//!             Ok(rx)
+//!         })
+//!         .unwrap();
+//!     while let Some(value) = rx.recv().unwrap() {
+//!         println!("{value}");
+//!     }
+//! }
+//! ```
+//!
+//! Running the program with `CROSSMIST_RECORD=log.bin` saves the messages to `log.bin`, and running
+//! it with `CROSSMIST_REPLAY=log.bin` replays them. If neither variable is set, the session is
+//! transparent.
+//!
+//! Messages are matched to channels by the names passed to [`Session`], so the names should be
+//! unique and stable between runs. Messages that contain file descriptors or handles, e.g.
+//! channels, cannot be recorded.
+//!
+//! A recording is only meaningful to the build of the program that made it, as the messages are
+//! stored in the internal serialization format. Recordings made by other builds are rejected if the
+//! build can be identified, but the contents of the recording are trusted otherwise, so replaying
+//! is `unsafe`.
+
+use crate::relocation::build_id;
+use crate::{blocking, Deserializer, Object, Serializer};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MAGIC: [u8; 8] = *b"crossrec";
+
+/// The direction of a recorded message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Object)]
+pub enum Direction {
+    /// The message was sent to the child.
+    Sent,
+    /// The message was received from the child.
+    Received,
+    /// The child closed the channel.
+    Closed,
+}
+
+/// A single recorded event.
+#[derive(Clone, Debug, Object)]
+pub struct Entry {
+    /// The name of the channel.
+    pub channel: String,
+    /// The direction of the message.
+    pub direction: Direction,
+    /// The time since the start of the session.
+    pub elapsed: Duration,
+    /// The serialized message.
+    pub data: Vec<u8>,
+}
+
+/// Read all events from a recording, e.g. to inspect their timing.
+///
+/// Fails with [`ErrorKind::InvalidData`] if the recording was made by a different build of the
+/// program.
+///
+/// # Safety
+///
+/// The recording must have been made by [`Session::record`] in the same build of the program and
+/// not modified since. This cannot be verified if the build of the program cannot be identified,
+/// e.g. if `/proc` is not mounted.
+pub unsafe fn read_log<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 16];
+    file.read_exact(&mut header).map_err(|e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            Error::new(ErrorKind::InvalidData, "The recording is truncated")
+        } else {
+            e
+        }
+    })?;
+    if header[..8] != MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The file is not a crossmist recording",
+        ));
+    }
+    let recorded = u64::from_le_bytes(header[8..].try_into().unwrap());
+    let own = build_id();
+    // An unknown ID on either side, e.g. if /proc is not mounted, cannot be verified
+    if recorded != own && recorded != 0 && own != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The recording was made by a different build of the program",
+        ));
+    }
+    let mut entries = Vec::new();
+    loop {
+        let mut len = [0u8; 8];
+        match file.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(entries),
+            Err(e) => return Err(e),
+        }
+        let len = usize::try_from(u64::from_le_bytes(len))
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Entry is too long"))?;
+        let mut data = vec![0u8; len];
+        file.read_exact(&mut data)?;
+        entries.push(unsafe { Deserializer::new(data, Vec::new()).deserialize()? });
+    }
+}
+
+/// A set of channels that are recorded or replayed together.
+pub struct Session {
+    start: Instant,
+    mode: Mode,
+}
+
+enum Mode {
+    Live,
+    Record(Mutex<BufWriter<File>>),
+    Replay(Mutex<HashMap<String, VecDeque<Entry>>>),
+}
+
+impl Session {
+    /// Create a session according to the environment.
+    ///
+    /// If `CROSSMIST_RECORD` is set, the session records to the file it points to. If
+    /// `CROSSMIST_REPLAY` is set, the session replays from that file. Otherwise, the session is
+    /// transparent.
+    ///
+    /// Child processes inherit the environment, so this function should only be called in the
+    /// process that owns the recording.
+    ///
+    /// # Safety
+    ///
+    /// If `CROSSMIST_REPLAY` is set, the same requirements as for [`Session::replay`] apply.
+    pub unsafe fn from_env() -> Result<Arc<Self>> {
+        match (
+            std::env::var_os("CROSSMIST_RECORD"),
+            std::env::var_os("CROSSMIST_REPLAY"),
+        ) {
+            (Some(_), Some(_)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "CROSSMIST_RECORD and CROSSMIST_REPLAY cannot be set simultaneously",
+            )),
+            (Some(path), None) => Self::record(path),
+            (None, Some(path)) => unsafe { Self::replay(path) },
+            (None, None) => Ok(Self::live()),
+        }
+    }
+
+    /// Create a session that neither records nor replays messages.
+    pub fn live() -> Arc<Self> {
+        Self::new(Mode::Live)
+    }
+
+    /// Create a session that records messages to a file, overwriting it.
+    pub fn record<P: AsRef<Path>>(path: P) -> Result<Arc<Self>> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&MAGIC)?;
+        file.write_all(&build_id().to_le_bytes())?;
+        file.flush()?;
+        Ok(Self::new(Mode::Record(Mutex::new(file))))
+    }
+
+    /// Create a session that replays messages from a file.
+    ///
+    /// Fails with [`ErrorKind::InvalidData`] if the recording was made by a different build of the
+    /// program.
+    ///
+    /// # Safety
+    ///
+    /// The recording must have been made by [`Session::record`] in the same build of the program
+    /// and not modified since, as the recorded messages are deserialized without validation. This
+    /// cannot be verified if the build of the program cannot be identified, e.g. if `/proc` is
+    /// not mounted.
+    pub unsafe fn replay<P: AsRef<Path>>(path: P) -> Result<Arc<Self>> {
+        let mut channels: HashMap<String, VecDeque<Entry>> = HashMap::new();
+        for entry in unsafe { read_log(path)? } {
+            channels
+                .entry(entry.channel.clone())
+                .or_default()
+                .push_back(entry);
+        }
+        Ok(Self::new(Mode::Replay(Mutex::new(channels))))
+    }
+
+    fn new(mode: Mode) -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            mode,
+        })
+    }
+
+    /// Whether the session is replaying a recording.
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.mode, Mode::Replay(_))
+    }
+
+    /// Open a receiving channel.
+    ///
+    /// `connect` is not invoked in replay mode.
+    pub fn receiver<T: Object>(
+        self: &Arc<Self>,
+        name: &str,
+        connect: impl FnOnce() -> Result<blocking::Receiver<T>>,
+    ) -> Result<Receiver<T>> {
+        Ok(Receiver {
+            inner: self.connect(connect)?,
+            tap: self.tap(name),
+        })
+    }
+
+    /// Open a sending channel.
+    ///
+    /// `connect` is not invoked in replay mode. Messages sent during replay are compared to the
+    /// recorded ones.
+    pub fn sender<T: Object>(
+        self: &Arc<Self>,
+        name: &str,
+        connect: impl FnOnce() -> Result<blocking::Sender<T>>,
+    ) -> Result<Sender<T>> {
+        Ok(Sender {
+            inner: self.connect(connect)?,
+            tap: self.tap(name),
+        })
+    }
+
+    /// Open a bidirectional channel.
+    ///
+    /// `connect` is not invoked in replay mode. Messages sent during replay are compared to the
+    /// recorded ones.
+    pub fn duplex<S: Object, R: Object>(
+        self: &Arc<Self>,
+        name: &str,
+        connect: impl FnOnce() -> Result<blocking::Duplex<S, R>>,
+    ) -> Result<Duplex<S, R>> {
+        Ok(Duplex {
+            inner: self.connect(connect)?,
+            tap: self.tap(name),
+        })
+    }
+
+    fn connect<C>(&self, connect: impl FnOnce() -> Result<C>) -> Result<Option<C>> {
+        if self.is_replaying() {
+            Ok(None)
+        } else {
+            connect().map(Some)
+        }
+    }
+
+    fn tap(self: &Arc<Self>, name: &str) -> Tap {
+        Tap {
+            session: self.clone(),
+            channel: name.to_string(),
+        }
+    }
+
+    fn write(&self, entry: &Entry) -> Result<()> {
+        let Mode::Record(file) = &self.mode else {
+            return Ok(());
+        };
+        let mut s = Serializer::new();
+        s.serialize(entry);
+        let data = s.into_vec();
+        let mut file = file.lock().expect("Recording mutex is poisoned");
+        file.write_all(&(data.len() as u64).to_le_bytes())?;
+        file.write_all(&data)?;
+        // Keep the recording usable if the process crashes
+        file.flush()
+    }
+
+    fn next(&self, channel: &str) -> Result<Entry> {
+        let Mode::Replay(channels) = &self.mode else {
+            unreachable!();
+        };
+        channels
+            .lock()
+            .expect("Replay mutex is poisoned")
+            .get_mut(channel)
+            .and_then(|entries| entries.pop_front())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("The recording of channel {channel:?} ends here"),
+                )
+            })
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Live => "Live",
+            Mode::Record(_) => "Record",
+            Mode::Replay(_) => "Replay",
+        };
+        fmt.debug_struct("Session").field("mode", &mode).finish()
+    }
+}
+
+#[derive(Debug)]
+struct Tap {
+    session: Arc<Session>,
+    channel: String,
+}
+
+impl Tap {
+    fn record(&self, direction: Direction, data: Vec<u8>) -> Result<()> {
+        self.session.write(&Entry {
+            channel: self.channel.clone(),
+            direction,
+            elapsed: self.session.start.elapsed(),
+            data,
+        })
+    }
+
+    fn after_send<T: Object>(&self, value: &T) -> Result<()> {
+        match self.session.mode {
+            Mode::Live => Ok(()),
+            Mode::Record(_) => self.record(Direction::Sent, serialize(value)?),
+            Mode::Replay(_) => {
+                let entry = self.session.next(&self.channel)?;
+                if entry.direction != Direction::Sent || entry.data != serialize(value)? {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "The message sent to channel {:?} diverges from the recording",
+                            self.channel,
+                        ),
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn after_recv<T: Object>(&self, value: &Option<T>) -> Result<()> {
+        if !matches!(self.session.mode, Mode::Record(_)) {
+            return Ok(());
+        }
+        match value {
+            Some(value) => self.record(Direction::Received, serialize(value)?),
+            None => self.record(Direction::Closed, Vec::new()),
+        }
+    }
+
+    fn replay_recv<T: Object>(&self) -> Result<Option<T>> {
+        let entry = self.session.next(&self.channel)?;
+        match entry.direction {
+            Direction::Received => unsafe {
+                Deserializer::new(entry.data, Vec::new())
+                    .deserialize()
+                    .map(Some)
+            },
+            Direction::Closed => Ok(None),
+            Direction::Sent => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "A message was sent to channel {:?} at this point of the recording",
+                    self.channel,
+                ),
+            )),
+        }
+    }
+}
+
+fn serialize<T: Object>(value: &T) -> Result<Vec<u8>> {
+    let mut s = Serializer::new();
+    s.serialize(value);
+    if !s.drain_handles().is_empty() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Messages with file descriptors or handles cannot be recorded",
+        ));
+    }
+    Ok(s.into_vec())
+}
+
+/// The receiving side of a recorded channel.
+pub struct Receiver<T: Object> {
+    inner: Option<blocking::Receiver<T>>,
+    tap: Tap,
+}
+
+impl<T: Object> Receiver<T> {
+    /// Receive a value from the child or the recording.
+    ///
+    /// Returns `None` if the child closed the channel.
+    pub fn recv(&mut self) -> Result<Option<T>> {
+        match &mut self.inner {
+            Some(inner) => {
+                let value = inner.recv()?;
+                self.tap.after_recv(&value)?;
+                Ok(value)
+            }
+            None => self.tap.replay_recv(),
+        }
+    }
+}
+
+impl<T: Object> fmt::Debug for Receiver<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Receiver")
+            .field("channel", &self.tap.channel)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The sending side of a recorded channel.
+pub struct Sender<T: Object> {
+    inner: Option<blocking::Sender<T>>,
+    tap: Tap,
+}
+
+impl<T: Object> Sender<T> {
+    /// Send a value to the child, or check it against the recording.
+    pub fn send(&mut self, value: &T) -> Result<()> {
+        if let Some(inner) = &mut self.inner {
+            inner.send(value)?;
+        }
+        self.tap.after_send(value)
+    }
+}
+
+impl<T: Object> fmt::Debug for Sender<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Sender")
+            .field("channel", &self.tap.channel)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A side of a recorded bidirectional channel.
+pub struct Duplex<S: Object, R: Object> {
+    inner: Option<blocking::Duplex<S, R>>,
+    tap: Tap,
+}
+
+impl<S: Object, R: Object> Duplex<S, R> {
+    /// Send a value to the child, or check it against the recording.
+    pub fn send(&mut self, value: &S) -> Result<()> {
+        if let Some(inner) = &mut self.inner {
+            inner.send(value)?;
+        }
+        self.tap.after_send(value)
+    }
+
+    /// Receive a value from the child or the recording.
+    ///
+    /// Returns `None` if the child closed the channel.
+    pub fn recv(&mut self) -> Result<Option<R>> {
+        match &mut self.inner {
+            Some(inner) => {
+                let value = inner.recv()?;
+                self.tap.after_recv(&value)?;
+                Ok(value)
+            }
+            None => self.tap.replay_recv(),
+        }
+    }
+
+    /// Send a value and receive the response.
+    pub fn request(&mut self, value: &S) -> Result<R> {
+        self.send(value)?;
+        self.recv()?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::UnexpectedEof,
+                "The subprocess exitted before responding to the request",
+            )
+        })
+    }
+}
+
+impl<S: Object, R: Object> fmt::Debug for Duplex<S, R> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Duplex")
+            .field("channel", &self.tap.channel)
+            .finish_non_exhaustive()
+    }
+}
@@ -5,6 +5,9 @@
 
 use crate::{
     handles::{BorrowedHandle, OwnedHandle},
+    imp::implements,
+    pod::PlainOldData,
+    shm::ReadOnlyMapping,
     Object,
 };
 use std::any::Any;
@@ -20,8 +23,12 @@ use std::os::raw::c_void;
 /// descriptors inside the object for `'fd`.
 pub struct Serializer<'fd> {
     data: Vec<u8>,
+    // Large chunks that are not copied into `data`, along with the offsets in `data` they precede
+    borrowed: Vec<(usize, &'fd [u8])>,
     handles: Vec<BorrowedHandle<'fd>>,
     cyclic_ids: HashMap<*const c_void, NonZeroUsize>,
+    // Whether `data` has been reserved for the whole object, so that borrowed chunks are copied
+    inline_borrowed: bool,
 }
 
 impl<'fd> Serializer<'fd> {
@@ -29,8 +36,23 @@ impl<'fd> Serializer<'fd> {
     pub fn new() -> Self {
         Serializer {
             data: Vec::new(),
+            borrowed: Vec::new(),
             handles: Vec::new(),
             cyclic_ids: HashMap::new(),
+            inline_borrowed: false,
+        }
+    }
+
+    /// Create a new serializer that writes to `buffer`, reusing its allocation.
+    ///
+    /// The contents of `buffer` are discarded. This saves an allocation per message when many
+    /// messages are serialized in a row: get the buffer back with [`Serializer::into_vec`] and pass
+    /// it to the next serializer.
+    pub fn with_buffer(mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
+        Serializer {
+            data: buffer,
+            ..Self::new()
         }
     }
 
@@ -39,8 +61,30 @@ impl<'fd> Serializer<'fd> {
         self.data.extend_from_slice(data);
     }
 
+    /// Append chunk of serialized data borrowed for `'fd`.
+    ///
+    /// This is equivalent to [`Serializer::write`], but large chunks are not copied into the
+    /// serializer and are sent directly from `data` where possible.
+    pub fn write_borrowed(&mut self, data: &'fd [u8]) {
+        let fits = data.len() <= self.data.capacity() - self.data.len();
+        if data.len() < BORROW_THRESHOLD || (self.inline_borrowed && fits) {
+            self.write(data);
+        } else {
+            self.borrowed.push((self.data.len(), data));
+        }
+    }
+
     /// Append serialized data of an object.
+    ///
+    /// If this is the first object written to the serializer, capacity for it is reserved up
+    /// front according to [`Object::serialized_size_hint`].
     pub fn serialize<T: Object>(&mut self, data: &'fd T) {
+        if self.data.is_empty() && self.borrowed.is_empty() {
+            if let Some(size) = Object::serialized_size_hint(data) {
+                self.data.reserve(size);
+                self.inline_borrowed = true;
+            }
+        }
         data.serialize_self(self);
     }
 
@@ -54,6 +98,13 @@ impl<'fd> Serializer<'fd> {
     ///
     /// Panics if the object contains file handles.
     pub fn serialize_temporary<T: Object>(&mut self, data: T) {
+        if implements!(T: PlainOldData) {
+            // Plain old data contains no handles and can be copied without an intermediate buffer
+            self.write(unsafe {
+                std::slice::from_raw_parts(&data as *const T as *const u8, std::mem::size_of::<T>())
+            });
+            return;
+        }
         let mut s1 = Serializer::new();
         s1.serialize(&data);
         assert!(
@@ -87,8 +138,93 @@ impl<'fd> Serializer<'fd> {
 
     /// Extract serialized data.
     pub fn into_vec(self) -> Vec<u8> {
+        self.into_segments().into_vec()
+    }
+
+    pub(crate) fn into_segments(self) -> Segments<'fd> {
+        Segments {
+            data: self.data,
+            borrowed: self.borrowed,
+        }
+    }
+}
+
+// Chunks shorter than this are cheaper to copy than to send separately
+const BORROW_THRESHOLD: usize = 16384;
+
+// Serialization buffers that have grown larger than this are freed after a message is sent rather
+// than kept for the next one
+const MAX_RETAINED_BUFFER: usize = 1 << 20;
+
+/// Keep `buffer` in `slot` for serializing the next message if it is worth it.
+pub(crate) fn retain_buffer(slot: &mut Vec<u8>, mut buffer: Vec<u8>) {
+    if buffer.capacity() > slot.capacity() && buffer.capacity() <= MAX_RETAINED_BUFFER {
+        buffer.clear();
+        *slot = buffer;
+    }
+}
+
+/// Serialized data that might borrow large chunks from the serialized object.
+pub(crate) struct Segments<'fd> {
+    data: Vec<u8>,
+    borrowed: Vec<(usize, &'fd [u8])>,
+}
+
+impl<'fd> Segments<'fd> {
+    pub(crate) fn borrowed(data: &'fd [u8]) -> Self {
+        Self {
+            data: Vec::new(),
+            borrowed: vec![(0, data)],
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.data.len()
+            + self
+                .borrowed
+                .iter()
+                .map(|(_, chunk)| chunk.len())
+                .sum::<usize>()
+    }
+
+    /// The data as a sequence of slices, in order, without empty slices.
+    pub(crate) fn slices(&self) -> Vec<&[u8]> {
+        let mut slices = Vec::with_capacity(self.borrowed.len() * 2 + 1);
+        let mut pos = 0;
+        for &(offset, chunk) in &self.borrowed {
+            slices.push(&self.data[pos..offset]);
+            slices.push(chunk);
+            pos = offset;
+        }
+        slices.push(&self.data[pos..]);
+        slices.retain(|slice| !slice.is_empty());
+        slices
+    }
+
+    /// Take the buffer the serializer wrote to, without the borrowed chunks.
+    pub(crate) fn into_buffer(self) -> Vec<u8> {
         self.data
     }
+
+    pub(crate) fn into_vec(self) -> Vec<u8> {
+        if self.borrowed.is_empty() {
+            return self.data;
+        }
+        let mut data = Vec::with_capacity(self.len());
+        for slice in self.slices() {
+            data.extend_from_slice(slice);
+        }
+        data
+    }
+}
+
+impl From<Vec<u8>> for Segments<'_> {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            borrowed: Vec::new(),
+        }
+    }
 }
 
 impl fmt::Debug for Serializer<'_> {
@@ -108,16 +244,18 @@ impl IntoIterator for Serializer<'_> {
     type Item = u8;
     type IntoIter = <Vec<u8> as IntoIterator>::IntoIter;
     fn into_iter(self) -> Self::IntoIter {
-        self.data.into_iter()
+        self.into_vec().into_iter()
     }
 }
 
 /// Stateful deserialization.
 pub struct Deserializer {
     data: Vec<u8>,
+    // Used instead of `data` if the message is read from shared memory
+    mapped: Option<ReadOnlyMapping>,
     pub(crate) handles: std::vec::IntoIter<OwnedHandle>,
     pos: usize,
-    cyclics: Vec<Box<dyn Any>>,
+    cyclics: Vec<Option<Box<dyn Any>>>,
 }
 
 impl Deserializer {
@@ -125,18 +263,38 @@ impl Deserializer {
     pub fn new(data: Vec<u8>, handles: Vec<OwnedHandle>) -> Self {
         Deserializer {
             data,
+            mapped: None,
             handles: handles.into_iter(),
             pos: 0,
             cyclics: Vec::new(),
         }
     }
 
+    /// Start deserializing data in shared memory, without copying it.
+    pub(crate) fn from_mapping(mapping: ReadOnlyMapping, handles: Vec<OwnedHandle>) -> Self {
+        Deserializer {
+            mapped: Some(mapping),
+            ..Self::new(Vec::new(), handles)
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.mapped {
+            Some(ref mapping) => mapping.as_slice(),
+            None => &self.data,
+        }
+    }
+
     /// Fill the buffer from internal data.
     pub fn read(&mut self, data: &mut [u8]) {
-        data.clone_from_slice(&self.data[self.pos..self.pos + data.len()]);
+        data.clone_from_slice(&self.bytes()[self.pos..self.pos + data.len()]);
         self.pos += data.len();
     }
 
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes().len() - self.pos
+    }
+
     /// Deserialize an object of a given type from `self`.
     ///
     /// Note that the deserializer is not safe to call on untrusted or corrupted data. This function
@@ -185,21 +343,77 @@ impl Deserializer {
         T::deserialize_self(self)
     }
 
+    /// Deserialize an object of a given type from `self` into an existing object.
+    ///
+    /// This is equivalent to `*target = self.deserialize()?`, except that the allocations owned by
+    /// `target` may be reused. See [`NonTrivialObject::deserialize_into_non_trivial`].
+    ///
+    /// # Safety
+    ///
+    /// The same requirements as for [`deserialize`](Self::deserialize) apply.
+    pub unsafe fn deserialize_into<T: Object>(&mut self, target: &mut T) -> Result<()> {
+        target.deserialize_into(self)
+    }
+
     /// Store a reference to a newly built potentially cyclic object.
     pub fn learn_cyclic<T: 'static>(&mut self, obj: T) {
-        self.cyclics.push(Box::new(obj));
+        self.cyclics.push(Some(Box::new(obj)));
+    }
+
+    /// Allocate an index for a potentially cyclic object before deserializing its contents.
+    ///
+    /// [`Serializer::learn_cyclic`] numbers objects in the order they are first encountered, i.e.
+    /// an object is numbered before the objects it contains. Use this method and
+    /// [`Deserializer::fill_cyclic`] to number objects in the same order.
+    pub fn reserve_cyclic(&mut self) -> NonZeroUsize {
+        self.cyclics.push(None);
+        NonZeroUsize::new(self.cyclics.len()).unwrap()
+    }
+
+    /// Store a reference to an object whose index was allocated with
+    /// [`Deserializer::reserve_cyclic`].
+    pub fn fill_cyclic<T: 'static>(&mut self, id: NonZeroUsize, obj: T) {
+        self.cyclics[id.get() - 1] = Some(Box::new(obj));
     }
 
     /// Get a reference to an object built earlier.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the object does not exist, has not been built yet, or has a different type. Use
+    /// [`Deserializer::try_get_cyclic`] to handle such corrupted data gracefully.
     pub fn get_cyclic<T: 'static>(&self, id: NonZeroUsize) -> &T {
-        self.cyclics[id.get() - 1]
+        self.try_get_cyclic(id).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Get a reference to an object built earlier, failing on corrupted references.
+    ///
+    /// An error is returned if the object does not exist, has a different type, or is referenced
+    /// from its own contents, i.e. the data encodes a reference cycle.
+    pub fn try_get_cyclic<T: 'static>(&self, id: NonZeroUsize) -> Result<&T> {
+        let invalid = |message| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+        self.cyclics
+            .get(id.get() - 1)
+            .ok_or_else(|| invalid("The cyclic object does not exist"))?
+            .as_ref()
+            .ok_or_else(|| invalid("The cyclic object is referenced before it is built"))?
             .downcast_ref()
-            .expect("The cyclic object is of unexpected type")
+            .ok_or_else(|| invalid("The cyclic object is of unexpected type"))
+    }
+
+    #[cfg(feature = "capture")]
+    pub(crate) fn data(&self) -> &[u8] {
+        self.bytes()
     }
 
     #[cfg(windows)]
     pub(crate) fn get_rest(&self) -> &[u8] {
-        &self.data[self.pos..]
+        &self.bytes()[self.pos..]
+    }
+
+    /// Take the data back to reuse the buffer. The buffer is empty if the data was mapped.
+    pub(crate) fn into_data(self) -> Vec<u8> {
+        self.data
     }
 }
 
@@ -360,4 +574,37 @@ pub unsafe trait NonTrivialObject: Sized {
     /// deserialization matches, up to serialization layout. See the documentation of
     /// [`Deserializer::deserialize`] for more details.
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self>;
+    /// Estimate the number of bytes [`Self::serialize_self_non_trivial`] is going to write.
+    ///
+    /// [`Serializer`] uses this to allocate its buffer once instead of growing it as the object is
+    /// serialized. The hint must be cheap to compute, and should be exact if it is provided at all.
+    /// The default implementation returns `None`, meaning that the size is unknown.
+    ///
+    /// crossmist implements this method for strings, collections and other containers whose
+    /// elements provide a hint, and `#[derive(Object)]` sums up the hints of the fields.
+    fn serialized_size_hint(&self) -> Option<usize> {
+        None
+    }
+    /// Deserialize a single object from a deserializer into an existing object.
+    ///
+    /// This lets types reuse the allocations they own, e.g. the capacity of a `Vec` or a `String`,
+    /// when values of similar shape are received repeatedly, see
+    /// [`Receiver::recv_ref`](crate::Receiver::recv_ref). The default implementation deserializes a
+    /// new object with [`Self::deserialize_self_non_trivial`] and overwrites `self`.
+    ///
+    /// crossmist overrides this method for [`Vec`] and [`String`], reusing both the buffer and the
+    /// existing elements, so that e.g. `Vec<Vec<u8>>` keeps the inner buffers too.
+    /// `#[derive(Object)]` forwards it to the fields of structs. Other types, e.g. maps and enums,
+    /// are rebuilt from scratch. If this function fails, `self` is left in a valid but unspecified
+    /// state.
+    ///
+    /// # Safety
+    ///
+    /// This function is safe to call if the order of serialized types during serialization and
+    /// deserialization matches, up to serialization layout. See the documentation of
+    /// [`Deserializer::deserialize`] for more details.
+    unsafe fn deserialize_into_non_trivial(&mut self, d: &mut Deserializer) -> Result<()> {
+        *self = Self::deserialize_self_non_trivial(d)?;
+        Ok(())
+    }
 }
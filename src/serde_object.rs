@@ -0,0 +1,80 @@
+//! A wrapper for passing types implementing [serde](https://serde.rs) traits.
+//!
+//! Types from other crates often implement [`Serialize`] and [`Deserialize`](serde::Deserialize),
+//! but not [`Object`](crate::Object), and the derive macro cannot be applied to foreign types.
+//! [`SerdeObject`] allows to pass such values anyway:
+//!
+//! ```rust
+//! use crossmist::{func, main, SerdeObject};
+//! use serde_json::{json, Value};
+//!
+//! #[main]
+//! fn main() {
+//!     let value = json!({ "name": "crossmist", "tags": ["ipc"] });
+//!     assert_eq!(name.run(SerdeObject(value)).unwrap(), "crossmist");
+//! }
+//!
+//! #[func]
+//! fn name(value: SerdeObject<Value>) -> String {
+//!     value["name"].as_str().unwrap().to_string()
+//! }
+//! ```
+//!
+//! The value is encoded with [MessagePack](https://msgpack.org), which is self-describing, so types
+//! that rely on [`Deserializer::deserialize_any`](::serde::Deserializer::deserialize_any), e.g.
+//! `serde_json::Value` or untagged enums, are supported.
+//!
+//! Only plain data can be passed this way. crossmist types carrying handles, such as channels and
+//! files, do not implement serde traits, so they cannot be put inside a `SerdeObject`; pass them
+//! alongside it instead.
+
+use crate::{Deserializer, NonTrivialObject, Serializer};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{Deref, DerefMut};
+
+/// A wrapper that implements [`Object`](crate::Object) for types implementing serde traits.
+///
+/// Serialization panics if the [`Serialize`] implementation of the inner value fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SerdeObject<T: Serialize + DeserializeOwned>(pub T);
+
+impl<T: Serialize + DeserializeOwned> SerdeObject<T> {
+    /// Unwrap the value.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> From<T> for SerdeObject<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Deref for SerdeObject<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> DerefMut for SerdeObject<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+unsafe impl<T: Serialize + DeserializeOwned> NonTrivialObject for SerdeObject<T> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        let data = rmp_serde::to_vec(&self.0).expect("Failed to serialize SerdeObject");
+        s.serialize_temporary(data.len());
+        s.write(&data);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let data: Vec<u8> = d.deserialize()?;
+        rmp_serde::from_slice(&data)
+            .map(Self)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
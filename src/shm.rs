@@ -0,0 +1,348 @@
+//! Shared memory buffers.
+//!
+//! Large data is sent over channels by copying it through the kernel. [`SharedBuffer`] avoids this:
+//! the buffer lives in shared memory, and passing it to another process only transfers a handle,
+//! after which both processes map the same memory:
+//!
+//! ```rust
+//! use crossmist::{func, main, shm::SharedBuffer};
+//!
+//! #[func]
+//! fn fill(mut buffer: SharedBuffer) -> SharedBuffer {
+//!     // The parent does not access the buffer until the child returns
+//!     unsafe { buffer.as_mut_slice() }.fill(7);
+//!     buffer
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let buffer = SharedBuffer::new(1 << 20).unwrap();
+//!     let buffer = fill.run(buffer).unwrap();
+//!     // The child has exited, so nothing else accesses the buffer
+//!     assert!(unsafe { buffer.as_slice() }.iter().all(|&x| x == 7));
+//! }
+//! ```
+//!
+//! The memory is released once all processes drop their copies of the buffer.
+//!
+//! To pass the same large read-mostly value, e.g. a configuration or an index, to many children,
+//! use [`Snapshot`]. The value is serialized into shared memory once, and each child maps the same
+//! pages read-only:
+//!
+//! ```rust
+//! use crossmist::{func, main, shm::Snapshot};
+//! use std::collections::HashMap;
+//!
+//! #[func]
+//! fn lookup(index: Snapshot<HashMap<String, u32>>, key: String) -> Option<u32> {
+//!     index.get().unwrap().get(&key).copied()
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let index = HashMap::from([("one".to_string(), 1), ("two".to_string(), 2)]);
+//!     let index = Snapshot::new(&index).unwrap();
+//!     assert_eq!(lookup.run(index.try_clone().unwrap(), "two".to_string()).unwrap(), Some(2));
+//!     assert_eq!(lookup.run(index, "three".to_string()).unwrap(), None);
+//! }
+//! ```
+
+use crate::{
+    handles::{AsHandle, OwnedHandle},
+    imp::implements,
+    internals::{
+        create_sealed_buffer, create_shared_buffer, map_private_buffer, map_shared_buffer,
+        unmap_shared_buffer,
+    },
+    pod::PlainOldData,
+    Deserializer, NonTrivialObject, Object, Serializer,
+};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::ptr::NonNull;
+use std::sync::OnceLock;
+
+/// A fixed-size byte buffer in memory shared between processes.
+///
+/// Each copy of the buffer, whether obtained by passing it to another process or via
+/// [`SharedBuffer::try_clone`], refers to the same memory, and writes via one copy are visible via
+/// the others.
+///
+/// As other copies can modify the memory at any time, even from other processes, the contents are
+/// only accessible via `unsafe` methods. The caller has to make sure that the copies are not
+/// accessed simultaneously if one of them writes to the buffer, e.g. by passing messages over a
+/// channel or by moving the buffer back and forth.
+pub struct SharedBuffer {
+    handle: OwnedHandle,
+    // Dangling if the buffer is empty, as empty mappings are not supported
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+unsafe impl Send for SharedBuffer {}
+unsafe impl Sync for SharedBuffer {}
+
+impl SharedBuffer {
+    /// Allocate a zero-filled buffer.
+    pub fn new(len: usize) -> Result<Self> {
+        Self::map(create_shared_buffer(len)?, len)
+    }
+
+    /// Allocate a buffer holding a copy of `data`.
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let mut buffer = Self::new(data.len())?;
+        // The buffer has not been cloned yet
+        unsafe { buffer.as_mut_slice() }.copy_from_slice(data);
+        Ok(buffer)
+    }
+
+    fn map(handle: OwnedHandle, len: usize) -> Result<Self> {
+        let ptr = if len == 0 {
+            NonNull::dangling()
+        } else {
+            NonNull::new(map_shared_buffer(&handle, len)?).expect("Mapped a null pointer")
+        };
+        Ok(Self { handle, ptr, len })
+    }
+
+    /// Create another copy of the buffer, referring to the same memory.
+    pub fn try_clone(&self) -> Result<Self> {
+        Self::map(self.handle.try_clone()?, self.len)
+    }
+
+    /// The size of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Access the contents of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// While the returned slice is alive, no other copy of the buffer, in this or any other
+    /// process, may be written to.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Access the contents of the buffer mutably.
+    ///
+    /// # Safety
+    ///
+    /// While the returned slice is alive, no other copy of the buffer, in this or any other
+    /// process, may be accessed.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Copy the contents of the buffer to a vector and release this copy of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// No other copy of the buffer, in this or any other process, may be written to during the
+    /// call.
+    pub unsafe fn into_vec(self) -> Vec<u8> {
+        unsafe { self.as_slice() }.to_vec()
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                unmap_shared_buffer(self.ptr.as_ptr(), self.len);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SharedBuffer {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("SharedBuffer")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl NonTrivialObject for SharedBuffer {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize_handle(self.handle.as_handle());
+        s.serialize_temporary(self.len);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let handle = d.deserialize()?;
+        let len = d.deserialize()?;
+        Self::map(handle, len)
+    }
+}
+
+/// A private read-only mapping of a shared memory object, released when dropped.
+///
+/// Messages that are passed via shared memory are deserialized from such a mapping directly.
+pub(crate) struct ReadOnlyMapping {
+    _handle: OwnedHandle,
+    // Dangling if the mapping is empty, as empty mappings are not supported
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+unsafe impl Send for ReadOnlyMapping {}
+unsafe impl Sync for ReadOnlyMapping {}
+
+impl ReadOnlyMapping {
+    /// Map the first `len` bytes of a shared memory object.
+    pub(crate) fn new(handle: OwnedHandle, len: usize) -> Result<Self> {
+        let ptr = if len == 0 {
+            NonNull::dangling()
+        } else {
+            NonNull::new(map_private_buffer(&handle, len)?).expect("Mapped a null pointer")
+        };
+        Ok(Self {
+            _handle: handle,
+            ptr,
+            len,
+        })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for ReadOnlyMapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                unmap_shared_buffer(self.ptr.as_ptr(), self.len);
+            }
+        }
+    }
+}
+
+/// A read-only snapshot of a value in shared memory.
+///
+/// The value is serialized once, when the snapshot is created. Passing the snapshot to another
+/// process only transfers a handle, after which the process maps the serialized value privately
+/// and read-only, so the pages are shared until the process exits, and neither the creator of the
+/// snapshot nor its recipients can modify them. On Linux, this is enforced by sealing the memory
+/// file.
+///
+/// Plain old data is accessed in place. Other types are deserialized from the shared pages the
+/// first time [`Snapshot::get`] is called in each process, and the result is kept until the
+/// snapshot is dropped.
+///
+/// The value must not contain file handles.
+pub struct Snapshot<T: Object> {
+    handle: OwnedHandle,
+    // Dangling if the snapshot is empty, as empty mappings are not supported
+    ptr: NonNull<u8>,
+    len: usize,
+    value: OnceLock<T>,
+}
+
+unsafe impl<T: Object + Send> Send for Snapshot<T> {}
+unsafe impl<T: Object + Send + Sync> Sync for Snapshot<T> {}
+
+impl<T: Object> Snapshot<T> {
+    /// Serialize a value into a new snapshot.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the value contains file handles.
+    pub fn new(value: &T) -> Result<Self> {
+        let mut s = Serializer::new();
+        s.serialize(value);
+        if !s.drain_handles().is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Snapshots cannot contain file handles",
+            ));
+        }
+        let data = s.into_vec();
+        Self::map(create_sealed_buffer(&data)?, data.len())
+    }
+
+    fn map(handle: OwnedHandle, len: usize) -> Result<Self> {
+        if implements!(T: PlainOldData) && len != std::mem::size_of::<T>() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Snapshot size does not match the type",
+            ));
+        }
+        let ptr = if len == 0 {
+            NonNull::dangling()
+        } else {
+            NonNull::new(map_private_buffer(&handle, len)?).expect("Mapped a null pointer")
+        };
+        Ok(Self {
+            handle,
+            ptr,
+            len,
+            value: OnceLock::new(),
+        })
+    }
+
+    /// Create another copy of the snapshot, referring to the same memory.
+    pub fn try_clone(&self) -> Result<Self> {
+        Self::map(self.handle.try_clone()?, self.len)
+    }
+
+    /// Access the value.
+    ///
+    /// Returns an error if the value cannot be deserialized.
+    pub fn get(&self) -> Result<&T> {
+        if implements!(T: PlainOldData) {
+            // Mappings are page-aligned, and the size has been checked in map()
+            let ptr = if self.len == 0 {
+                NonNull::<T>::dangling().as_ptr()
+            } else {
+                self.ptr.as_ptr() as *const T
+            };
+            return Ok(unsafe { &*ptr });
+        }
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        let mut d = Deserializer::new(self.as_bytes().to_vec(), Vec::new());
+        let value = unsafe { d.deserialize()? };
+        Ok(self.value.get_or_init(|| value))
+    }
+
+    /// The serialized value.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Object> Drop for Snapshot<T> {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                unmap_shared_buffer(self.ptr.as_ptr(), self.len);
+            }
+        }
+    }
+}
+
+impl<T: Object> fmt::Debug for Snapshot<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Snapshot")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl<T: Object> NonTrivialObject for Snapshot<T> {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize_handle(self.handle.as_handle());
+        s.serialize_temporary(self.len);
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let handle = d.deserialize()?;
+        let len = d.deserialize()?;
+        Self::map(handle, len)
+    }
+}
@@ -5,9 +5,15 @@
 use crate::{
     asynchronous,
     handles::{AsHandle, AsRawHandle, BorrowedHandle, RawHandle},
-    FnOnceObject, Object,
+    multiplex,
+    ready::{self, ReadySignal},
+    ChannelOptions, FnOnceObject, JoinError, Object, SpawnOptions,
 };
+use std::future::Future;
 use std::io::Result;
+#[cfg(unix)]
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 /// `smol` marker type.
 #[derive(Debug, Object)]
@@ -42,6 +48,20 @@ unsafe impl asynchronous::AsyncStream for Smol {
     async fn blocking_write<T>(&self, mut f: impl FnMut() -> Result<T> + Send) -> Result<T> {
         self.0.write_with(|_| f()).await
     }
+    #[cfg(unix)]
+    fn poll_blocking_write<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>> {
+        loop {
+            match f() {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+            std::task::ready!(self.0.poll_writable(cx))?;
+        }
+    }
     #[cfg(windows)]
     async fn write(&mut self, buf: &[u8]) -> Result<()> {
         use futures_lite::io::AsyncWriteExt;
@@ -53,12 +73,49 @@ unsafe impl asynchronous::AsyncStream for Smol {
     async fn blocking_read<T>(&self, mut f: impl FnMut() -> Result<T> + Send) -> Result<T> {
         self.0.read_with(|_| f()).await
     }
+    #[cfg(unix)]
+    fn poll_blocking_read<T>(
+        &self,
+        cx: &mut Context<'_>,
+        mut f: impl FnMut() -> Result<T>,
+    ) -> Poll<Result<T>> {
+        loop {
+            match f() {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+            std::task::ready!(self.0.poll_readable(cx))?;
+        }
+    }
     #[cfg(windows)]
     async fn read(&mut self, buf: &mut [u8]) -> Result<()> {
         use futures_lite::io::AsyncReadExt;
         self.0.read_exact(buf).await?;
         Ok(())
     }
+
+    async fn wait_readable(&self, timeout: Duration) -> Result<bool> {
+        #[cfg(unix)]
+        {
+            futures_lite::future::or(async { self.0.readable().await.map(|()| true) }, async {
+                async_io::Timer::after(timeout).await;
+                Ok(false)
+            })
+            .await
+        }
+        #[cfg(windows)]
+        {
+            // Anonymous pipes cannot be waited for, so the pipe is checked every millisecond
+            let deadline = std::time::Instant::now().checked_add(timeout);
+            while !crate::internals::is_pipe_readable(self.0.as_raw_handle()) {
+                if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+                    return Ok(false);
+                }
+                async_io::Timer::after(Duration::from_millis(1)).await;
+            }
+            Ok(true)
+        }
+    }
 }
 
 /// The transmitting side of a unidirectional channel.
@@ -77,22 +134,106 @@ pub type Receiver<T> = asynchronous::Receiver<Smol, T>;
 /// is the type of the objects the other side sends via the channel and this side receives.
 pub type Duplex<S, R> = asynchronous::Duplex<Smol, S, R>;
 
+/// A side of a bidirectional channel that supports concurrent requests.
+///
+/// See [`multiplex`] for more information.
+pub type MultiplexedDuplex<S, R> = multiplex::MultiplexedDuplex<Smol, S, R>;
+
+/// A side of a bidirectional channel that can send and receive concurrently.
+///
+/// See [`asynchronous::SharedDuplex`] for more information.
+pub type SharedDuplex<S, R> = asynchronous::SharedDuplex<Smol, S, R>;
+
+/// The side of a readiness notification that waits for the child.
+///
+/// See [`ready`] for more information.
+pub type ReadyWaiter<T> = ready::ReadyWaiter<Smol, T>;
+
 /// The subprocess object created by calling `spawn_smol` on a function annotated with `#[func]`.
 pub type Child<T> = asynchronous::Child<Smol, T>;
 
+/// A subprocess created by calling `spawn_smol_deferred_with` on a function annotated with
+/// `#[func]`, which has not received the function to run yet.
+pub type PendingChild<T> = asynchronous::PendingChild<Smol, T>;
+
+/// The subprocess object created by calling `spawn_smol` on a streaming function annotated with
+/// `#[func]`.
+///
+/// See [`asynchronous::ChildStream`] for more information.
+pub type ChildStream<T> = asynchronous::ChildStream<Smol, T>;
+
+/// The reading end of a pipe connected to the standard output or error of a child.
+///
+/// See [`asynchronous::ChildPipe`] for more information.
+pub type ChildPipe = asynchronous::ChildPipe<Smol>;
+
 /// Create a unidirectional channel.
 pub fn channel<T: Object>() -> Result<(Sender<T>, Receiver<T>)> {
     asynchronous::channel::<Smol, T>()
 }
 
+/// Create a unidirectional channel with custom options.
+pub fn channel_with<T: Object>(options: &ChannelOptions) -> Result<(Sender<T>, Receiver<T>)> {
+    asynchronous::channel_with::<Smol, T>(options)
+}
+
 /// Create a bidirectional channel.
 pub fn duplex<A: Object, B: Object>() -> Result<(Duplex<A, B>, Duplex<B, A>)> {
     asynchronous::duplex::<Smol, A, B>()
 }
 
+/// Create a bidirectional channel with custom options.
+pub fn duplex_with<A: Object, B: Object>(
+    options: &ChannelOptions,
+) -> Result<(Duplex<A, B>, Duplex<B, A>)> {
+    asynchronous::duplex_with::<Smol, A, B>(options)
+}
+
+/// Create a readiness notification.
+pub fn ready_signal<T: Object>() -> Result<(ReadySignal<T>, ReadyWaiter<T>)> {
+    ready::ready_signal::<Smol, T>()
+}
+
+/// Receive a value from whichever of `receivers` has one available first.
+///
+/// See [`asynchronous::select_recv`] for more information.
+pub async fn select_recv<T: Object>(
+    receivers: &mut [&mut Receiver<T>],
+) -> Result<(usize, Option<T>)> {
+    asynchronous::select_recv(receivers).await
+}
+
+/// Wait for several processes to finish, yielding their results in the order they finish.
+///
+/// See [`asynchronous::join_all_in_order`] for more information.
+pub fn join_all_in_order<T: Object>(
+    children: impl IntoIterator<Item = Child<T>>,
+) -> asynchronous::JoinAllInOrder<impl Future<Output = std::result::Result<T, JoinError>>> {
+    asynchronous::join_all_in_order(children)
+}
+
 #[doc(hidden)]
 pub async unsafe fn spawn<T: Object>(
     entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
 ) -> Result<Child<T>> {
-    asynchronous::spawn::<Smol, T>(entry).await
+    asynchronous::spawn::<Smol, T>(entry, options).await
+}
+
+#[doc(hidden)]
+pub async unsafe fn spawn_stream<T: Object>(
+    entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
+) -> Result<ChildStream<T>> {
+    asynchronous::spawn::<Smol, T>(entry, options)
+        .await
+        .map(ChildStream::new)
+}
+
+#[doc(hidden)]
+pub unsafe fn spawn_deferred<T: Object>(
+    entry: Box<dyn FnOnceObject<(RawHandle,), Output = i32>>,
+    options: &SpawnOptions,
+) -> Result<PendingChild<T>> {
+    asynchronous::spawn_deferred::<Smol, T>(entry, options)
 }
@@ -0,0 +1,195 @@
+//! Passing points in time between processes.
+//!
+//! [`Duration`], [`SystemTime`] and [`Instant`] implement [`Object`](crate::Object) and can be
+//! passed as is. On Linux, macOS and Windows, an [`Instant`] is a reading of a monotonic clock
+//! shared by the whole system, so instants from different processes can be compared:
+//!
+//! ```rust
+//! use crossmist::{func, main};
+//! use std::time::{Duration, Instant};
+//!
+//! #[func]
+//! fn remaining(deadline: Instant) -> bool {
+//!     deadline > Instant::now()
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     assert!(remaining.run(Instant::now() + Duration::from_secs(60)).unwrap());
+//! }
+//! ```
+//!
+//! The standard library does not guarantee this on other platforms. [`MonotonicInstant`] is an
+//! opt-in helper that does not rely on it: it is passed as wall-clock time, and maps all points of
+//! a process consistently, so that intervals are preserved exactly, e.g. to attribute latency of a
+//! request:
+//!
+//! ```rust
+//! use crossmist::{func, main, MonotonicInstant};
+//! use std::time::Duration;
+//!
+//! #[func]
+//! fn queued_for(sent: MonotonicInstant) -> Duration {
+//!     sent.elapsed()
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let latency = queued_for.run(MonotonicInstant::now()).unwrap();
+//!     assert!(latency < Duration::from_secs(60));
+//! }
+//! ```
+
+use crate::{Deserializer, NonTrivialObject, Serializer};
+use std::io::Result;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+
+// The monotonic clock of the process and the wall-clock time at the same moment, captured once so
+// that all conversions of MonotonicInstant use the same mapping
+static REFERENCE: OnceLock<(Instant, SystemTime)> = OnceLock::new();
+
+fn reference() -> (Instant, SystemTime) {
+    *REFERENCE.get_or_init(|| (Instant::now(), SystemTime::now()))
+}
+
+fn instant_to_system_time(instant: Instant) -> SystemTime {
+    let (reference_instant, reference_time) = reference();
+    match instant.checked_duration_since(reference_instant) {
+        Some(after) => reference_time.checked_add(after),
+        None => reference_time.checked_sub(reference_instant.duration_since(instant)),
+    }
+    .unwrap_or(reference_time)
+}
+
+fn system_time_to_instant(time: SystemTime) -> Instant {
+    let (reference_instant, reference_time) = reference();
+    match time.duration_since(reference_time) {
+        Ok(after) => reference_instant.checked_add(after),
+        Err(err) => reference_instant.checked_sub(err.duration()),
+    }
+    .unwrap_or(reference_instant)
+}
+
+/// Capture the reference of the current process.
+///
+/// `parent` is the reference the parent has passed in the handshake, as returned by
+/// [`reference_for_child`]. If it is present, the reference is derived from it, so that the parent
+/// and the child map instants identically. Otherwise, e.g. in the root process, the current time is
+/// used.
+pub(crate) fn capture_reference(parent: Option<(Duration, SystemTime)>) {
+    let derived = parent.and_then(|(parent_clock, parent_time)| {
+        let instant = Instant::now();
+        let elapsed = crate::internals::monotonic_clock()?.checked_sub(parent_clock)?;
+        Some((instant, parent_time.checked_add(elapsed)?))
+    });
+    if let Some(derived) = derived {
+        // Nothing can have captured the reference before the handshake
+        let _ = REFERENCE.set(derived);
+    }
+    reference();
+}
+
+/// Get the reference to pass to a child: a reading of the system-wide monotonic clock and the
+/// wall-clock time that the current instant maps to.
+pub(crate) fn reference_for_child() -> Option<(Duration, SystemTime)> {
+    let time = instant_to_system_time(Instant::now());
+    Some((crate::internals::monotonic_clock()?, time))
+}
+
+/// An [`Instant`] that is passed between processes as wall-clock time.
+///
+/// Each process maps its [`Instant`]s to wall-clock time via a reference: an [`Instant`] and the
+/// wall-clock time at the same moment. An instant is transferred as the wall-clock time at the
+/// same offset from the reference of the sending process, and is mapped back to an [`Instant`] via
+/// the reference of the receiving process.
+///
+/// The mapping is fixed for the lifetime of the process, so the difference between two instants
+/// from the same process is preserved exactly, and instants received from another process are
+/// consistent with each other.
+///
+/// The root process captures its reference when it starts. A child derives its reference from that
+/// of its parent, which is passed in the handshake together with a reading of the system-wide
+/// monotonic clock, so a parent and all its descendants map instants identically, regardless of
+/// the adjustments of the system clock, up to the time it takes to read the clocks.
+///
+/// Processes that are not related this way, e.g. two separately started programs connected by a
+/// socket, capture their references independently. The mapping between them is approximate: it is
+/// off by the time it takes to capture the reference, and by the adjustments of the system clock
+/// made between the moments the two processes started, if any. The same applies if the monotonic
+/// clock cannot be read.
+///
+/// Instants that cannot be represented in the receiving process are clamped to its reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MonotonicInstant(Instant);
+
+impl MonotonicInstant {
+    /// The current instant.
+    pub fn now() -> Self {
+        Self(Instant::now())
+    }
+
+    /// Wrap an [`Instant`] of the current process.
+    pub fn from_instant(instant: Instant) -> Self {
+        Self(instant)
+    }
+
+    /// Get the [`Instant`] in the clock domain of the current process.
+    pub fn to_instant(&self) -> Instant {
+        self.0
+    }
+
+    /// Map wall-clock time to an instant of the current process.
+    ///
+    /// Points too far in the past or in the future to be represented as [`Instant`] are clamped to
+    /// the reference of the current process.
+    pub fn from_system_time(time: SystemTime) -> Self {
+        Self(system_time_to_instant(time))
+    }
+
+    /// Map the instant to wall-clock time.
+    ///
+    /// Points too far in the past or in the future to be represented as [`SystemTime`] are
+    /// clamped to the reference of the current process.
+    pub fn to_system_time(&self) -> SystemTime {
+        instant_to_system_time(self.0)
+    }
+
+    /// The time elapsed since the instant, or zero if the instant is in the future.
+    pub fn elapsed(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    /// The time elapsed from another instant to this one, or zero if that instant is later.
+    pub fn duration_since(&self, earlier: MonotonicInstant) -> Duration {
+        self.0.saturating_duration_since(earlier.0)
+    }
+}
+
+impl From<Instant> for MonotonicInstant {
+    fn from(instant: Instant) -> Self {
+        Self::from_instant(instant)
+    }
+}
+
+impl From<SystemTime> for MonotonicInstant {
+    fn from(time: SystemTime) -> Self {
+        Self::from_system_time(time)
+    }
+}
+
+impl From<MonotonicInstant> for Instant {
+    fn from(instant: MonotonicInstant) -> Self {
+        instant.0
+    }
+}
+
+unsafe impl NonTrivialObject for MonotonicInstant {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+        s.serialize_temporary(self.to_system_time());
+    }
+    unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
+        let time = d.deserialize::<SystemTime>()?;
+        Ok(Self::from_system_time(time))
+    }
+}
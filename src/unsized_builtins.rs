@@ -48,6 +48,19 @@ unsafe impl<T: Object + ?Sized> NonTrivialObject for Box<T> {
         self.as_ref().serialize_self(s);
     }
 
+    fn serialized_size_hint(&self) -> Option<usize> {
+        let mut pointers = 0;
+        if TypeClass::of::<T>() == TypeClass::Dyn {
+            pointers += 1;
+        }
+        if cfg!(not(feature = "nightly")) {
+            pointers += 1;
+        }
+        self.as_ref()
+            .serialized_size_hint()?
+            .checked_add(pointers * std::mem::size_of::<usize>())
+    }
+
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         let mut pointer: *mut T = match TypeClass::of::<T>() {
             TypeClass::Sized => std::mem::transmute_copy::<usize, *mut T>(&0usize),
@@ -76,6 +89,9 @@ unsafe impl<T: Object> NonTrivialObject for Box<[T]> {
         s.serialize_temporary(self.len());
         s.serialize_slice(self.as_ref());
     }
+    fn serialized_size_hint(&self) -> Option<usize> {
+        T::serialized_slice_size_hint(self)?.checked_add(std::mem::size_of::<usize>())
+    }
     unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> Result<Self> {
         Ok(d.deserialize::<Vec<T>>()?.into_boxed_slice())
     }
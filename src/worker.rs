@@ -0,0 +1,106 @@
+//! Stateful workers.
+//!
+//! Workers often need expensive one-time setup, e.g. loading a model or opening a database, before
+//! they can handle requests. [`spawn_worker`] starts a child that calls `init` once and then calls
+//! `handle` for each request, passing it a reference to the state returned by `init`:
+//!
+//! ```rust
+//! use crossmist::{func, main, worker::spawn_worker};
+//! use std::collections::HashMap;
+//!
+//! #[func]
+//! fn init() -> HashMap<String, u32> {
+//!     // Expensive initialization
+//!     HashMap::from([("one".to_string(), 1), ("two".to_string(), 2)])
+//! }
+//!
+//! #[func]
+//! fn handle(state: &HashMap<String, u32>, key: String) -> Option<u32> {
+//!     state.get(&key).copied()
+//! }
+//!
+//! #[main]
+//! fn main() {
+//!     let mut worker = spawn_worker(init, handle).unwrap();
+//!     assert_eq!(worker.request(&"two".to_string()).unwrap(), Some(2));
+//!     assert_eq!(worker.request(&"three".to_string()).unwrap(), None);
+//!     worker.join().unwrap();
+//! }
+//! ```
+//!
+//! The state is constructed in the child and is never sent between processes. Note, however, that
+//! the return type of a [`func`](crate::func) must implement [`Object`], so an `init` defined with
+//! `#[func]` is limited to such states.
+
+use crate::{Child, Duplex, FnObject, FnOnceObject, Object, SpawnOptions};
+use std::io::Result;
+
+/// A child process handling requests with a state initialized once.
+#[derive(Debug)]
+pub struct Worker<Req: Object, Resp: Object> {
+    chan: Duplex<Req, Resp>,
+    child: Child<()>,
+}
+
+impl<Req: Object, Resp: Object> Worker<Req, Resp> {
+    /// Send a request to the worker and wait for the response.
+    ///
+    /// An error is returned if the worker terminates before responding, e.g. if the handler
+    /// panics.
+    pub fn request(&mut self, request: &Req) -> Result<Resp> {
+        self.chan.request(request)
+    }
+
+    /// Get the ID of the worker process.
+    pub fn id(&self) -> crate::asynchronous::ProcID {
+        self.child.id()
+    }
+
+    /// Stop accepting requests and wait for the worker to exit.
+    pub fn join(self) -> Result<()> {
+        drop(self.chan);
+        Ok(self.child.join()?)
+    }
+}
+
+#[crate::func]
+fn worker_main<
+    State: 'static,
+    Req: Object + 'static,
+    Resp: Object + 'static,
+    Init: FnOnceObject<(), Output = State> + 'static,
+    Handle: for<'a> FnObject<(&'a State, Req), Output = Resp> + 'static,
+>(
+    init: Init,
+    handle: Handle,
+    mut chan: Duplex<Resp, Req>,
+) {
+    let state = init.call_object_once(());
+    while let Some(request) = chan.recv().expect("Failed to receive request") {
+        chan.send(&handle.call_object((&state, request)))
+            .expect("Failed to send response");
+    }
+}
+
+/// Start a worker.
+///
+/// `init` is called once when the child starts, and `handle` is called for each request.
+pub fn spawn_worker<State: 'static, Req: Object + 'static, Resp: Object + 'static>(
+    init: impl FnOnceObject<(), Output = State> + 'static,
+    handle: impl for<'a> FnObject<(&'a State, Req), Output = Resp> + 'static,
+) -> Result<Worker<Req, Resp>> {
+    spawn_worker_with(&SpawnOptions::new(), init, handle)
+}
+
+/// Start a worker with custom options.
+///
+/// See [`spawn_worker`] for more information.
+pub fn spawn_worker_with<State: 'static, Req: Object + 'static, Resp: Object + 'static>(
+    options: &SpawnOptions,
+    init: impl FnOnceObject<(), Output = State> + 'static,
+    handle: impl for<'a> FnObject<(&'a State, Req), Output = Resp> + 'static,
+) -> Result<Worker<Req, Resp>> {
+    let (local, remote) = crate::duplex()?;
+    let child = worker_main.spawn_with(options, init, handle, remote)?;
+    Ok(Worker { chan: local, child })
+}
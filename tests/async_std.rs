@@ -0,0 +1,187 @@
+use crossmist::async_std::{channel, duplex, Duplex, Receiver, Sender};
+use crossmist::{FnOnceObject, Object, RequestError};
+use std::time::Duration;
+
+#[ctor::ctor]
+fn ctor() {
+    crossmist::init();
+}
+
+#[derive(Debug, PartialEq, Object)]
+struct SimplePair {
+    x: i32,
+    y: i32,
+}
+
+#[async_std::test]
+async fn simple() {
+    #[crossmist::func(async_std)]
+    async fn inner() -> i64 {
+        0x123456789abcdef
+    }
+    assert_eq!(inner.run_async_std().await.unwrap(), 0x123456789abcdef);
+    assert_eq!(
+        inner.spawn_async_std().await.unwrap().join().await.unwrap(),
+        0x123456789abcdef
+    );
+}
+
+#[async_std::test]
+async fn add_with_arguments() {
+    #[crossmist::func(async_std)]
+    async fn inner(x: i32, y: i32) -> i32 {
+        x + y
+    }
+    assert_eq!(
+        inner
+            .spawn_async_std(5, 7)
+            .await
+            .unwrap()
+            .join()
+            .await
+            .unwrap(),
+        12
+    );
+    assert_eq!(inner.call_object_once((5, 7)).await, 12);
+}
+
+#[async_std::test]
+async fn swap_complex_argument() {
+    #[crossmist::func(async_std)]
+    async fn inner(pair: SimplePair) -> SimplePair {
+        SimplePair {
+            x: pair.y,
+            y: pair.x,
+        }
+    }
+    assert_eq!(
+        inner
+            .spawn_async_std(SimplePair { x: 5, y: 7 })
+            .await
+            .unwrap()
+            .join()
+            .await
+            .unwrap(),
+        SimplePair { x: 7, y: 5 }
+    );
+}
+
+#[async_std::test]
+async fn with_passed_rx() {
+    #[crossmist::func(async_std)]
+    async fn inner(mut rx: Receiver<i32>) -> i32 {
+        let a = rx.recv().await.unwrap().unwrap();
+        let b = rx.recv().await.unwrap().unwrap();
+        a - b
+    }
+    let (mut tx, rx) = channel::<i32>().unwrap();
+    let child = inner.spawn_async_std(rx).await.unwrap();
+    tx.send(&5).await.unwrap();
+    tx.send(&7).await.unwrap();
+    assert_eq!(child.join().await.unwrap(), -2);
+}
+
+#[async_std::test]
+async fn with_passed_tx() {
+    #[crossmist::func(async_std)]
+    async fn inner(mut tx: Sender<i32>) {
+        tx.send(&5).await.unwrap();
+        tx.send(&7).await.unwrap();
+    }
+    let (tx, mut rx) = channel::<i32>().unwrap();
+    let child = inner.spawn_async_std(tx).await.unwrap();
+    assert_eq!(
+        rx.recv().await.unwrap().unwrap() - rx.recv().await.unwrap().unwrap(),
+        -2
+    );
+    child.join().await.unwrap();
+}
+
+#[async_std::test]
+async fn with_passed_duplex() {
+    #[crossmist::func(async_std)]
+    async fn inner(mut chan: Duplex<i32, (i32, i32)>) {
+        while let Some((x, y)) = chan.recv().await.unwrap() {
+            chan.send(&(x - y)).await.unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<(i32, i32), i32>().unwrap();
+    let child = inner.spawn_async_std(downstream).await.unwrap();
+    for (x, y) in [(5, 7), (100, -1), (53, 2354)] {
+        local.send(&(x, y)).await.unwrap();
+        assert_eq!(local.recv().await.unwrap().unwrap(), x - y);
+    }
+    drop(local);
+    child.join().await.unwrap();
+}
+
+#[async_std::test]
+async fn with_passed_nested_channel() {
+    #[crossmist::func(async_std)]
+    async fn inner(mut chan: Receiver<Receiver<i32>>) -> i32 {
+        let mut chan1 = chan.recv().await.unwrap().unwrap();
+        chan1.recv().await.unwrap().unwrap()
+    }
+    let (mut tx, rx) = channel::<i32>().unwrap();
+    let (mut tx1, rx1) = channel::<Receiver<i32>>().unwrap();
+    tx.send(&5).await.unwrap();
+    tx1.send(&rx).await.unwrap();
+    assert_eq!(inner.run_async_std(rx1).await.unwrap(), 5);
+}
+
+#[async_std::test]
+async fn with_async_write() {
+    #[crossmist::func(async_std)]
+    async fn inner(mut tx_data: Sender<i32>, mut tx_signal: Sender<()>) {
+        let future = async_std::task::spawn(async move {
+            for i in 0..1000 {
+                tx_data.send(&i).await.unwrap();
+            }
+        });
+        tx_signal.send(&()).await.unwrap();
+        future.await;
+    }
+    let (tx_data, mut rx_data) = channel().unwrap();
+    let (tx_signal, mut rx_signal) = channel().unwrap();
+    let child = inner.spawn_async_std(tx_data, tx_signal).await.unwrap();
+    rx_signal.recv().await.unwrap();
+    for i in 0..1000 {
+        assert_eq!(rx_data.recv().await.unwrap().unwrap(), i);
+    }
+    child.join().await.unwrap();
+}
+
+#[async_std::test]
+async fn exitting() {
+    #[crossmist::func(async_std)]
+    async fn inner() {
+        std::process::exit(0);
+    }
+    inner.run_async_std().await.unwrap();
+}
+
+#[async_std::test]
+async fn request_timeout() {
+    #[crossmist::func(async_std)]
+    async fn inner(mut chan: Duplex<u64, u64>) {
+        while let Some(delay) = chan.recv().await.unwrap() {
+            async_std::task::sleep(Duration::from_millis(delay)).await;
+            chan.send(&delay).await.unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<u64, u64>().unwrap();
+    let child = inner.spawn_async_std(downstream).await.unwrap();
+    assert!(matches!(
+        local.request_timeout(&500, Duration::from_millis(50)).await,
+        Err(RequestError::Timeout)
+    ));
+    assert_eq!(
+        local
+            .request_timeout(&1, Duration::from_secs(5))
+            .await
+            .unwrap(),
+        1
+    );
+    drop(local);
+    child.join().await.unwrap();
+}
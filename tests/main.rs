@@ -1,7 +1,11 @@
 use crossmist::{
-    channel, duplex, static_ref, BindValue, Duplex, FnOnceObject, Object, Receiver, Sender,
-    StaticRef,
+    channel, channel_with, duplex, duplex_with, ready_signal, static_ref, BindValue,
+    ChannelOptions, Duplex, FnOnceObject, Framing, JoinError, MapDelta, Object, ProcessGroup,
+    ReadySignal, Receiver, RequestError, ResourceLimit, Sender, SpawnOptions, StaticRef, Stdio,
+    TryRecvError,
 };
+use std::collections::HashMap;
+use std::time::Duration;
 
 #[ctor::ctor]
 fn ctor() {
@@ -159,6 +163,74 @@ fn with_passed_trait() {
     );
 }
 
+trait Registered: crossmist::registry::AnyObject {
+    fn describe(&self) -> String;
+}
+
+impl Registered for ImplA {
+    fn describe(&self) -> String {
+        format!("ImplA: {}", self.0)
+    }
+}
+
+impl Registered for ImplB {
+    fn describe(&self) -> String {
+        format!("ImplB: {}", self.0)
+    }
+}
+
+impl Registered for bool {
+    fn describe(&self) -> String {
+        format!("bool: {self}")
+    }
+}
+
+crossmist::register_object!(dyn Registered: ImplA = "a", ImplB);
+
+#[test]
+fn registered_trait_objects() {
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<String, Box<dyn Registered>>) {
+        while let Some(value) = chan.recv().unwrap() {
+            chan.send(&value.describe()).unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<Box<dyn Registered>, String>().unwrap();
+    let child = inner.spawn(downstream).unwrap();
+    assert_eq!(
+        local
+            .request(&(Box::new(ImplA("hello".to_string())) as Box<dyn Registered>))
+            .unwrap(),
+        "ImplA: hello"
+    );
+    assert_eq!(
+        local
+            .request(&(Box::new(ImplB(5)) as Box<dyn Registered>))
+            .unwrap(),
+        "ImplB: 5"
+    );
+    drop(local);
+    child.join().unwrap();
+
+    let (mut tx, _rx) = channel::<Box<dyn Registered>>().unwrap();
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        tx.send(&(Box::new(true) as Box<dyn Registered>))
+    }))
+    .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<String>().unwrap(),
+        "bool is not registered as an implementor of dyn Registered"
+    );
+
+    // A tag the receiver does not know
+    use crossmist::handles::{FromRawHandle, IntoRawHandle};
+    let (mut tx, rx) = channel::<(String, i32)>().unwrap();
+    tx.send(&("c".to_string(), 1)).unwrap();
+    let mut rx = unsafe { Receiver::<Box<dyn Registered>>::from_raw_handle(rx.into_raw_handle()) };
+    let err = rx.recv().map(|_| ()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
 #[test]
 fn with_passed_fn() {
     #[crossmist::func]
@@ -315,3 +387,2627 @@ fn with_static_ref() {
         "Hello, world!"
     );
 }
+
+#[test]
+fn with_varint_framing() {
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<String, Vec<u8>>) {
+        assert_eq!(chan.options().get_framing(), Framing::Varint);
+        while let Some(data) = chan.recv().unwrap() {
+            chan.send(&format!("{}", data.len())).unwrap();
+        }
+    }
+    let options = ChannelOptions::new().framing(Framing::Varint);
+    let (mut local, downstream) = duplex_with::<Vec<u8>, String>(&options).unwrap();
+    let child = inner.spawn(downstream).unwrap();
+    for len in [0, 1, 127, 128, 100000] {
+        assert_eq!(local.request(&vec![0; len]).unwrap(), format!("{len}"));
+    }
+    drop(local);
+    child.join().unwrap();
+}
+
+#[test]
+fn request_timeout() {
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<u64, u64>) {
+        while let Some(delay) = chan.recv().unwrap() {
+            std::thread::sleep(Duration::from_millis(delay));
+            chan.send(&delay).unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<u64, u64>().unwrap();
+    let child = inner.spawn(downstream).unwrap();
+    assert!(matches!(
+        local.request_timeout(&500, Duration::from_millis(50)),
+        Err(RequestError::Timeout)
+    ));
+    assert_eq!(
+        local.request_timeout(&1, Duration::from_secs(5)).unwrap(),
+        1
+    );
+    assert_eq!(local.request(&2).unwrap(), 2);
+    drop(local);
+    child.join().unwrap();
+}
+
+#[test]
+fn request_timeout_peer_closed() {
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<(), ()>) {
+        chan.recv().unwrap();
+    }
+    let (mut local, downstream) = duplex::<(), ()>().unwrap();
+    let child = inner.spawn(downstream).unwrap();
+    assert!(matches!(
+        local.request_timeout(&(), Duration::from_secs(5)),
+        Err(RequestError::PeerClosed)
+    ));
+    child.join().unwrap();
+}
+
+#[test]
+fn iterate_receiver() {
+    #[crossmist::func]
+    fn inner(mut tx: Sender<i32>) {
+        for i in 0..10 {
+            tx.send(&i).unwrap();
+        }
+    }
+    let (tx, rx) = channel::<i32>().unwrap();
+    let child = inner.spawn(tx).unwrap();
+    let mut sum = 0;
+    for value in rx {
+        sum += value.unwrap();
+    }
+    assert_eq!(sum, 45);
+    child.join().unwrap();
+}
+
+#[test]
+fn cloned_senders() {
+    #[crossmist::func]
+    fn inner(mut tx: Sender<(u8, Vec<u8>)>, id: u8) {
+        for _ in 0..10 {
+            tx.send(&(id, vec![id; 100000])).unwrap();
+        }
+    }
+    let (tx, mut rx) = channel::<(u8, Vec<u8>)>().unwrap();
+    let child1 = inner.spawn(tx.try_clone().unwrap(), 1).unwrap();
+    let child2 = inner.spawn(tx, 2).unwrap();
+    let mut counts = [0; 3];
+    while let Some((id, data)) = rx.recv().unwrap() {
+        assert!(data.len() == 100000 && data.iter().all(|&x| x == id));
+        counts[id as usize] += 1;
+    }
+    assert_eq!(counts, [0, 10, 10]);
+    child1.join().unwrap();
+    child2.join().unwrap();
+}
+
+#[test]
+fn feed_and_flush() {
+    #[crossmist::func]
+    fn inner(mut tx: Sender<(i32, Receiver<i32>)>) {
+        for i in 0..100 {
+            let (mut tx1, rx1) = channel::<i32>().unwrap();
+            tx1.send(&(i * 2)).unwrap();
+            tx.feed(&(i, rx1)).unwrap();
+        }
+        tx.flush().unwrap();
+    }
+    let (tx, mut rx) = channel::<(i32, Receiver<i32>)>().unwrap();
+    let child = inner.spawn(tx).unwrap();
+    for i in 0..100 {
+        let (j, mut rx1) = rx.recv().unwrap().unwrap();
+        assert_eq!(j, i);
+        assert_eq!(rx1.recv().unwrap(), Some(i * 2));
+    }
+    assert!(rx.recv().unwrap().is_none());
+    child.join().unwrap();
+}
+
+#[test]
+fn prioritized_feed() {
+    let (mut tx, mut rx) = channel::<String>().unwrap();
+    for i in 0..3 {
+        tx.feed(&format!("bulk {i}")).unwrap();
+    }
+    tx.feed_prioritized(&"health".to_string(), 1).unwrap();
+    tx.feed(&"bulk 3".to_string()).unwrap();
+    tx.feed_prioritized(&"cancel 1".to_string(), 5).unwrap();
+    tx.feed_prioritized(&"cancel 2".to_string(), 5).unwrap();
+    tx.send_prioritized(&"large".repeat(10000), 2).unwrap();
+    let expected = [
+        "cancel 1", "cancel 2", "large", "health", "bulk 0", "bulk 1", "bulk 2", "bulk 3",
+    ];
+    for value in expected {
+        let received = rx.recv().unwrap().unwrap();
+        if value == "large" {
+            assert_eq!(received, "large".repeat(10000));
+        } else {
+            assert_eq!(received, value);
+        }
+    }
+
+    // Values that have already been written are not overtaken
+    tx.send(&"first".to_string()).unwrap();
+    tx.send_prioritized(&"second".to_string(), 255).unwrap();
+    drop(tx);
+    assert_eq!(rx.recv().unwrap().as_deref(), Some("first"));
+    assert_eq!(rx.recv().unwrap().as_deref(), Some("second"));
+    assert!(rx.recv().unwrap().is_none());
+}
+
+#[test]
+fn try_iterate_receiver() {
+    #[crossmist::func]
+    fn inner(mut tx: Sender<i32>) {
+        for i in 0..50 {
+            tx.send(&i).unwrap();
+        }
+    }
+    let (tx, mut rx) = channel::<i32>().unwrap();
+    assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+    inner.spawn(tx).unwrap().join().unwrap();
+    let values: Vec<i32> = rx.try_iter().collect::<std::io::Result<_>>().unwrap();
+    assert_eq!(values, (0..50).collect::<Vec<_>>());
+    assert!(matches!(rx.try_recv(), Err(TryRecvError::Disconnected)));
+}
+
+#[test]
+fn batches() {
+    #[crossmist::func]
+    fn inner(mut tx: Sender<(u32, u32)>) {
+        let values: Vec<(u32, u32)> = (0..100000).map(|i| (i, i * 3)).collect();
+        for chunk in values.chunks(1000) {
+            tx.send_batch(chunk).unwrap();
+        }
+        tx.send(&(100000, 300000)).unwrap();
+    }
+    let (tx, mut rx) = channel::<(u32, u32)>().unwrap();
+    let child = inner.spawn(tx).unwrap();
+    let mut expected = 0;
+    loop {
+        let values = rx.recv_batch(4096).unwrap();
+        if values.is_empty() {
+            break;
+        }
+        for value in values {
+            assert_eq!(value, (expected, expected * 3));
+            expected += 1;
+        }
+    }
+    assert_eq!(expected, 100001);
+    child.join().unwrap();
+}
+
+#[test]
+fn reused_send_buffer() {
+    fn message(i: usize) -> Vec<String> {
+        // Grows past the retention limit and shrinks back
+        let count = if i < 50 { i * 1000 } else { (100 - i) * 10 };
+        (0..count).map(|j| format!("{i}-{j}")).collect()
+    }
+
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<Vec<String>, ()>, mut tx: Sender<Vec<String>>) {
+        for i in 0..100 {
+            chan.send(&message(i)).unwrap();
+            tx.send(&message(i)).unwrap();
+            if i == 75 {
+                chan.shrink_to_fit();
+                tx.shrink_to_fit();
+            }
+        }
+    }
+    let (mut local, remote) = duplex::<(), Vec<String>>().unwrap();
+    let (tx, mut rx) = channel::<Vec<String>>().unwrap();
+    let child = inner.spawn(remote, tx).unwrap();
+    for i in 0..100 {
+        assert_eq!(local.recv().unwrap().unwrap(), message(i));
+        assert_eq!(rx.recv().unwrap().unwrap(), message(i));
+    }
+    child.join().unwrap();
+}
+
+type Message = (Vec<u8>, Receiver<i32>);
+type Reply = (usize, Receiver<i32>);
+
+#[test]
+fn shared_memory() {
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<Reply, Message>) {
+        assert_eq!(chan.options().get_shared_memory_threshold(), Some(65536));
+        while let Some((data, mut rx)) = chan.recv().unwrap() {
+            assert!(data.iter().enumerate().all(|(i, &x)| x == i as u8));
+            let value = rx.recv().unwrap().unwrap();
+            let (mut tx1, rx1) = channel::<i32>().unwrap();
+            tx1.send(&(value + 1)).unwrap();
+            chan.send(&(data.len(), rx1)).unwrap();
+        }
+    }
+    let options = ChannelOptions::new().shared_memory_threshold(Some(65536));
+    let (mut local, downstream) = duplex_with::<Message, Reply>(&options).unwrap();
+    let child = inner.spawn(downstream).unwrap();
+    for len in [0, 10, 65535, 65536, 10_000_000] {
+        let (mut tx, rx) = channel::<i32>().unwrap();
+        tx.send(&(len as i32)).unwrap();
+        let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let (got_len, mut rx1) = local.request(&(data, rx)).unwrap();
+        assert_eq!(got_len, len);
+        assert_eq!(rx1.recv().unwrap(), Some(len as i32 + 1));
+    }
+    drop(local);
+    child.join().unwrap();
+}
+
+#[test]
+fn max_message_size() {
+    assert_eq!(
+        ChannelOptions::new().get_max_message_size(),
+        Some(crossmist::options::DEFAULT_MAX_MESSAGE_SIZE)
+    );
+    let options = ChannelOptions::new().max_message_size(Some(100_000));
+    assert_eq!(options.get_max_message_size(), Some(100_000));
+    let (mut tx, mut rx) = channel_with::<Vec<u8>>(&options).unwrap();
+    tx.send(&vec![1; 50_000]).unwrap();
+    assert_eq!(rx.recv().unwrap().unwrap().len(), 50_000);
+    let sender = std::thread::spawn(move || {
+        // On Windows, the receiver stops reading after the oversized message
+        let _ = tx.send(&vec![2; 200_000]);
+        let _ = tx.send(&vec![3; 10]);
+    });
+    let err = rx.recv().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    #[cfg(unix)]
+    assert_eq!(rx.recv().unwrap(), Some(vec![3; 10]));
+    drop(rx);
+    sender.join().unwrap();
+
+    let (mut tx, mut rx) = channel_with::<Vec<u8>>(&options).unwrap();
+    rx.set_max_message_size(None);
+    assert_eq!(rx.options().get_max_message_size(), None);
+    let sender = std::thread::spawn(move || tx.send(&vec![4; 200_000]).unwrap());
+    assert_eq!(rx.recv().unwrap().unwrap().len(), 200_000);
+    sender.join().unwrap();
+}
+
+#[test]
+fn raw_handle_round_trip() {
+    use crossmist::handles::IntoRawHandle;
+    let options = ChannelOptions::new().framing(Framing::Varint);
+    let (tx, rx) = channel_with::<String>(&options).unwrap();
+    let tx_handle = tx.into_raw_handle();
+    let rx_handle = rx.into_raw_handle();
+    let mut tx = unsafe { Sender::<String>::from_raw_handle_with(tx_handle, &options) };
+    let mut rx = unsafe { Receiver::<String>::from_raw_handle_with(rx_handle, &options) };
+    assert_eq!(rx.options().get_framing(), Framing::Varint);
+    tx.send(&"hello".to_string()).unwrap();
+    assert_eq!(rx.recv().unwrap(), Some("hello".to_string()));
+    drop(tx);
+    assert_eq!(rx.recv().unwrap(), None);
+}
+
+#[test]
+fn buffer_size() {
+    assert_eq!(ChannelOptions::new().get_buffer_size(), None);
+    let options = ChannelOptions::new().buffer_size(Some(32768));
+    assert_eq!(options.get_buffer_size(), Some(32768));
+
+    // The kernel buffer is of the requested size, not twice as large
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let (tx, _rx) = channel_with::<()>(&options).unwrap();
+        let mut size: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                tx.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &mut size as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(size, 32768);
+    }
+
+    // Far more than the buffer holds, so the sender has to wait for the receiver
+    let (mut tx, mut rx) = channel_with::<Vec<u8>>(&options).unwrap();
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let sender = std::thread::spawn(move || {
+        for i in 0..1000 {
+            tx.send(&vec![i as u8; 1000]).unwrap();
+        }
+        done_tx.send(()).unwrap();
+    });
+    assert!(done_rx.recv_timeout(Duration::from_millis(200)).is_err());
+    for i in 0..1000 {
+        assert_eq!(rx.recv().unwrap(), Some(vec![i as u8; 1000]));
+    }
+    done_rx.recv().unwrap();
+    sender.join().unwrap();
+
+    // Messages larger than the buffer are delivered in parts
+    let (mut tx, mut rx) = duplex_with::<Vec<u8>, Vec<u8>>(&options).unwrap();
+    let sender = std::thread::spawn(move || tx.send(&vec![5; 1 << 20]).unwrap());
+    assert_eq!(rx.recv().unwrap(), Some(vec![5; 1 << 20]));
+    sender.join().unwrap();
+}
+
+#[test]
+fn map_deltas() {
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<HashMap<u32, String>, MapDelta<u32, String>>) {
+        let mut map = HashMap::new();
+        while let Some(delta) = chan.recv().unwrap() {
+            delta.apply(&mut map);
+            chan.send(&map).unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<MapDelta<u32, String>, HashMap<u32, String>>().unwrap();
+    let child = inner.spawn(downstream).unwrap();
+    let mut old = HashMap::new();
+    for step in 0..20u32 {
+        let mut new = old.clone();
+        for key in 0..50 {
+            match (key * 7 + step * 3) % 5 {
+                0 => {
+                    new.remove(&key);
+                }
+                1 => {
+                    new.insert(key, format!("{key} at {step}"));
+                }
+                _ => {}
+            }
+        }
+        let delta = MapDelta::diff(&old, &new);
+        assert!(delta.len() <= 50);
+        assert_eq!(local.request(&delta).unwrap(), new);
+        old = new;
+    }
+    drop(local);
+    child.join().unwrap();
+}
+
+#[test]
+fn with_passed_tcp_stream() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[crossmist::func]
+    fn inner(mut stream: TcpStream) {
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).unwrap();
+        }
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let child = inner.spawn(server).unwrap();
+    client.write_all(b"Hello, world!").unwrap();
+    let mut buf = [0u8; 13];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"Hello, world!");
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn with_sent_tcp_stream() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    // The child is already running when the connection is accepted
+    #[crossmist::func]
+    fn inner(mut streams: Receiver<TcpStream>) {
+        let mut stream = streams.recv().unwrap().unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            stream.write_all(&buf[..n]).unwrap();
+        }
+    }
+    let (mut tx, rx) = channel::<TcpStream>().unwrap();
+    let child = inner.spawn(rx).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    tx.send(&server).unwrap();
+    drop(server);
+    client.write_all(b"Hello, world!").unwrap();
+    let mut buf = [0u8; 13];
+    client.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"Hello, world!");
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn with_passed_udp_socket() {
+    use std::net::UdpSocket;
+
+    #[crossmist::func]
+    fn inner(socket: UdpSocket) {
+        let mut buf = [0u8; 1024];
+        let (n, peer) = socket.recv_from(&mut buf).unwrap();
+        socket.send_to(&buf[..n], peer).unwrap();
+    }
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(server.local_addr().unwrap()).unwrap();
+    let child = inner.spawn(server).unwrap();
+    client.send(b"ping").unwrap();
+    let mut buf = [0u8; 4];
+    assert_eq!(client.recv(&mut buf).unwrap(), 4);
+    assert_eq!(&buf, b"ping");
+    child.join().unwrap();
+}
+
+#[test]
+fn with_passed_tcp_listener() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[crossmist::func]
+    fn inner(listener: TcpListener, ready: ReadySignal<bool>) {
+        // The parent made the listener non-blocking and does not connect until signalled
+        let nonblocking = listener.accept().unwrap_err().kind() == std::io::ErrorKind::WouldBlock;
+        listener.set_nonblocking(false).unwrap();
+        ready.signal(nonblocking).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"Hello, world!").unwrap();
+    }
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (signal, waiter) = ready_signal().unwrap();
+    let child = inner.spawn(listener, signal).unwrap();
+    assert!(waiter.wait().unwrap());
+    let mut client = TcpStream::connect(addr).unwrap();
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"Hello, world!");
+    child.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn with_passed_unix_sockets() {
+    use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+
+    #[crossmist::func]
+    fn inner(listener: UnixListener, socket: UnixDatagram) {
+        let (stream, _) = listener.accept().unwrap();
+        socket.send(b"accepted").unwrap();
+        drop(stream);
+    }
+    let dir = std::env::temp_dir().join(format!("crossmist-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("listener.sock");
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let (local, remote) = UnixDatagram::pair().unwrap();
+    let child = inner.spawn(listener, remote).unwrap();
+    let _stream = UnixStream::connect(&path).unwrap();
+    let mut buf = [0u8; 16];
+    let n = local.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"accepted");
+    child.join().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn large_byte_buffers() {
+    #[crossmist::func]
+    fn inner(mut rx: Receiver<Vec<u8>>) -> Vec<(usize, u64)> {
+        rx.iter()
+            .map(|data| {
+                let data = data.unwrap();
+                (data.len(), data.iter().map(|&x| x as u64).sum())
+            })
+            .collect()
+    }
+    let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+    let child = inner.spawn(rx).unwrap();
+    let data: Vec<u8> = (0..50_000_000u32).map(|i| (i % 251) as u8).collect();
+    let sum = |data: &[u8]| data.iter().map(|&x| x as u64).sum::<u64>();
+    tx.send(&data).unwrap();
+    tx.send_bytes(&data[1..]).unwrap();
+    tx.send_bytes(&data[..100]).unwrap();
+    drop(tx);
+    assert_eq!(
+        child.join().unwrap(),
+        [
+            (data.len(), sum(&data)),
+            (data.len() - 1, sum(&data[1..])),
+            (100, sum(&data[..100])),
+        ]
+    );
+}
+
+#[test]
+fn wait_ready() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[crossmist::func]
+    fn server(ready: ReadySignal<u16>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        ready.signal(listener.local_addr().unwrap().port()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"hello").unwrap();
+    }
+    let (signal, waiter) = ready_signal::<u16>().unwrap();
+    let child = server.spawn(signal).unwrap();
+    let port = waiter.wait().unwrap();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut buf = String::new();
+    stream.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "hello");
+    child.join().unwrap();
+}
+
+#[test]
+fn wait_ready_exitted() {
+    #[crossmist::func]
+    fn inner(_ready: ReadySignal<u16>) {}
+    let (signal, waiter) = ready_signal::<u16>().unwrap();
+    let child = inner.spawn(signal).unwrap();
+    assert_eq!(
+        waiter.wait().unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+    child.join().unwrap();
+}
+
+#[test]
+fn with_passed_file() {
+    use std::io::{Read, Seek, Write};
+
+    #[crossmist::func]
+    fn inner(mut file: std::fs::File, mut chan: Duplex<(), ()>) {
+        for i in 0..3 {
+            chan.recv().unwrap().unwrap();
+            write!(file, "c{i}").unwrap();
+            chan.send(&()).unwrap();
+        }
+    }
+    let path = std::env::temp_dir().join(format!("crossmist-file-{}", std::process::id()));
+    let mut file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    let (mut local, downstream) = duplex::<(), ()>().unwrap();
+    let child = inner.spawn(file.try_clone().unwrap(), downstream).unwrap();
+    for i in 0..3 {
+        write!(file, "p{i}").unwrap();
+        local.request(&()).unwrap();
+    }
+    child.join().unwrap();
+    assert_eq!(file.stream_position().unwrap(), 12);
+    file.rewind().unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "p0c0p1c1p2c2");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[derive(Object)]
+struct TaggedFiles {
+    tags: Vec<String>,
+    files: Vec<std::fs::File>,
+}
+
+#[test]
+fn with_sent_files() {
+    use std::io::{Read, Write};
+
+    #[crossmist::func]
+    fn inner(mut rx: Receiver<TaggedFiles>) -> Vec<String> {
+        let mut tagged = rx.recv().unwrap().unwrap();
+        tagged
+            .tags
+            .iter()
+            .zip(&mut tagged.files)
+            .map(|(tag, file)| {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).unwrap();
+                format!("{tag}: {contents}")
+            })
+            .collect()
+    }
+
+    let (mut tx, rx) = channel::<TaggedFiles>().unwrap();
+    let child = inner.spawn(rx).unwrap();
+
+    let mut tagged = TaggedFiles {
+        tags: Vec::new(),
+        files: Vec::new(),
+    };
+    let mut paths = Vec::new();
+    for i in 0..3 {
+        let path = std::env::temp_dir().join(format!("crossmist-sent-{}-{i}", std::process::id()));
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(format!("contents {i}").as_bytes())
+            .unwrap();
+        tagged.tags.push(format!("file {i}"));
+        tagged.files.push(std::fs::File::open(&path).unwrap());
+        paths.push(path);
+    }
+    tx.send(&tagged).unwrap();
+    drop(tagged);
+
+    assert_eq!(
+        child.join().unwrap(),
+        [
+            "file 0: contents 0",
+            "file 1: contents 1",
+            "file 2: contents 2"
+        ]
+    );
+    for path in paths {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn with_passed_owned_fd() {
+    use std::io::{Seek, Write};
+    use std::os::fd::OwnedFd;
+
+    #[crossmist::func]
+    fn inner(fd: OwnedFd) {
+        std::fs::File::from(fd).write_all(b"child").unwrap();
+    }
+    let path = std::env::temp_dir().join(format!("crossmist-fd-{}", std::process::id()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    file.write_all(b"parent").unwrap();
+    inner.run(OwnedFd::from(file.try_clone().unwrap())).unwrap();
+    assert_eq!(file.stream_position().unwrap(), 11);
+    assert_eq!(std::fs::read(&path).unwrap(), b"parentchild");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn shared_buffer() {
+    use crossmist::shm::SharedBuffer;
+
+    #[crossmist::func]
+    fn fill(mut buffer: SharedBuffer, mut chan: Duplex<(), ()>) {
+        // The parent waits for the message before reading the buffer
+        for (i, x) in unsafe { buffer.as_mut_slice() }.iter_mut().enumerate() {
+            *x = (i % 253) as u8;
+        }
+        chan.send(&()).unwrap();
+    }
+    let buffer = SharedBuffer::new(64 << 20).unwrap();
+    let (mut local, downstream) = duplex::<(), ()>().unwrap();
+    let child = fill.spawn(buffer.try_clone().unwrap(), downstream).unwrap();
+    local.recv().unwrap().unwrap();
+    assert_eq!(buffer.len(), 64 << 20);
+    assert!(unsafe { buffer.as_slice() }
+        .iter()
+        .enumerate()
+        .all(|(i, &x)| x == (i % 253) as u8));
+    child.join().unwrap();
+
+    let small = SharedBuffer::from_slice(b"hello").unwrap();
+    assert_eq!(unsafe { small.try_clone().unwrap().into_vec() }, b"hello");
+    assert!(SharedBuffer::new(0).unwrap().is_empty());
+}
+
+#[test]
+fn snapshot() {
+    use crossmist::shm::Snapshot;
+
+    #[crossmist::func]
+    fn total(
+        index: Snapshot<HashMap<String, Vec<u32>>>,
+        weights: Snapshot<[u64; 1024]>,
+        key: String,
+    ) -> u64 {
+        let values = &index.get().unwrap()[&key];
+        values
+            .iter()
+            .map(|&i| weights.get().unwrap()[i as usize])
+            .sum()
+    }
+
+    let index = HashMap::from([
+        ("a".to_string(), vec![1, 2, 3]),
+        ("b".to_string(), (0..1024).collect()),
+    ]);
+    let index = Snapshot::new(&index).unwrap();
+    let weights = Snapshot::new(&std::array::from_fn(|i| i as u64 * 2)).unwrap();
+    assert_eq!(weights.as_bytes().len(), 8192);
+    let children: Vec<_> = ["a", "b"]
+        .into_iter()
+        .map(|key| {
+            total
+                .spawn(
+                    index.try_clone().unwrap(),
+                    weights.try_clone().unwrap(),
+                    key.to_string(),
+                )
+                .unwrap()
+        })
+        .collect();
+    let totals: Vec<u64> = children
+        .into_iter()
+        .map(|child| child.join().unwrap())
+        .collect();
+    assert_eq!(totals, [12, 1023 * 1024]);
+    assert_eq!(index.get().unwrap()["a"], [1, 2, 3]);
+
+    assert!(Snapshot::new(&()).unwrap().get().is_ok());
+    let file = std::fs::File::open(std::env::current_exe().unwrap()).unwrap();
+    assert_eq!(
+        Snapshot::new(&file).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn cpu_time_limit() {
+    #[crossmist::func]
+    fn spin(iterations: Option<u64>) -> u64 {
+        let mut x = 0u64;
+        let mut i = 0;
+        while iterations.is_none_or(|n| i < n) {
+            x = std::hint::black_box(x.wrapping_mul(6364136223846793005).wrapping_add(i));
+            i += 1;
+        }
+        x
+    }
+    let options = SpawnOptions::new().cpu_time_limit(Some(Duration::from_millis(500)));
+    assert_eq!(
+        options.get_cpu_time_limit(),
+        Some(Duration::from_millis(500))
+    );
+
+    assert!(spin
+        .spawn_with(&options, Some(1000))
+        .unwrap()
+        .join()
+        .is_ok());
+
+    let start = std::time::Instant::now();
+    let error = spin.spawn_with(&options, None).unwrap().join().unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert!(
+        matches!(error, JoinError::LimitExceeded(ResourceLimit::CpuTime)),
+        "{error}"
+    );
+    #[cfg(unix)]
+    assert_eq!(error.signal(), Some(libc::SIGXCPU));
+}
+
+#[cfg(unix)]
+#[test]
+fn cpu_time_limit_confirmed() {
+    #[crossmist::func]
+    fn raise_xcpu() {
+        unsafe {
+            libc::raise(libc::SIGXCPU);
+        }
+    }
+
+    // Without consuming the CPU time, SIGXCPU is just a signal
+    let options = SpawnOptions::new().cpu_time_limit(Some(Duration::from_secs(60)));
+    let error = raise_xcpu.spawn_with(&options).unwrap().join().unwrap_err();
+    assert!(
+        matches!(error, JoinError::KilledBySignal(libc::SIGXCPU)),
+        "{error}"
+    );
+
+    #[cfg(target_os = "linux")]
+    {
+        #[crossmist::func]
+        fn spin_ignoring_xcpu() {
+            unsafe {
+                libc::signal(libc::SIGXCPU, libc::SIG_IGN);
+            }
+            let mut x = 0u64;
+            loop {
+                x = std::hint::black_box(x.wrapping_mul(6364136223846793005).wrapping_add(1));
+            }
+        }
+
+        // The kernel kills the child with SIGKILL once the grace period is over
+        let options = SpawnOptions::new().cpu_time_limit(Some(Duration::from_millis(500)));
+        let error = spin_ignoring_xcpu
+            .spawn_with(&options)
+            .unwrap()
+            .join()
+            .unwrap_err();
+        assert!(
+            matches!(error, JoinError::LimitExceeded(ResourceLimit::CpuTime)),
+            "{error}"
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn oom_score_adj() {
+    #[crossmist::func]
+    fn read_oom_score_adj() -> String {
+        std::fs::read_to_string("/proc/self/oom_score_adj")
+            .unwrap()
+            .trim()
+            .to_string()
+    }
+
+    let options = SpawnOptions::new().oom_score_adj(Some(1000));
+    assert_eq!(options.get_oom_score_adj(), Some(1000));
+    assert_eq!(
+        read_oom_score_adj
+            .spawn_with(&options)
+            .unwrap()
+            .join()
+            .unwrap(),
+        "1000"
+    );
+
+    let options = SpawnOptions::new().oom_score_adj(Some(1001));
+    assert_eq!(
+        read_oom_score_adj.spawn_with(&options).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn posix_spawn() {
+    #[crossmist::func]
+    fn sum_and_spin(mut rx: Receiver<u64>, spin: bool) -> u64 {
+        let mut sum = 0;
+        while let Some(n) = rx.recv().unwrap() {
+            sum += n;
+        }
+        if spin {
+            loop {
+                sum = std::hint::black_box(sum.wrapping_mul(6364136223846793005).wrapping_add(1));
+            }
+        }
+        sum
+    }
+
+    let options = SpawnOptions::new().posix_spawn(true);
+    assert!(options.get_posix_spawn());
+    let (mut tx, rx) = channel::<u64>().unwrap();
+    let child = sum_and_spin.spawn_with(&options, rx, false).unwrap();
+    for n in 1..=10 {
+        tx.send(&n).unwrap();
+    }
+    drop(tx);
+    assert_eq!(child.join().unwrap(), 55);
+
+    let options = options.cpu_time_limit(Some(Duration::from_millis(500)));
+    let (tx, rx) = channel::<u64>().unwrap();
+    drop(tx);
+    assert!(sum_and_spin
+        .spawn_with(&options, rx, true)
+        .unwrap()
+        .join()
+        .is_err());
+}
+
+// macOS accepts RLIMIT_AS but does not enforce it
+#[cfg(not(target_os = "macos"))]
+#[test]
+fn memory_limit() {
+    #[crossmist::func]
+    fn can_reserve(bytes: usize) -> bool {
+        Vec::<u8>::new().try_reserve_exact(bytes).is_ok()
+    }
+
+    let options = SpawnOptions::new().memory_limit(Some(512 << 20));
+    assert_eq!(options.get_memory_limit(), Some(512 << 20));
+    assert!(can_reserve
+        .spawn_with(&options, 1 << 20)
+        .unwrap()
+        .join()
+        .unwrap());
+    assert!(!can_reserve
+        .spawn_with(&options, 1 << 30)
+        .unwrap()
+        .join()
+        .unwrap());
+    assert!(can_reserve.run(1 << 30).unwrap());
+
+    #[crossmist::func]
+    fn allocate(bytes: usize) -> usize {
+        std::hint::black_box(vec![1u8; bytes]).len()
+    }
+
+    // The child reports the failed allocation before aborting
+    let options = options.stderr(Stdio::null());
+    let error = allocate
+        .spawn_with(&options, 1 << 30)
+        .unwrap()
+        .join()
+        .unwrap_err();
+    // An abort cannot be attributed to the limit on Unix-like systems
+    #[cfg(unix)]
+    assert!(
+        matches!(error, JoinError::KilledBySignal(libc::SIGABRT)),
+        "{error}"
+    );
+    #[cfg(windows)]
+    assert!(
+        matches!(error, JoinError::LimitExceeded(ResourceLimit::Memory)),
+        "{error}"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn open_files_limit() {
+    #[crossmist::func]
+    fn open_many(n: usize) -> (usize, Option<i32>) {
+        let mut files = Vec::new();
+        for _ in 0..n {
+            match std::fs::File::open("/dev/null") {
+                Ok(file) => files.push(file),
+                Err(e) => return (files.len(), e.raw_os_error()),
+            }
+        }
+        (files.len(), None)
+    }
+
+    // posix_spawn can only apply resource limits on Linux
+    for posix_spawn in [false, cfg!(target_os = "linux")] {
+        let options = SpawnOptions::new()
+            .posix_spawn(posix_spawn)
+            .open_files_limit(Some(64));
+        assert_eq!(options.get_open_files_limit(), Some(64));
+        let (opened, error) = open_many.spawn_with(&options, 100).unwrap().join().unwrap();
+        assert!(opened < 64);
+        assert_eq!(error, Some(libc::EMFILE));
+    }
+    assert_eq!(open_many.run(100).unwrap(), (100, None));
+}
+
+#[cfg(unix)]
+#[test]
+fn pre_exec() {
+    #[crossmist::func]
+    fn session_leader() -> bool {
+        unsafe { libc::getsid(0) == libc::getpid() }
+    }
+
+    let options = unsafe {
+        SpawnOptions::new().pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    assert!(session_leader.spawn_with(&options).unwrap().join().unwrap());
+    assert!(!session_leader.run().unwrap());
+
+    let failing = unsafe {
+        options
+            .clone()
+            .pre_exec(|| Err(std::io::Error::from_raw_os_error(libc::EPERM)))
+    };
+    assert_ne!(failing, options);
+    let error = session_leader.spawn_with(&failing).unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EPERM));
+
+    assert_eq!(
+        session_leader
+            .spawn_with(&options.posix_spawn(true))
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::Unsupported
+    );
+}
+
+#[test]
+fn time_types() {
+    use crossmist::MonotonicInstant;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    #[crossmist::func]
+    fn echo(
+        times: Vec<SystemTime>,
+        duration: Duration,
+        stamp: MonotonicInstant,
+        deadline: Instant,
+    ) -> (Vec<SystemTime>, Duration, MonotonicInstant, bool, Instant) {
+        let in_future = stamp.to_instant() > Instant::now();
+        (times, duration, stamp, in_future, deadline)
+    }
+
+    let times = vec![
+        UNIX_EPOCH,
+        SystemTime::now(),
+        UNIX_EPOCH + Duration::new(1, 999_999_999),
+        UNIX_EPOCH - Duration::new(86400 * 365, 123_456_700),
+    ];
+    let duration = Duration::new(12345, 6789);
+    let deadline = Instant::now() + Duration::from_secs(3600);
+    let stamp = MonotonicInstant::from_system_time(SystemTime::now() + Duration::from_secs(3600));
+    let (times2, duration2, stamp2, in_future, deadline2) =
+        echo.run(times.clone(), duration, stamp, deadline).unwrap();
+    assert_eq!(times2, times);
+    assert_eq!(deadline2, deadline);
+    assert_eq!(duration2, duration);
+    assert_eq!(stamp2, stamp);
+    assert!(in_future);
+    let error = stamp2.to_instant().max(deadline) - stamp2.to_instant().min(deadline);
+    assert!(error < Duration::from_secs(1));
+}
+
+#[test]
+fn monotonic_instant_clamped() {
+    use crossmist::MonotonicInstant;
+    use std::time::{Instant, SystemTime};
+
+    // The latest representable instant lies too far in the future for SystemTime
+    let mut far = Instant::now();
+    for shift in (0..64).rev() {
+        if let Some(later) = far.checked_add(Duration::from_secs(1 << shift)) {
+            far = later;
+        }
+    }
+    let time = MonotonicInstant::from_instant(far).to_system_time();
+    assert!(time <= SystemTime::now());
+}
+
+#[test]
+fn monotonic_instant() {
+    use crossmist::MonotonicInstant;
+    use std::time::Instant;
+
+    #[crossmist::func]
+    fn echo(instants: Vec<MonotonicInstant>) -> (Vec<MonotonicInstant>, Duration, bool) {
+        let interval = instants[1].duration_since(instants[0]);
+        let in_past = instants[0].to_instant() <= Instant::now();
+        (instants, interval, in_past)
+    }
+
+    let start = MonotonicInstant::now();
+    let instants = vec![
+        start,
+        MonotonicInstant::from_instant(start.to_instant() + Duration::new(3, 141_592_653)),
+        MonotonicInstant::from(start.to_instant() - Duration::from_millis(1)),
+    ];
+    let (instants2, interval, in_past) = echo.run(instants.clone()).unwrap();
+    assert_eq!(instants2, instants);
+    assert_eq!(interval, Duration::new(3, 141_592_653));
+    assert!(in_past);
+    assert!(start.elapsed() < Duration::from_secs(60));
+}
+
+#[test]
+fn monotonic_instant_in_child() {
+    use crossmist::MonotonicInstant;
+
+    #[crossmist::func]
+    fn now() -> MonotonicInstant {
+        MonotonicInstant::now()
+    }
+
+    // The child maps instants like the parent, so its current instant is between the moments it
+    // was spawned and joined
+    let before = MonotonicInstant::now();
+    let child = now.run().unwrap();
+    let after = MonotonicInstant::now();
+    let margin = Duration::from_millis(1);
+    assert!(child.to_instant() + margin >= before.to_instant());
+    assert!(child.to_instant() <= after.to_instant() + margin);
+}
+
+#[cfg(unix)]
+#[test]
+fn process_group() {
+    #[crossmist::func]
+    fn group_and_session() -> (bool, bool) {
+        unsafe {
+            let pid = libc::getpid();
+            (libc::getpgid(0) == pid, libc::getsid(0) == pid)
+        }
+    }
+
+    #[crossmist::func]
+    fn spin_forever() {
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    #[crossmist::func]
+    fn spawn_grandchild(mut tx: Sender<i32>) {
+        let grandchild = spin_forever.spawn().unwrap();
+        tx.send(&grandchild.id()).unwrap();
+        grandchild.join().unwrap();
+    }
+
+    assert_eq!(
+        SpawnOptions::new().get_process_group(),
+        ProcessGroup::Inherit
+    );
+    for posix_spawn in [false, true] {
+        let options = SpawnOptions::new().posix_spawn(posix_spawn);
+        for (group, expected) in [
+            (ProcessGroup::Inherit, (false, false)),
+            (ProcessGroup::NewGroup, (true, false)),
+            (ProcessGroup::NewSession, (true, true)),
+        ] {
+            // posix_spawn can only create sessions on Linux
+            if posix_spawn && group == ProcessGroup::NewSession && !cfg!(target_os = "linux") {
+                continue;
+            }
+            let options = options.clone().process_group(group);
+            assert_eq!(options.get_process_group(), group);
+            let child = group_and_session.spawn_with(&options).unwrap();
+            assert_eq!(child.join().unwrap(), expected);
+        }
+    }
+
+    let (tx, mut rx) = channel::<i32>().unwrap();
+    let child = spawn_grandchild.spawn(tx).unwrap();
+    let grandchild = rx.recv().unwrap().unwrap();
+    assert_eq!(
+        child.get_kill_handle().kill_group().unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+    child.get_kill_handle().kill().unwrap();
+    assert!(matches!(child.join(), Err(JoinError::Killed)));
+    unsafe {
+        libc::kill(grandchild, libc::SIGKILL);
+    }
+
+    let (tx, mut rx) = channel::<i32>().unwrap();
+    let options = SpawnOptions::new().process_group(ProcessGroup::NewGroup);
+    let child = spawn_grandchild.spawn_with(&options, tx).unwrap();
+    let grandchild = rx.recv().unwrap().unwrap();
+    child.get_kill_handle().kill_group().unwrap();
+    assert!(matches!(child.join(), Err(JoinError::Killed)));
+    // The grandchild is reparented and reaped by someone else, so it may linger as a zombie
+    let stat = format!("/proc/{grandchild}/stat");
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while std::fs::read_to_string(&stat).is_ok_and(|stat| !stat.contains(") Z ")) {
+        assert!(std::time::Instant::now() < deadline, "Grandchild survived");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_path() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    #[crossmist::func]
+    fn inner(path: PathBuf) -> Vec<u8> {
+        path.as_os_str().as_bytes().to_vec()
+    }
+    let raw = b"/tmp/\xff/name\x80";
+    let path = PathBuf::from(OsStr::from_bytes(raw));
+    assert_eq!(inner.run(path).unwrap(), raw);
+}
+
+#[test]
+fn stateful_worker() {
+    use crossmist::worker::spawn_worker;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static INIT_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    #[crossmist::func]
+    fn init(base: u32) -> (u32, u32) {
+        (base, INIT_CALLS.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    #[crossmist::func]
+    fn handle(state: &(u32, u32), request: u32) -> (u32, u32) {
+        (state.0 + request, state.1)
+    }
+
+    let mut worker = spawn_worker(init.bind_value(100), handle).unwrap();
+    for request in 1..4 {
+        assert_eq!(worker.request(&request).unwrap(), (100 + request, 1));
+    }
+    worker.join().unwrap();
+}
+
+#[test]
+fn network_addresses() {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+    type Addresses = (
+        Ipv4Addr,
+        Ipv6Addr,
+        Vec<IpAddr>,
+        SocketAddrV4,
+        SocketAddrV6,
+        Vec<SocketAddr>,
+    );
+
+    #[crossmist::func]
+    fn echo(addresses: Addresses) -> Addresses {
+        addresses
+    }
+
+    let v4 = Ipv4Addr::new(192, 168, 1, 42);
+    let v6 = Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1234, 0x5678, 0x9abc, 0xdef0);
+    let scoped = SocketAddrV6::new(v6, 8080, 0x12345, 7);
+    let addresses: Addresses = (
+        v4,
+        v6,
+        vec![v4.into(), v6.into(), Ipv6Addr::LOCALHOST.into()],
+        SocketAddrV4::new(v4, 443),
+        scoped,
+        vec![SocketAddrV4::new(v4, 1).into(), scoped.into()],
+    );
+    let echoed = echo.run(addresses.clone()).unwrap();
+    assert_eq!(echoed, addresses);
+    assert_eq!(echoed.4.flowinfo(), 0x12345);
+    assert_eq!(echoed.4.scope_id(), 7);
+}
+
+#[test]
+fn join_killed() {
+    use crossmist::JoinError;
+
+    #[crossmist::func]
+    fn sleep_forever() {
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    #[crossmist::func]
+    fn crash() {
+        std::process::abort();
+    }
+
+    #[crossmist::func]
+    fn finish() -> u32 {
+        42
+    }
+
+    let child = sleep_forever.spawn().unwrap();
+    child.get_kill_handle().kill().unwrap();
+    assert!(matches!(child.join(), Err(JoinError::Killed)));
+
+    let error = crash.spawn().unwrap().join().unwrap_err();
+    #[cfg(unix)]
+    assert_eq!(error.signal(), Some(libc::SIGABRT));
+    #[cfg(windows)]
+    assert_eq!(error.code(), Some(3));
+
+    let child = finish.spawn().unwrap();
+    let handle = child.get_kill_handle();
+    assert_eq!(child.join().unwrap(), 42);
+    assert!(handle.kill().is_err());
+}
+
+#[test]
+fn spawn_with_kill_handle() {
+    use crossmist::JoinError;
+
+    #[crossmist::func]
+    fn sleep_forever() {
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    let (child, handle) = sleep_forever.spawn_with_kill_handle().unwrap();
+    let joining = std::thread::spawn(move || child.join());
+    let clone = handle.clone();
+    clone.kill().unwrap();
+    assert!(matches!(joining.join().unwrap(), Err(JoinError::Killed)));
+    // Clones share the state, so none of them can kill the process after it is joined
+    assert!(handle.kill().is_err());
+    assert!(clone.kill().is_err());
+}
+
+#[cfg(any(target_os = "linux", windows))]
+#[test]
+fn kill_handle_transfer() {
+    use crossmist::{JoinError, KillHandle};
+
+    #[crossmist::func]
+    fn sleep_forever() {
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    #[crossmist::func]
+    fn supervise(handle: KillHandle) {
+        handle.kill().unwrap();
+        assert!(handle.kill_group().is_err());
+    }
+
+    let child = sleep_forever.spawn().unwrap();
+    supervise.run(child.get_kill_handle()).unwrap();
+    #[cfg(unix)]
+    assert!(matches!(
+        child.join(),
+        Err(JoinError::KilledBySignal(libc::SIGKILL))
+    ));
+    #[cfg(windows)]
+    assert!(matches!(child.join(), Err(JoinError::ExitedWithCode(1))));
+
+    let child = sleep_forever.spawn().unwrap();
+    let handle = child.get_kill_handle();
+    child.get_kill_handle().kill().unwrap();
+    assert!(matches!(child.join(), Err(JoinError::Killed)));
+    assert!(supervise.run(handle).is_err());
+}
+
+#[test]
+fn join_exit_status() {
+    use crossmist::JoinError;
+
+    #[crossmist::func]
+    fn exit_with(code: i32) -> u32 {
+        std::process::exit(code);
+    }
+
+    let error = exit_with.spawn(3).unwrap().join().unwrap_err();
+    assert!(matches!(error, JoinError::ExitedWithCode(3)));
+    assert_eq!(error.code(), Some(3));
+    assert_eq!(error.to_string(), "The subprocess exited with code 3");
+    assert!(matches!(
+        exit_with.spawn(0).unwrap().join(),
+        Err(JoinError::NoReturnValue)
+    ));
+
+    #[cfg(unix)]
+    {
+        #[crossmist::func]
+        fn raise(signal: i32) -> u32 {
+            unsafe {
+                libc::raise(signal);
+            }
+            unreachable!()
+        }
+
+        let error = raise.spawn(libc::SIGTERM).unwrap().join().unwrap_err();
+        assert!(matches!(error, JoinError::KilledBySignal(libc::SIGTERM)));
+        assert_eq!(error.signal(), Some(libc::SIGTERM));
+        assert_eq!(error.code(), None);
+        assert_eq!(
+            std::io::Error::from(error).kind(),
+            std::io::ErrorKind::Other
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn join_interrupted_by_signal() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+    extern "C" fn handler(_: libc::c_int) {
+        HANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[crossmist::func]
+    fn slow() -> u32 {
+        std::thread::sleep(Duration::from_millis(500));
+        42
+    }
+
+    // Without SA_RESTART, blocking syscalls fail with EINTR instead of being restarted
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        assert_eq!(
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+            0
+        );
+    }
+
+    let child = slow.spawn().unwrap();
+    let thread = unsafe { libc::pthread_self() } as usize;
+    let signaller = std::thread::spawn(move || {
+        for _ in 0..8 {
+            std::thread::sleep(Duration::from_millis(100));
+            unsafe {
+                libc::pthread_kill(thread as libc::pthread_t, libc::SIGUSR1);
+            }
+        }
+    });
+    assert_eq!(child.join().unwrap(), 42);
+    signaller.join().unwrap();
+    assert!(HANDLED.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn join_panicked() {
+    #[crossmist::func]
+    fn fail(code: u32) -> u32 {
+        panic!("failed with code {code}");
+    }
+
+    #[crossmist::func]
+    fn fail_void() {
+        std::panic::panic_any(57);
+    }
+
+    #[crossmist::func]
+    fn recover() -> u32 {
+        std::panic::catch_unwind(|| panic!("caught")).unwrap_or(42)
+    }
+
+    #[crossmist::func]
+    fn fail_with_backtrace() {
+        std::env::set_var("RUST_LIB_BACKTRACE", "1");
+        panic!("failed with a backtrace");
+    }
+
+    match fail.spawn(7).unwrap().join() {
+        Err(error @ JoinError::Panicked { .. }) => {
+            assert!(
+                error.to_string().ends_with(": failed with code 7"),
+                "{error}"
+            );
+            let JoinError::Panicked {
+                message, location, ..
+            } = error
+            else {
+                unreachable!()
+            };
+            assert_eq!(message, "failed with code 7");
+            assert!(location.unwrap().starts_with("tests/main.rs:"));
+        }
+        result => panic!("Unexpected result {result:?}"),
+    }
+
+    assert!(matches!(
+        fail_void.spawn().unwrap().join(),
+        Err(JoinError::Panicked { message, .. }) if message == "Box<dyn Any>"
+    ));
+
+    assert_eq!(recover.run().unwrap(), 42);
+
+    match fail_with_backtrace.spawn().unwrap().join() {
+        Err(JoinError::Panicked {
+            message,
+            backtrace: Some(backtrace),
+            ..
+        }) => {
+            assert_eq!(message, "failed with a backtrace");
+            assert!(backtrace.contains("fail_with_backtrace"), "{backtrace}");
+        }
+        result => panic!("Unexpected result {result:?}"),
+    }
+}
+
+#[test]
+fn mpsc_bridge() {
+    use crossmist::{bridge_mpsc_receiver, bridge_mpsc_sender};
+
+    #[crossmist::func]
+    fn double(mut rx: Receiver<u32>, mut tx: Sender<u32>) {
+        while let Some(value) = rx.recv().unwrap() {
+            tx.send(&(value * 2)).unwrap();
+        }
+    }
+
+    let (input_tx, input_rx) = std::sync::mpsc::channel();
+    let (output_tx, output_rx) = std::sync::mpsc::channel();
+    let child = double
+        .spawn(
+            bridge_mpsc_receiver(input_rx).unwrap(),
+            bridge_mpsc_sender(output_tx).unwrap(),
+        )
+        .unwrap();
+    for i in 0..100 {
+        input_tx.send(i).unwrap();
+    }
+    drop(input_tx);
+    child.join().unwrap();
+    assert_eq!(
+        output_rx.iter().collect::<Vec<_>>(),
+        (0..100).map(|i| i * 2).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn kill_on_drop() {
+    #[crossmist::func]
+    fn hold(_tx: Sender<()>) {
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    let (tx, mut rx) = channel::<()>().unwrap();
+    let child = hold.spawn(tx).unwrap().kill_on_drop();
+    #[cfg(target_os = "linux")]
+    let id = child.id();
+    drop(child);
+    // The only sender is owned by the child, so the channel is closed once the child dies
+    assert!(rx.recv().unwrap().is_none());
+
+    // The killed process is reaped rather than left as a zombie
+    #[cfg(target_os = "linux")]
+    {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while std::path::Path::new(&format!("/proc/{id}")).exists() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "Killed process was not reaped"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn detach() {
+    #[crossmist::func]
+    fn short_lived() {}
+
+    let ids: Vec<_> = (0..20)
+        .map(|_| {
+            let child = short_lived.spawn().unwrap();
+            let id = child.id();
+            child.detach();
+            id
+        })
+        .collect();
+
+    // Reaped processes disappear from /proc, while zombies stay
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    for id in ids {
+        while std::path::Path::new(&format!("/proc/{id}")).exists() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "Detached process was not reaped"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[test]
+fn deferred_handshake() {
+    #[crossmist::func]
+    fn square(x: u64, mut tx: Sender<u64>) -> u64 {
+        tx.send(&x).unwrap();
+        x * x
+    }
+
+    let (tx, mut rx) = channel::<u64>().unwrap();
+    let options = SpawnOptions::new();
+    let pending: Vec<_> = (0..10)
+        .map(|x| square.spawn_deferred_with(&options, x, tx.try_clone().unwrap()))
+        .collect::<std::io::Result<_>>()
+        .unwrap();
+    drop(tx);
+    assert!(pending.iter().all(|child| child.id() > 0));
+    // The children do not run until the handshake is performed
+    assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+
+    let mut pending = pending.into_iter();
+    let dropped = pending.next().unwrap();
+    drop(dropped);
+    let last = pending.next_back().unwrap();
+    let children: Vec<_> = pending.map(|child| child.finish().unwrap()).collect();
+    let results: Vec<u64> = children
+        .into_iter()
+        .map(|child| child.join().unwrap())
+        .collect();
+    assert_eq!(results, (1..9).map(|x| x * x).collect::<Vec<_>>());
+    assert_eq!(last.join().unwrap(), 81);
+
+    let mut received: Vec<u64> = rx.into_iter().map(Result::unwrap).collect();
+    received.sort();
+    assert_eq!(received, (1..10).collect::<Vec<_>>());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_object() {
+    use crossmist::SerdeObject;
+    use serde_json::{json, Value};
+
+    // Pretend this comes from another crate
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Foreign {
+        id: u64,
+        name: String,
+        #[serde(default)]
+        tags: Vec<String>,
+        parent: Option<Box<Foreign>>,
+    }
+
+    #[crossmist::func]
+    fn process(value: SerdeObject<Value>, foreign: SerdeObject<Foreign>) -> SerdeObject<Value> {
+        SerdeObject(json!({
+            "value": value.into_inner(),
+            "id": foreign.id,
+            "parent": foreign.parent.as_ref().map(|parent| parent.name.clone()),
+        }))
+    }
+
+    let value = json!({ "list": [1, 2.5, null, "x"], "nested": { "flag": true } });
+    let foreign = Foreign {
+        id: 7,
+        name: "child".to_string(),
+        tags: vec!["a".to_string()],
+        parent: Some(Box::new(Foreign {
+            id: 1,
+            name: "root".to_string(),
+            tags: Vec::new(),
+            parent: None,
+        })),
+    };
+    let result = process
+        .run(SerdeObject(value.clone()), SerdeObject(foreign))
+        .unwrap();
+    assert_eq!(
+        result.into_inner(),
+        json!({ "value": value, "id": 7, "parent": "root" })
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn bare_channels() {
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    // The simplest executor possible: bare futures never return Pending
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let mut cx = Context::from_waker(Waker::noop());
+        match std::pin::pin!(f).poll(&mut cx) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("Bare future is pending"),
+        }
+    }
+
+    #[crossmist::func]
+    fn produce(mut tx: crossmist::bare::Sender<Vec<u8>>) {
+        // Large enough to fill the socket buffer, so that the writer has to wait
+        for i in 0..100u8 {
+            block_on(tx.send(&vec![i; 100000])).unwrap();
+        }
+    }
+
+    let (tx, mut rx) = crossmist::bare::channel::<Vec<u8>>().unwrap();
+    let child = produce.spawn(tx).unwrap();
+    for i in 0..100u8 {
+        assert_eq!(block_on(rx.recv()).unwrap().unwrap(), vec![i; 100000]);
+    }
+    assert!(block_on(rx.recv()).unwrap().is_none());
+    child.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn bare_select_recv() {
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let mut cx = Context::from_waker(Waker::noop());
+        match std::pin::pin!(f).poll(&mut cx) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("Bare future is pending"),
+        }
+    }
+
+    let (mut tx1, mut rx1) = crossmist::bare::channel::<i32>().unwrap();
+    let (mut tx2, mut rx2) = crossmist::bare::channel::<i32>().unwrap();
+    // Only the second receiver has a value, so waiting on the first one would hang
+    for _ in 0..2 {
+        block_on(tx2.send(&57)).unwrap();
+        let (index, value) = block_on(crossmist::asynchronous::select_recv(&mut [
+            &mut rx1, &mut rx2,
+        ]))
+        .unwrap();
+        assert_eq!((index, value), (1, Some(57)));
+    }
+    block_on(tx1.send(&42)).unwrap();
+    let (index, value) = block_on(crossmist::asynchronous::select_recv(&mut [
+        &mut rx1, &mut rx2,
+    ]))
+    .unwrap();
+    assert_eq!((index, value), (0, Some(42)));
+}
+
+#[test]
+fn verify_build() {
+    #[crossmist::func]
+    fn describe(value: Vec<u32>) -> String {
+        format!("{value:?}")
+    }
+
+    assert!(SpawnOptions::new().get_verify_build());
+    for verify in [true, false] {
+        let options = SpawnOptions::new().verify_build(verify);
+        assert_eq!(options.get_verify_build(), verify);
+        let child = describe.spawn_with(&options, vec![1, 2, 3]).unwrap();
+        assert_eq!(child.join().unwrap(), "[1, 2, 3]");
+        let pending = describe.spawn_deferred_with(&options, vec![4]).unwrap();
+        assert_eq!(pending.join().unwrap(), "[4]");
+    }
+}
+
+#[test]
+fn build_id_mismatch() {
+    #[crossmist::func]
+    fn describe(value: Vec<u32>) -> String {
+        format!("{value:?}")
+    }
+
+    // Corrupting the ID affects the whole process, so this is done in a child
+    #[crossmist::func]
+    fn spawn_with_corrupt_build_id() -> (String, bool) {
+        crossmist::imp::corrupt_build_id();
+        let error = describe.spawn(vec![1]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let unverified = SpawnOptions::new().verify_build(false);
+        let joined = describe.spawn_with(&unverified, vec![2]).unwrap().join();
+        (
+            error.to_string(),
+            matches!(joined, Err(JoinError::ExitedWithCode(1))),
+        )
+    }
+
+    let (message, failed) = spawn_with_corrupt_build_id.run().unwrap();
+    assert!(message.contains("different build"), "{message}");
+    assert!(message.contains("alloc::string::String"), "{message}");
+    assert!(failed);
+}
+
+#[test]
+fn control_channel() {
+    #[crossmist::func]
+    fn work(steps: u32) -> u32 {
+        // Mismatching types do not consume the channel
+        assert!(crossmist::control::control_channel::<String, String>().is_none());
+        let mut chan = crossmist::control::control_channel::<u32, bool>().unwrap();
+        assert!(crossmist::control::control_channel::<u32, bool>().is_none());
+        let mut done = 0;
+        for i in 0..steps {
+            chan.send(&i).unwrap();
+            if !chan.recv().unwrap().unwrap() {
+                break;
+            }
+            done += 1;
+        }
+        done
+    }
+
+    #[crossmist::func]
+    fn without_channel() -> bool {
+        crossmist::control::control_channel::<u32, bool>().is_none()
+    }
+
+    let (child, mut chan) = work.spawn_with_channel::<bool, u32>(10).unwrap();
+    for i in 0..5 {
+        assert_eq!(chan.recv().unwrap(), Some(i));
+        chan.send(&(i < 4)).unwrap();
+    }
+    assert_eq!(child.join().unwrap(), 4);
+    assert_eq!(chan.recv().unwrap(), None);
+
+    assert!(without_channel.run().unwrap());
+}
+
+#[test]
+fn executable() {
+    #[crossmist::func]
+    fn current_exe() -> std::path::PathBuf {
+        std::env::current_exe().unwrap()
+    }
+
+    let copy = std::env::temp_dir().join(format!(
+        "crossmist-executable-{}{}",
+        std::process::id(),
+        std::env::consts::EXE_SUFFIX,
+    ));
+    std::fs::copy(std::env::current_exe().unwrap(), &copy).unwrap();
+
+    let options = SpawnOptions::new().executable(Some(copy.clone()));
+    assert_eq!(options.get_executable(), Some(copy.as_path()));
+    let result = current_exe
+        .spawn_with(&options)
+        .and_then(|child| Ok(child.join()?));
+    std::fs::remove_file(&copy).unwrap();
+    assert_eq!(result.unwrap(), copy);
+
+    let missing = SpawnOptions::new().executable(Some(copy.clone()));
+    assert_eq!(
+        current_exe.spawn_with(&missing).unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn recv_into() {
+    let (mut tx, mut rx) = channel::<Vec<u32>>().unwrap();
+    let lens = [0, 1, 100, 1 << 20, 5];
+    let sender = std::thread::spawn(move || {
+        for len in lens {
+            tx.send(&(0..len).collect()).unwrap();
+        }
+        tx
+    });
+    let mut buf = Vec::new();
+    for len in lens {
+        let value: Vec<u32> = (0..len).collect();
+        assert_eq!(rx.recv_into(&mut buf).unwrap(), Some(value));
+        assert!(!buf.is_empty());
+    }
+    let mut tx = sender.join().unwrap();
+
+    // The buffer is reused for small messages once it is large enough
+    tx.send(&vec![1, 2, 3]).unwrap();
+    rx.recv_into(&mut buf).unwrap();
+    let capacity = buf.capacity();
+    tx.send(&vec![4, 5, 6]).unwrap();
+    assert_eq!(rx.recv_into(&mut buf).unwrap(), Some(vec![4, 5, 6]));
+    assert_eq!(buf.capacity(), capacity);
+
+    drop(tx);
+    assert_eq!(rx.recv_into(&mut buf).unwrap(), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn handshake_send_failure() {
+    #[crossmist::func]
+    fn inner() -> i32 {
+        1
+    }
+
+    for verify in [true, false] {
+        let options = SpawnOptions::new().verify_build(verify);
+        let pending = inner.spawn_deferred_with(&options).unwrap();
+        let pid = pending.id();
+        // Wait for the process to die without reaping it, so that the send fails for sure
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            let mut info = std::mem::zeroed();
+            assert_eq!(
+                libc::waitid(
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                ),
+                0
+            );
+        }
+        assert!(pending.finish().is_err());
+
+        // The process is reaped in background
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while unsafe { libc::kill(pid, 0) } == 0 {
+            assert!(std::time::Instant::now() < deadline, "Child is not reaped");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[test]
+fn duplex_shutdown_write() {
+    #[crossmist::func]
+    fn inner(mut chan: Duplex<u32, u32>) {
+        let mut sum = 0;
+        while let Some(value) = chan.recv().unwrap() {
+            sum += value;
+        }
+        chan.send(&sum).unwrap();
+        chan.send(&(sum + 1)).unwrap();
+    }
+
+    let (mut local, remote) = duplex::<u32, u32>().unwrap();
+    let child = inner.spawn(remote).unwrap();
+    for i in 1..=10 {
+        local.send(&i).unwrap();
+    }
+    local.shutdown_write().unwrap();
+    assert!(local.send(&0).is_err());
+    assert_eq!(local.recv().unwrap(), Some(55));
+    assert_eq!(local.into_receiver().recv().unwrap(), Some(56));
+    child.join().unwrap();
+}
+
+#[test]
+fn sender_close() {
+    let (mut tx, mut rx) = channel::<i32>().unwrap();
+    tx.send(&1).unwrap();
+    tx.feed(&2).unwrap();
+    #[cfg(unix)]
+    let mut clone = tx.try_clone().unwrap();
+    tx.close().unwrap();
+    assert_eq!(rx.recv().unwrap(), Some(1));
+    assert_eq!(rx.recv().unwrap(), Some(2));
+    // On Unix, the socket is shut down even though another sender is alive
+    assert_eq!(rx.recv().unwrap(), None);
+    #[cfg(unix)]
+    assert!(clone.send(&3).is_err());
+}
+
+#[test]
+fn concurrent_spawns() {
+    #[crossmist::func]
+    fn inner(id: u32, mut rx: Receiver<u32>, mut tx: Sender<u32>) -> u32 {
+        tx.send(&id).unwrap();
+        // Only returns once the parent drops the sender, which would hang if another child had
+        // inherited it
+        let mut sum = 0;
+        while let Some(value) = rx.recv().unwrap() {
+            sum += value;
+        }
+        sum
+    }
+
+    let threads: Vec<_> = (0..8)
+        .map(|thread| {
+            std::thread::spawn(move || {
+                (0..4)
+                    .map(|i| {
+                        let id = thread * 4 + i;
+                        let (to_child, rx) = channel::<u32>().unwrap();
+                        let (tx, from_child) = channel::<u32>().unwrap();
+                        let child = inner.spawn(id, rx, tx).unwrap();
+                        (id, child, to_child, from_child)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    let children: Vec<_> = threads
+        .into_iter()
+        .flat_map(|thread| thread.join().unwrap())
+        .collect();
+    assert_eq!(children.len(), 32);
+
+    for (id, child, mut to_child, mut from_child) in children {
+        assert_eq!(from_child.recv().unwrap(), Some(id));
+        to_child.send(&id).unwrap();
+        drop(to_child);
+        assert_eq!(child.join().unwrap(), id);
+        // The child's sender is not held by any other child either
+        assert_eq!(from_child.recv().unwrap(), None);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn many_fds() {
+    use std::io::{Read, Write};
+    use std::os::fd::OwnedFd;
+
+    #[crossmist::func]
+    fn inner(mut rx: Receiver<Vec<OwnedFd>>) -> Vec<u32> {
+        rx.recv()
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|fd| {
+                let mut buf = [0; 4];
+                std::fs::File::from(fd).read_exact(&mut buf).unwrap();
+                u32::from_le_bytes(buf)
+            })
+            .collect()
+    }
+
+    #[crossmist::func]
+    fn inner_limited(mut rx: Receiver<Vec<OwnedFd>>) -> (String, usize) {
+        let error = rx.recv().unwrap_err().to_string();
+        // The rest of the message is skipped
+        (error, rx.recv().unwrap().unwrap().len())
+    }
+
+    let fds = || {
+        (0..1000u32)
+            .map(|i| {
+                let (reader, mut writer) = std::io::pipe().unwrap();
+                writer.write_all(&i.to_le_bytes()).unwrap();
+                OwnedFd::from(reader)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let (mut tx, rx) = channel::<Vec<OwnedFd>>().unwrap();
+    let child = inner.spawn(rx).unwrap();
+    tx.send(&fds()).unwrap();
+    assert_eq!(child.join().unwrap(), (0..1000).collect::<Vec<_>>());
+
+    let (mut tx, rx) = channel::<Vec<OwnedFd>>().unwrap();
+    let options = SpawnOptions::new().open_files_limit(Some(64));
+    let child = inner_limited.spawn_with(&options, rx).unwrap();
+    tx.send(&fds()).unwrap();
+    tx.send(&Vec::new()).unwrap();
+    let (error, len) = child.join().unwrap();
+    assert!(
+        error.contains("limit on the number of open files"),
+        "{error}"
+    );
+    assert_eq!(len, 0);
+}
+
+#[test]
+fn process_name() {
+    #[crossmist::func]
+    fn inner() -> (String, String) {
+        let name = std::env::args().next().unwrap();
+        #[cfg(target_os = "linux")]
+        let comm = std::fs::read_to_string("/proc/self/comm")
+            .unwrap()
+            .trim_end()
+            .to_string();
+        #[cfg(not(target_os = "linux"))]
+        let comm = String::new();
+        (name, comm)
+    }
+
+    let own_name = std::env::args().next().unwrap();
+    let (name, _) = inner.run().unwrap();
+    assert_eq!(name, own_name);
+
+    for posix_spawn in [false, true] {
+        let options = SpawnOptions::new()
+            .posix_spawn(posix_spawn)
+            .process_name(Some("worker 1".into()));
+        assert_eq!(
+            options.get_process_name(),
+            Some(std::ffi::OsStr::new("worker 1"))
+        );
+        let (name, comm) = inner.spawn_with(&options).unwrap().join().unwrap();
+        assert_eq!(name, "worker 1");
+        if cfg!(target_os = "linux") {
+            assert_eq!(comm, "worker 1");
+        }
+    }
+
+    let options = SpawnOptions::new().process_name(Some("a\0b".into()));
+    assert_eq!(
+        inner.spawn_with(&options).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}
+
+#[derive(Debug, PartialEq, Object)]
+struct Frame {
+    name: String,
+    rows: Vec<Vec<u8>>,
+}
+
+#[test]
+fn recv_ref() {
+    let (mut tx, mut rx) = channel::<Frame>().unwrap();
+    let frame = |name: &str, n: usize| Frame {
+        name: name.to_string(),
+        rows: (0..n).map(|i| vec![i as u8; 1000]).collect(),
+    };
+
+    let mut target = Frame {
+        name: String::new(),
+        rows: Vec::new(),
+    };
+    tx.send(&frame("first frame", 10)).unwrap();
+    assert!(rx.recv_ref(&mut target).unwrap());
+    assert_eq!(target, frame("first frame", 10));
+
+    // The allocations of the previous value are reused
+    let name_ptr = target.name.as_ptr();
+    let rows_ptr = target.rows.as_ptr();
+    let row_ptr = target.rows[3].as_ptr();
+    tx.send(&frame("second", 5)).unwrap();
+    assert!(rx.recv_ref(&mut target).unwrap());
+    assert_eq!(target, frame("second", 5));
+    assert_eq!(target.name.as_ptr(), name_ptr);
+    assert_eq!(target.rows.as_ptr(), rows_ptr);
+    assert_eq!(target.rows[3].as_ptr(), row_ptr);
+
+    tx.send(&frame("third", 20)).unwrap();
+    assert!(rx.recv_ref(&mut target).unwrap());
+    assert_eq!(target, frame("third", 20));
+
+    drop(tx);
+    assert!(!rx.recv_ref(&mut target).unwrap());
+    assert_eq!(target, frame("third", 20));
+}
+
+// A stream for a runtime crossmist has no built-in support for
+#[cfg(unix)]
+#[derive(Debug)]
+struct CustomStream(smol::Async<std::os::unix::net::UnixStream>);
+
+#[cfg(unix)]
+unsafe impl crossmist::NonTrivialObject for CustomStream {
+    fn serialize_self_non_trivial<'a>(&'a self, s: &mut crossmist::Serializer<'a>) {
+        use std::os::fd::AsFd;
+        s.serialize_handle(self.0.as_fd());
+    }
+    unsafe fn deserialize_self_non_trivial(
+        d: &mut crossmist::Deserializer,
+    ) -> std::io::Result<Self> {
+        <Self as crossmist::asynchronous::AsyncStream>::try_new(d.deserialize()?)
+    }
+}
+
+#[cfg(unix)]
+unsafe impl crossmist::asynchronous::AsyncStream for CustomStream {
+    fn try_new(stream: crossmist::asynchronous::SyncStream) -> std::io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        smol::Async::new(stream).map(Self)
+    }
+
+    fn as_handle(&self) -> std::os::fd::BorrowedFd<'_> {
+        use std::os::fd::AsFd;
+        self.0.as_fd()
+    }
+
+    fn as_raw_handle(&self) -> std::os::fd::RawFd {
+        use std::os::fd::AsRawFd;
+        self.0.as_raw_fd()
+    }
+
+    const IS_BLOCKING: bool = false;
+
+    async fn blocking_write<T>(
+        &self,
+        mut f: impl FnMut() -> std::io::Result<T> + Send,
+    ) -> std::io::Result<T> {
+        self.0.write_with(|_| f()).await
+    }
+
+    fn poll_blocking_write<T>(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        mut f: impl FnMut() -> std::io::Result<T>,
+    ) -> std::task::Poll<std::io::Result<T>> {
+        loop {
+            match f() {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return std::task::Poll::Ready(result),
+            }
+            std::task::ready!(self.0.poll_writable(cx))?;
+        }
+    }
+
+    async fn blocking_read<T>(
+        &self,
+        mut f: impl FnMut() -> std::io::Result<T> + Send,
+    ) -> std::io::Result<T> {
+        self.0.read_with(|_| f()).await
+    }
+
+    fn poll_blocking_read<T>(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        mut f: impl FnMut() -> std::io::Result<T>,
+    ) -> std::task::Poll<std::io::Result<T>> {
+        loop {
+            match f() {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                result => return std::task::Poll::Ready(result),
+            }
+            std::task::ready!(self.0.poll_readable(cx))?;
+        }
+    }
+
+    // wait_readable is left to the default implementation
+}
+
+#[cfg(unix)]
+#[crossmist::func]
+fn send_via_custom_stream(mut tx: crossmist::asynchronous::Sender<CustomStream, u32>, n: u32) {
+    smol::block_on(async {
+        for i in 0..n {
+            tx.send(&i).await.unwrap();
+        }
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn custom_async_stream() {
+    use crossmist::asynchronous;
+
+    smol::block_on(async {
+        let (mut tx, mut rx) = asynchronous::channel::<CustomStream, String>().unwrap();
+        tx.send(&"hello".to_string()).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Some("hello".to_string()));
+
+        // Channels created by the sync API can be converted
+        let (mut sync_tx, rx) = channel::<String>().unwrap();
+        let mut rx = asynchronous::Receiver::<CustomStream, String>::try_from(rx).unwrap();
+        sync_tx.send(&"converted".to_string()).unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Some("converted".to_string()));
+
+        // Custom streams can be passed to children
+        let (tx, mut rx) = asynchronous::channel::<CustomStream, u32>().unwrap();
+        let child = send_via_custom_stream.spawn(tx, 5).unwrap();
+        for i in 0..5 {
+            assert_eq!(rx.recv().await.unwrap(), Some(i));
+        }
+        assert_eq!(rx.recv().await.unwrap(), None);
+        child.join().unwrap();
+
+        let (mut local, mut remote) = asynchronous::duplex::<CustomStream, u32, u32>().unwrap();
+        assert!(matches!(
+            local.request_timeout(&1, Duration::from_millis(50)).await,
+            Err(RequestError::Timeout)
+        ));
+        let responder = std::thread::spawn(move || {
+            smol::block_on(async {
+                while let Some(x) = remote.recv().await.unwrap() {
+                    remote.send(&(x * x)).await.unwrap();
+                }
+            })
+        });
+        assert_eq!(
+            local
+                .request_timeout(&2, Duration::from_secs(5))
+                .await
+                .unwrap(),
+            4
+        );
+        drop(local);
+        responder.join().unwrap();
+    });
+}
+
+#[test]
+fn spawn_with_stdio() {
+    use std::io::Read;
+
+    #[crossmist::func]
+    fn print(message: String) {
+        println!("{message}");
+        eprintln!("error: {message}");
+    }
+
+    assert_eq!(SpawnOptions::new().get_stdout(), &Stdio::inherit());
+    let options = SpawnOptions::new()
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    assert_eq!(options.get_stderr(), &Stdio::null());
+    let mut child = print.spawn_with(&options, "hello".to_string()).unwrap();
+    assert!(child.take_stderr().is_none());
+    let mut stdout = child.take_stdout().unwrap();
+    assert!(child.take_stdout().is_none());
+    child.join().unwrap();
+    let mut output = String::new();
+    stdout.read_to_string(&mut output).unwrap();
+    assert_eq!(output, "hello\n");
+
+    let path = std::env::temp_dir().join(format!("crossmist-stderr-{}", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    let options = SpawnOptions::new()
+        .stdout(Stdio::null())
+        .stderr(Stdio::from(file));
+    print
+        .spawn_with(&options, "world".to_string())
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "error: world\n");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn inherit_fd() {
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    #[crossmist::func]
+    fn read_all(fds: Vec<RawFd>, file: File) -> Vec<String> {
+        fds.into_iter()
+            .map(|fd| unsafe { File::from_raw_fd(fd) })
+            .chain([file])
+            .map(|mut file| {
+                let mut data = String::new();
+                file.read_to_string(&mut data).unwrap();
+                data
+            })
+            .collect()
+    }
+
+    fn pipe_with(data: &str) -> OwnedFd {
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        writer.write_all(data.as_bytes()).unwrap();
+        reader.into()
+    }
+
+    for posix_spawn in [false, true] {
+        let a = pipe_with("a");
+        let b = pipe_with("b");
+        let c = pipe_with("c");
+        let argument = File::from(pipe_with("argument"));
+        // The descriptors that are allocated next are likely taken by the channel to the child
+        let next = File::open("/dev/null").unwrap().as_raw_fd();
+        let targets = vec![
+            b.as_raw_fd(),
+            a.as_raw_fd(),
+            argument.as_raw_fd(),
+            next,
+            next + 1,
+        ];
+        let options = SpawnOptions::new()
+            .posix_spawn(posix_spawn)
+            .inherit_fd(a, targets[0])
+            .inherit_fd(b, targets[1])
+            .inherit_fd(c, targets[2])
+            .inherit_fd(pipe_with("d"), targets[3])
+            .inherit_fd(pipe_with("e"), targets[4]);
+        assert_eq!(
+            options
+                .get_inherited_fds()
+                .map(|(_, target)| target)
+                .collect::<Vec<_>>(),
+            targets
+        );
+        assert_eq!(
+            read_all
+                .spawn_with(&options, targets, argument)
+                .unwrap()
+                .join()
+                .unwrap(),
+            ["a", "b", "c", "d", "e", "argument"]
+        );
+    }
+
+    for targets in [[-1, 100], [100, 100], [1, 100]] {
+        let options = SpawnOptions::new()
+            .stdout(Stdio::null())
+            .inherit_fd(pipe_with(""), targets[0])
+            .inherit_fd(pipe_with(""), targets[1]);
+        let argument = File::from(pipe_with(""));
+        assert_eq!(
+            read_all
+                .spawn_with(&options, vec![], argument)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
+
+#[test]
+fn spawn_with_env_and_current_dir() {
+    #[crossmist::func]
+    fn inspect() -> (Option<String>, Option<String>, usize, std::path::PathBuf) {
+        (
+            std::env::var("CROSSMIST_TEST_VAR").ok(),
+            std::env::var("PATH").ok(),
+            std::env::vars_os().count(),
+            std::env::current_dir().unwrap(),
+        )
+    }
+
+    let dir = std::env::temp_dir().canonicalize().unwrap();
+    let options = SpawnOptions::new()
+        .env("CROSSMIST_TEST_VAR", "value")
+        .env_remove("PATH")
+        .current_dir(Some(dir.clone()));
+    assert_eq!(
+        options.get_envs().collect::<Vec<_>>(),
+        [
+            ("CROSSMIST_TEST_VAR".as_ref(), Some("value".as_ref())),
+            ("PATH".as_ref(), None),
+        ]
+    );
+    assert_eq!(options.get_current_dir(), Some(dir.as_path()));
+    let (var, path, _, cwd) = inspect.spawn_with(&options).unwrap().join().unwrap();
+    assert_eq!(var.as_deref(), Some("value"));
+    assert_eq!(path, None);
+    assert_eq!(cwd, dir);
+    assert_eq!(std::env::var_os("CROSSMIST_TEST_VAR"), None);
+
+    let options = SpawnOptions::new()
+        .env("CROSSMIST_TEST_VAR", "ignored")
+        .env_clear()
+        .env("CROSSMIST_TEST_VAR", "cleared");
+    assert!(options.get_env_clear());
+    let (var, _, n_vars, _) = inspect.spawn_with(&options).unwrap().join().unwrap();
+    assert_eq!(var.as_deref(), Some("cleared"));
+    assert_eq!(n_vars, 1);
+
+    #[cfg(target_os = "linux")]
+    {
+        let options = options.posix_spawn(true).current_dir(Some(dir.clone()));
+        let (var, _, _, cwd) = inspect.spawn_with(&options).unwrap().join().unwrap();
+        assert_eq!(var.as_deref(), Some("cleared"));
+        assert_eq!(cwd, dir);
+    }
+
+    let options = SpawnOptions::new().env("INVALID=NAME", "value");
+    assert_eq!(
+        inspect.spawn_with(&options).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+    let options = SpawnOptions::new().current_dir(Some(dir.join("crossmist-nonexistent")));
+    assert!(inspect.spawn_with(&options).is_err());
+}
+
+#[test]
+fn streaming() {
+    #[crossmist::func]
+    fn squares(n: u32) -> impl Iterator<Item = u32> {
+        (0..n).map(|i| i * i)
+    }
+
+    #[crossmist::func]
+    fn fail_after(n: u32) -> impl Iterator<Item = u32> {
+        (0..).inspect(move |&i| {
+            if i == n {
+                panic!("Stopped after {n} items");
+            }
+        })
+    }
+
+    let items: Vec<u32> = squares.spawn(5).unwrap().map(Result::unwrap).collect();
+    assert_eq!(items, [0, 1, 4, 9, 16]);
+    squares.spawn(1000).unwrap().join().unwrap();
+
+    let mut stream = fail_after.spawn(3).unwrap();
+    for i in 0..3 {
+        assert_eq!(stream.next().unwrap().unwrap(), i);
+    }
+    match stream.next() {
+        Some(Err(JoinError::Panicked { message, .. })) => {
+            assert_eq!(message, "Stopped after 3 items")
+        }
+        other => panic!("Unexpected item {other:?}"),
+    }
+    assert!(stream.next().is_none());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn cpu_affinity() {
+    fn current_affinity() -> Vec<usize> {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        assert_eq!(
+            unsafe { libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) },
+            0
+        );
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+            .collect()
+    }
+
+    #[crossmist::func]
+    fn report_affinity(mut tx: Sender<Vec<usize>>) {
+        tx.send(&current_affinity()).unwrap();
+    }
+
+    let cpu = *current_affinity().last().unwrap();
+    for posix_spawn in [false, true] {
+        let options = SpawnOptions::new()
+            .cpu_affinity(Some(vec![cpu]))
+            .posix_spawn(posix_spawn);
+        assert_eq!(options.get_cpu_affinity(), Some(&[cpu][..]));
+        let (tx, mut rx) = channel().unwrap();
+        let child = report_affinity.spawn_with(&options, tx).unwrap();
+        assert_eq!(rx.recv().unwrap(), Some(vec![cpu]));
+        assert_eq!(child.get_cpu_affinity().unwrap(), vec![cpu]);
+        child.join().unwrap();
+
+        // The OS would silently ignore the CPU that does not exist
+        let missing = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) } as usize;
+        let (tx, _rx) = channel().unwrap();
+        let options = options.cpu_affinity(Some(vec![cpu, missing]));
+        assert_eq!(
+            report_affinity.spawn_with(&options, tx).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    for cpus in [vec![], vec![usize::MAX]] {
+        let (tx, _rx) = channel().unwrap();
+        let options = SpawnOptions::new().cpu_affinity(Some(cpus));
+        assert_eq!(
+            report_affinity.spawn_with(&options, tx).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
+
+#[test]
+fn priority() {
+    #[crossmist::func]
+    fn wait(mut rx: Receiver<()>) {
+        rx.recv().unwrap();
+    }
+
+    #[cfg(unix)]
+    let modes = [false, true];
+    #[cfg(windows)]
+    let modes = [false];
+    for posix_spawn in modes {
+        // Lowering the priority is always allowed
+        let options = SpawnOptions::new()
+            .priority(Some(19))
+            .posix_spawn(posix_spawn);
+        assert_eq!(options.get_priority(), Some(19));
+        let (mut tx, rx) = channel().unwrap();
+        let child = wait.spawn_with(&options, rx).unwrap();
+        assert_eq!(child.get_priority().unwrap(), 19);
+        tx.send(&()).unwrap();
+        child.join().unwrap();
+    }
+
+    for priority in [-21, 20] {
+        let (_tx, rx) = channel().unwrap();
+        let options = SpawnOptions::new().priority(Some(priority));
+        assert_eq!(
+            wait.spawn_with(&options, rx).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn unshare() {
+    use crossmist::Namespaces;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+
+    #[crossmist::func]
+    fn inspect(addr: SocketAddr) -> (bool, u32, u32) {
+        (
+            TcpStream::connect(addr).is_ok(),
+            std::process::id(),
+            unsafe { libc::getuid() },
+        )
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert!(inspect.run(addr).unwrap().0);
+
+    let namespaces = Namespaces::USER | Namespaces::NET | Namespaces::PID;
+    assert!(namespaces.contains(Namespaces::NET));
+    assert!(!namespaces.contains(Namespaces::MOUNT));
+    let options = SpawnOptions::new().unshare(namespaces);
+    assert_eq!(options.get_unshare(), namespaces);
+    let child = match inspect.spawn_with(&options, addr) {
+        // Unprivileged user namespaces may be disabled
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return,
+        result => result.unwrap(),
+    };
+    assert_ne!(child.id(), 1);
+    assert_eq!(child.join().unwrap(), (false, 1, 0));
+
+    let options = options.posix_spawn(true);
+    assert_eq!(
+        inspect.spawn_with(&options, addr).unwrap_err().kind(),
+        std::io::ErrorKind::Unsupported
+    );
+}
@@ -0,0 +1,62 @@
+use crossmist::{duplex, replay::Direction, replay::Session, Duplex};
+use std::io::ErrorKind;
+
+#[ctor::ctor]
+fn ctor() {
+    crossmist::init();
+}
+
+#[crossmist::func]
+fn square(mut chan: Duplex<i32, i32>) {
+    while let Some(x) = chan.recv().unwrap() {
+        chan.send(&(x * x)).unwrap();
+    }
+}
+
+#[test]
+fn record_and_replay() {
+    let path = std::env::temp_dir().join(format!("crossmist-replay-{}", std::process::id()));
+
+    let session = Session::record(&path).unwrap();
+    let mut chan = session
+        .duplex("square", || {
+            let (local, downstream) = duplex::<i32, i32>()?;
+            square.spawn(downstream)?;
+            Ok(local)
+        })
+        .unwrap();
+    for x in [3, 5, 7] {
+        assert_eq!(chan.request(&x).unwrap(), x * x);
+    }
+    drop(chan);
+    drop(session);
+
+    let entries = unsafe { crossmist::replay::read_log(&path) }.unwrap();
+    assert_eq!(entries.len(), 6);
+    assert!(entries.iter().all(|entry| entry.channel == "square"));
+    assert_eq!(entries[0].direction, Direction::Sent);
+    assert_eq!(entries[1].direction, Direction::Received);
+    assert!(entries.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+
+    let session = unsafe { Session::replay(&path) }.unwrap();
+    assert!(session.is_replaying());
+    let mut chan = session
+        .duplex::<i32, i32>("square", || panic!("Replay must not spawn children"))
+        .unwrap();
+    assert_eq!(chan.request(&3).unwrap(), 9);
+    assert_eq!(chan.request(&5).unwrap(), 25);
+    assert_eq!(chan.request(&8).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(chan.recv().unwrap(), Some(49));
+    assert_eq!(chan.recv().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+    // Pretend that the recording was made by another build
+    let mut data = std::fs::read(&path).unwrap();
+    data[8] ^= 1;
+    std::fs::write(&path, data).unwrap();
+    assert_eq!(
+        unsafe { Session::replay(&path) }.unwrap_err().kind(),
+        ErrorKind::InvalidData,
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
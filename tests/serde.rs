@@ -1,5 +1,11 @@
-use crossmist::{lambda, Deserializer, FnOnceObject, Object, Serializer};
+use crossmist::handles::OwnedHandle;
+use crossmist::{lambda, Deserializer, FnOnceObject, Framing, MapDelta, Object, Serializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
 
 fn serde<T: Object>(x: &T) -> T {
     let mut s = Serializer::new();
@@ -158,3 +164,748 @@ fn test_duplex() {
     drop(local);
     assert!(downstream.recv().unwrap().is_none());
 }
+
+#[test]
+fn framing_prefix() {
+    for framing in [Framing::Fixed, Framing::Varint] {
+        for len in [0, 1, 127, 128, 16383, 16384, usize::MAX] {
+            let prefix = framing.encode_len(len);
+            assert_eq!(
+                framing.decode_len(&prefix),
+                Some((len as u64, prefix.len()))
+            );
+            assert_eq!(framing.decode_len(&prefix[..prefix.len() - 1]), None);
+        }
+    }
+    assert_eq!(Framing::Varint.encode_len(127), [0x7f]);
+    assert_eq!(Framing::Varint.encode_len(300), [0xac, 0x02]);
+}
+
+#[test]
+fn fixed_framing_prefix_width() {
+    for len in [0, 1, 255, 256, u32::MAX as usize, usize::MAX] {
+        let prefix = Framing::Fixed.encode_len(len);
+        assert_eq!(prefix.len(), 8);
+        assert_eq!(prefix, (len as u64).to_le_bytes());
+    }
+}
+
+#[test]
+fn map_delta() {
+    let mut delta = MapDelta::new();
+    delta.insert("a".to_string(), vec![1, 2]);
+    delta.remove("b".to_string());
+    delta.insert("a".to_string(), vec![3]);
+    test_idempotency(delta.clone());
+
+    let mut map = HashMap::from([("b".to_string(), vec![0])]);
+    serde(&delta).apply(&mut map);
+    assert_eq!(map, HashMap::from([("a".to_string(), vec![3])]));
+}
+
+#[test]
+fn large_slices() {
+    test_idempotency((0..100000u32).collect::<Vec<_>>());
+    test_idempotency(vec![
+        vec![1u8; 100000],
+        vec![],
+        vec![2u8; 20000],
+        vec![3u8; 10],
+    ]);
+    test_idempotency((
+        "a".repeat(50000),
+        [7u64; 4096],
+        Box::new([5u16; 10000]) as Box<[u16]>,
+    ));
+}
+
+#[derive(Debug, PartialEq, Object)]
+enum Generic<T> {
+    A(T),
+    B { x: T, y: String },
+    C,
+}
+
+#[derive(Debug, PartialEq, Object)]
+enum List<T> {
+    Cons(T, Box<List<T>>),
+    Nil,
+}
+
+#[derive(Debug, PartialEq, Object)]
+enum Single {
+    Only(String),
+}
+
+#[derive(Debug, PartialEq, Object)]
+#[repr(u8)]
+enum Discriminants {
+    A = 3,
+    B = 200,
+}
+
+fn serialized_len<T: Object>(x: &T) -> usize {
+    let mut s = Serializer::new();
+    s.serialize(x);
+    s.into_vec().len()
+}
+
+#[test]
+fn generic_enum() {
+    test_idempotency(Generic::A(vec![1, 2, 3]));
+    test_idempotency(Generic::B {
+        x: "x".to_string(),
+        y: "y".to_string(),
+    });
+    test_idempotency(Generic::<i32>::C);
+    test_idempotency(List::Cons(1, Box::new(List::Cons(2, Box::new(List::Nil)))));
+}
+
+#[test]
+fn enum_tag() {
+    let s = "hello".to_string();
+    assert_eq!(serialized_len(&Single::Only(s.clone())), serialized_len(&s));
+    assert_eq!(
+        serialized_len(&Generic::A(s.clone())),
+        serialized_len(&s) + 1
+    );
+    test_idempotency(Single::Only(s));
+    assert_eq!(serde(&Discriminants::A) as u8, 3);
+    assert_eq!(serde(&Discriminants::B) as u8, 200);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Object)]
+struct Point {
+    x: f64,
+    y: f64,
+    tag: [u8; 4],
+}
+
+#[derive(Clone, Debug, PartialEq, Object)]
+struct Labeled {
+    point: Point,
+    label: String,
+}
+
+#[test]
+fn serializer_with_buffer() {
+    let mut buffer = Vec::with_capacity(1024);
+    buffer.extend_from_slice(b"stale");
+    let ptr = buffer.as_ptr();
+    let value = "hello".to_string();
+    let mut s = Serializer::with_buffer(buffer);
+    s.serialize(&value);
+    let data = s.into_vec();
+    assert_eq!(data.as_ptr(), ptr);
+    assert_eq!(data.len(), serialized_len(&value));
+    let mut d = Deserializer::new(data, Vec::new());
+    assert_eq!(unsafe { d.deserialize::<String>() }.unwrap(), "hello");
+}
+
+#[test]
+fn plain_old_data_sequences() {
+    // Sequences of plain old data are a length followed by the bytes of the elements
+    let empty: Vec<f64> = Vec::new();
+    assert_eq!(serialized_len(&empty), std::mem::size_of::<usize>());
+    test_idempotency(empty);
+
+    let huge: Vec<f64> = (0..1 << 22).map(|i| i as f64 * 0.5).collect();
+    assert_eq!(
+        serialized_len(&huge),
+        std::mem::size_of::<usize>() + std::mem::size_of_val(huge.as_slice())
+    );
+    test_idempotency(huge);
+
+    let boxed: Box<[u32]> = (0..1000).collect();
+    assert_eq!(serialized_len(&boxed), std::mem::size_of::<usize>() + 4000);
+    test_idempotency(boxed);
+
+    let array = [[1u16, 2, 3]; 100];
+    assert_eq!(serialized_len(&array), 600);
+    test_idempotency(array);
+
+    test_idempotency(vec![(); 1000]);
+
+    // A length exceeding the message is rejected before allocating
+    let mut s = Serializer::new();
+    s.serialize(&(usize::MAX / 8));
+    let mut d = Deserializer::new(s.into_vec(), Vec::new());
+    assert!(unsafe { d.deserialize::<Vec<f64>>() }.is_err());
+}
+
+#[test]
+fn derived_plain_old_data() {
+    let point = Point {
+        x: 1.5,
+        y: -2.0,
+        tag: *b"pt00",
+    };
+
+    // Plain old data is copied as is, including the representation of Option
+    assert_eq!(
+        serialized_len(&Some(point)),
+        std::mem::size_of::<Option<Point>>()
+    );
+    let points = vec![point; 1000];
+    assert_eq!(
+        serialized_len(&points),
+        std::mem::size_of::<usize>() + std::mem::size_of_val(points.as_slice())
+    );
+    test_idempotency(points);
+
+    // Fields that are not plain old data make the whole type serialized field by field
+    let labeled = Labeled {
+        point,
+        label: "origin".to_string(),
+    };
+    assert_eq!(
+        serialized_len(&Some(labeled.clone())),
+        1 + serialized_len(&labeled)
+    );
+    test_idempotency(labeled);
+}
+
+#[derive(Debug, PartialEq, Object)]
+struct Named {
+    name: Cow<'static, str>,
+    data: Cow<'static, [u8]>,
+}
+
+#[test]
+fn cow() {
+    let borrowed = vec![
+        Named {
+            name: Cow::Borrowed("borrowed"),
+            data: Cow::Borrowed(&[1, 2, 3]),
+        },
+        Named {
+            name: Cow::Owned("owned".to_string()),
+            data: Cow::Owned(vec![4, 5]),
+        },
+    ];
+    let owned = serde(&borrowed);
+    assert_eq!(owned, borrowed);
+    for item in &owned {
+        assert!(matches!(item.name, Cow::Owned(_)));
+        assert!(matches!(item.data, Cow::Owned(_)));
+    }
+
+    test_idempotency(HashMap::from([(
+        "key".to_string(),
+        Cow::<'static, str>::Borrowed("value"),
+    )]));
+
+    // Compatible with the owned types
+    let mut s = Serializer::new();
+    s.serialize(&Cow::<'static, str>::Borrowed("text"));
+    let mut d = Deserializer::new(s.into_vec(), Vec::new());
+    assert_eq!(unsafe { d.deserialize::<String>() }.unwrap(), "text");
+}
+
+#[test]
+fn shared_pointers() {
+    let inner = Arc::new(5);
+    let outer = Arc::new(inner.clone());
+    let (v, other, inner) = serde(&(vec![outer.clone(), outer], Arc::new(1), inner));
+    assert!(Arc::ptr_eq(&v[0], &v[1]));
+    assert!(Arc::ptr_eq(&*v[0], &inner));
+    assert!(!Arc::ptr_eq(&other, &inner));
+}
+
+#[test]
+fn shared_unsized_pointers() {
+    use std::rc::Rc;
+
+    let name: Arc<str> = Arc::from("config");
+    let values: Arc<[Arc<str>]> = Arc::from(vec![name.clone(), Arc::from("other")]);
+    let (name, values, copy) = serde(&(name, values.clone(), values));
+    assert_eq!(&*name, "config");
+    assert_eq!(&*values[1], "other");
+    assert!(Arc::ptr_eq(&values, &copy));
+    assert!(Arc::ptr_eq(&values[0], &name));
+
+    let empty: Rc<[u32]> = Rc::from(Vec::new());
+    let (a, b) = serde(&(empty.clone(), empty));
+    assert!(a.is_empty());
+    assert!(Rc::ptr_eq(&a, &b));
+
+    // Each message produces a fresh allocation
+    let value = Arc::new(1);
+    assert!(!Arc::ptr_eq(&serde(&value), &serde(&value)));
+}
+
+#[test]
+fn corrupt_shared_pointer() {
+    let mut s = Serializer::new();
+    s.serialize(&5usize);
+    let mut d = Deserializer::new(s.into_vec(), Vec::new());
+    assert!(unsafe { d.deserialize::<Arc<u32>>() }.is_err());
+}
+
+#[test]
+fn large_tuples_and_arrays() {
+    // std only implements PartialEq and Debug for tuples up to 12 items long
+    let (a, b, c, d, e, f, g, h, i, j, k, l, m, n, o, p) = serde(&(
+        1u8,
+        2u16,
+        3u32,
+        4u64,
+        5i8,
+        6i16,
+        7i32,
+        8i64,
+        "9".to_string(),
+        10usize,
+        vec![11],
+        Some(12),
+        13u128,
+        [14; 3],
+        15isize,
+        (16,),
+    ));
+    assert_eq!((a, b, c, d, e, f, g, h), (1, 2, 3, 4, 5, 6, 7, 8));
+    assert_eq!(
+        (i, j, k, l, m, n, o, p),
+        (
+            "9".to_string(),
+            10,
+            vec![11],
+            Some(12),
+            13,
+            [14; 3],
+            15,
+            (16,)
+        )
+    );
+    test_idempotency(std::array::from_fn::<u32, 64, _>(|i| i as u32));
+    test_idempotency(std::array::from_fn::<String, 8, _>(|i| "x".repeat(i)));
+    test_idempotency([[vec![1, 2], vec![]], [vec![3], vec![4, 5, 6]]]);
+}
+
+#[test]
+fn partially_deserialized_array() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Tracked(u32);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    unsafe impl crossmist::NonTrivialObject for Tracked {
+        fn serialize_self_non_trivial<'a>(&'a self, s: &mut Serializer<'a>) {
+            s.serialize(&self.0);
+        }
+        unsafe fn deserialize_self_non_trivial(d: &mut Deserializer) -> std::io::Result<Self> {
+            match d.deserialize()? {
+                u32::MAX => Err(std::io::Error::other("invalid value")),
+                value => Ok(Self(value)),
+            }
+        }
+    }
+
+    let array: [Tracked; 8] = std::array::from_fn(|i| Tracked(if i == 3 { u32::MAX } else { 0 }));
+    let mut s = Serializer::new();
+    s.serialize(&array);
+    let mut d = Deserializer::new(s.into_vec(), Vec::new());
+    assert!(unsafe { d.deserialize::<[Tracked; 8]>() }.is_err());
+    // The three elements deserialized before the failure are dropped exactly once
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 3);
+    drop(array);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 11);
+}
+
+fn tagged_file(tag: &str) -> File {
+    let path = std::env::temp_dir().join(format!("crossmist-serde-{}-{tag}", std::process::id()));
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file.write_all(tag.as_bytes()).unwrap();
+    file
+}
+
+fn read_tag(mut file: &File) -> String {
+    let mut tag = String::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_string(&mut tag).unwrap();
+    tag
+}
+
+#[test]
+fn collections_with_handles() {
+    let keys = ["a", "b", "c", "d", "e"];
+
+    let map: HashMap<String, File> = keys
+        .iter()
+        .map(|&key| (key.to_string(), tagged_file(&format!("map-{key}"))))
+        .collect();
+    for (key, file) in serde(&map) {
+        assert_eq!(read_tag(&file), format!("map-{key}"));
+    }
+
+    let map: BTreeMap<String, OwnedHandle> = keys
+        .iter()
+        .map(|&key| (key.to_string(), tagged_file(&format!("btree-{key}")).into()))
+        .collect();
+    for (key, handle) in serde(&map) {
+        assert_eq!(read_tag(&File::from(handle)), format!("btree-{key}"));
+    }
+
+    let deque: VecDeque<(u32, File)> = (0..5)
+        .map(|i| (i, tagged_file(&format!("deque-{i}"))))
+        .collect();
+    for (i, file) in serde(&deque) {
+        assert_eq!(read_tag(&file), format!("deque-{i}"));
+    }
+
+    test_idempotency(keys.map(String::from).into_iter().collect::<HashSet<_>>());
+    test_idempotency(keys.map(String::from).into_iter().collect::<BTreeSet<_>>());
+    let heap: BinaryHeap<_> = keys.map(String::from).into_iter().collect();
+    assert_eq!(serde(&heap).into_sorted_vec(), heap.into_sorted_vec());
+}
+
+#[test]
+fn maps() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    test_idempotency(HashMap::from([
+        ("empty".to_string(), Vec::new()),
+        ("bytes".to_string(), vec![1u8, 2, 3]),
+    ]));
+    test_idempotency(HashMap::<String, Vec<u8>>::new());
+    test_idempotency(BTreeMap::<u32, String>::new());
+
+    // The hasher is constructed anew on the receiving side
+    let mut map: HashMap<u32, u32, BuildHasherDefault<DefaultHasher>> = HashMap::default();
+    map.extend((0..100).map(|i| (i, i * i)));
+    test_idempotency(map);
+    let set: HashSet<u32, BuildHasherDefault<DefaultHasher>> = (0..100).collect();
+    test_idempotency(set);
+
+    let map: BTreeMap<i32, String> = [5, -3, 10, 0]
+        .into_iter()
+        .map(|i| (i, i.to_string()))
+        .collect();
+    let keys: Vec<i32> = serde(&map).into_keys().collect();
+    assert_eq!(keys, [-3, 0, 5, 10]);
+}
+
+#[test]
+fn deserialize_into() {
+    fn serde_into<T: Object>(x: &T, target: &mut T) {
+        let mut s = Serializer::new();
+        s.serialize(x);
+        let mut d = Deserializer::new(s.into_vec(), Vec::new());
+        unsafe { d.deserialize_into(target) }.expect("Deserialization failed");
+    }
+
+    let mut bytes = Vec::with_capacity(100);
+    let ptr = bytes.as_ptr();
+    serde_into(&vec![1u8, 2, 3], &mut bytes);
+    assert_eq!(bytes, [1, 2, 3]);
+    assert_eq!(bytes.as_ptr(), ptr);
+
+    let mut text = "a longer string than the next one".to_string();
+    let ptr = text.as_ptr();
+    serde_into(&"short".to_string(), &mut text);
+    assert_eq!(text, "short");
+    assert_eq!(text.as_ptr(), ptr);
+
+    let mut nested = vec![vec![0u32; 10]; 3];
+    serde_into(&vec![vec![1], vec![2, 3]], &mut nested);
+    assert_eq!(nested, [vec![1], vec![2, 3]]);
+    serde_into(&vec![vec![4]; 4], &mut nested);
+    assert_eq!(nested, vec![vec![4]; 4]);
+
+    // Types without an override are overwritten
+    let mut map = HashMap::from([(1, 2)]);
+    serde_into(&HashMap::from([(3, 4)]), &mut map);
+    assert_eq!(map, HashMap::from([(3, 4)]));
+    let mut pair = SimplePair { x: 1, y: 2 };
+    serde_into(&SimplePair { x: 3, y: 4 }, &mut pair);
+    assert_eq!(pair, SimplePair { x: 3, y: 4 });
+}
+
+#[test]
+fn corrupt_collection_length() {
+    let mut s = Serializer::new();
+    s.serialize(&usize::MAX);
+    let mut d = Deserializer::new(s.into_vec(), Vec::new());
+    assert!(unsafe { d.deserialize::<Vec<u8>>() }.is_err());
+}
+
+#[test]
+fn paths() {
+    use std::ffi::{CStr, CString, OsStr, OsString};
+    use std::path::{Path, PathBuf};
+
+    test_idempotency(PathBuf::from("/tmp/crossmist/path"));
+    test_idempotency(OsString::from("string"));
+    test_idempotency(CString::new("c string").unwrap());
+    test_idempotency(Cow::<'static, Path>::Borrowed(Path::new("borrowed")));
+    test_idempotency(Cow::<'static, OsStr>::Borrowed(OsStr::new("borrowed")));
+    test_idempotency(Cow::<'static, CStr>::Borrowed(c"borrowed"));
+    assert!(matches!(
+        serde(&Cow::<'static, Path>::Borrowed(Path::new("borrowed"))),
+        Cow::Owned(_)
+    ));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let raw = OsStr::from_bytes(b"/tmp/\xff\xfe/non-utf8");
+        assert!(raw.to_str().is_none());
+        let path = serde(&PathBuf::from(raw));
+        assert_eq!(path.as_os_str().as_bytes(), raw.as_bytes());
+        test_idempotency(raw.to_os_string());
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::{OsStrExt, OsStringExt};
+        // An unpaired surrogate
+        let wide = [0x61, 0xd800, 0x62];
+        let raw = OsString::from_wide(&wide);
+        assert!(raw.to_str().is_none());
+        let path = serde(&PathBuf::from(raw));
+        assert_eq!(path.as_os_str().encode_wide().collect::<Vec<_>>(), wide);
+    }
+}
+
+#[test]
+fn time() {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    test_idempotency(Duration::ZERO);
+    test_idempotency(Duration::MAX);
+    test_idempotency(Duration::new(1, 999_999_999));
+    test_idempotency(UNIX_EPOCH);
+    test_idempotency(SystemTime::now());
+    test_idempotency(UNIX_EPOCH - Duration::from_nanos(100));
+    test_idempotency(UNIX_EPOCH - Duration::new(86400 * 365 * 100, 500));
+    test_idempotency(UNIX_EPOCH + Duration::new(86400 * 365 * 1000, 100));
+}
+
+#[test]
+fn io_errors() {
+    use std::io::{Error, ErrorKind};
+
+    fn check(error: Error) {
+        let received = serde(&error);
+        assert_eq!(received.kind(), error.kind());
+        assert_eq!(received.raw_os_error(), error.raw_os_error());
+        assert_eq!(received.to_string(), error.to_string());
+    }
+
+    #[cfg(unix)]
+    check(Error::from_raw_os_error(libc::ENOENT));
+    check(Error::from(ErrorKind::UnexpectedEof));
+    check(Error::new(ErrorKind::InvalidData, "bad data"));
+    check(Error::other("something else"));
+    check(Error::new(ErrorKind::ResourceBusy, Error::other("inner")));
+
+    test_idempotency(ErrorKind::NotFound);
+    test_idempotency(ErrorKind::Other);
+
+    let result: Result<(), Error> = serde(&Err(Error::new(ErrorKind::TimedOut, "late")));
+    let error = result.unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::TimedOut);
+    assert_eq!(error.to_string(), "late");
+    test_idempotency(Some(Result::<u32, SimplePair>::Err(SimplePair {
+        x: 1,
+        y: 2,
+    })));
+    test_idempotency(Some(Result::<u32, SimplePair>::Ok(3)));
+}
+
+#[test]
+fn nonzero() {
+    use std::num::{NonZeroI128, NonZeroI8, NonZeroU32, NonZeroUsize};
+
+    test_idempotency(NonZeroI8::new(-128).unwrap());
+    test_idempotency(NonZeroU32::new(1).unwrap());
+    test_idempotency(NonZeroUsize::MAX);
+    test_idempotency(NonZeroI128::MIN);
+    test_idempotency(Some(NonZeroU32::new(5).unwrap()));
+    test_idempotency(None::<NonZeroU32>);
+
+    let mut s = Serializer::new();
+    s.serialize(&0u32);
+    let mut d = Deserializer::new(s.into_vec(), Vec::new());
+    assert!(unsafe { d.deserialize::<NonZeroU32>() }.is_err());
+}
+
+#[test]
+fn atomics() {
+    use std::sync::atomic::{AtomicBool, AtomicI32, AtomicI8, AtomicU64, AtomicUsize, Ordering};
+
+    assert!(serde(&AtomicBool::new(true)).into_inner());
+    assert_eq!(serde(&AtomicI8::new(i8::MIN)).into_inner(), i8::MIN);
+    assert_eq!(serde(&AtomicI32::new(-5)).into_inner(), -5);
+    assert_eq!(
+        serde(&AtomicUsize::new(usize::MAX)).into_inner(),
+        usize::MAX
+    );
+
+    // The copy is independent of the original
+    let counter = AtomicU64::new(1);
+    let copy = serde(&counter);
+    counter.fetch_add(1, Ordering::SeqCst);
+    assert_eq!(copy.load(Ordering::SeqCst), 1);
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+    #[derive(Object)]
+    struct Stats {
+        name: String,
+        hits: AtomicU64,
+    }
+    let stats = serde(&Stats {
+        name: "cache".to_string(),
+        hits: AtomicU64::new(57),
+    });
+    assert_eq!(stats.name, "cache");
+    assert_eq!(stats.hits.into_inner(), 57);
+}
+
+#[test]
+fn wrappers() {
+    use std::cmp::Reverse;
+    use std::num::{Saturating, Wrapping};
+    use std::ops::{Bound, ControlFlow};
+    use std::task::Poll;
+
+    test_idempotency(Wrapping(u8::MAX));
+    test_idempotency(Saturating(-5i64));
+    test_idempotency(Reverse("hello".to_string()));
+    test_idempotency(Bound::Included(1u32));
+    test_idempotency(Bound::Excluded("end".to_string()));
+    test_idempotency(Bound::<u32>::Unbounded);
+    test_idempotency(ControlFlow::<String, u8>::Continue(7));
+    test_idempotency(ControlFlow::<String, u8>::Break("stop".to_string()));
+    test_idempotency(Poll::Ready(vec![1, 2, 3]));
+    test_idempotency(Poll::<Vec<i32>>::Pending);
+}
+
+#[test]
+fn socket_addresses() {
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+    let v6 = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 8080, 0x12345, 7);
+    test_idempotency(v6);
+    test_idempotency(IpAddr::from([10, 0, 0, 1]));
+    test_idempotency(IpAddr::from(Ipv6Addr::LOCALHOST));
+    test_idempotency(SocketAddr::from(([127, 0, 0, 1], 80)));
+    test_idempotency(SocketAddr::from(v6));
+
+    // Only the fields are sent, not the padding between them
+    assert_eq!(serialized_len(&v6), 16 + 2 + 4 + 4);
+    assert_eq!(serialized_len(&IpAddr::from([10, 0, 0, 1])), 1 + 4);
+}
+
+#[test]
+fn bound_unknown_variant() {
+    use std::ops::Bound;
+
+    let mut s = Serializer::new();
+    s.serialize(&3u8);
+    let mut d = Deserializer::new(s.into_vec(), Vec::new());
+    let error = unsafe { d.deserialize::<Bound<String>>() }.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "capture")]
+#[test]
+fn capture_invalid_frame() {
+    use crossmist::capture::{InvalidFrame, MAX_CAPTURED_BYTES};
+    use crossmist::handles::{FromRawHandle, IntoRawHandle};
+    use crossmist::{channel, Receiver};
+    use std::num::NonZeroU8;
+
+    let (mut tx, rx) = channel::<Vec<u8>>().unwrap();
+    let mut rx = unsafe { Receiver::<Vec<NonZeroU8>>::from_raw_handle(rx.into_raw_handle()) };
+    let mut data = vec![1u8; MAX_CAPTURED_BYTES * 2];
+    data[MAX_CAPTURED_BYTES] = 0;
+    tx.send(&data).unwrap();
+
+    let error = rx.recv().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    let frame = error
+        .get_ref()
+        .unwrap()
+        .downcast_ref::<InvalidFrame>()
+        .unwrap();
+    assert!(frame.is_truncated());
+    assert_eq!(frame.len(), std::mem::size_of::<usize>() + data.len());
+    assert_eq!(frame.frame().len(), MAX_CAPTURED_BYTES);
+    assert_eq!(
+        &frame.frame()[..std::mem::size_of::<usize>()],
+        data.len().to_ne_bytes()
+    );
+}
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn assert_exact_size_hint<T: Object>(x: &T) {
+    assert_eq!(Object::serialized_size_hint(x), Some(serialized_len(x)));
+}
+
+#[test]
+fn size_hint() {
+    assert_exact_size_hint(&0x123456789abcdefi64);
+    assert_exact_size_hint(&"hello".to_string());
+    assert_exact_size_hint(&SimplePair { x: 5, y: 7 });
+    assert_exact_size_hint(&(vec![1, 2, 3], Box::new([4, 5, 6])));
+    assert_exact_size_hint(&vec!["a".to_string(), "bc".to_string()]);
+    assert_exact_size_hint(&Some((1u8, "x".to_string())));
+    assert_exact_size_hint(&Generic::B {
+        x: 1u16,
+        y: "y".to_string(),
+    });
+    assert_exact_size_hint(&Generic::<String>::C);
+    assert_exact_size_hint(&List::Cons(1, Box::new(List::Cons(2, Box::new(List::Nil)))));
+    assert_exact_size_hint(&Single::Only("only".to_string()));
+    assert_exact_size_hint(&HashMap::from([("a".to_string(), vec![1u32, 2])]));
+    assert_exact_size_hint(&std::result::Result::<String, u8>::Ok("ok".to_string()));
+    assert_exact_size_hint(&vec![0u8; 100000].into_boxed_slice());
+
+    // Shared pointers are deduplicated, so their size depends on the rest of the message
+    assert_eq!(Object::serialized_size_hint(&Arc::new(1)), None);
+}
+
+#[test]
+fn size_hint_preallocation() {
+    let value: Vec<u64> = (0..100000).collect();
+    let allocations = ALLOCATIONS.with(|count| count.get());
+    let mut s = Serializer::new();
+    s.serialize(&value);
+    let data = s.into_vec();
+    assert_eq!(ALLOCATIONS.with(|count| count.get()) - allocations, 1);
+    assert_eq!(data.len(), std::mem::size_of::<usize>() + 800000);
+}
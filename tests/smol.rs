@@ -1,5 +1,6 @@
 use crossmist::smol::{channel, duplex, Duplex, Receiver, Sender};
-use crossmist::{FnOnceObject, Object};
+use crossmist::{FnOnceObject, Object, RequestError};
+use std::time::Duration;
 
 #[ctor::ctor]
 fn ctor() {
@@ -12,7 +13,7 @@ struct SimplePair {
     y: i32,
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn simple() {
     #[crossmist::func(smol)]
     async fn inner() -> i64 {
@@ -25,7 +26,7 @@ async fn simple() {
     );
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn add_with_arguments() {
     #[crossmist::func(smol)]
     async fn inner(x: i32, y: i32) -> i32 {
@@ -38,7 +39,7 @@ async fn add_with_arguments() {
     assert_eq!(inner.call_object_once((5, 7)).await, 12);
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn swap_complex_argument() {
     #[crossmist::func(smol)]
     async fn inner(pair: SimplePair) -> SimplePair {
@@ -59,7 +60,7 @@ async fn swap_complex_argument() {
     );
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn with_passed_rx() {
     #[crossmist::func(smol)]
     async fn inner(mut rx: Receiver<i32>) -> i32 {
@@ -74,7 +75,7 @@ async fn with_passed_rx() {
     assert_eq!(child.join().await.unwrap(), -2);
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn with_passed_tx() {
     #[crossmist::func(smol)]
     async fn inner(mut tx: Sender<i32>) {
@@ -90,7 +91,7 @@ async fn with_passed_tx() {
     child.join().await.unwrap();
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn with_passed_duplex() {
     #[crossmist::func(smol)]
     async fn inner(mut chan: Duplex<i32, (i32, i32)>) {
@@ -108,7 +109,7 @@ async fn with_passed_duplex() {
     child.join().await.unwrap();
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn with_passed_nested_channel() {
     #[crossmist::func(smol)]
     async fn inner(mut chan: Receiver<Receiver<i32>>) -> i32 {
@@ -122,7 +123,7 @@ async fn with_passed_nested_channel() {
     assert_eq!(inner.run_smol(rx1).await.unwrap(), 5);
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn with_async_write() {
     #[crossmist::func(smol)]
     async fn inner(mut tx_data: Sender<i32>, mut tx_signal: Sender<()>) {
@@ -144,7 +145,7 @@ async fn with_async_write() {
     child.join().await.unwrap();
 }
 
-#[macro_rules_attribute::apply(smol_macros::main!)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
 async fn exitting() {
     #[crossmist::func(smol)]
     async fn inner() {
@@ -152,3 +153,128 @@ async fn exitting() {
     }
     inner.run_smol().await.unwrap();
 }
+
+#[macro_rules_attribute::apply(smol_macros::test!)]
+async fn request_timeout() {
+    #[crossmist::func(smol)]
+    async fn inner(mut chan: Duplex<u64, u64>) {
+        while let Some(delay) = chan.recv().await.unwrap() {
+            smol::Timer::after(Duration::from_millis(delay)).await;
+            chan.send(&delay).await.unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<u64, u64>().unwrap();
+    let child = inner.spawn_smol(downstream).await.unwrap();
+    assert!(matches!(
+        local.request_timeout(&500, Duration::from_millis(50)).await,
+        Err(RequestError::Timeout)
+    ));
+    assert_eq!(
+        local
+            .request_timeout(&1, Duration::from_secs(5))
+            .await
+            .unwrap(),
+        1
+    );
+    drop(local);
+    child.join().await.unwrap();
+}
+
+#[cfg(unix)]
+#[macro_rules_attribute::apply(smol_macros::test!)]
+async fn receiver_stream() {
+    use smol::stream::StreamExt;
+
+    let (mut tx, rx) = channel::<i32>().unwrap();
+    for i in 0..10 {
+        tx.send(&i).await.unwrap();
+    }
+    drop(tx);
+    let values: Vec<i32> = rx.map(Result::unwrap).collect().await;
+    assert_eq!(values, (0..10).collect::<Vec<_>>());
+}
+
+#[macro_rules_attribute::apply(smol_macros::test!)]
+async fn streaming() {
+    #[crossmist::func(smol)]
+    async fn squares(n: u32) -> impl smol::stream::Stream<Item = u32> {
+        smol::Timer::after(Duration::from_millis(10)).await;
+        smol::stream::iter((0..n).map(|i| i * i))
+    }
+
+    let mut stream = squares.spawn_smol(5).await.unwrap();
+    let mut items = Vec::new();
+    while let Some(item) = stream.next().await {
+        items.push(item.unwrap());
+    }
+    assert_eq!(items, [0, 1, 4, 9, 16]);
+    assert!(stream.next().await.is_none());
+    squares.spawn_smol(100).await.unwrap().join().await.unwrap();
+}
+
+#[cfg(feature = "futures-io")]
+#[macro_rules_attribute::apply(smol_macros::test!)]
+async fn byte_stream() {
+    use crossmist::byte_stream::{self, SendError};
+    use smol::io::AsyncWriteExt;
+    use smol::net::{TcpListener, TcpStream};
+
+    #[crossmist::func(smol)]
+    async fn square_server(port: u16) {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut chan = unsafe { byte_stream::Duplex::<_, u64, u64>::new(stream) };
+        while let Some(x) = chan.recv().await.unwrap() {
+            chan.send(&(x * x)).await.unwrap();
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let child = square_server.spawn_smol(port).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut chan = unsafe { byte_stream::Duplex::<_, u64, u64>::new(stream) };
+    for x in [3, 1 << 20] {
+        assert_eq!(chan.request(&x).await.unwrap(), x * x);
+    }
+    drop(chan);
+    child.join().await.unwrap();
+
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let mut tx = unsafe { byte_stream::Sender::<_, Vec<u8>>::new(client.clone()) };
+    let mut rx = unsafe { byte_stream::Receiver::<_, Vec<u8>>::new(server) };
+
+    // Handles cannot be transferred
+    let (local, _remote) = channel::<i32>().unwrap();
+    let mut handle_tx = unsafe { byte_stream::Sender::<_, Sender<i32>>::new(client.clone()) };
+    assert!(matches!(
+        handle_tx.send(&local).await,
+        Err(SendError::HandlesNotSupported)
+    ));
+
+    tx.send(&vec![1; 100]).await.unwrap();
+    assert_eq!(rx.recv().await.unwrap(), Some(vec![1; 100]));
+    rx.set_max_message_size(Some(10));
+    tx.send(&vec![2; 100]).await.unwrap();
+    assert_eq!(
+        rx.recv().await.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+
+    // A message cut short is reported as such
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let mut rx = unsafe { byte_stream::Receiver::<_, Vec<u8>>::new(server) };
+    let mut writer = client;
+    writer.write_all(&100u64.to_le_bytes()).await.unwrap();
+    writer.write_all(&[0; 10]).await.unwrap();
+    drop(writer);
+    assert_eq!(
+        rx.recv().await.unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+}
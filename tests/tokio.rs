@@ -1,5 +1,9 @@
-use crossmist::tokio::{channel, duplex, Duplex, Receiver, Sender};
-use crossmist::{FnOnceObject, Object};
+use crossmist::tokio::{
+    channel, duplex, ready_signal, Duplex, MultiplexedDuplex, Receiver, Sender, SharedDuplex,
+};
+use crossmist::{FnOnceObject, Object, ReadySignal, RequestError, RequestId};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[ctor::ctor]
 fn ctor() {
@@ -152,3 +156,360 @@ async fn exitting() {
     }
     inner.run_tokio().await.unwrap();
 }
+
+#[tokio::test(flavor = "current_thread")]
+async fn request_timeout() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn inner(mut chan: Duplex<u64, u64>) {
+        while let Some(delay) = chan.recv().await.unwrap() {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            chan.send(&delay).await.unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<u64, u64>().unwrap();
+    let child = inner.spawn_tokio(downstream).await.unwrap();
+    assert!(matches!(
+        local.request_timeout(&500, Duration::from_millis(50)).await,
+        Err(RequestError::Timeout)
+    ));
+    assert_eq!(
+        local
+            .request_timeout(&1, Duration::from_secs(5))
+            .await
+            .unwrap(),
+        1
+    );
+    drop(local);
+    child.join().await.unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn request_timeout_partial() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn inner(mut chan: Duplex<Vec<u8>, usize>) {
+        while let Some(len) = chan.recv().await.unwrap() {
+            chan.send(&vec![len as u8; len]).await.unwrap();
+        }
+    }
+    let (mut local, downstream) = duplex::<usize, Vec<u8>>().unwrap();
+    let child = inner.spawn_tokio(downstream).await.unwrap();
+    // The reply takes a while to arrive as a whole, so the request most likely times out after a
+    // part of it has been received
+    let len = 16 * 1024 * 1024;
+    match local
+        .request_timeout(&len, Duration::from_micros(100))
+        .await
+    {
+        Ok(reply) => assert_eq!(reply.len(), len),
+        Err(RequestError::Timeout) => {}
+        Err(e) => panic!("{e}"),
+    }
+    assert_eq!(
+        local
+            .request_timeout(&3, Duration::from_secs(5))
+            .await
+            .unwrap(),
+        vec![3; 3]
+    );
+    drop(local);
+    child.join().await.unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn multiplexed_requests() {
+    #[crossmist::func]
+    fn inner(mut chan: crossmist::Duplex<(RequestId, u32), (RequestId, u32)>) {
+        let mut requests = Vec::new();
+        for _ in 0..100 {
+            requests.push(chan.recv().unwrap().unwrap());
+        }
+        for (id, value) in requests.into_iter().rev() {
+            chan.send(&(id, value * 2)).unwrap();
+        }
+        // Exit without responding to the rest
+        for _ in 0..2 {
+            chan.recv().unwrap().unwrap();
+        }
+    }
+    let (local, downstream) = crossmist::duplex::<(RequestId, u32), (RequestId, u32)>().unwrap();
+    let child = inner.spawn_tokio(downstream).await.unwrap();
+    let local = Arc::new(MultiplexedDuplex::new(local.try_into().unwrap()).unwrap());
+
+    let tasks: Vec<_> = (0..100)
+        .map(|i| {
+            let local = local.clone();
+            tokio::spawn(async move { local.request(i).await.unwrap() == i * 2 })
+        })
+        .collect();
+    for task in tasks {
+        assert!(task.await.unwrap());
+    }
+
+    let tasks: Vec<_> = (0..2)
+        .map(|i| {
+            let local = local.clone();
+            tokio::spawn(async move { local.request(i).await })
+        })
+        .collect();
+    for task in tasks {
+        assert!(matches!(task.await.unwrap(), Err(RequestError::PeerClosed)));
+    }
+
+    child.join().await.unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn feed_and_flush() {
+    let (mut tx, mut rx) = channel::<String>().unwrap();
+    for i in 0..100 {
+        tx.feed(&i.to_string()).unwrap();
+    }
+    tx.send(&"end".to_string()).await.unwrap();
+    for i in 0..100 {
+        assert_eq!(rx.recv().await.unwrap(), Some(i.to_string()));
+    }
+    assert_eq!(rx.recv().await.unwrap(), Some("end".to_string()));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn select_recv() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn inner(mut tx: Sender<u64>, delay: u64) {
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+        tx.send(&delay).await.unwrap();
+    }
+    let mut delays = vec![200, 0, 100];
+    let mut receivers = Vec::new();
+    let mut children = Vec::new();
+    for &delay in &delays {
+        let (tx, rx) = channel::<u64>().unwrap();
+        children.push(inner.spawn_tokio(tx, delay).await.unwrap());
+        receivers.push(rx);
+    }
+    let mut values = Vec::new();
+    while !receivers.is_empty() {
+        let mut refs: Vec<&mut Receiver<u64>> = receivers.iter_mut().collect();
+        match crossmist::tokio::select_recv(&mut refs).await.unwrap() {
+            (i, Some(value)) => {
+                assert_eq!(value, delays[i]);
+                values.push(value);
+            }
+            (i, None) => {
+                receivers.remove(i);
+                delays.remove(i);
+            }
+        }
+    }
+    assert_eq!(values, [0, 100, 200]);
+    for child in children {
+        child.join().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn wait_ready() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn inner(ready: ReadySignal<String>, mut rx: Receiver<i32>) -> i32 {
+        ready.signal("ready".to_string()).unwrap();
+        rx.recv().await.unwrap().unwrap()
+    }
+    let (signal, waiter) = ready_signal::<String>().unwrap();
+    let (mut tx, rx) = channel::<i32>().unwrap();
+    let child = inner.spawn_tokio(signal, rx).await.unwrap();
+    assert_eq!(waiter.wait().await.unwrap(), "ready");
+    tx.send(&5).await.unwrap();
+    assert_eq!(child.join().await.unwrap(), 5);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn join_all_in_order() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn inner(mut release: Receiver<()>, value: u32) -> u32 {
+        release.recv().await.unwrap();
+        value
+    }
+
+    let mut releases = Vec::new();
+    let mut children = Vec::new();
+    for value in [10, 20, 30] {
+        let (tx, rx) = channel::<()>().unwrap();
+        releases.push(tx);
+        children.push(inner.spawn_tokio(rx, value).await.unwrap());
+    }
+
+    let mut results = crossmist::tokio::join_all_in_order(children);
+    assert_eq!(results.remaining(), 3);
+    for index in [1, 2, 0] {
+        releases[index].send(&()).await.unwrap();
+        let (joined, value) = results.next().await.unwrap();
+        assert_eq!(joined, index);
+        assert_eq!(value.unwrap(), (index as u32 + 1) * 10);
+    }
+    assert!(results.next().await.is_none());
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn deferred_handshake() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn double(x: u32) -> u32 {
+        x * 2
+    }
+
+    let options = crossmist::SpawnOptions::new();
+    let pending: Vec<_> = (0..5)
+        .map(|x| double.spawn_tokio_deferred_with(&options, x).unwrap())
+        .collect();
+    let mut results = Vec::new();
+    for child in pending {
+        results.push(child.finish().await.unwrap().join().await.unwrap());
+    }
+    assert_eq!(results, [0, 2, 4, 6, 8]);
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "current_thread")]
+async fn cancelled_handshake() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn sleep_forever() {
+        std::future::pending::<()>().await;
+    }
+
+    let pending = sleep_forever
+        .spawn_tokio_deferred_with(&crossmist::SpawnOptions::new())
+        .unwrap();
+    let pid = pending.id();
+    // A stopped child cannot confirm the build, so the handshake is cancelled while waiting
+    unsafe {
+        libc::kill(pid, libc::SIGSTOP);
+    }
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), pending.finish())
+            .await
+            .is_err()
+    );
+
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    while unsafe { libc::kill(pid, 0) } == 0 {
+        assert!(std::time::Instant::now() < deadline, "Child is not killed");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "current_thread")]
+async fn poll_channels() {
+    use std::future::poll_fn;
+    use std::task::Poll;
+
+    let (mut tx, mut rx) = channel::<String>().unwrap();
+    assert!(poll_fn(|cx| Poll::Ready(rx.poll_recv(cx).is_pending())).await);
+
+    poll_fn(|cx| tx.poll_send_ready(cx)).await.unwrap();
+    tx.start_send(&"hello".to_string()).unwrap();
+    poll_fn(|cx| tx.poll_flush(cx)).await.unwrap();
+    assert_eq!(
+        poll_fn(|cx| rx.poll_recv(cx)).await.unwrap(),
+        Some("hello".to_string())
+    );
+
+    // A pending receiver is woken up once a value arrives
+    let receiving = tokio::spawn(async move { poll_fn(|cx| rx.poll_recv(cx)).await.unwrap() });
+    tokio::task::yield_now().await;
+    tx.send(&"world".to_string()).await.unwrap();
+    assert_eq!(receiving.await.unwrap(), Some("world".to_string()));
+
+    // More data than fits into the socket buffer, so flushing has to wait for the other side
+    let (mut local, mut remote) = duplex::<Vec<u8>, Vec<u8>>().unwrap();
+    for i in 0..100 {
+        local.start_send(&vec![i; 100000]).unwrap();
+    }
+    assert!(poll_fn(|cx| Poll::Ready(local.poll_flush(cx).is_pending())).await);
+    let receiving = tokio::spawn(async move {
+        for i in 0..100 {
+            assert_eq!(remote.recv().await.unwrap(), Some(vec![i; 100000]));
+        }
+        remote.send(&vec![]).await.unwrap();
+    });
+    poll_fn(|cx| local.poll_flush(cx)).await.unwrap();
+    assert_eq!(
+        poll_fn(|cx| local.poll_recv(cx)).await.unwrap(),
+        Some(vec![])
+    );
+    receiving.await.unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn shared_duplex() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn reply_after(mut chan: Duplex<u32, u32>, n: u32) {
+        let mut sum = 0;
+        for _ in 0..n {
+            sum += chan.recv().await.unwrap().unwrap();
+        }
+        chan.send(&sum).await.unwrap();
+    }
+
+    let (local, remote) = duplex::<u32, u32>().unwrap();
+    let child = reply_after.spawn_tokio(remote, 10).await.unwrap();
+    let chan = SharedDuplex::new(local).unwrap();
+    // The reply is awaited while the messages it depends on are still being sent
+    let (sent, received) = tokio::join!(
+        async {
+            for i in 0..10 {
+                chan.send(&i).await?;
+                tokio::task::yield_now().await;
+            }
+            Ok::<_, std::io::Error>(())
+        },
+        chan.recv(),
+    );
+    sent.unwrap();
+    assert_eq!(received.unwrap(), Some(45));
+    assert_eq!(chan.recv().await.unwrap(), None);
+    child.join().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "current_thread")]
+async fn join_does_not_block_runtime() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn linger() {
+        // Close the channel to the parent early, so that the parent has to wait for the exit
+        for fd in 3..1024 {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+        std::thread::sleep(Duration::from_millis(500));
+        std::process::exit(0);
+    }
+
+    let child = linger.spawn_tokio().await.unwrap();
+    let joining = tokio::spawn(child.join());
+    let mut ticks = 0;
+    while !joining.is_finished() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        ticks += 1;
+    }
+    joining.await.unwrap().unwrap();
+    assert!(ticks >= 10, "the runtime was blocked, only {ticks} ticks");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn spawn_with_piped_stdout() {
+    #[crossmist::func(tokio(flavor = "current_thread"))]
+    async fn print(count: u32) {
+        for i in 0..count {
+            println!("line {i}");
+        }
+    }
+
+    let options = crossmist::SpawnOptions::new().stdout(crossmist::Stdio::piped());
+    let mut child = print.spawn_tokio_with(&options, 1000).await.unwrap();
+    let mut stdout = child.take_stdout().unwrap().unwrap();
+    let mut output = String::new();
+    stdout.read_to_string(&mut output).await.unwrap();
+    child.join().await.unwrap();
+    let expected: String = (0..1000).map(|i| format!("line {i}\n")).collect();
+    assert_eq!(output, expected);
+}